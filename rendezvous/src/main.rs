@@ -24,9 +24,9 @@ use std::{error::Error, time::Duration};
 
 use futures::StreamExt;
 use libp2p::{
-    identify, noise, ping, rendezvous,
+    autonat, identify, noise, ping, relay, rendezvous,
     swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    tcp, yamux, Multiaddr,
 };
 use tracing_subscriber::EnvFilter;
 
@@ -40,6 +40,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // used as the rendezvous point by the other peer examples.
     let keypair = libp2p::identity::Keypair::ed25519_from_bytes([0; 32]).unwrap();
 
+    // The server also relays connections between peers that can't dial each other directly
+    // (until they manage a direct connection by hole punching). Relays hand out their own
+    // addresses, so the public address can be given with `--external-address <multiaddr>`;
+    // without one, the addresses the server listens on are used.
+    let external_addresses = external_addresses_from_args()?;
+
     let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
@@ -54,13 +60,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
             )),
             rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
             ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(1))),
+            relay: relay::Behaviour::new(key.public().to_peer_id(), relay_config()),
+            // Dials peers back on the addresses they ask about, so they learn whether they are
+            // reachable; the server never asks anyone itself.
+            autonat: autonat::Behaviour::new(
+                key.public().to_peer_id(),
+                autonat::Config {
+                    use_connected: false,
+                    ..autonat::Config::default()
+                },
+            ),
         })?
         .build();
 
     let _ = swarm.listen_on("/ip4/0.0.0.0/tcp/62649".parse().unwrap());
+    for address in &external_addresses {
+        swarm.add_external_address(address.clone());
+    }
 
     while let Some(event) = swarm.next().await {
         match event {
+            SwarmEvent::NewListenAddr { address, .. } if external_addresses.is_empty() => {
+                tracing::info!("Listening on {}", address);
+                swarm.add_external_address(address);
+            }
             SwarmEvent::ConnectionEstablished { peer_id, .. } => {
                 tracing::info!("Connected to {}", peer_id);
             }
@@ -88,6 +111,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     registrations.len()
                 );
             }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(
+                relay::Event::ReservationReqAccepted { src_peer_id, .. },
+            )) => {
+                tracing::info!("Relaying for peer {}", src_peer_id);
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            })) => {
+                tracing::info!("Relaying a connection from {} to {}", src_peer_id, dst_peer_id);
+            }
             other => {
                 tracing::debug!("Unhandled {:?}", other);
             }
@@ -97,9 +131,37 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Limits on what the server relays: enough for chat and file offers between SwapBytes peers,
+/// while file data waits for a direct connection.
+fn relay_config() -> relay::Config {
+    relay::Config {
+        max_circuit_duration: Duration::from_secs(30 * 60),
+        max_circuit_bytes: 4 * 1024 * 1024,
+        ..relay::Config::default()
+    }
+}
+
+/// Reads the `--external-address <multiaddr>` arguments (repeatable).
+fn external_addresses_from_args() -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    let mut addresses = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--external-address" => {
+                let address = args.next().ok_or("--external-address needs a multiaddr")?;
+                addresses.push(address.parse()?);
+            }
+            other => return Err(format!("Unknown argument '{}'", other).into()),
+        }
+    }
+    Ok(addresses)
+}
+
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
     identify: identify::Behaviour,
     rendezvous: rendezvous::server::Behaviour,
     ping: ping::Behaviour,
+    relay: relay::Behaviour,
+    autonat: autonat::Behaviour,
 }
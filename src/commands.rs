@@ -3,24 +3,28 @@ Handles all the slash commands (e.g. /ping, /setname, /chat).
 */
 
 
-use crate::tui::{App, AppEvent, ChatContext, FocusPane, InputMode, OnlineStatus};
+use crate::tui::{App, ChatContext, FocusPane, InputMode, OnlineStatus};
+use swapbytes_core::event::{Reachability, SwapDetails, TransferAction};
+use swapbytes_core::protocol::TransferDirection;
+use swapbytes_core::ratelimit::LimitScope;
+use swapbytes_core::{bundle, identity, swap, utils, Command};
 use libp2p::{Multiaddr, PeerId};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 /// Processes a command entered by the user in the console input.
 ///
 /// Takes the raw text after the '/' and the main application state.
 /// It figures out the command and any arguments, updates the app state accordingly
-/// (like adding messages to the console log), and sometimes returns an `AppEvent`.
-/// These events signal actions that need to be handled elsewhere, like sending network
-/// messages (Dial, NicknameUpdate) or quitting the app.
-pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
+/// (like adding messages to the console log), and sometimes returns a `Command`
+/// for the swarm task, like sending network messages (Dial, SetNickname).
+pub fn process_command(command_input: &str, app: &mut App) -> Option<Command> {
     // Split the input into the command name (like "ping") and the rest (arguments).
     let command_parts: Vec<&str> = command_input.trim().splitn(2, ' ').collect();
-    let command_name = *command_parts.get(0).unwrap_or(&""); // The command itself, e.g., "ping"
+    let command_name = *command_parts.first().unwrap_or(&""); // The command itself, e.g., "ping"
     let args = command_parts.get(1).unwrap_or(&"").trim(); // The arguments, e.g., "12D..."
 
-    let mut event_to_send = None; // This will hold an event if the command needs to trigger a network action or quit.
+    let mut command_to_send = None; // This will hold a command if a network action is needed.
 
     // Figure out which command was entered and run the corresponding code.
     match command_name {
@@ -39,7 +43,7 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
                         app.ping_start_time = Some(Instant::now()); // Record when we started.
                         app.push(format!("Attempting ping to: {}", args));
                         // Prepare an event to tell the network task to actually send the ping.
-                        event_to_send = Some(AppEvent::Dial(addr));
+                        command_to_send = Some(Command::Dial(addr));
                     }
                     Err(e) => {
                         // If the address is not valid, tell the user.
@@ -58,15 +62,42 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
             if app.listening_addresses.is_empty() {
                 app.push("  (Not listening on any addresses right now)".to_string());
             } else {
-                // Format and print each address neatly.
+                // Format and print each address neatly, with the transport it is reached over.
                 let addrs_to_print: Vec<String> = app.listening_addresses
                     .iter()
-                    .map(|addr| format!("  {}", addr))
+                    .map(|addr| format!("  {} ({})", addr, utils::transport_name(addr)))
                     .collect();
                 for addr_str in addrs_to_print {
                     app.push(addr_str);
                 }
             }
+            // Show the addresses peers see us at, and whether they can dial us there
+            if !app.observed_addresses.is_empty() {
+                app.push("Peers see you at:".to_string());
+                let observed: Vec<String> = app.observed_addresses.iter().map(|addr| format!("  {}", addr)).collect();
+                for addr_str in observed {
+                    app.push(addr_str);
+                }
+            }
+            let reachability = match &app.reachability {
+                Reachability::Public(addr) => format!("Reachability: public, peers can dial you at {}", addr),
+                Reachability::Private => "Reachability: private (behind a NAT or firewall), peers reach you through relays".to_string(),
+                Reachability::Unknown => "Reachability: unknown (no peer has been able to check yet)".to_string(),
+            };
+            app.push(reachability);
+            // Only these are registered with Rendezvous points
+            if app.external_addresses.is_empty() {
+                app.push("Confirmed external addresses: none yet, so you are not registered with a Rendezvous point".to_string());
+            } else {
+                app.push("Confirmed external addresses (registered with Rendezvous points):".to_string());
+                let confirmed: Vec<String> = app.external_addresses
+                    .iter()
+                    .map(|addr| format!("  {} ({})", addr, utils::transport_name(addr)))
+                    .collect();
+                for addr_str in confirmed {
+                    app.push(addr_str);
+                }
+            }
             // Show Peer ID
             match &app.local_peer_id {
                 Some(id) => app.push(format!("Peer ID: {}", id)),
@@ -86,6 +117,61 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
             app.push(format!("Visibility: {}", if app.is_visible { "Online" } else { "Hidden" }));
         }
 
+        // -------------------------------------
+        // Command: /id [export <path> | rotate]
+        // Shows, exports or replaces the persistent identity keypair.
+        "id" => {
+            let id_parts: Vec<&str> = args.splitn(2, ' ').collect();
+            let subcommand = id_parts.first().copied().unwrap_or("");
+            let sub_args = id_parts.get(1).copied().unwrap_or("").trim();
+
+            match (subcommand, app.identity_path.clone()) {
+                // Show the current identity.
+                ("", identity_path) => {
+                    match &app.local_peer_id {
+                        Some(id) => app.push(format!("Peer ID: {}", id)),
+                        None => app.push("Peer ID: (Unknown - this shouldn't happen)".to_string()),
+                    }
+                    match identity_path {
+                        Some(path) => app.push(format!("Identity file: {}", path.display())),
+                        None => app.push("Identity file: (None - using a temporary identity)".to_string()),
+                    }
+                }
+                // Neither export nor rotate make sense without a key file.
+                ("export" | "rotate", None) => {
+                    app.push("Error: This session uses a temporary identity that is not stored on disk.".to_string());
+                }
+                // Copy the key file to a new location.
+                ("export", Some(identity_path)) => {
+                    if sub_args.is_empty() {
+                        app.push("Usage: /id export <path>".to_string());
+                    } else {
+                        match identity::export_keypair(&identity_path, std::path::Path::new(sub_args)) {
+                            Ok(dest) => {
+                                app.push(format!("Identity exported to: {}", dest.display()));
+                                app.push("Keep this file private - anyone holding it can impersonate you.".to_string());
+                            }
+                            Err(err_msg) => app.push(format!("Error exporting identity: {}", err_msg)),
+                        }
+                    }
+                }
+                // Replace the key file with a new keypair.
+                ("rotate", Some(identity_path)) => {
+                    match identity::rotate_keypair(&identity_path) {
+                        Ok((new_peer_id, backup_path)) => {
+                            app.push(format!("New identity generated: {}", new_peer_id));
+                            app.push(format!("Old identity backed up to: {}", backup_path.display()));
+                            app.push("Restart SwapBytes to start using the new identity.".to_string());
+                        }
+                        Err(err_msg) => app.push(format!("Error rotating identity: {}", err_msg)),
+                    }
+                }
+                _ => {
+                    app.push("Usage: /id [export <path> | rotate]".to_string());
+                }
+            }
+        }
+
         // -------------------------------------
         // Command: /setdir <absolute_path>
         // Sets the directory where downloaded files will be saved.
@@ -94,13 +180,13 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
                 app.push("Usage: /setdir <absolute_path>".to_string());
             } else {
                 // Check if the provided path is a valid, writable directory.
-                match crate::utils::verify_download_directory(args) {
+                match utils::verify_download_directory(args) {
                     Ok(verified_path) => {
                         // If valid, update the app state and tell the user.
                         app.push(format!("Download directory set to: {}", verified_path.display()));
                         app.download_dir = Some(verified_path.clone()); // Clone here
                         // Send event to swarm task
-                        event_to_send = Some(AppEvent::DownloadDirChanged(Some(verified_path)));
+                        command_to_send = Some(Command::SetDownloadDir(Some(verified_path)));
                     }
                     Err(err_msg) => {
                         // If invalid, show the error message.
@@ -118,7 +204,7 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
                 app.push("Usage: /setname <nickname>".to_string());
             } else {
                 // Check if the nickname meets the required format (length, characters).
-                match crate::utils::verify_nickname(args) {
+                match utils::verify_nickname(args) {
                     Ok(verified_name) => {
                         // If valid, update the nickname in the app state.
                         app.push(format!("Nickname set to: {}", verified_name));
@@ -131,7 +217,7 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
 
                         // Prepare an event to tell the network task about the new nickname,
                         // so it can be included in heartbeats.
-                        event_to_send = Some(AppEvent::NicknameUpdated(app.local_peer_id.unwrap(), verified_name));
+                        command_to_send = Some(Command::SetNickname(verified_name));
                     }
                     Err(err_msg) => {
                         // If the nickname is invalid, show the error message.
@@ -235,7 +321,7 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
                 app.is_visible = false; // Update the local visibility state.
                 app.push("You are now hidden. Use /show to become visible again.".to_string());
                 // Tell the network task to stop sending heartbeats.
-                event_to_send = Some(AppEvent::VisibilityChanged(false));
+                command_to_send = Some(Command::SetVisibility(false));
             } else {
                 app.push("You are already hidden.".to_string());
             }
@@ -249,7 +335,7 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
                 app.is_visible = true; // Update the local visibility state.
                 app.push("You are now visible.".to_string());
                 // Tell the network task to start sending heartbeats again.
-                event_to_send = Some(AppEvent::VisibilityChanged(true));
+                command_to_send = Some(Command::SetVisibility(true));
             } else {
                 app.push("You are already visible.".to_string());
             }
@@ -259,8 +345,8 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
         // Command: /quit or /q
         // Exits the application gracefully.
         "quit" | "q" => {
-            // Prepare an event to signal the main loop to shut down.
-            event_to_send = Some(AppEvent::Quit);
+            // The main loop shuts down once it sees the exit flag.
+            app.exit = true;
         }
 
         // -------------------------------------
@@ -269,6 +355,9 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
         "help" | "h" => {
             app.push("SwapBytes Commands:".to_string());
             app.push("  /me               - Show my info (addrs, dir, nickname).".to_string());
+            app.push("  /id               - Show my Peer ID and identity file.".to_string());
+            app.push("  /id export <path> - Save a copy of my identity keypair.".to_string());
+            app.push("  /id rotate        - Replace my identity (takes effect on restart).".to_string());
             app.push("  /setdir <path>    - Set the absolute path for downloads.".to_string());
             app.push("  /setname <name>   - Set your nickname (3-16 chars, a-z, A-Z, 0-9, -, _).".to_string());
             app.push("  /chat <name>      - Switch chat (e.g. 'bob' or 'global').".to_string());
@@ -278,21 +367,30 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
             app.push("  /hide             - Set your status to appear offline.".to_string());
             app.push("  /show             - Set your status to appear online.".to_string());
             app.push("  /who <name>       - Show information about a specific user.".to_string());
-            app.push("  /offer <path>     - Offer a file to the current private chat peer.".to_string());
+            app.push("  /offer <path> [<path>...] - Offer a file, a directory or several files to the current private chat peer.".to_string());
             app.push("  /quit             - Exit SwapBytes.".to_string());
             app.push("  /myoffers         - List pending incoming file offers.".to_string());
-            app.push("  /decline          - Decline the offer from the current chat peer.".to_string());
-            app.push("  /accept           - Accept the offer from the current chat peer.".to_string());
+            app.push("  /decline [id|file] - Decline an offer from the current chat peer.".to_string());
+            app.push("  /accept [id|file] - Accept an offer from the current chat peer.".to_string());
+            app.push("  /transfers        - List active uploads and downloads.".to_string());
+            app.push("  /pause <file>     - Pause a transfer.".to_string());
+            app.push("  /resume [file]    - List interrupted downloads, or resume a transfer.".to_string());
+            app.push("  /cancel <file>    - Cancel a transfer (a download's partial file is deleted).".to_string());
+            app.push("  /limit [peers|<name>] [up|down <rate|off>] - Show or set bandwidth limits (e.g. /limit up 500K).".to_string());
+            app.push("  /swap <path> for <file> - Propose swapping your file for one of theirs.".to_string());
+            app.push("  /swap accept <path> - Accept a swap with the file they asked for.".to_string());
+            app.push("  /swap decline [file] - Decline a swap proposal.".to_string());
+            app.push("  /swap             - List pending swap proposals.".to_string());
             // Add other commands here as needed
             app.push("  /help             - Show this help message.".to_string());
         }
 
         // -------------------------------------
-        // Command: /offer <file_path>
-        // Offers a file to the peer in the current private chat context.
+        // Command: /offer <path> [<path>...]
+        // Offers a file, a directory or several paths to the peer in the current private chat context.
         "offer" => {
             if args.is_empty() {
-                app.push("Usage: /offer <file_path>".to_string());
+                app.push("Usage: /offer <path> [<path>...]".to_string());
             } else {
                 // First, check if we are in a private chat context
                 if let ChatContext::Private { target_peer_id, target_nickname } = app.current_chat_context.clone() {
@@ -300,43 +398,94 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
                     let target_peer_id_cloned = target_peer_id;
                     let target_nickname_cloned = target_nickname;
 
-                    // Verify the file exists and is readable
-                    match crate::utils::verify_offer_file(args) {
-                        Ok((verified_path, size_bytes)) => {
-                            let target_name = target_nickname_cloned.as_deref().unwrap_or("the peer"); // Use nickname or fallback
-                            app.push(format!("Sending offer to {}...", target_name));
-
-                            // Create details for local history and event
-                            let offer_details = crate::tui::PendingOfferDetails {
-                                filename: verified_path.file_name().map_or_else(
-                                    || args.to_string(), // Fallback to original arg if filename extraction fails
-                                    |name| name.to_string_lossy().into_owned()
-                                ),
-                                size_bytes,
-                                path: verified_path.clone(),
-                            };
-
-                            // Add the sent offer to local history
-                            let history = app.private_chat_histories.entry(target_peer_id_cloned).or_default();
-                            let current_len = history.len();
-                            history.push(crate::tui::PrivateChatItem::OfferSent(offer_details.clone()));
+                    // A path with spaces in it is taken whole if it exists; otherwise the arguments are separate paths
+                    let paths: Vec<PathBuf> = if Path::new(args).exists() {
+                        vec![PathBuf::from(args)]
+                    } else {
+                        args.split_whitespace().map(PathBuf::from).collect()
+                    };
+                    let is_single_file = matches!(paths.as_slice(), [path] if path.is_file());
+
+                    if !is_single_file {
+                        // --- Offer Several Files ---
+                        match bundle::collect_files(&paths) {
+                            Ok((name, files)) => {
+                                let target_name = target_nickname_cloned.as_deref().unwrap_or("the peer");
+                                app.push(format!("Sending offer of {} files to {}...", files.len(), target_name));
+
+                                let offer_details = crate::tui::PendingOfferDetails {
+                                    offer_id: utils::new_offer_id(),
+                                    filename: name.clone(),
+                                    size_bytes: files.iter().map(|file| file.size_bytes).sum(),
+                                    path: PathBuf::new(), // Each file is registered by the swarm task.
+                                    hash: None,
+                                    file_count: Some(files.len()),
+                                };
 
-                            // Auto-scroll local chat if user is viewing it
-                            let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
-                             if app.chat_scroll >= current_max_scroll {
-                                let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
-                                app.chat_scroll = new_max_scroll;
-                            }
+                                // Add the sent offer to local history
+                                let history = app.private_chat_histories.entry(target_peer_id_cloned).or_default();
+                                let current_len = history.len();
+                                history.push(crate::tui::PrivateChatItem::OfferSent(offer_details.clone()));
 
-                            // Create the event to send the offer via the swarm task
-                            event_to_send = Some(AppEvent::SendFileOffer { 
-                                target_peer: target_peer_id_cloned, 
-                                file_path: verified_path 
-                            });
+                                // Auto-scroll local chat if user is viewing it
+                                let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
+                                if app.chat_scroll >= current_max_scroll {
+                                    let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                                    app.chat_scroll = new_max_scroll;
+                                }
+
+                                command_to_send = Some(Command::SendBundleOffer {
+                                    target_peer: target_peer_id_cloned,
+                                    offer_id: offer_details.offer_id,
+                                    name,
+                                    files,
+                                });
+                            }
+                            Err(err_msg) => app.push(format!("Error offering files: {}", err_msg)),
                         }
-                        Err(err_msg) => {
-                            // If invalid, show the error message.
-                            app.push(format!("Error offering file: {}", err_msg));
+                    } else {
+                        // Verify the file exists and is readable
+                        match utils::verify_offer_file(args) {
+                            Ok((verified_path, size_bytes)) => {
+                                let target_name = target_nickname_cloned.as_deref().unwrap_or("the peer"); // Use nickname or fallback
+                                app.push(format!("Sending offer to {}...", target_name));
+
+                                // Create details for local history and event
+                                let offer_details = crate::tui::PendingOfferDetails {
+                                    offer_id: utils::new_offer_id(),
+                                    filename: verified_path.file_name().map_or_else(
+                                        || args.to_string(), // Fallback to original arg if filename extraction fails
+                                        |name| name.to_string_lossy().into_owned()
+                                    ),
+                                    size_bytes,
+                                    path: verified_path.clone(),
+                                    hash: None, // Computed by the swarm task before the offer is sent.
+                                    file_count: None,
+                                };
+
+                                // Add the sent offer to local history
+                                let history = app.private_chat_histories.entry(target_peer_id_cloned).or_default();
+                                let current_len = history.len();
+                                history.push(crate::tui::PrivateChatItem::OfferSent(offer_details.clone()));
+
+                                // Auto-scroll local chat if user is viewing it
+                                let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
+                                 if app.chat_scroll >= current_max_scroll {
+                                    let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                                    app.chat_scroll = new_max_scroll;
+                                }
+
+                                // Create the event to send the offer via the swarm task
+                                command_to_send = Some(Command::SendFileOffer {
+                                    target_peer: target_peer_id_cloned,
+                                    offer_id: offer_details.offer_id,
+                                    file_path: verified_path
+                                });
+                            }
+                            Err(err_msg) => {
+                                // If invalid, show the error message.
+                                app.push(format!("Error offering file: {}", err_msg));
+                            }
                         }
                     }
                 } else {
@@ -346,23 +495,22 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
         }
 
         // -------------------------------------
-        // Command: /decline
-        // Declines the most recent file offer received from the peer in the current private chat.
+        // Command: /decline [id|filename]
+        // Declines a file offer received from the peer in the current private chat.
         "decline" => {
-            if !args.is_empty() {
-                 app.push("Usage: /decline (takes no arguments)".to_string());
-            } else {
-                // Check if we are in a private chat context
-                if let ChatContext::Private { target_peer_id, target_nickname } = app.current_chat_context.clone() {
-                    let target_peer_id_cloned = target_peer_id; // Clone for use after borrow ends
-                    let target_nickname_cloned = target_nickname.clone(); // Clone for use after borrow ends
-                    let target_name = target_nickname_cloned.as_deref().unwrap_or("the peer");
-
-                    // Check if there is a pending offer from this specific peer
-                    if let Some(offer_details) = app.pending_offers.remove(&target_peer_id_cloned) {
+            // Check if we are in a private chat context
+            if let ChatContext::Private { target_peer_id, target_nickname } = app.current_chat_context.clone() {
+                let target_peer_id_cloned = target_peer_id; // Clone for use after borrow ends
+                let target_nickname_cloned = target_nickname.clone(); // Clone for use after borrow ends
+                let target_name = target_nickname_cloned.as_deref().unwrap_or("the peer");
+
+                // Find the offer the user picked among those pending from this peer
+                match take_pending_offer(app, target_peer_id_cloned, args) {
+                    Ok(offer_details) => {
                         // Offer found and removed, now update history and notify user
                         app.push(format!(
-                            "Offer for '{}' from {} declined.",
+                            "Offer [{}] for '{}' from {} declined.",
+                            offer_details.offer_id,
                             offer_details.filename,
                             target_name
                         ));
@@ -380,98 +528,79 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
                         }
 
                         // Send event to swarm task to notify the peer
-                        event_to_send = Some(AppEvent::DeclineFileOffer { 
-                            target_peer: target_peer_id_cloned, 
+                        command_to_send = Some(Command::DeclineFileOffer {
+                            target_peer: target_peer_id_cloned,
+                            offer_id: offer_details.offer_id,
                             filename: offer_details.filename // Include filename in event
                         });
-
-                    } else {
-                        // No pending offer found from this user
-                        app.push(format!("You have no pending file offer from {}.", target_name));
                     }
-                } else {
-                    // Not in a private chat context
-                    app.push("Error: /decline can only be used in a private chat with a pending offer.".to_string());
+                    Err(err_msg) => app.push(format!("{} Use /decline <id|filename>.", err_msg)),
                 }
+            } else {
+                // Not in a private chat context
+                app.push("Error: /decline can only be used in a private chat with a pending offer.".to_string());
             }
         }
 
         // -------------------------------------
-        // Command: /accept
-        // Accepts the most recent file offer received from the peer in the current private chat.
+        // Command: /accept [id|filename]
+        // Accepts a file offer received from the peer in the current private chat.
         "accept" => {
-            if !args.is_empty() {
-                 app.push("Usage: /accept (takes no arguments)".to_string());
-            } else {
-                // Check if we are in a private chat context
-                if let ChatContext::Private { target_peer_id, target_nickname } = app.current_chat_context.clone() {
-                    let target_peer_id_cloned = target_peer_id; // Clone for use after borrow ends
-                    let target_nickname_cloned = target_nickname.clone(); // Clone for use after borrow ends
-                    let target_name = target_nickname_cloned.as_deref().unwrap_or("the peer");
-
-                    // Check if there is a pending offer from this specific peer
-                    // Use remove() to take ownership of the offer details if it exists.
-                    if let Some(offer_details) = app.pending_offers.remove(&target_peer_id_cloned) {
-                        // Offer existed and was removed. Now check download dir.
-                        match app.download_dir.as_deref() {
-                            None => {
-                                app.push("Error: Download directory not set. Use /setdir <path>.".to_string());
-                                // Put the offer back since we couldn't accept it!
-                                app.pending_offers.insert(target_peer_id_cloned, offer_details);
-                            }
-                            Some(dir_path) => {
-                                match crate::utils::verify_download_directory(dir_path.to_string_lossy().as_ref()) {
-                                    Ok(_) => {
-                                        // Directory is valid, proceed with acceptance steps 1 & 2.
-                                        app.push(format!(
-                                            "Accepted offer for '{}' from {}.",
-                                            offer_details.filename,
-                                            target_name
-                                        ));
-
-                                        // 2. Add an item to `app.private_chat_histories` indicating acceptance.
-                                        let history = app.private_chat_histories.entry(target_peer_id_cloned).or_default();
-                                        let current_len = history.len();
-                                        history.push(crate::tui::PrivateChatItem::OfferAccepted(offer_details.clone()));
-
-                                        // Auto-scroll chat view if we are viewing it
-                                        let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
-                                        if app.chat_scroll >= current_max_scroll {
-                                            let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
-                                            app.chat_scroll = new_max_scroll;
-                                        }
-
-                                        // 3. Send an AppEvent to the swarm task to initiate the transfer.
-                                        event_to_send = Some(AppEvent::SendAcceptOffer {
-                                            target_peer: target_peer_id_cloned,
-                                            filename: offer_details.filename, // Send filename along
-                                            size_bytes: offer_details.size_bytes // <<< Add size_bytes
-                                        });
-                                        // TODO: File Transfer - Step 1: After accepting, prepare the UI/state for download.
-                                        //    - Maybe add a status indicator to the `OfferAccepted` chat item?
-                                        //    - Need to tell the swarm task to expect incoming data for this file.
+            // Check if we are in a private chat context
+            if let ChatContext::Private { target_peer_id, target_nickname } = app.current_chat_context.clone() {
+                let target_peer_id_cloned = target_peer_id; // Clone for use after borrow ends
+                let target_nickname_cloned = target_nickname.clone(); // Clone for use after borrow ends
+                let target_name = target_nickname_cloned.as_deref().unwrap_or("the peer");
+
+                // Check the download directory first, so a rejected /accept leaves the offer pending
+                let dir_check = match app.download_dir.as_deref() {
+                    None => Err("Error: Download directory not set. Use /setdir <path>.".to_string()),
+                    Some(dir_path) => utils::verify_download_directory(dir_path.to_string_lossy().as_ref())
+                        .map_err(|err_msg| format!(
+                            "Error: Download directory '{}' is invalid: {}. Use /setdir to set a valid one.",
+                            dir_path.display(),
+                            err_msg
+                        )),
+                };
+                if let Err(err_msg) = dir_check {
+                    app.push(err_msg);
+                } else {
+                    // Find the offer the user picked among those pending from this peer
+                    match take_pending_offer(app, target_peer_id_cloned, args) {
+                        Ok(offer_details) => {
+                            app.push(format!(
+                                "Accepted offer [{}] for '{}' from {}.",
+                                offer_details.offer_id,
+                                offer_details.filename,
+                                target_name
+                            ));
+
+                            // Add an item to `app.private_chat_histories` indicating acceptance.
+                            let history = app.private_chat_histories.entry(target_peer_id_cloned).or_default();
+                            let current_len = history.len();
+                            history.push(crate::tui::PrivateChatItem::OfferAccepted(offer_details.clone()));
 
-                                    }
-                                    Err(err_msg) => {
-                                        app.push(format!(
-                                            "Error: Download directory '{}' is invalid: {}. Use /setdir to set a valid one.",
-                                            dir_path.display(),
-                                            err_msg
-                                        ));
-                                        // Put the offer back since we couldn't accept it!
-                                        app.pending_offers.insert(target_peer_id_cloned, offer_details);
-                                    }
-                                }
+                            // Auto-scroll chat view if we are viewing it
+                            let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
+                            if app.chat_scroll >= current_max_scroll {
+                                let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                                app.chat_scroll = new_max_scroll;
                             }
+
+                            // Ask the swarm task to initiate the transfer.
+                            command_to_send = Some(Command::SendAcceptOffer {
+                                target_peer: target_peer_id_cloned,
+                                offer_id: offer_details.offer_id,
+                                filename: offer_details.filename, // Send filename along
+                                subdir: None,
+                            });
                         }
-                    } else {
-                        // No pending offer found from this user
-                        app.push(format!("You have no pending file offer from {}.", target_name));
+                        Err(err_msg) => app.push(format!("{} Use /accept <id|filename>.", err_msg)),
                     }
-                } else {
-                    // Not in a private chat context
-                    app.push("Error: /accept can only be used in a private chat with a pending offer.".to_string());
                 }
+            } else {
+                // Not in a private chat context
+                app.push("Error: /accept can only be used in a private chat with a pending offer.".to_string());
             }
         }
 
@@ -479,26 +608,297 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
         // Command: /myoffers
         // Lists all pending incoming file offers.
         "myoffers" => {
-            if app.pending_offers.is_empty() {
+            if app.pending_offers.values().all(|offers| offers.is_empty()) {
                 app.push("You have no pending file offers.".to_string());
             } else {
                 app.push("Pending file offers:".to_string());
                 // Collect offer details first to avoid borrow checker issues
-                let offer_summaries: Vec<(String, String, u64)> = app.pending_offers.iter().map(|(peer_id, offer)| {
+                let mut offer_summaries: Vec<(String, String, String, u64)> = app.pending_offers.iter().flat_map(|(peer_id, offers)| {
                     let sender_display_name = app.peers.get(peer_id)
                         .and_then(|info| info.nickname.clone())
-                        .unwrap_or_else(|| {
-                            let id_str = peer_id.to_base58();
-                            let len = id_str.len();
-                            format!("user(...{})", &id_str[len.saturating_sub(6)..])
-                        });
-                    (sender_display_name, offer.filename.clone(), offer.size_bytes)
+                        .unwrap_or_else(|| utils::peer_id_to_short_string(peer_id));
+                    offers.iter().map(move |offer| (sender_display_name.clone(), offer.offer_id.clone(), offer.filename.clone(), offer.size_bytes))
                 }).collect();
+                offer_summaries.sort();
 
                 // Now print the collected summaries
-                for (sender_display_name, filename, size_bytes) in offer_summaries {
-                    let formatted_size = crate::utils::format_bytes(size_bytes);
-                    app.push(format!("  - From {}: {} ({})", sender_display_name, filename, formatted_size));
+                for (sender_display_name, offer_id, filename, size_bytes) in offer_summaries {
+                    let formatted_size = utils::format_bytes(size_bytes);
+                    app.push(format!("  - From {}: [{}] {} ({})", sender_display_name, offer_id, filename, formatted_size));
+                }
+            }
+        }
+
+        // -------------------------------------
+        // Command: /resume [filename]
+        // Lists interrupted downloads, or asks the swarm task to resume a paused or interrupted transfer.
+        "resume" => {
+            // Helper closure to get a display name for a peer
+            let display_name = |app: &App, peer_id: &PeerId| {
+                app.peers.get(peer_id)
+                    .and_then(|info| info.nickname.clone())
+                    .unwrap_or_else(|| utils::peer_id_to_short_string(peer_id))
+            };
+
+            if args.is_empty() {
+                if app.interrupted_downloads.is_empty() {
+                    app.push("You have no interrupted downloads.".to_string());
+                } else {
+                    app.push("Interrupted downloads:".to_string());
+                    let mut summaries: Vec<String> = app.interrupted_downloads.iter().map(|((peer_id, filename), (received, total))| {
+                        format!(
+                            "  - From {}: {} ({} / {})",
+                            display_name(app, peer_id),
+                            filename,
+                            utils::format_bytes(*received),
+                            utils::format_bytes(*total)
+                        )
+                    }).collect();
+                    summaries.sort();
+                    for summary in summaries {
+                        app.push(summary);
+                    }
+                }
+            } else {
+                // The swarm task knows what is running; the current private chat breaks ties between peers
+                command_to_send = Some(Command::ControlTransfer {
+                    action: TransferAction::Resume,
+                    peer_id: current_private_peer(app),
+                    filename: args.to_string(),
+                });
+            }
+        }
+
+        // -------------------------------------
+        // Command: /limit [peers|<name>] [up|down <rate|off>]
+        // Shows or changes the bandwidth limits: in total, for each peer, or for one peer.
+        "limit" => {
+            let parts: Vec<&str> = args.split_whitespace().collect();
+            // Split off who the limit is for; the last two words are the direction and the rate
+            let (target, setting) = match parts.as_slice() {
+                [] => (None, None),
+                [direction, rate] => (None, Some((*direction, *rate))),
+                [target, direction, rate] => (Some(*target), Some((*direction, *rate))),
+                _ => {
+                    app.push("Usage: /limit [peers|<name>] [up|down <rate|off>], e.g. /limit up 500K".to_string());
+                    return None;
+                }
+            };
+            match setting {
+                None => show_rate_limits(app),
+                Some((direction, rate)) => {
+                    let direction = match direction.to_ascii_lowercase().as_str() {
+                        "up" | "upload" => TransferDirection::Upload,
+                        "down" | "download" => TransferDirection::Download,
+                        _ => {
+                            app.push(format!("Error: '{}' is not a direction. Use up or down.", direction));
+                            return None;
+                        }
+                    };
+                    let limit = match utils::parse_rate(rate) {
+                        Ok(limit) => limit,
+                        Err(e) => {
+                            app.push(format!("Error: {}", e));
+                            return None;
+                        }
+                    };
+                    let (scope, who) = match target {
+                        None => (LimitScope::Total, "in total".to_string()),
+                        Some(target) if target.eq_ignore_ascii_case("peers") => (LimitScope::EachPeer, "for each peer".to_string()),
+                        Some(name) => {
+                            // Look the peer up by nickname, as /chat does
+                            let matches: Vec<PeerId> = app.peers.iter()
+                                .filter(|(_, info)| info.nickname.as_ref().is_some_and(|nick| nick.eq_ignore_ascii_case(name)))
+                                .map(|(peer_id, _)| *peer_id)
+                                .collect();
+                            match matches.as_slice() {
+                                [peer_id] => (LimitScope::Peer(*peer_id), format!("for {}", name)),
+                                [] => {
+                                    app.push(format!("Error: User '{}' not found.", name));
+                                    return None;
+                                }
+                                _ => {
+                                    app.push(format!("Error: Several users are called '{}'.", name));
+                                    return None;
+                                }
+                            }
+                        }
+                    };
+                    app.rate_limits.set(scope, direction, limit);
+                    let activity = match direction {
+                        TransferDirection::Upload => "Uploads",
+                        TransferDirection::Download => "Downloads",
+                    };
+                    match limit {
+                        Some(limit) => app.push(format!("{} {} are now limited to {}.", activity, who, utils::format_rate(limit as f64))),
+                        None => app.push(format!("{} {} are no longer limited.", activity, who)),
+                    }
+                    command_to_send = Some(Command::SetRateLimit { scope, direction, limit });
+                }
+            }
+        }
+
+        // -------------------------------------
+        // Command: /transfers
+        // Asks the swarm task for the list of active uploads and downloads.
+        "transfers" => {
+            command_to_send = Some(Command::ListTransfers);
+        }
+
+        // -------------------------------------
+        // Command: /pause <filename> | /cancel <filename>
+        // Pauses or cancels an upload or download by file name.
+        "pause" | "cancel" => {
+            if args.is_empty() {
+                app.push(format!("Usage: /{} <file>", command_name));
+            } else {
+                let action = if command_name == "pause" { TransferAction::Pause } else { TransferAction::Cancel };
+                command_to_send = Some(Command::ControlTransfer {
+                    action,
+                    peer_id: current_private_peer(app),
+                    filename: args.to_string(),
+                });
+            }
+        }
+
+        // -------------------------------------
+        // Command: /swap <path> for <their file> | /swap accept <path> | /swap decline [file] | /swap
+        // Proposes, accepts or declines a two-sided swap, or lists pending proposals.
+        "swap" => {
+            let (subcommand, sub_args) = args.split_once(' ').map_or((args, ""), |(sub, rest)| (sub, rest.trim()));
+            let private_peer = match &app.current_chat_context {
+                ChatContext::Private { target_peer_id, target_nickname } => Some((*target_peer_id, target_nickname.clone())),
+                ChatContext::Global => None,
+            };
+
+            match (subcommand, private_peer) {
+                // --- List Pending Proposals ---
+                ("", _) => {
+                    if app.pending_swaps.is_empty() {
+                        app.push("You have no pending swap proposals.".to_string());
+                    } else {
+                        app.push("Pending swap proposals:".to_string());
+                        let mut summaries: Vec<String> = app.pending_swaps.values().map(|(peer_id, details)| {
+                            let sender_display_name = app.peers.get(peer_id)
+                                .and_then(|info| info.nickname.clone())
+                                .unwrap_or_else(|| utils::peer_id_to_short_string(peer_id));
+                            format!(
+                                "  - From {}: their '{}' ({}) for your '{}'",
+                                sender_display_name,
+                                details.take,
+                                utils::format_bytes(details.take_size.unwrap_or(0)),
+                                details.give
+                            )
+                        }).collect();
+                        summaries.sort();
+                        for summary in summaries {
+                            app.push(summary);
+                        }
+                    }
+                }
+                (_, None) => {
+                    app.push("Error: /swap can only be used in a private chat. Use /chat <nickname> first.".to_string());
+                }
+
+                // --- Accept A Proposal ---
+                ("accept", Some((target_peer_id, _))) => {
+                    if sub_args.is_empty() {
+                        app.push("Usage: /swap accept <path to the file they asked for>".to_string());
+                    } else if app.download_dir.is_none() {
+                        app.push("Error: Download directory not set. Use /setdir <path>.".to_string());
+                    } else {
+                        match utils::verify_offer_file(sub_args) {
+                            Ok((verified_path, _size_bytes)) => {
+                                let filename = verified_path.file_name()
+                                    .map(|name| name.to_string_lossy().into_owned())
+                                    .unwrap_or_default();
+                                // The file picked must be the one the peer asked for
+                                let swap_id = app.pending_swaps.iter()
+                                    .find(|(_, (peer_id, details))| *peer_id == target_peer_id && details.give == filename)
+                                    .map(|(swap_id, _)| swap_id.clone());
+                                match swap_id.and_then(|swap_id| app.pending_swaps.remove(&swap_id)) {
+                                    Some((_, details)) => {
+                                        app.push(format!("Accepted swap: your '{}' for their '{}'.", details.give, details.take));
+                                        command_to_send = Some(Command::AcceptSwap {
+                                            target_peer: target_peer_id,
+                                            swap_id: details.swap_id,
+                                            file_path: verified_path,
+                                        });
+                                    }
+                                    None => app.push(format!("No swap proposal in this chat asks for '{}'.", filename)),
+                                }
+                            }
+                            Err(err_msg) => app.push(format!("Error accepting swap: {}", err_msg)),
+                        }
+                    }
+                }
+
+                // --- Decline A Proposal ---
+                ("decline", Some((target_peer_id, _))) => {
+                    // Name the file they asked for if they proposed several swaps
+                    let candidates: Vec<String> = app.pending_swaps.iter()
+                        .filter(|(_, (peer_id, details))| *peer_id == target_peer_id && (sub_args.is_empty() || details.give == sub_args))
+                        .map(|(swap_id, _)| swap_id.clone())
+                        .collect();
+                    match candidates.as_slice() {
+                        [] => app.push("No matching swap proposal in this chat.".to_string()),
+                        [swap_id] => {
+                            if let Some((_, details)) = app.pending_swaps.remove(swap_id) {
+                                app.push(format!("Declined swap for your '{}'.", details.give));
+                                command_to_send = Some(Command::DeclineSwap {
+                                    target_peer: target_peer_id,
+                                    swap_id: details.swap_id,
+                                });
+                            }
+                        }
+                        _ => app.push("Several swap proposals are pending. Use /swap decline <the file they asked for>.".to_string()),
+                    }
+                }
+
+                // --- Propose A Swap ---
+                (_, Some((target_peer_id, target_nickname))) => {
+                    match args.rsplit_once(" for ").map(|(path, wanted)| (path.trim(), wanted.trim())) {
+                        None | Some((_, "")) => app.push("Usage: /swap <your file path> for <their file name>".to_string()),
+                        Some((path_arg, wanted)) => match utils::verify_offer_file(path_arg) {
+                            Ok((verified_path, _size_bytes)) => {
+                                let details = SwapDetails {
+                                    swap_id: swap::new_swap_id(),
+                                    give: verified_path.file_name().map_or_else(
+                                        || path_arg.to_string(),
+                                        |name| name.to_string_lossy().into_owned()
+                                    ),
+                                    take: wanted.to_string(),
+                                    take_size: None, // Known once they accept
+                                };
+                                app.push(format!(
+                                    "Proposing swap to {}: your '{}' for their '{}'...",
+                                    target_nickname.as_deref().unwrap_or("the peer"),
+                                    details.give,
+                                    details.take
+                                ));
+
+                                // Add the proposal to local history
+                                let history = app.private_chat_histories.entry(target_peer_id).or_default();
+                                let current_len = history.len();
+                                history.push(crate::tui::PrivateChatItem::SwapProposed(details.clone()));
+
+                                // Auto-scroll local chat if user is viewing it
+                                let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
+                                if app.chat_scroll >= current_max_scroll {
+                                    let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                                    app.chat_scroll = new_max_scroll;
+                                }
+
+                                command_to_send = Some(Command::ProposeSwap {
+                                    target_peer: target_peer_id,
+                                    swap_id: details.swap_id,
+                                    file_path: verified_path,
+                                    wanted: details.take,
+                                });
+                            }
+                            Err(err_msg) => app.push(format!("Error proposing swap: {}", err_msg)),
+                        },
+                    }
                 }
             }
         }
@@ -568,6 +968,55 @@ pub fn process_command(command_input: &str, app: &mut App) -> Option<AppEvent> {
         }
     }
 
-    // Return the event we prepared, if any.
-    event_to_send
+    // Return the command we prepared, if any.
+    command_to_send
+}
+
+/// Removes and returns the pending offer from `peer_id` picked by `selector`: its ID or filename,
+/// or nothing if only one offer is pending. Returns a message for the user if none or several match.
+fn take_pending_offer(app: &mut App, peer_id: PeerId, selector: &str) -> Result<crate::tui::PendingOfferDetails, String> {
+    let offers = app.pending_offers.entry(peer_id).or_default();
+    let matching: Vec<usize> = offers
+        .iter()
+        .enumerate()
+        .filter(|(_, offer)| selector.is_empty() || offer.offer_id == selector || offer.filename == selector)
+        .map(|(index, _)| index)
+        .collect();
+    match matching.as_slice() {
+        [index] => Ok(offers.remove(*index)),
+        [] if offers.is_empty() => Err("You have no pending file offer in this chat.".to_string()),
+        [] => Err(format!("No pending offer matches '{}'.", selector)),
+        _ => Err(format!("{} offers match.", matching.len())),
+    }
+}
+
+/// The peer of the current private chat, if any.
+fn current_private_peer(app: &App) -> Option<PeerId> {
+    match &app.current_chat_context {
+        ChatContext::Private { target_peer_id, .. } => Some(*target_peer_id),
+        ChatContext::Global => None,
+    }
+}
+
+/// Prints the bandwidth limits: in total, for each peer, and for particular peers.
+fn show_rate_limits(app: &mut App) {
+    let format_limit = |limit: Option<u64>| limit.map_or_else(|| "unlimited".to_string(), |limit| utils::format_rate(limit as f64));
+    let limits = app.rate_limits.clone();
+    app.push("Bandwidth limits:".to_string());
+    app.push(format!("  Uploads:   {} in total, {} per peer", format_limit(limits.upload), format_limit(limits.peer_upload)));
+    app.push(format!("  Downloads: {} in total, {} per peer", format_limit(limits.download), format_limit(limits.peer_download)));
+    let mut peer_lines: Vec<String> = limits.peers.iter().map(|((peer_id, direction), limit)| {
+        let name = app.peers.get(peer_id)
+            .and_then(|info| info.nickname.clone())
+            .unwrap_or_else(|| utils::peer_id_to_short_string(peer_id));
+        let activity = match direction {
+            TransferDirection::Upload => "uploads",
+            TransferDirection::Download => "downloads",
+        };
+        format!("  {}: {} {}", name, activity, format_limit(*limit))
+    }).collect();
+    peer_lines.sort();
+    for line in peer_lines {
+        app.push(line);
+    }
 }
@@ -0,0 +1,448 @@
+/*
+Loads the client configuration from the config file and command-line arguments.
+*/
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+
+use swapbytes_core::{autoaccept::AutoAcceptRule, config::{parse_dht_seed, parse_rendezvous_point}, constants, utils, Config};
+
+/// Name of the config file inside the per-user config directory.
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Command-line arguments. Any flag given here overrides the config file.
+#[derive(Debug, Parser)]
+#[command(name = "swapbytes", version, about = "Peer-to-peer file bartering in your terminal.")]
+pub struct Cli {
+    /// Path to the config file [default: <config dir>/swapbytes/config.toml]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on (repeatable) [default: /ip4/0.0.0.0/tcp/0 and /ip4/0.0.0.0/udp/0/quic-v1]
+    #[arg(long = "listen", value_name = "MULTIADDR")]
+    pub listen_addrs: Vec<String>,
+    /// Rendezvous point to register with, ending in /p2p/<peer-id> (repeatable)
+    #[arg(long = "rendezvous", value_name = "MULTIADDR")]
+    pub rendezvous: Vec<String>,
+    /// Don't use any rendezvous point
+    #[arg(long, conflicts_with = "rendezvous")]
+    pub no_rendezvous: bool,
+    /// Node to join the DHT through, ending in /p2p/<peer-id> (repeatable)
+    #[arg(long = "dht-seed", value_name = "MULTIADDR")]
+    pub dht_seeds: Vec<String>,
+    /// Initial nickname
+    #[arg(long, value_name = "NAME")]
+    pub nickname: Option<String>,
+    /// Absolute path of the directory to save downloads in
+    #[arg(long, value_name = "PATH")]
+    pub download_dir: Option<PathBuf>,
+    /// Start hidden (no heartbeats are broadcast)
+    #[arg(long, conflicts_with = "visible")]
+    pub hidden: bool,
+    /// Start visible, even if the config file says otherwise
+    #[arg(long)]
+    pub visible: bool,
+    /// Seconds between heartbeat broadcasts
+    #[arg(long, value_name = "SECS")]
+    pub heartbeat_interval: Option<u64>,
+    /// Seconds without a heartbeat before a peer is shown as offline
+    #[arg(long, value_name = "SECS")]
+    pub peer_timeout: Option<u64>,
+    /// Size in bytes of the chunks we serve files in
+    #[arg(long, value_name = "BYTES")]
+    pub chunk_size: Option<usize>,
+    /// Path to the identity key file [default: <data dir>/swapbytes/identity.key]
+    #[arg(long, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+    /// Most bytes per second to upload in total (e.g. 500K, 2M, or off)
+    #[arg(long, value_name = "RATE")]
+    pub upload_limit: Option<String>,
+    /// Most bytes per second to download in total
+    #[arg(long, value_name = "RATE")]
+    pub download_limit: Option<String>,
+    /// Most bytes per second to upload to each peer
+    #[arg(long, value_name = "RATE")]
+    pub peer_upload_limit: Option<String>,
+    /// Most bytes per second to download from each peer
+    #[arg(long, value_name = "RATE")]
+    pub peer_download_limit: Option<String>,
+    /// Most uploads served at the same time; further requests wait in line [default: 4]
+    #[arg(long, value_name = "N")]
+    pub max_uploads: Option<usize>,
+    /// Most downloads running at the same time; further ones wait for a free slot [default: 8]
+    #[arg(long, value_name = "N")]
+    pub max_downloads: Option<usize>,
+    /// Run without the terminal UI, controlled through a local socket (Unix only)
+    #[arg(long)]
+    pub headless: bool,
+    /// Path of the control socket in headless mode [default: <runtime dir>/swapbytes/control.sock]
+    #[arg(long, value_name = "PATH", requires = "headless")]
+    pub socket: Option<PathBuf>,
+    /// Do one thing and exit instead of starting the TUI
+    #[command(subcommand)]
+    pub action: Option<Action>,
+}
+
+/// One-shot actions. Each waits for peers to turn up, does one thing, prints its result as JSON and exits.
+#[derive(Debug, Subcommand)]
+pub enum Action {
+    /// Offer a file to a peer and wait until they have downloaded it
+    Send {
+        /// The file to send
+        file: PathBuf,
+        /// Who to send it to: a peer ID or nickname
+        #[arg(long, value_name = "PEER")]
+        to: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// Wait for a file offer and download it
+    Receive {
+        /// Directory to save the download in
+        #[arg(long, value_name = "PATH")]
+        dir: PathBuf,
+        /// Only take offers from this peer (a peer ID or nickname)
+        #[arg(long, value_name = "PEER")]
+        from: Option<String>,
+        /// Accept the first offer without asking
+        #[arg(long)]
+        auto_accept: bool,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// List the peers found on the network
+    Peers {
+        /// Connect to this address as well (repeatable)
+        #[arg(long = "dial", value_name = "MULTIADDR")]
+        dial: Vec<Multiaddr>,
+        /// Seconds to look for peers
+        #[arg(long, value_name = "SECS", default_value_t = 5)]
+        wait: u64,
+    },
+}
+
+/// How a one-shot action finds its peer and how long it waits.
+#[derive(Debug, Args)]
+pub struct WaitArgs {
+    /// Connect to this address as well, for peers mDNS and rendezvous can't find (repeatable)
+    #[arg(long = "dial", value_name = "MULTIADDR")]
+    pub dial: Vec<Multiaddr>,
+    /// Seconds to wait for the peer, an offer, an answer or the transfer's next progress before giving up (0: no limit)
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    pub timeout: u64,
+    /// Print the node's log messages to stderr
+    #[arg(long)]
+    pub verbose: bool,
+}
+
+/// Contents of the TOML config file. Every field is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen_addrs: Option<Vec<String>>,
+    rendezvous: Option<Vec<String>>,
+    dht_seeds: Option<Vec<String>>,
+    nickname: Option<String>,
+    download_dir: Option<PathBuf>,
+    visible: Option<bool>,
+    heartbeat_interval_secs: Option<u64>,
+    peer_timeout_secs: Option<u64>,
+    chunk_size: Option<usize>,
+    identity_file: Option<PathBuf>,
+    upload_limit: Option<String>,
+    download_limit: Option<String>,
+    peer_upload_limit: Option<String>,
+    peer_download_limit: Option<String>,
+    max_uploads: Option<usize>,
+    max_downloads: Option<usize>,
+    auto_accept: Vec<FileAutoAcceptRule>,
+}
+
+/// An `[[auto_accept]]` table of the config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAutoAcceptRule {
+    peer: Option<String>,
+    nickname: Option<String>,
+    filename: Option<String>,
+    max_size: Option<String>,
+    dir: Option<String>,
+}
+
+/// Builds the configuration from the command line and the config file.
+///
+/// Values are taken from the command line first, then the config file, then the defaults.
+/// Returns `Err(String)` with a descriptive error message if any value is invalid.
+pub fn load(cli: Cli) -> Result<Config, String> {
+    let file = read_config_file(cli.config.as_ref())?;
+    let mut config = Config::default();
+
+    // --- Network ---
+    let listen_addrs = if !cli.listen_addrs.is_empty() { Some(cli.listen_addrs) } else { file.listen_addrs };
+    if let Some(addrs) = listen_addrs {
+        if addrs.is_empty() {
+            return Err("At least one listen address is required.".to_string());
+        }
+        config.listen_addrs = addrs
+            .iter()
+            .map(|addr| addr.parse::<Multiaddr>().map_err(|e| format!("Invalid listen address '{}': {}", addr, e)))
+            .collect::<Result<_, _>>()?;
+    }
+
+    let rendezvous = if cli.no_rendezvous {
+        Some(Vec::new())
+    } else if !cli.rendezvous.is_empty() {
+        Some(cli.rendezvous)
+    } else {
+        file.rendezvous
+    };
+    if let Some(points) = rendezvous {
+        config.rendezvous_points = points
+            .iter()
+            .map(|addr| parse_rendezvous_point(addr))
+            .collect::<Result<_, _>>()?;
+    }
+
+    let dht_seeds = if !cli.dht_seeds.is_empty() { Some(cli.dht_seeds) } else { file.dht_seeds };
+    if let Some(seeds) = dht_seeds {
+        config.dht_seeds = seeds
+            .iter()
+            .map(|addr| parse_dht_seed(addr))
+            .collect::<Result<_, _>>()?;
+    }
+
+    // --- User ---
+    if let Some(nickname) = cli.nickname.or(file.nickname) {
+        config.nickname = Some(utils::verify_nickname(&nickname).map_err(|e| format!("Invalid nickname: {}", e))?);
+    }
+    if let Some(dir) = cli.download_dir.or(file.download_dir) {
+        let verified = utils::verify_download_directory(&dir.to_string_lossy())
+            .map_err(|e| format!("Invalid download directory: {}", e))?;
+        config.download_dir = Some(verified);
+    }
+    if cli.hidden {
+        config.visible = false;
+    } else if cli.visible {
+        config.visible = true;
+    } else if let Some(visible) = file.visible {
+        config.visible = visible;
+    }
+    if let Some(path) = cli.identity.or(file.identity_file) {
+        config.identity_path = Some(path);
+    }
+
+    // --- Timing ---
+    if let Some(secs) = cli.heartbeat_interval.or(file.heartbeat_interval_secs) {
+        if secs == 0 {
+            return Err("Heartbeat interval must be at least 1 second.".to_string());
+        }
+        config.heartbeat_interval = Duration::from_secs(secs);
+    }
+    if let Some(secs) = cli.peer_timeout.or(file.peer_timeout_secs) {
+        config.peer_timeout = Duration::from_secs(secs);
+    }
+    if config.peer_timeout <= config.heartbeat_interval {
+        return Err(format!(
+            "Peer timeout ({}s) must be longer than the heartbeat interval ({}s).",
+            config.peer_timeout.as_secs(),
+            config.heartbeat_interval.as_secs()
+        ));
+    }
+
+    // --- Transfers ---
+    if let Some(chunk_size) = cli.chunk_size.or(file.chunk_size) {
+        if !(constants::MIN_CHUNK_SIZE..=constants::MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(format!(
+                "Chunk size must be between {} and {} bytes.",
+                constants::MIN_CHUNK_SIZE,
+                constants::MAX_CHUNK_SIZE
+            ));
+        }
+        config.chunk_size = chunk_size;
+    }
+    if let Some(max_uploads) = cli.max_uploads.or(file.max_uploads) {
+        if max_uploads == 0 {
+            return Err("At least 1 upload must be allowed at a time.".to_string());
+        }
+        config.max_uploads = max_uploads;
+    }
+    if let Some(max_downloads) = cli.max_downloads.or(file.max_downloads) {
+        if max_downloads == 0 {
+            return Err("At least 1 download must be allowed at a time.".to_string());
+        }
+        config.max_downloads = max_downloads;
+    }
+
+    // --- Bandwidth Limits ---
+    let limits = [
+        ("upload limit", cli.upload_limit.or(file.upload_limit), &mut config.rate_limits.upload),
+        ("download limit", cli.download_limit.or(file.download_limit), &mut config.rate_limits.download),
+        ("peer upload limit", cli.peer_upload_limit.or(file.peer_upload_limit), &mut config.rate_limits.peer_upload),
+        ("peer download limit", cli.peer_download_limit.or(file.peer_download_limit), &mut config.rate_limits.peer_download),
+    ];
+    for (name, value, limit) in limits {
+        if let Some(value) = value {
+            *limit = utils::parse_rate(&value).map_err(|e| format!("Invalid {}: {}", name, e))?;
+        }
+    }
+
+    // --- Auto-Accept Rules ---
+    for (index, rule) in file.auto_accept.into_iter().enumerate() {
+        config.auto_accept.push(auto_accept_rule(rule).map_err(|e| format!("Invalid auto-accept rule #{}: {}", index + 1, e))?);
+    }
+
+    Ok(config)
+}
+
+/// Checks an `[[auto_accept]]` table and turns it into a rule.
+fn auto_accept_rule(rule: FileAutoAcceptRule) -> Result<AutoAcceptRule, String> {
+    let peer_id = rule.peer
+        .map(|peer| peer.parse::<PeerId>().map_err(|e| format!("invalid peer ID '{}': {}", peer, e)))
+        .transpose()?;
+    let max_size = rule.max_size.map(|size| utils::parse_size(&size)).transpose()?;
+    AutoAcceptRule { peer_id, nickname: rule.nickname, filename: rule.filename, max_size, subdir: rule.dir }.validate()
+}
+
+/// Returns the default location of the config file.
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("swapbytes").join(CONFIG_FILE_NAME))
+}
+
+/// Reads and parses the config file.
+///
+/// A missing file is only an error if its path was given explicitly.
+fn read_config_file(explicit_path: Option<&PathBuf>) -> Result<FileConfig, String> {
+    let path = match explicit_path.cloned().or_else(default_config_path) {
+        Some(path) => path,
+        None => return Ok(FileConfig::default()),
+    };
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file '{}': {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && explicit_path.is_none() => Ok(FileConfig::default()),
+        Err(e) => Err(format!("Failed to read config file '{}': {}", path.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+
+    /// Loads the configuration from `file` (written to a config file of its own) and `args`.
+    fn load_with(name: &str, file: &str, args: &[&str]) -> Config {
+        let path = std::env::temp_dir().join(format!("swapbytes-config-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, file).unwrap();
+        let path_arg = path.to_string_lossy().into_owned();
+        let cli = Cli::try_parse_from(["swapbytes", "--config", path_arg.as_str()].iter().chain(args)).unwrap();
+        let config = load(cli);
+        fs::remove_file(&path).unwrap();
+        config.unwrap()
+    }
+
+    /// Every field the config file and the command line can set, by name.
+    fn fields(config: &Config) -> Vec<(&'static str, String)> {
+        vec![
+            ("listen_addrs", format!("{:?}", config.listen_addrs)),
+            ("rendezvous", format!("{:?}", config.rendezvous_points)),
+            ("dht_seeds", format!("{:?}", config.dht_seeds)),
+            ("nickname", format!("{:?}", config.nickname)),
+            ("download_dir", format!("{:?}", config.download_dir)),
+            ("visible", format!("{:?}", config.visible)),
+            ("heartbeat_interval", format!("{:?}", config.heartbeat_interval)),
+            ("peer_timeout", format!("{:?}", config.peer_timeout)),
+            ("chunk_size", format!("{:?}", config.chunk_size)),
+            ("identity", format!("{:?}", config.identity_path)),
+            ("upload_limit", format!("{:?}", config.rate_limits.upload)),
+            ("download_limit", format!("{:?}", config.rate_limits.download)),
+            ("peer_upload_limit", format!("{:?}", config.rate_limits.peer_upload)),
+            ("peer_download_limit", format!("{:?}", config.rate_limits.peer_download)),
+            ("max_uploads", format!("{:?}", config.max_uploads)),
+            ("max_downloads", format!("{:?}", config.max_downloads)),
+        ]
+    }
+
+    #[test]
+    fn command_line_beats_file_beats_defaults() {
+        let dirs = ["file", "cli"].map(|name| {
+            let dir = std::env::temp_dir().join(format!("swapbytes-config-{}-{}-downloads", std::process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+            dir.to_string_lossy().into_owned()
+        });
+        let file = format!(
+            r#"
+            listen_addrs = ["/ip4/127.0.0.1/tcp/4001"]
+            rendezvous = ["/ip4/10.0.0.1/tcp/62649/p2p/{SERVER}"]
+            dht_seeds = ["/ip4/10.0.0.1/tcp/4001/p2p/{SERVER}"]
+            nickname = "from_file"
+            download_dir = "{}"
+            visible = false
+            heartbeat_interval_secs = 20
+            peer_timeout_secs = 90
+            chunk_size = {}
+            identity_file = "/file/identity.key"
+            upload_limit = "1M"
+            download_limit = "2M"
+            peer_upload_limit = "3M"
+            peer_download_limit = "4M"
+            max_uploads = 2
+            max_downloads = 3
+            "#,
+            dirs[0],
+            constants::MIN_CHUNK_SIZE,
+        );
+        let rendezvous = format!("/ip4/10.0.0.2/tcp/62649/p2p/{SERVER}");
+        let dht_seed = format!("/ip4/10.0.0.2/tcp/4001/p2p/{SERVER}");
+        let max_chunk_size = constants::MAX_CHUNK_SIZE.to_string();
+        let args = [
+            "--listen", "/ip4/127.0.0.1/tcp/4002",
+            "--rendezvous", &rendezvous,
+            "--dht-seed", &dht_seed,
+            "--nickname", "from_cli",
+            "--download-dir", &dirs[1],
+            "--visible",
+            "--heartbeat-interval", "30",
+            "--peer-timeout", "120",
+            "--chunk-size", &max_chunk_size,
+            "--identity", "/cli/identity.key",
+            "--upload-limit", "5M",
+            "--download-limit", "6M",
+            "--peer-upload-limit", "7M",
+            "--peer-download-limit", "8M",
+            "--max-uploads", "5",
+            "--max-downloads", "6",
+        ];
+
+        let defaults = fields(&load_with("defaults", "", &[]));
+        let from_file = fields(&load_with("file", &file, &[]));
+        let from_cli = fields(&load_with("cli", "", &args));
+        let from_both = fields(&load_with("both", &file, &args));
+        for dir in dirs {
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        for i in 0..defaults.len() {
+            let name = defaults[i].0;
+            assert_ne!(from_file[i], defaults[i], "the file doesn't set {}", name);
+            assert_ne!(from_cli[i], from_file[i], "the command line doesn't set {}", name);
+            assert_eq!(from_both[i], from_cli[i], "the file overrides the command line for {}", name);
+        }
+    }
+
+    #[test]
+    fn fields_left_out_keep_their_defaults() {
+        let config = load_with("partial", "chunk_size = 8192", &["--nickname", "from_cli"]);
+        let defaults = Config::default();
+        assert_eq!(config.chunk_size, 8192);
+        assert_eq!(config.nickname.as_deref(), Some("from_cli"));
+        assert_eq!(config.max_uploads, defaults.max_uploads);
+        assert_eq!(config.heartbeat_interval, defaults.heartbeat_interval);
+        assert_eq!(config.rendezvous_points, defaults.rendezvous_points);
+    }
+}
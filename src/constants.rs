@@ -1,32 +1,32 @@
 /*
-Constants for the SwapBytes application.
+Constants for the SwapBytes terminal interface.
 */
 
 use std::time::Duration;
 
-/// Public topic for global chat messages via Gossipsub.
-pub const SWAPBYTES_TOPIC: &str = "swapbytes-global-chat";
-/// Unique identifier for the private messaging and file transfer protocol.
-pub const PROTOCOL_NAME: &[u8] = b"/swapbytes/private/1.0.0";
-/// Interval for sending heartbeat messages.
-pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
-/// Time before a peer is considered offline if no heartbeat is received.
-pub const PEER_TIMEOUT: Duration = Duration::from_secs(8);
-/// File transfer chunk size in bytes.
-pub const CHUNK_SIZE: usize = 64 * 1024; // 64 KiB
-/// Number of bytes transferred before sending a progress update to the UI.
-pub const PROGRESS_UPDATE_BYTES: u64 = 512 * 1024; // 512 KiB 
+/// Finished transfers kept in the Transfers pane (Ctrl+T) besides the running ones.
+pub const MAX_RECENT_TRANSFERS: usize = 20;
+/// A running transfer with no progress for this long is shown as stalled instead of with its last rate.
+pub const TRANSFER_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the Transfers pane is redrawn while it is open, so rates and ETAs keep moving.
+pub const TRANSFERS_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// How old samples are weighted against the latest one when smoothing a transfer's current rate (0-1).
+pub const TRANSFER_RATE_SMOOTHING: f64 = 0.7;
 /// How long the "Pinging..." indicator stays visible after sending a ping.
 pub const PINGING_DURATION: Duration = Duration::from_millis(2000);
 // Limit how many lines we keep in the console log to prevent using too much memory.
 pub const MAX_LOG_LINES: usize = 1000;
+/// Name of the control socket of headless mode, inside the runtime (or data) directory.
+pub const CONTROL_SOCKET_NAME: &str = "control.sock";
+/// Events kept for each control client that is slow to read them; it misses any beyond this.
+pub const CONTROL_EVENT_BUFFER: usize = 1024;
+/// How long a stopping daemon waits for the swarm task to wind down.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
 
-// --- Rendezvous Configuration ---
-/// Rendezvous namespace we register in.
-pub const RENDEZVOUS_NS: &str = "swapbytes";
-/// Peer ID of the Rendezvous server.
-pub const RENDEZVOUS_PEER_ID: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
-/// Multiaddress of the Rendezvous server.
-pub const RENDEZVOUS_ADDR: &str = "/ip4/192.168.1.216/tcp/62649";
-// LOCAL MACHINE: 127.0.0.1
-// LAPTOP ON HOME NETWORK: 192.168.1.216
+// --- Exit Statuses Of One-Shot Actions ---
+/// Something went wrong: a bad argument, a failed transfer, ...
+pub const EXIT_FAILURE: i32 = 1;
+/// Gave up waiting for the peer, an offer, or an answer to ours, or the transfer stopped making progress.
+pub const EXIT_TIMEOUT: i32 = 3;
+/// The peer declined our offer.
+pub const EXIT_DECLINED: i32 = 4;
@@ -1,5 +1,5 @@
 /*
-Handles application events received from the main loop or the swarm task.
+Handles the events a SwapBytes node reports to the UI.
 */
 
 // --- Standard Library Imports ---
@@ -9,131 +9,101 @@ use std::path::PathBuf;
 // --- Async and Tokio Imports ---
 use tokio::sync::mpsc;
 
-// --- libp2p Imports ---
-use libp2p::{swarm::SwarmEvent, gossipsub, ping};
-
 // --- Local Crate Imports ---
-use crate::{
-    App, AppEvent,
-    tui::{self, PeerInfo, OnlineStatus, ChatMessage, PrivateChatItem, PendingOfferDetails},
-    behavior::SwapBytesBehaviourEvent,
-    input_handler,
-    utils,
-};
+use crate::tui::{self, App, PeerInfo, OnlineStatus, ChatMessage, PrivateChatItem, PendingOfferDetails, TransferRowStatus};
+use swapbytes_core::event::{SwapDetails, TransferState};
+use swapbytes_core::protocol::TransferDirection;
+use swapbytes_core::{utils, Command, Event};
 
 // --- Function Definition ---
 
-/// Handles application-level events (`AppEvent`).
+/// Handles an event reported by the swarm task.
 ///
 /// This function takes the current application state (`App`), a sender channel (`cmd_tx`)
-/// to send commands back to the swarm task, and the `AppEvent` to process.
+/// to send commands back to the swarm task, and the `Event` to process.
 ///
 /// It updates the `App` state based on the event and returns `true` if the
 /// UI needs to be redrawn, `false` otherwise.
-pub fn handle_app_event(
+pub fn handle_node_event(
     app: &mut App,
-    cmd_tx: &mpsc::UnboundedSender<AppEvent>, // Used for sending commands back to swarm_task
-    event: AppEvent,
+    cmd_tx: &mpsc::UnboundedSender<Command>, // Used for sending commands back to swarm_task
+    event: Event,
 ) -> bool {
     let mut redraw = false; // Assume no UI redraw needed initially
 
     match event {
-        // --- Swarm Events ---
-        // Handle events forwarded from the libp2p Swarm task.
-        AppEvent::Swarm(se) => {
-            match se {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    // Store new listening addresses announced by the Swarm.
-                    app.listening_addresses.push(address.clone());
-                }
-                SwarmEvent::Behaviour(
-                    SwapBytesBehaviourEvent::Ping(ping::Event { peer, result, .. })
-                ) => {
-                    // Handle results of ping requests initiated by the user.
-                    match result {
-                        Ok(latency) => {
-                            // Only log success if we explicitly initiated the ping.
-                            if app.pinging {
-                                app.push(format!("Successfully pinged peer: {peer} ({latency:?})"));
-                                // The ping timer in `App` handles resetting `app.pinging`.
-                            }
-                        }
-                        Err(e) => {
-                            // Only log failure if we explicitly initiated the ping.
-                            if app.pinging {
-                                app.push(format!("Ping failed for peer: {peer} ({e:?})"));
-                            }
-                        }
-                    }
-                    // Any ping activity (even incoming) updates the peer's status.
-                    if let Some(peer_info) = app.peers.get_mut(&peer) {
-                        peer_info.last_seen = Instant::now();
-                        peer_info.status = OnlineStatus::Online;
-                    }
-                }
-                // Note: mDNS events (discovery/expiration) are now primarily handled
-                // directly in the `swarm_task` and result in `PeerDiscovered`/`PeerExpired`
-                // `AppEvent`s being sent to the UI thread.
-
-                // Handle forwarded Gossipsub messages primarily to update peer activity.
-                SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                    propagation_source: peer_id,
-                    ..
-                })) => {
-                    // Update the last seen time and status for the peer sending the message.
-                    let now = Instant::now();
-                    let peer_info = app.peers.entry(peer_id).or_insert_with(|| PeerInfo {
-                        nickname: None,
-                        status: OnlineStatus::Online,
-                        last_seen: now,
-                    });
-                    peer_info.last_seen = now;
-                    peer_info.status = OnlineStatus::Online;
-                }
-
-                SwarmEvent::OutgoingConnectionError { error, .. } => {
-                    // Optionally log or handle outgoing connection errors.
-                    let _ = error; // Avoid unused variable warning
-                }
-                SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
-                    // Update peer status when a connection is established.
-                     if let Some(peer_info) = app.peers.get_mut(&peer_id) {
-                        peer_info.status = OnlineStatus::Online;
-                        peer_info.last_seen = Instant::now();
-                    }
-                    let _ = endpoint; // Avoid unused variable warning
-                    let _ = num_established; // Avoid unused variable warning
-                }
-                SwarmEvent::ConnectionClosed { peer_id, cause, num_established, .. } => {
-                    // Handle connection closures. Peer status is primarily managed by the
-                    // heartbeat/timeout mechanism, not just connection closure.
-                    let _ = peer_id; // Avoid unused variable warning
-                    let _ = cause; // Avoid unused variable warning
-                    let _ = num_established; // Avoid unused variable warning
+        // --- Network Events ---
+        Event::ListeningOn(address) => {
+            // Store new listening addresses announced by the Swarm.
+            app.listening_addresses.push(address);
+            redraw = true;
+        }
+        Event::ObservedAddress(address) => {
+            app.observed_addresses.push(address);
+        }
+        Event::ExternalAddressConfirmed(address) => {
+            app.push(format!("Confirmed external address: {} ({})", address, utils::transport_name(&address)));
+            if !app.external_addresses.contains(&address) {
+                app.external_addresses.push(address);
+            }
+            redraw = true;
+        }
+        Event::ExternalAddressExpired(address) => {
+            app.external_addresses.retain(|known| *known != address);
+        }
+        Event::ReachabilityChanged(reachability) => {
+            // The swarm task logs the change itself
+            app.reachability = reachability;
+        }
+        Event::PingResult { peer_id, result } => {
+            // Only log results of pings we explicitly initiated.
+            // The ping timer in `App` handles resetting `app.pinging`.
+            if app.pinging {
+                match result {
+                    Ok(latency) => app.push(format!("Successfully pinged peer: {peer_id} ({latency:?})")),
+                    Err(e) => app.push(format!("Ping failed for peer: {peer_id} ({e})")),
                 }
-                // Catch-all for other SwarmEvents we don't explicitly handle here.
-                _ => {}
             }
-            redraw = true; // Most swarm events likely require a UI redraw.
+            // Any ping activity (even incoming) updates the peer's status.
+            if let Some(peer_info) = app.peers.get_mut(&peer_id) {
+                peer_info.last_seen = Instant::now();
+                peer_info.status = OnlineStatus::Online;
+            }
+            redraw = true;
         }
-
-        // --- User Input ---
-        AppEvent::Input(key) => {
-            // Delegate keyboard input handling to the dedicated input handler module.
-            let needs_redraw = input_handler::handle_key_event(app, cmd_tx, key);
-            redraw = redraw || needs_redraw;
-            // Note: Checking `app.exit` happens after this function returns in main.rs
+        Event::PeerSeen(peer_id) => {
+            // A peer passed on a global message: update its last seen time and status.
+            let now = Instant::now();
+            let peer_info = app.peers.entry(peer_id).or_insert_with(|| PeerInfo {
+                nickname: None,
+                status: OnlineStatus::Online,
+                last_seen: now,
+            });
+            peer_info.last_seen = now;
+            peer_info.status = OnlineStatus::Online;
+            redraw = true;
+        }
+        Event::PeerConnected(peer_id) => {
+            // Update peer status when a connection is established.
+            if let Some(peer_info) = app.peers.get_mut(&peer_id) {
+                peer_info.status = OnlineStatus::Online;
+                peer_info.last_seen = Instant::now();
+            }
+            redraw = true;
+        }
+        Event::PeerDisconnected(_) => {
+            // Peer status is managed by the heartbeat/timeout mechanism, not just connection closure.
         }
 
         // --- Logging ---
-        AppEvent::LogMessage(msg) => {
+        Event::LogMessage(msg) => {
             // Add a message to the application's console/log buffer.
             app.push(msg);
             redraw = true;
         }
 
         // --- Peer Discovery and Status ---
-        AppEvent::PeerDiscovered(peer_id) => {
+        Event::PeerDiscovered(peer_id) => {
             // Handle peers discovered via mDNS or other mechanisms.
             if peer_id != app.local_peer_id.expect("Local peer ID should be set") {
                 let now = Instant::now();
@@ -148,14 +118,8 @@ pub fn handle_app_event(
                 redraw = true;
             }
         }
-        AppEvent::PeerExpired(peer_id) => {
-            // Peer expiration is primarily handled by the heartbeat timeout check now.
-            // This event might be used for logging or immediate removal if needed later.
-            let _ = peer_id; // Avoid unused variable warning
-        }
-
         // --- Nickname Updates ---
-        AppEvent::NicknameUpdated(peer_id, new_nickname) => {
+        Event::NicknameUpdated(peer_id, new_nickname) => {
             // Update a peer's nickname based on received gossipsub messages.
             if Some(peer_id) == app.local_peer_id {
                 // Ignore updates for the local user (handled directly by input command).
@@ -179,19 +143,19 @@ pub fn handle_app_event(
                 }
 
                 // Update Chat Title if viewing private chat with this peer.
-                if let tui::ChatContext::Private { target_peer_id, target_nickname } = &mut app.current_chat_context {
-                    if *target_peer_id == peer_id {
-                        *target_nickname = Some(new_nickname.clone());
-                    }
+                if let tui::ChatContext::Private { target_peer_id, target_nickname } = &mut app.current_chat_context
+                    && *target_peer_id == peer_id
+                {
+                    *target_nickname = Some(new_nickname.clone());
                 }
 
                 // Update nickname in Private Chat History for this peer.
                 if let Some(history) = app.private_chat_histories.get_mut(&peer_id) {
                     for item in history.iter_mut() {
-                        if let PrivateChatItem::Message(message) = item {
-                            if message.sender_id == peer_id {
-                                message.sender_nickname = Some(new_nickname.clone());
-                            }
+                        if let PrivateChatItem::Message(message) = item
+                            && message.sender_id == peer_id
+                        {
+                            message.sender_nickname = Some(new_nickname.clone());
                         }
                         // Future: Update Offer/OfferSent/etc. if they store nicknames.
                     }
@@ -207,7 +171,7 @@ pub fn handle_app_event(
         }
 
         // --- Global Chat ---
-        AppEvent::GlobalMessageReceived { sender_id, sender_nickname, content, timestamp_ms } => {
+        Event::GlobalMessageReceived { sender_id, sender_nickname, content, timestamp_ms } => {
             // Handle incoming global chat messages received via gossipsub.
             let chat_msg = ChatMessage {
                 sender_id,
@@ -237,7 +201,7 @@ pub fn handle_app_event(
         }
 
         // --- Private Chat ---
-        AppEvent::PrivateMessageReceived { sender_id, content } => {
+        Event::PrivateMessageReceived { sender_id, content } => {
             // Handle incoming private messages received via direct send.
             let sender_nickname = app.peers.get(&sender_id).and_then(|info| info.nickname.clone());
             let timestamp_ms = SystemTime::now()
@@ -284,7 +248,7 @@ pub fn handle_app_event(
         }
 
         // --- File Transfer Offers ---
-        AppEvent::FileOfferReceived { sender_id, filename, size_bytes } => {
+        Event::FileOfferReceived { sender_id, offer_id, filename, size_bytes, hash, file_count } => {
             // Handle an incoming file transfer offer.
             let sender_display_name = app.peers.get(&sender_id)
                 .and_then(|info| info.nickname.clone())
@@ -292,31 +256,41 @@ pub fn handle_app_event(
 
             // Check if the user is currently viewing the chat with this sender.
             let mut is_viewing_chat = false;
-            if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context {
-                if *target_peer_id == sender_id {
-                    is_viewing_chat = true;
-                }
+            if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context
+                && *target_peer_id == sender_id
+            {
+                is_viewing_chat = true;
             }
 
             // Store the details of the pending offer.
             let offer_details = PendingOfferDetails {
+                offer_id,
                 filename: filename.clone(),
                 size_bytes,
                 path: PathBuf::new(), // Path is irrelevant for received offers.
+                // Offers of several files carry a hash per file, which the swarm task keeps
+                hash: if file_count.is_some() { None } else { Some(hash) },
+                file_count,
             };
-            // Overwrite any previous pending offer from this sender.
-            app.pending_offers.insert(sender_id, offer_details.clone());
+
+            // Keep it alongside any other offers from this sender. A new offer of the same
+            // filename replaces the old one, since downloads are tracked by filename.
+            let offers = app.pending_offers.entry(sender_id).or_default();
+            offers.retain(|offer| offer.filename != filename);
+            offers.push(offer_details.clone());
 
             // Add the offer to the private chat history for this sender.
             let history = app.private_chat_histories.entry(sender_id).or_default();
             let current_len = history.len(); // Length before adding the offer.
-            history.push(PrivateChatItem::Offer(offer_details));
+            history.push(PrivateChatItem::Offer(offer_details.clone()));
 
             // Notify the user in the console if they aren't viewing the chat.
             if !is_viewing_chat {
                 app.push(format!(
-                    "{} sent you a file offer: {} ({})",
+                    "{} sent you an offer of {} [{}]: {} ({})",
                     sender_display_name,
+                    offer_details.kind(),
+                    offer_details.offer_id,
                     filename,
                     utils::format_bytes(size_bytes)
                 ));
@@ -330,7 +304,55 @@ pub fn handle_app_event(
             }
             redraw = true;
         }
-        AppEvent::FileOfferDeclined { peer_id, filename } => {
+        Event::OfferAutoAccepted { sender_id, offer_id, filename, size_bytes, hash, file_count, rule, .. } => {
+            // An offer the node accepted by an auto-accept rule, as it arrived or once the sender's nickname was known
+            let offer = PendingOfferDetails {
+                offer_id,
+                filename,
+                size_bytes,
+                path: PathBuf::new(),
+                hash: if file_count.is_some() { None } else { Some(hash) },
+                file_count,
+            };
+            app.push(format!(
+                "Auto-accepted offer [{}] for '{}' from {} ({}).",
+                offer.offer_id,
+                offer.filename,
+                display_name(app, &sender_id),
+                utils::format_bytes(offer.size_bytes)
+            ));
+            if let Some(offers) = app.pending_offers.get_mut(&sender_id) {
+                offers.retain(|pending| pending.offer_id != offer.offer_id);
+            }
+            // An offer that was already waiting for /accept is replaced in the chat by the accepted one
+            let waiting = app.private_chat_histories.get(&sender_id)
+                .and_then(|history| history.iter().position(|item| matches!(item, PrivateChatItem::Offer(pending) if pending.offer_id == offer.offer_id)));
+            let item = PrivateChatItem::OfferAutoAccepted { offer, rule };
+            match waiting {
+                Some(index) => app.private_chat_histories.entry(sender_id).or_default()[index] = item,
+                None => push_chat_item(app, sender_id, item),
+            }
+            redraw = true;
+        }
+        Event::OfferRejected { peer_id, filename, reason } => {
+            // An offer we refused on arrival; the peer has already been told why.
+            let peer_display_name = app.peers.get(&peer_id)
+                .and_then(|info| info.nickname.clone())
+                .unwrap_or_else(|| utils::peer_id_to_short_string(&peer_id));
+            app.push(format!("⚠️ Refused an offer of '{}' from {}: {}", filename, peer_display_name, reason));
+            push_chat_item(app, peer_id, PrivateChatItem::OfferRejected { filename, reason });
+            redraw = true;
+        }
+        Event::FileOfferRejected { peer_id, filename, reason, .. } => {
+            // An offer of ours the peer refused on arrival; its user never saw it.
+            let peer_display_name = app.peers.get(&peer_id)
+                .and_then(|info| info.nickname.clone())
+                .unwrap_or_else(|| utils::peer_id_to_short_string(&peer_id));
+            app.push(format!("⚠️ {} refused your offer of '{}': {}", peer_display_name, filename, reason));
+            push_chat_item(app, peer_id, PrivateChatItem::RemoteOfferRejected { filename, reason });
+            redraw = true;
+        }
+        Event::FileOfferDeclined { peer_id, offer_id, filename } => {
             // Handle notification that a peer declined our file offer.
             let peer_display_name = app.peers.get(&peer_id)
                 .and_then(|info| info.nickname.clone())
@@ -343,12 +365,12 @@ pub fn handle_app_event(
             if let Some(history) = app.private_chat_histories.get_mut(&peer_id) {
                 // Find the original `OfferSent` details to include in the Declined item.
                 let mut offer_details_opt: Option<PendingOfferDetails> = None;
-                for item in history.iter() {
-                    if let PrivateChatItem::OfferSent(details) = item {
-                        if details.filename == filename {
-                            offer_details_opt = Some(details.clone());
-                            break;
-                        }
+                for item in history.iter().rev() {
+                    if let PrivateChatItem::OfferSent(details) = item
+                        && details.matches(&offer_id, &filename)
+                    {
+                        offer_details_opt = Some(details.clone());
+                        break;
                     }
                 }
 
@@ -357,13 +379,13 @@ pub fn handle_app_event(
                     history.push(PrivateChatItem::RemoteOfferDeclined(offer_details));
 
                     // Auto-scroll if viewing this chat and already at the bottom.
-                    if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context {
-                        if *target_peer_id == peer_id {
-                            let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
-                            if app.chat_scroll >= current_max_scroll {
-                                let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
-                                app.chat_scroll = new_max_scroll;
-                            }
+                    if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context
+                        && *target_peer_id == peer_id
+                    {
+                        let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
+                        if app.chat_scroll >= current_max_scroll {
+                            let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                            app.chat_scroll = new_max_scroll;
                         }
                     }
                 } else {
@@ -376,7 +398,7 @@ pub fn handle_app_event(
             }
             redraw = true;
         }
-        AppEvent::FileOfferAccepted { peer_id, filename } => {
+        Event::FileOfferAccepted { peer_id, offer_id, filename } => {
             // Handle notification that a peer accepted our file offer.
             let peer_display_name = app.peers.get(&peer_id)
                 .and_then(|info| info.nickname.clone())
@@ -386,32 +408,38 @@ pub fn handle_app_event(
             app.push(format!("{} accepted your offer for '{}'.", peer_display_name, filename));
 
             let mut found_path: Option<PathBuf> = None;
+            let mut handled_by_swarm = false;
             // Add a record of the acceptance to the private chat history.
             if let Some(history) = app.private_chat_histories.get_mut(&peer_id) {
                 // Find the original `OfferSent` details, including the file path.
                 let mut offer_details_opt: Option<PendingOfferDetails> = None;
-                for item in history.iter() {
-                    if let PrivateChatItem::OfferSent(details) = item {
-                        if details.filename == filename {
-                            offer_details_opt = Some(details.clone());
-                            found_path = Some(details.path.clone()); // Extract the path here.
-                            break;
-                        }
+                for item in history.iter().rev() {
+                    if let PrivateChatItem::OfferSent(details) = item
+                        && details.matches(&offer_id, &filename)
+                    {
+                        offer_details_opt = Some(details.clone());
+                        found_path = Some(details.path.clone()); // Extract the path here.
+                        break;
                     }
                 }
 
+                // The files of a bundle were registered by the swarm task when it sent the offer
+                if offer_details_opt.as_ref().is_some_and(|details| details.file_count.is_some()) {
+                    found_path = None;
+                    handled_by_swarm = true;
+                }
                 if let Some(offer_details) = offer_details_opt {
                     let current_len = history.len(); // Length before adding accepted item.
                     history.push(PrivateChatItem::RemoteOfferAccepted(offer_details));
 
                     // Auto-scroll if viewing this chat and already at the bottom.
-                    if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context {
-                        if *target_peer_id == peer_id {
-                            let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
-                            if app.chat_scroll >= current_max_scroll {
-                                let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
-                                app.chat_scroll = new_max_scroll;
-                            }
+                    if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context
+                        && *target_peer_id == peer_id
+                    {
+                        let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
+                        if app.chat_scroll >= current_max_scroll {
+                            let new_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                            app.chat_scroll = new_max_scroll;
                         }
                     }
                 } else {
@@ -428,12 +456,12 @@ pub fn handle_app_event(
             if let Some(path) = found_path {
                 app.outgoing_transfers.insert((peer_id, filename.clone()), path.clone());
                 // Send event to swarm task to register the stream handler for this transfer.
-                let _ = cmd_tx.send(AppEvent::RegisterOutgoingTransfer {
+                let _ = cmd_tx.send(Command::RegisterOutgoingTransfer {
                     peer_id,
                     filename: filename.clone(),
                     path
                 });
-            } else {
+            } else if !handled_by_swarm {
                  // Log an error if the path is missing, as the transfer cannot start.
                  app.log(format!("Error: Missing path for accepted offer '{}' from {}. Cannot start transfer.", filename, peer_display_name));
             }
@@ -441,21 +469,21 @@ pub fn handle_app_event(
         }
 
         // --- File Transfer Progress & Status ---
-        AppEvent::FileTransferProgress { peer_id, filename, received, total } => {
+        Event::FileTransferProgress { peer_id, filename, received, total, throttled } => {
             // Update the UI with the progress of an incoming or outgoing file transfer.
+            app.track_transfer_progress(peer_id, &filename, TransferDirection::Download, received, total, throttled);
             let history = app.private_chat_histories.entry(peer_id).or_default();
             let current_len = history.len(); // Length before potential update/add.
             let mut updated_existing = false;
 
             // Check if the last item in the history is a progress update for the same file.
             // If so, update it in place to avoid cluttering the history.
-            if let Some(last_item) = history.last_mut() {
-                if let PrivateChatItem::TransferProgress { filename: item_filename, received: item_received, .. } = last_item {
-                    if *item_filename == filename {
-                        *item_received = received; // Update bytes received.
-                        updated_existing = true;
-                    }
-                }
+            if let Some(last_item) = history.last_mut()
+                && let PrivateChatItem::TransferProgress { filename: item_filename, received: item_received, .. } = last_item
+                && *item_filename == filename
+            {
+                *item_received = received; // Update bytes received.
+                updated_existing = true;
             }
 
             // If no existing progress item was updated, add a new one.
@@ -468,27 +496,28 @@ pub fn handle_app_event(
             }
 
             // Auto-scroll if viewing this chat and near the bottom.
-            if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context {
-                if *target_peer_id == peer_id {
-                    let scroll_target = if updated_existing { current_len } else { history.len() };
-                    let current_max_scroll = scroll_target.saturating_sub(app.chat_viewport_height.max(1));
-                    // Scroll if current scroll position is at or just above the last item.
-                    if app.chat_scroll >= current_max_scroll.saturating_sub(1) {
-                        app.chat_scroll = current_max_scroll;
-                    }
+            if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context
+                && *target_peer_id == peer_id
+            {
+                let scroll_target = if updated_existing { current_len } else { history.len() };
+                let current_max_scroll = scroll_target.saturating_sub(app.chat_viewport_height.max(1));
+                // Scroll if current scroll position is at or just above the last item.
+                if app.chat_scroll >= current_max_scroll.saturating_sub(1) {
+                    app.chat_scroll = current_max_scroll;
                 }
             }
             redraw = true;
         }
-        AppEvent::FileTransferComplete { peer_id, filename, path, total_size } => {
+        Event::FileTransferComplete { peer_id, filename, path, total_size, hash } => {
             // Handle the completion of a file download (transfer received).
+            app.set_transfer_status(peer_id, &filename, TransferDirection::Download, TransferRowStatus::Complete, Some((total_size, total_size)));
             let history = app.private_chat_histories.entry(peer_id).or_default();
 
             // Optional: Remove the last progress item for this file to clean up history.
-            if let Some(last_item) = history.last() {
-                if matches!(last_item, PrivateChatItem::TransferProgress { filename: item_filename, .. } if *item_filename == filename) {
-                    history.pop();
-                }
+            if let Some(last_item) = history.last()
+                && matches!(last_item, PrivateChatItem::TransferProgress { filename: item_filename, .. } if *item_filename == filename)
+            {
+                history.pop();
             }
 
             // Add a completion record to the private chat history.
@@ -496,15 +525,16 @@ pub fn handle_app_event(
                 filename: filename.clone(),
                 final_path: path, // Store the final path where the file was saved.
                 size: total_size,
+                hash,
             });
 
-             // Auto-scroll if viewing this chat and near the bottom.
-             if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context {
-                if *target_peer_id == peer_id {
-                    let current_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
-                     if app.chat_scroll >= current_max_scroll.saturating_sub(1) { // Scroll if near bottom
-                        app.chat_scroll = current_max_scroll;
-                    }
+            // Auto-scroll if viewing this chat and near the bottom.
+            if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context
+                && *target_peer_id == peer_id
+            {
+                let current_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                if app.chat_scroll >= current_max_scroll.saturating_sub(1) { // Scroll if near bottom
+                    app.chat_scroll = current_max_scroll;
                 }
             }
 
@@ -512,18 +542,20 @@ pub fn handle_app_event(
             let peer_display_name = app.peers.get(&peer_id)
                 .and_then(|info| info.nickname.clone())
                 .unwrap_or_else(|| utils::peer_id_to_short_string(&peer_id));
-            app.push(format!("✅ Download finished: '{}' ({}) from {}", filename, utils::format_bytes(total_size), peer_display_name));
+            app.push(format!("✅ Download finished and verified: '{}' ({}) from {}", filename, utils::format_bytes(total_size), peer_display_name));
+            app.interrupted_downloads.remove(&(peer_id, filename));
             redraw = true;
         }
-        AppEvent::FileTransferFailed { peer_id, filename, error } => {
+        Event::FileTransferFailed { peer_id, filename, error } => {
            // Handle the failure of a file transfer (incoming or outgoing).
+           app.set_transfer_status(peer_id, &filename, TransferDirection::Download, TransferRowStatus::Failed, None);
            let history = app.private_chat_histories.entry(peer_id).or_default();
 
             // Optional: Remove the last progress item for this file.
-            if let Some(last_item) = history.last() {
-                if matches!(last_item, PrivateChatItem::TransferProgress { filename: item_filename, .. } if *item_filename == filename) {
-                    history.pop();
-                }
+            if let Some(last_item) = history.last()
+                && matches!(last_item, PrivateChatItem::TransferProgress { filename: item_filename, .. } if *item_filename == filename)
+            {
+                history.pop();
             }
 
             // Add a failure record to the private chat history.
@@ -532,13 +564,13 @@ pub fn handle_app_event(
                 error: error.clone(), // Store the error message.
             });
 
-             // Auto-scroll if viewing this chat and near the bottom.
-             if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context {
-                if *target_peer_id == peer_id {
-                     let current_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
-                     if app.chat_scroll >= current_max_scroll.saturating_sub(1) { // Scroll if near bottom
-                        app.chat_scroll = current_max_scroll;
-                    }
+            // Auto-scroll if viewing this chat and near the bottom.
+            if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context
+                && *target_peer_id == peer_id
+            {
+                let current_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                if app.chat_scroll >= current_max_scroll.saturating_sub(1) { // Scroll if near bottom
+                    app.chat_scroll = current_max_scroll;
                 }
             }
 
@@ -547,34 +579,299 @@ pub fn handle_app_event(
                 .and_then(|info| info.nickname.clone())
                 .unwrap_or_else(|| utils::peer_id_to_short_string(&peer_id));
             app.push(format!("❌ Transfer failed for '{}' from {}: {}", filename, peer_display_name, error));
+            // A failed download can't be resumed any more
+            app.interrupted_downloads.remove(&(peer_id, filename));
             redraw = true;
         }
+        Event::DownloadInterrupted { peer_id, filename, received, total } => {
+            // A download stopped part-way; the swarm task kept its partial file for resuming.
+            app.set_transfer_status(peer_id, &filename, TransferDirection::Download, TransferRowStatus::Interrupted, Some((received, total)));
+            let history = app.private_chat_histories.entry(peer_id).or_default();
 
-        // --- Events Primarily Handled by Swarm Task ---
-        // These events represent commands sent *from* the UI/Input handler *to* the
-        // swarm task, or events that should be directly handled by the swarm task.
-        // If they arrive here, it's likely an unexpected state or logic error.
-        AppEvent::SendAcceptOffer { target_peer, filename, size_bytes } => {
-             // Forward this command directly to the swarm task.
-            let _ = cmd_tx.send(AppEvent::SendAcceptOffer { target_peer, filename, size_bytes });
-            // No UI state change needed here, so no redraw.
-        }
-        AppEvent::PublishGossipsub(_) |
-        AppEvent::SendPrivateMessage { .. } |
-        AppEvent::SendFileOffer { .. } |
-        AppEvent::DeclineFileOffer { .. } | // This is a command *to* the swarm task.
-        AppEvent::DownloadDirChanged(_) |
-        AppEvent::RegisterOutgoingTransfer { .. } | // Handled in FileOfferAccepted above.
-        AppEvent::VisibilityChanged(_) |
-        AppEvent::Dial(_) |
-        AppEvent::EnterChat(_) | // Handled by input_handler.
-        AppEvent::Quit => { // Handled after this function returns in main loop.
-            // Log a warning if these events are received unexpectedly in the UI event handler.
-            app.log(format!("Warning: Received unexpected event meant for swarm task or input handler in UI handler: {:?}", event));
-            // Decide if redraw is needed for unexpected events; default to false.
-            // redraw = true;
+            // Replace the last progress item for this file with the interruption notice.
+            if let Some(last_item) = history.last()
+                && matches!(last_item, PrivateChatItem::TransferProgress { filename: item_filename, .. } if *item_filename == filename)
+            {
+                history.pop();
+            }
+            history.push(PrivateChatItem::TransferInterrupted {
+                filename: filename.clone(),
+                received,
+                total,
+            });
+
+            // Auto-scroll if viewing this chat and near the bottom.
+            if let tui::ChatContext::Private { target_peer_id, .. } = &app.current_chat_context
+                && *target_peer_id == peer_id
+            {
+                let current_max_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+                if app.chat_scroll >= current_max_scroll.saturating_sub(1) { // Scroll if near bottom
+                    app.chat_scroll = current_max_scroll;
+                }
+            }
+
+            let peer_display_name = app.peers.get(&peer_id)
+                .and_then(|info| info.nickname.clone())
+                .unwrap_or_else(|| utils::peer_id_to_short_string(&peer_id));
+            app.push(format!(
+                "⏸ Download of '{}' from {} interrupted at {} / {}. Use /resume to continue.",
+                filename, peer_display_name, utils::format_bytes(received), utils::format_bytes(total)
+            ));
+            app.interrupted_downloads.insert((peer_id, filename), (received, total));
+            redraw = true;
+        }
+        Event::DownloadResumed { peer_id, filename, received, total } => {
+            // An interrupted download is running again; show it as in progress.
+            app.track_transfer_progress(peer_id, &filename, TransferDirection::Download, received, total, false);
+            app.interrupted_downloads.remove(&(peer_id, filename.clone()));
+            let history = app.private_chat_histories.entry(peer_id).or_default();
+            history.push(PrivateChatItem::TransferProgress {
+                filename: filename.clone(),
+                received,
+                total,
+            });
+
+            let peer_display_name = app.peers.get(&peer_id)
+                .and_then(|info| info.nickname.clone())
+                .unwrap_or_else(|| utils::peer_id_to_short_string(&peer_id));
+            app.push(format!(
+                "▶ Resuming '{}' from {} at {} / {}",
+                filename, peer_display_name, utils::format_bytes(received), utils::format_bytes(total)
+            ));
+            redraw = true;
+        }
+
+        Event::TransferList(transfers) => {
+            if transfers.is_empty() {
+                app.push("No active transfers.".to_string());
+            } else {
+                app.push("Transfers:".to_string());
+                let mut lines: Vec<String> = transfers.iter().map(|transfer| {
+                    let peer_display_name = app.peers.get(&transfer.peer_id)
+                        .and_then(|info| info.nickname.clone())
+                        .unwrap_or_else(|| utils::peer_id_to_short_string(&transfer.peer_id));
+                    let (arrow, preposition) = match transfer.direction {
+                        TransferDirection::Download => ("↓", "from"),
+                        TransferDirection::Upload => ("↑", "to"),
+                    };
+                    // Uploads don't know how far the receiver has got
+                    let size = match transfer.received {
+                        Some(received) => format!("{} / {}", utils::format_bytes(received), utils::format_bytes(transfer.total)),
+                        None => utils::format_bytes(transfer.total),
+                    };
+                    let state = match transfer.state {
+                        TransferState::Running => String::new(),
+                        TransferState::Paused => " [paused]".to_string(),
+                        TransferState::PausedByPeer => " [paused by peer]".to_string(),
+                        TransferState::Interrupted => " [interrupted]".to_string(),
+                        TransferState::Queued(place) => format!(" [queued, #{} in line]", place),
+                        TransferState::QueuedByPeer(place) => format!(" [#{} in the peer's queue]", place),
+                    };
+                    format!("  {} '{}' {} {}: {}{}", arrow, transfer.filename, preposition, peer_display_name, size, state)
+                }).collect();
+                lines.sort();
+                for line in lines {
+                    app.push(line);
+                }
+            }
+            redraw = true;
+        }
+        Event::TransferPaused { peer_id, filename, direction, by_peer, progress } => {
+            app.set_transfer_status(peer_id, &filename, direction, TransferRowStatus::Paused, progress);
+            let who = if by_peer { display_name(app, &peer_id) } else { "You".to_string() };
+            app.push(format!("⏸ {} paused {} '{}'.", who, tui::transfer_activity(direction, by_peer), filename));
+            // A paused download is listed by /resume like an interrupted one
+            if let Some((received, total)) = progress {
+                app.interrupted_downloads.insert((peer_id, filename.clone()), (received, total));
+                if let Some(history) = app.private_chat_histories.get_mut(&peer_id)
+                    && matches!(history.last(), Some(PrivateChatItem::TransferProgress { filename: item_filename, .. }) if *item_filename == filename)
+                {
+                    history.pop();
+                }
+            }
+            push_chat_item(app, peer_id, PrivateChatItem::TransferPaused { filename, direction, by_peer });
+            redraw = true;
+        }
+        Event::TransferResumed { peer_id, filename, direction, by_peer } => {
+            let who = if by_peer { display_name(app, &peer_id) } else { "You".to_string() };
+            app.push(format!("▶ {} resumed {} '{}'.", who, tui::transfer_activity(direction, by_peer), filename));
+            push_chat_item(app, peer_id, PrivateChatItem::TransferResumed { filename, direction, by_peer });
+            redraw = true;
+        }
+        Event::TransferQueued { peer_id, filename, direction, place, by_peer, progress } => {
+            // Only say so when it joins the line; after that the Transfers pane keeps its place up to date
+            let newly_queued = !app.transfers.iter().rev()
+                .find(|row| row.peer_id == peer_id && row.direction == direction && row.filename == filename)
+                .is_some_and(|row| matches!(row.status, TransferRowStatus::Queued(_)));
+            app.set_transfer_status(peer_id, &filename, direction, TransferRowStatus::Queued(place), progress);
+            if newly_queued {
+                let peer_display_name = display_name(app, &peer_id);
+                let message = match (direction, by_peer) {
+                    (TransferDirection::Download, true) => format!("⏳ {} is busy sending other files. '{}' is #{} in their line.", peer_display_name, filename, place),
+                    (TransferDirection::Download, false) => format!("⏳ All download slots are in use. '{}' from {} is #{} in line.", filename, peer_display_name, place),
+                    (TransferDirection::Upload, _) => format!("⏳ All upload slots are in use. {} is #{} in line for '{}'.", peer_display_name, place, filename),
+                };
+                app.push(message);
+            }
+            redraw = newly_queued || app.show_transfers;
+        }
+        Event::TransferCancelled { peer_id, filename, direction, by_peer } => {
+            app.set_transfer_status(peer_id, &filename, direction, TransferRowStatus::Cancelled, None);
+            let who = if by_peer { display_name(app, &peer_id) } else { "You".to_string() };
+            app.push(format!("✖ {} cancelled {} '{}'.", who, tui::transfer_activity(direction, by_peer), filename));
+            app.interrupted_downloads.remove(&(peer_id, filename.clone()));
+            // Its progress line (or the bundle's) is stale now
+            if let Some(history) = app.private_chat_histories.get_mut(&peer_id) {
+                if matches!(history.last(), Some(PrivateChatItem::TransferProgress { filename: item_filename, .. }) if *item_filename == filename) {
+                    history.pop();
+                }
+                history.retain(|item| !matches!(item, PrivateChatItem::BundleProgress(status) if status.name == filename));
+            }
+            push_chat_item(app, peer_id, PrivateChatItem::TransferCancelled { filename, direction, by_peer });
+            redraw = true;
+        }
+
+        Event::UploadProgress { peer_id, filename, sent, total, throttled } => {
+            // Only the Transfers pane shows upload progress; the chat would fill up with it
+            app.track_transfer_progress(peer_id, &filename, TransferDirection::Upload, sent, total, throttled);
+            redraw = app.show_transfers;
+        }
+        Event::UploadComplete { peer_id, filename } => {
+            app.set_transfer_status(peer_id, &filename, TransferDirection::Upload, TransferRowStatus::Complete, None);
+            redraw = app.show_transfers;
+        }
+        Event::UploadInterrupted { peer_id, filename } => {
+            app.set_transfer_status(peer_id, &filename, TransferDirection::Upload, TransferRowStatus::Interrupted, None);
+            redraw = app.show_transfers;
+        }
+        Event::UploadFailed { peer_id, filename, error } => {
+            app.set_transfer_status(peer_id, &filename, TransferDirection::Upload, TransferRowStatus::Failed, None);
+            let peer_display_name = display_name(app, &peer_id);
+            app.push(format!("⚠️ Stopped sending '{}' to {}: {}", filename, peer_display_name, error));
+            redraw = true;
+        }
+
+        Event::BundleProgress { peer_id, status, throttled } => {
+            app.track_transfer_progress(peer_id, &status.name, TransferDirection::Download, status.received, status.total, throttled);
+            // Update the bundle's progress item in place, wherever it is in the history
+            let history = app.private_chat_histories.entry(peer_id).or_default();
+            let existing = history.iter().rposition(|item| matches!(item, PrivateChatItem::BundleProgress(item_status) if item_status.name == status.name));
+            match existing {
+                Some(index) => history[index] = PrivateChatItem::BundleProgress(status),
+                None => push_chat_item(app, peer_id, PrivateChatItem::BundleProgress(status)),
+            }
+            redraw = true;
+        }
+        Event::BundleFileComplete { peer_id, name, filename, path, size } => {
+            // A resumed file shows its own progress line; the bundle's item covers it from now on
+            if let Some(history) = app.private_chat_histories.get_mut(&peer_id)
+                && matches!(history.last(), Some(PrivateChatItem::TransferProgress { filename: item_filename, .. }) if *item_filename == filename)
+            {
+                history.pop();
+            }
+            app.log(format!("{}: '{}' ({}) saved to {}", name, filename, utils::format_bytes(size), path.display()));
+            app.interrupted_downloads.remove(&(peer_id, filename));
+            redraw = true;
+        }
+        Event::BundleComplete { peer_id, status, path } => {
+            let row_status = if status.files_failed == 0 { TransferRowStatus::Complete } else { TransferRowStatus::Failed };
+            app.set_transfer_status(peer_id, &status.name, TransferDirection::Download, row_status, Some((status.received, status.total)));
+            // Replace the progress item with the summary
+            if let Some(history) = app.private_chat_histories.get_mut(&peer_id)
+                && let Some(index) = history.iter().rposition(|item| matches!(item, PrivateChatItem::BundleProgress(item_status) if item_status.name == status.name))
+            {
+                history.remove(index);
+            }
+            let peer_display_name = app.peers.get(&peer_id)
+                .and_then(|info| info.nickname.clone())
+                .unwrap_or_else(|| utils::peer_id_to_short_string(&peer_id));
+            if status.files_failed == 0 {
+                app.push(format!(
+                    "✅ Download finished and verified: '{}' ({} files, {}) from {}",
+                    status.name, status.files_done, utils::format_bytes(status.received), peer_display_name
+                ));
+            } else {
+                app.push(format!(
+                    "❌ Download of '{}' from {} finished with {} of {} files missing.",
+                    status.name, peer_display_name, status.files_failed, status.file_count
+                ));
+            }
+            push_chat_item(app, peer_id, PrivateChatItem::BundleComplete { status, path });
+            redraw = true;
+        }
+
+        Event::SwapProposalReceived { sender_id, swap_id, offered, wanted } => {
+            // A peer proposed swapping one of their files for one of ours.
+            let sender_display_name = app.peers.get(&sender_id)
+                .and_then(|info| info.nickname.clone())
+                .unwrap_or_else(|| utils::peer_id_to_short_string(&sender_id));
+            let details = SwapDetails {
+                swap_id: swap_id.clone(),
+                give: wanted,
+                take: offered.filename,
+                take_size: Some(offered.size_bytes),
+            };
+            app.pending_swaps.insert(swap_id, (sender_id, details.clone()));
+            app.push(format!(
+                "{} proposes a swap: their '{}' ({}) for your '{}'",
+                sender_display_name, details.take, utils::format_bytes(offered.size_bytes), details.give
+            ));
+            push_chat_item(app, sender_id, PrivateChatItem::SwapProposal(details));
+            redraw = true;
+        }
+        Event::SwapAgreed { peer_id, details } => {
+            app.push(format!("🤝 Swap agreed: '{}' for '{}'. Both transfers are starting.", details.give, details.take));
+            push_chat_item(app, peer_id, PrivateChatItem::SwapAgreed(details));
+            redraw = true;
+        }
+        Event::SwapWaiting { peer_id, details } => {
+            // Our side is done; replace its progress line with the waiting notice.
+            if let Some(history) = app.private_chat_histories.get_mut(&peer_id)
+                && matches!(history.last(), Some(PrivateChatItem::TransferProgress { filename, .. }) if *filename == details.take)
+            {
+                history.pop();
+            }
+            app.push(format!("'{}' arrived and was verified. Waiting for the peer to receive '{}'...", details.take, details.give));
+            push_chat_item(app, peer_id, PrivateChatItem::SwapWaiting(details));
+            redraw = true;
+        }
+        Event::SwapCompleted { peer_id, details } => {
+            app.push(format!("🤝 Swap complete: gave '{}', got '{}'.", details.give, details.take));
+            push_chat_item(app, peer_id, PrivateChatItem::SwapCompleted(details));
+            redraw = true;
+        }
+        Event::SwapCancelled { peer_id, details, reason } => {
+            app.pending_swaps.remove(&details.swap_id);
+            // Its download, if any, is gone as well
+            app.interrupted_downloads.remove(&(peer_id, details.take.clone()));
+            app.set_transfer_status(peer_id, &details.take, TransferDirection::Download, TransferRowStatus::Cancelled, None);
+            app.set_transfer_status(peer_id, &details.give, TransferDirection::Upload, TransferRowStatus::Cancelled, None);
+            app.push(format!("Swap of '{}' for '{}' called off: {}", details.give, details.take, reason));
+            push_chat_item(app, peer_id, PrivateChatItem::SwapCancelled { details, reason });
+            redraw = true;
         }
     }
 
     redraw // Return whether the UI needs to be redrawn.
-} 
\ No newline at end of file
+} 
+
+/// Adds an item to the private chat with `peer_id`, keeping the view scrolled to the bottom if it was.
+fn push_chat_item(app: &mut App, peer_id: libp2p::PeerId, item: PrivateChatItem) {
+    let is_viewing_chat = matches!(&app.current_chat_context, tui::ChatContext::Private { target_peer_id, .. } if *target_peer_id == peer_id);
+    let history = app.private_chat_histories.entry(peer_id).or_default();
+    let current_len = history.len();
+    history.push(item);
+    if is_viewing_chat {
+        let current_max_scroll = current_len.saturating_sub(app.chat_viewport_height.max(1));
+        if app.chat_scroll >= current_max_scroll {
+            app.chat_scroll = history.len().saturating_sub(app.chat_viewport_height.max(1));
+        }
+    }
+}
+
+/// The peer's nickname, or its short Peer ID if it hasn't sent one.
+fn display_name(app: &App, peer_id: &libp2p::PeerId) -> String {
+    app.peers.get(peer_id)
+        .and_then(|info| info.nickname.clone())
+        .unwrap_or_else(|| utils::peer_id_to_short_string(peer_id))
+}
@@ -0,0 +1,810 @@
+/*
+Runs SwapBytes without the terminal UI, driven through a control socket.
+
+Clients connect to a Unix domain socket and speak line-delimited JSON. Each line a client
+sends is a request, named by its `command` field and answered by a line of its own:
+
+    {"id": 1, "command": "offer", "peer": "bob", "paths": ["/srv/builds/app.tar.gz"]}
+    {"id": 1, "ok": true, "result": {"offer_id": "3fa2c1", "filename": "app.tar.gz", "size_bytes": 5242880}}
+
+A request that fails is answered with `"ok": false` and an `"error"` message instead.
+Every event of the node (offers, messages, transfer progress, ...) is streamed to every
+connected client as it happens, e.g. `{"event": "file_offer_received", "data": {...}}`.
+*/
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use libp2p::identity::Keypair;
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use swapbytes_core::event::{Reachability, SwapDetails, TransferAction};
+use swapbytes_core::protocol::TransferDirection;
+use swapbytes_core::ratelimit::{LimitScope, RateLimits};
+use swapbytes_core::{bundle, identity, swap, utils, Command, Config, Event};
+
+use crate::constants::{CONTROL_EVENT_BUFFER, CONTROL_SOCKET_NAME, SHUTDOWN_GRACE};
+
+/// A request waiting for the main loop, with where to send its answer.
+type PendingRequest = (Request, oneshot::Sender<Result<Value, String>>);
+
+// --- Requests ---
+
+/// A request from a control client, named by its `command` field.
+/// Peers are given by nickname or by their full peer ID.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    /// Our addresses, peer ID, identity file, nickname, download directory and visibility.
+    Me,
+    /// Every peer we know of.
+    Peers,
+    /// The peers using this nickname.
+    Who { name: String },
+    Setname { name: String },
+    Setdir { path: String },
+    Hide,
+    Show,
+    /// Dial an address; the answer arrives as a `ping_result` event.
+    Ping { addr: String },
+    /// Forget every known peer.
+    Forget,
+    /// Send a message to `peer`, or to the global chat if no peer is given.
+    Chat { peer: Option<String>, message: String },
+    /// Offer a file, a directory or several files.
+    Offer { peer: String, paths: Vec<PathBuf> },
+    /// The offers peers sent us that are still waiting for an answer.
+    Offers,
+    /// Accept an offer from `peer`, picked by its ID or file name if they sent several.
+    Accept { peer: String, offer: Option<String> },
+    Decline { peer: String, offer: Option<String> },
+    /// Every active, paused, queued and interrupted transfer.
+    Transfers,
+    /// Pause, resume or cancel the transfer of `file`; `peer` breaks ties.
+    Pause { file: String, peer: Option<String> },
+    Resume { file: String, peer: Option<String> },
+    Cancel { file: String, peer: Option<String> },
+    /// The bandwidth limits in bytes per second (`null` is unlimited).
+    Limits,
+    /// Set a bandwidth limit: in total, for each peer (`"peers"`), or for one peer.
+    Limit { peer: Option<String>, direction: String, rate: String },
+    /// Propose swapping our file at `path` for their file named `wanted`.
+    Swap { peer: String, path: String, wanted: String },
+    /// Accept a swap proposal with the file they asked for.
+    SwapAccept { peer: String, path: String },
+    /// Decline a swap proposal, picked by the file they asked for if they sent several.
+    SwapDecline { peer: String, file: Option<String> },
+    /// The swap proposals waiting for an answer.
+    Swaps,
+    /// Save a copy of the identity keypair.
+    IdExport { path: PathBuf },
+    /// Replace the identity keypair (takes effect on restart).
+    IdRotate,
+    /// Stop the daemon.
+    Shutdown,
+}
+
+// --- Daemon State ---
+
+/// A peer we have heard from.
+struct KnownPeer {
+    nickname: Option<String>,
+    last_seen: Instant,
+}
+
+/// An offer a peer sent us, waiting for `accept` or `decline`.
+#[derive(Debug)]
+struct IncomingOffer {
+    offer_id: String,
+    filename: String,
+    size_bytes: u64,
+    file_count: Option<usize>,
+}
+
+/// What the daemon keeps track of in place of the TUI's `App`.
+struct Daemon {
+    config: Config,
+    local_peer_id: PeerId,
+    commands: mpsc::UnboundedSender<Command>,
+    listening_addresses: Vec<Multiaddr>,
+    /// Addresses peers see us at, and those confirmed reachable (the ones registered with Rendezvous points).
+    observed_addresses: Vec<Multiaddr>,
+    external_addresses: Vec<Multiaddr>,
+    reachability: Reachability,
+    peers: HashMap<PeerId, KnownPeer>,
+    pending_offers: HashMap<PeerId, Vec<IncomingOffer>>,
+    /// Offers we sent: the file to serve once accepted, or `None` for bundles (the swarm task serves those).
+    sent_offers: HashMap<(PeerId, String), Option<PathBuf>>,
+    /// Swap proposals from peers, by swap ID.
+    pending_swaps: HashMap<String, (PeerId, SwapDetails)>,
+    /// `transfers` requests waiting for the swarm task's list.
+    transfer_list_waiters: Vec<oneshot::Sender<Result<Value, String>>>,
+    exit: bool,
+}
+
+// --- Entry Point ---
+
+/// Runs the node headless until a `shutdown` request, Ctrl+C or SIGTERM.
+///
+/// `socket_path` defaults to `default_socket_path()`.
+pub async fn run(local_key: Keypair, mut config: Config, socket_path: Option<PathBuf>, identity_created: bool) -> Result<(), String> {
+    let socket_path = socket_path
+        .or_else(default_socket_path)
+        .ok_or_else(|| "No runtime or data directory found for the control socket. Use --socket <PATH>.".to_string())?;
+    let listener = bind_control_socket(&socket_path)?;
+
+    // Announce a nickname, as the TUI does: the configured one or a random one
+    if config.nickname.is_none() {
+        config.nickname = Some(utils::random_nickname());
+    }
+
+    let cancel = CancellationToken::new();
+    let mut node = swapbytes_core::spawn(local_key, config.clone(), cancel.clone()).map_err(|e| e.to_string())?;
+    match &config.identity_path {
+        Some(path) if identity_created => eprintln!("Created a new identity at {}", path.display()),
+        Some(_) => {}
+        None => eprintln!("Warning: No user data directory found. Using a temporary identity for this session."),
+    }
+    eprintln!("SwapBytes is running headless as {}", node.local_peer_id);
+    eprintln!("Control socket: {}", socket_path.display());
+
+    // Serialized events go to every connected client; a client that falls behind misses some
+    let (event_tx, _) = broadcast::channel::<String>(CONTROL_EVENT_BUFFER);
+    let (request_tx, mut request_rx) = mpsc::unbounded_channel::<PendingRequest>();
+    let mut terminate = signal(SignalKind::terminate()).map_err(|e| format!("Failed to listen for SIGTERM: {}", e))?;
+    let mut daemon = Daemon::new(config, node.local_peer_id, node.commands.clone());
+
+    // --- Main Loop ---
+    loop {
+        tokio::select! {
+            maybe_ev = node.events.recv() => {
+                let Some(ev) = maybe_ev else {
+                    // The swarm task has stopped
+                    break;
+                };
+                daemon.handle_event(&ev);
+                match serde_json::to_string(&ev) {
+                    Ok(line) => { let _ = event_tx.send(line); }
+                    Err(e) => eprintln!("Error: Failed to serialize an event: {}", e),
+                }
+            }
+            Some((request, reply)) = request_rx.recv() => {
+                daemon.handle_request(request, reply);
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => { tokio::spawn(serve_client(stream, request_tx.clone(), event_tx.subscribe())); }
+                Err(e) => eprintln!("Error: Failed to accept a control connection: {}", e),
+            },
+            _ = tokio::signal::ctrl_c() => daemon.exit = true,
+            _ = terminate.recv() => daemon.exit = true,
+        }
+
+        if daemon.exit {
+            break;
+        }
+    }
+
+    // Give the swarm task a moment to stop and clients to receive their last answers
+    cancel.cancel();
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, async { while node.events.recv().await.is_some() {} }).await;
+    let _ = fs::remove_file(&socket_path);
+    Ok(())
+}
+
+/// Returns the default location of the control socket: in the runtime directory, or the data directory without one.
+pub fn default_socket_path() -> Option<PathBuf> {
+    dirs::runtime_dir()
+        .or_else(dirs::data_dir)
+        .map(|dir| dir.join("swapbytes").join(CONTROL_SOCKET_NAME))
+}
+
+/// Listens on the control socket, readable and writable by the current user only.
+///
+/// The socket is bound in a directory only we can enter and moved into place once its permissions
+/// are restricted, so nobody else can connect to it in between.
+/// A socket left behind by a daemon that didn't stop cleanly is replaced; one still in use is not.
+fn bind_control_socket(path: &Path) -> Result<UnixListener, String> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent).map_err(|e| format!("Failed to create '{}': {}", parent.display(), e))?;
+    if path.exists() && std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(format!("Another SwapBytes daemon is already listening on '{}'.", path.display()));
+    }
+    let private_dir = parent.join(format!(".{}.{}", CONTROL_SOCKET_NAME, std::process::id()));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .map_err(|e| format!("Failed to create '{}': {}", private_dir.display(), e))?;
+    let bound = bind_private(&private_dir.join(CONTROL_SOCKET_NAME), path);
+    let _ = fs::remove_dir_all(&private_dir);
+    bound
+}
+
+/// Binds a socket at `temp_path`, restricts it to the current user and renames it to `path`
+/// (replacing any old socket there).
+fn bind_private(temp_path: &Path, path: &Path) -> Result<UnixListener, String> {
+    let listener = UnixListener::bind(temp_path).map_err(|e| format!("Failed to listen on '{}': {}", path.display(), e))?;
+    fs::set_permissions(temp_path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to restrict access to '{}': {}", path.display(), e))?;
+    fs::rename(temp_path, path).map_err(|e| format!("Failed to listen on '{}': {}", path.display(), e))?;
+    Ok(listener)
+}
+
+// --- Control Clients ---
+
+/// Answers the requests of one client and streams events to it, until it disconnects.
+async fn serve_client(stream: UnixStream, requests: mpsc::UnboundedSender<PendingRequest>, mut events: broadcast::Receiver<String>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim().is_empty() => continue,
+                Ok(Some(line)) => answer(&line, &requests).await,
+                // Disconnected, or sent something that isn't UTF-8
+                _ => break,
+            },
+            event = events.recv() => match event {
+                Ok(line) => line,
+                Err(broadcast::error::RecvError::Lagged(missed)) => json!({ "event": "events_dropped", "data": missed }).to_string(),
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        if writer.write_all(format!("{}\n", line).as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Parses one request line, has the main loop carry it out and formats the answer.
+async fn answer(line: &str, requests: &mpsc::UnboundedSender<PendingRequest>) -> String {
+    let value: Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => return response(Value::Null, Err(format!("Invalid JSON: {}", e))),
+    };
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let result = match serde_json::from_value::<Request>(value) {
+        Ok(request) => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            match requests.send((request, reply_tx)) {
+                Ok(()) => reply_rx.await.unwrap_or_else(|_| Err("SwapBytes is shutting down.".to_string())),
+                Err(_) => Err("SwapBytes is shutting down.".to_string()),
+            }
+        }
+        Err(e) => Err(format!("Invalid request: {}", e)),
+    };
+    response(id, result)
+}
+
+/// Formats the answer to the request with this `id`.
+fn response(id: Value, result: Result<Value, String>) -> String {
+    match result {
+        Ok(result) => json!({ "id": id, "ok": true, "result": result }),
+        Err(error) => json!({ "id": id, "ok": false, "error": error }),
+    }
+    .to_string()
+}
+
+impl Daemon {
+    fn new(config: Config, local_peer_id: PeerId, commands: mpsc::UnboundedSender<Command>) -> Self {
+        Daemon {
+            config,
+            local_peer_id,
+            commands,
+            listening_addresses: Vec::new(),
+            observed_addresses: Vec::new(),
+            external_addresses: Vec::new(),
+            reachability: Reachability::Unknown,
+            peers: HashMap::new(),
+            pending_offers: HashMap::new(),
+            sent_offers: HashMap::new(),
+            pending_swaps: HashMap::new(),
+            transfer_list_waiters: Vec::new(),
+            exit: false,
+        }
+    }
+
+    /// Passes a command on to the swarm task.
+    fn send(&self, command: Command) -> Result<(), String> {
+        self.commands.send(command).map_err(|_| "SwapBytes is shutting down.".to_string())
+    }
+
+    /// Marks a peer as seen just now.
+    fn touch_peer(&mut self, peer_id: PeerId) {
+        if peer_id != self.local_peer_id {
+            let peer = self.peers.entry(peer_id).or_insert(KnownPeer { nickname: None, last_seen: Instant::now() });
+            peer.last_seen = Instant::now();
+        }
+    }
+
+    // --- Events ---
+
+    /// Keeps the daemon's state up to date with an event from the swarm task.
+    fn handle_event(&mut self, ev: &Event) {
+        match ev {
+            Event::ListeningOn(addr) => self.listening_addresses.push(addr.clone()),
+            Event::ObservedAddress(addr) => self.observed_addresses.push(addr.clone()),
+            Event::ExternalAddressConfirmed(addr) if !self.external_addresses.contains(addr) => self.external_addresses.push(addr.clone()),
+            Event::ExternalAddressExpired(addr) => self.external_addresses.retain(|known| known != addr),
+            Event::ReachabilityChanged(reachability) => self.reachability = reachability.clone(),
+            Event::PeerDiscovered(peer_id) | Event::PeerSeen(peer_id) => self.touch_peer(*peer_id),
+            Event::PeerConnected(peer_id) => {
+                // Connections alone (e.g. to a rendezvous point) don't make a peer known
+                if let Some(peer) = self.peers.get_mut(peer_id) {
+                    peer.last_seen = Instant::now();
+                }
+            }
+            Event::NicknameUpdated(peer_id, nickname) => {
+                // Our own heartbeats are ignored by `touch_peer`
+                self.touch_peer(*peer_id);
+                if let Some(peer) = self.peers.get_mut(peer_id) {
+                    peer.nickname = Some(nickname.clone());
+                }
+            }
+            Event::LogMessage(msg) => eprintln!("{}", msg),
+            Event::FileOfferReceived { sender_id, offer_id, filename, size_bytes, file_count, .. } => {
+                let offer = IncomingOffer {
+                    offer_id: offer_id.clone(),
+                    filename: filename.clone(),
+                    size_bytes: *size_bytes,
+                    file_count: *file_count,
+                };
+                self.pending_offers.entry(*sender_id).or_default().push(offer);
+            }
+            // Accepted by the node, as it arrived or once the sender's nickname was known
+            Event::OfferAutoAccepted { sender_id, offer_id, .. } => {
+                if let Some(offers) = self.pending_offers.get_mut(sender_id) {
+                    offers.retain(|offer| offer.offer_id != *offer_id);
+                }
+            }
+            Event::FileOfferAccepted { peer_id, offer_id, filename } => {
+                match self.sent_offers.remove(&(*peer_id, offer_id.clone())) {
+                    Some(Some(path)) => {
+                        let _ = self.send(Command::RegisterOutgoingTransfer { peer_id: *peer_id, filename: filename.clone(), path });
+                    }
+                    // The files of a bundle were registered by the swarm task when it sent the offer
+                    Some(None) => {}
+                    None => eprintln!("Error: Missing path for accepted offer '{}' from {}. Cannot start transfer.", filename, peer_id),
+                }
+            }
+            Event::FileOfferDeclined { peer_id, offer_id, .. } | Event::FileOfferRejected { peer_id, offer_id, .. } => {
+                self.sent_offers.remove(&(*peer_id, offer_id.clone()));
+            }
+            Event::SwapProposalReceived { sender_id, swap_id, offered, wanted } => {
+                let details = SwapDetails {
+                    swap_id: swap_id.clone(),
+                    give: wanted.clone(),
+                    take: offered.filename.clone(),
+                    take_size: Some(offered.size_bytes),
+                };
+                self.pending_swaps.insert(swap_id.clone(), (*sender_id, details));
+            }
+            Event::SwapCancelled { details, .. } => {
+                self.pending_swaps.remove(&details.swap_id);
+            }
+            Event::TransferList(transfers) => {
+                let list = serde_json::to_value(transfers).map_err(|e| e.to_string());
+                for waiter in self.transfer_list_waiters.drain(..) {
+                    let _ = waiter.send(list.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // --- Requests ---
+
+    /// Carries out a request and answers it, once the swarm task has replied if it has to.
+    fn handle_request(&mut self, request: Request, reply: oneshot::Sender<Result<Value, String>>) {
+        if let Request::Transfers = request {
+            // Answered when the swarm task reports its list (see `handle_event`)
+            match self.send(Command::ListTransfers) {
+                Ok(()) => self.transfer_list_waiters.push(reply),
+                Err(e) => { let _ = reply.send(Err(e)); }
+            }
+            return;
+        }
+        let _ = reply.send(self.execute(request));
+    }
+
+    /// Carries out a request that can be answered straight away.
+    fn execute(&mut self, request: Request) -> Result<Value, String> {
+        match request {
+            Request::Me => Ok(json!({
+                "peer_id": self.local_peer_id,
+                "listening_addresses": self.listening_addresses,
+                "observed_addresses": self.observed_addresses,
+                "external_addresses": self.external_addresses,
+                "reachability": self.reachability,
+                "identity_file": self.config.identity_path,
+                "nickname": self.config.nickname,
+                "download_dir": self.config.download_dir,
+                "visible": self.config.visible,
+            })),
+
+            Request::Peers => {
+                let peers: Vec<Value> = self.peers.keys().map(|peer_id| self.peer_json(peer_id)).collect();
+                Ok(Value::Array(peers))
+            }
+
+            Request::Who { name } => {
+                let peers: Vec<Value> = self.peers.iter()
+                    .filter(|(_, peer)| peer.nickname.as_ref().is_some_and(|nick| nick.eq_ignore_ascii_case(&name)))
+                    .map(|(peer_id, _)| self.peer_json(peer_id))
+                    .collect();
+                if peers.is_empty() {
+                    return Err(format!("User '{}' not found.", name));
+                }
+                Ok(Value::Array(peers))
+            }
+
+            Request::Setname { name } => {
+                let verified_name = utils::verify_nickname(&name)?;
+                self.send(Command::SetNickname(verified_name.clone()))?;
+                self.config.nickname = Some(verified_name.clone());
+                Ok(json!({ "nickname": verified_name }))
+            }
+
+            Request::Setdir { path } => {
+                let verified_path = utils::verify_download_directory(&path)?;
+                self.send(Command::SetDownloadDir(Some(verified_path.clone())))?;
+                self.config.download_dir = Some(verified_path.clone());
+                Ok(json!({ "download_dir": verified_path }))
+            }
+
+            Request::Hide | Request::Show => {
+                let visible = matches!(request, Request::Show);
+                if self.config.visible != visible {
+                    self.send(Command::SetVisibility(visible))?;
+                    self.config.visible = visible;
+                }
+                Ok(json!({ "visible": visible }))
+            }
+
+            Request::Ping { addr } => {
+                let addr = addr.parse::<Multiaddr>().map_err(|e| format!("Invalid Multiaddr: {}", e))?;
+                self.send(Command::Dial(addr))?;
+                Ok(Value::Null)
+            }
+
+            Request::Forget => {
+                let forgotten = self.peers.len();
+                self.peers.clear();
+                Ok(json!({ "forgotten": forgotten }))
+            }
+
+            Request::Chat { peer: None, message } => {
+                self.send(Command::SendGlobalMessage(message))?;
+                Ok(Value::Null)
+            }
+            Request::Chat { peer: Some(peer), message } => {
+                let target_peer = self.resolve_peer(&peer)?;
+                self.send(Command::SendPrivateMessage { target_peer, message })?;
+                Ok(Value::Null)
+            }
+
+            Request::Offer { peer, paths } => {
+                let target_peer = self.resolve_peer(&peer)?;
+                let offer_id = utils::new_offer_id();
+                if let [path] = paths.as_slice() && path.is_file() {
+                    let (verified_path, size_bytes) = utils::verify_offer_file(&path.to_string_lossy())?;
+                    let filename = verified_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                    self.sent_offers.insert((target_peer, offer_id.clone()), Some(verified_path.clone()));
+                    self.send(Command::SendFileOffer { target_peer, offer_id: offer_id.clone(), file_path: verified_path })?;
+                    Ok(json!({ "offer_id": offer_id, "filename": filename, "size_bytes": size_bytes }))
+                } else {
+                    // A directory or several files go out as a bundle
+                    let (name, files) = bundle::collect_files(&paths)?;
+                    let size_bytes: u64 = files.iter().map(|file| file.size_bytes).sum();
+                    let file_count = files.len();
+                    self.sent_offers.insert((target_peer, offer_id.clone()), None);
+                    self.send(Command::SendBundleOffer { target_peer, offer_id: offer_id.clone(), name: name.clone(), files })?;
+                    Ok(json!({ "offer_id": offer_id, "filename": name, "size_bytes": size_bytes, "file_count": file_count }))
+                }
+            }
+
+            Request::Offers => {
+                let offers: Vec<Value> = self.pending_offers.iter()
+                    .flat_map(|(peer_id, offers)| offers.iter().map(move |offer| (peer_id, offer)))
+                    .map(|(peer_id, offer)| json!({
+                        "peer_id": peer_id,
+                        "nickname": self.nickname_of(peer_id),
+                        "offer_id": offer.offer_id,
+                        "filename": offer.filename,
+                        "size_bytes": offer.size_bytes,
+                        "file_count": offer.file_count,
+                    }))
+                    .collect();
+                Ok(Value::Array(offers))
+            }
+
+            Request::Accept { peer, offer } => {
+                let target_peer = self.resolve_peer(&peer)?;
+                // Check the download directory first, so a refused accept leaves the offer pending
+                let download_dir = self.config.download_dir.as_deref()
+                    .ok_or_else(|| "Download directory not set. Use setdir first.".to_string())?;
+                utils::verify_download_directory(&download_dir.to_string_lossy())
+                    .map_err(|e| format!("Download directory '{}' is invalid: {}", download_dir.display(), e))?;
+                let offer = self.take_pending_offer(target_peer, offer.as_deref().unwrap_or(""))?;
+                self.send(Command::SendAcceptOffer {
+                    target_peer,
+                    offer_id: offer.offer_id.clone(),
+                    filename: offer.filename.clone(),
+                    subdir: None,
+                })?;
+                Ok(json!({ "offer_id": offer.offer_id, "filename": offer.filename }))
+            }
+
+            Request::Decline { peer, offer } => {
+                let target_peer = self.resolve_peer(&peer)?;
+                let offer = self.take_pending_offer(target_peer, offer.as_deref().unwrap_or(""))?;
+                self.send(Command::DeclineFileOffer {
+                    target_peer,
+                    offer_id: offer.offer_id.clone(),
+                    filename: offer.filename.clone(),
+                })?;
+                Ok(json!({ "offer_id": offer.offer_id, "filename": offer.filename }))
+            }
+
+            // The outcome arrives as an event (e.g. `transfer_paused`), or a log message if nothing matched
+            Request::Pause { file, peer } => self.control_transfer(TransferAction::Pause, file, peer),
+            Request::Resume { file, peer } => self.control_transfer(TransferAction::Resume, file, peer),
+            Request::Cancel { file, peer } => self.control_transfer(TransferAction::Cancel, file, peer),
+
+            Request::Limits => Ok(self.limits_json()),
+
+            Request::Limit { peer, direction, rate } => {
+                let direction = match direction.to_ascii_lowercase().as_str() {
+                    "up" | "upload" => TransferDirection::Upload,
+                    "down" | "download" => TransferDirection::Download,
+                    _ => return Err(format!("'{}' is not a direction. Use up or down.", direction)),
+                };
+                let limit = utils::parse_rate(&rate)?;
+                let scope = match peer {
+                    None => LimitScope::Total,
+                    Some(peer) if peer.eq_ignore_ascii_case("peers") => LimitScope::EachPeer,
+                    Some(peer) => LimitScope::Peer(self.resolve_peer(&peer)?),
+                };
+                self.send(Command::SetRateLimit { scope, direction, limit })?;
+                self.config.rate_limits.set(scope, direction, limit);
+                Ok(self.limits_json())
+            }
+
+            Request::Swap { peer, path, wanted } => {
+                let target_peer = self.resolve_peer(&peer)?;
+                if wanted.trim().is_empty() {
+                    return Err("Name the file you want in return.".to_string());
+                }
+                let (verified_path, _size_bytes) = utils::verify_offer_file(&path)?;
+                let swap_id = swap::new_swap_id();
+                self.send(Command::ProposeSwap {
+                    target_peer,
+                    swap_id: swap_id.clone(),
+                    file_path: verified_path,
+                    wanted: wanted.trim().to_string(),
+                })?;
+                Ok(json!({ "swap_id": swap_id }))
+            }
+
+            Request::SwapAccept { peer, path } => {
+                let target_peer = self.resolve_peer(&peer)?;
+                if self.config.download_dir.is_none() {
+                    return Err("Download directory not set. Use setdir first.".to_string());
+                }
+                let (verified_path, _size_bytes) = utils::verify_offer_file(&path)?;
+                let filename = verified_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                // The file picked must be the one the peer asked for
+                let swap_id = self.pending_swaps.iter()
+                    .find(|(_, (peer_id, details))| *peer_id == target_peer && details.give == filename)
+                    .map(|(swap_id, _)| swap_id.clone())
+                    .ok_or_else(|| format!("No swap proposal from {} asks for '{}'.", peer, filename))?;
+                let (_, details) = self.pending_swaps.remove(&swap_id).expect("swap ID was just found");
+                self.send(Command::AcceptSwap { target_peer, swap_id: swap_id.clone(), file_path: verified_path })?;
+                Ok(json!({ "swap_id": swap_id, "give": details.give, "take": details.take }))
+            }
+
+            Request::SwapDecline { peer, file } => {
+                let target_peer = self.resolve_peer(&peer)?;
+                let candidates: Vec<String> = self.pending_swaps.iter()
+                    .filter(|(_, (peer_id, details))| *peer_id == target_peer && file.as_ref().is_none_or(|file| details.give == *file))
+                    .map(|(swap_id, _)| swap_id.clone())
+                    .collect();
+                match candidates.as_slice() {
+                    [swap_id] => {
+                        self.pending_swaps.remove(swap_id);
+                        self.send(Command::DeclineSwap { target_peer, swap_id: swap_id.clone() })?;
+                        Ok(json!({ "swap_id": swap_id }))
+                    }
+                    [] => Err(format!("No matching swap proposal from {}.", peer)),
+                    _ => Err("Several swap proposals are pending. Name the file they asked for.".to_string()),
+                }
+            }
+
+            Request::Swaps => {
+                let swaps: Vec<Value> = self.pending_swaps.values()
+                    .map(|(peer_id, details)| json!({
+                        "peer_id": peer_id,
+                        "nickname": self.nickname_of(peer_id),
+                        "details": details,
+                    }))
+                    .collect();
+                Ok(Value::Array(swaps))
+            }
+
+            Request::IdExport { path } => {
+                let identity_path = self.config.identity_path.as_deref()
+                    .ok_or_else(|| "This session uses a temporary identity that is not stored on disk.".to_string())?;
+                let dest = identity::export_keypair(identity_path, &path)?;
+                Ok(json!({ "path": dest }))
+            }
+
+            Request::IdRotate => {
+                let identity_path = self.config.identity_path.as_deref()
+                    .ok_or_else(|| "This session uses a temporary identity that is not stored on disk.".to_string())?;
+                let (new_peer_id, backup_path) = identity::rotate_keypair(identity_path)?;
+                Ok(json!({ "peer_id": new_peer_id, "backup_path": backup_path }))
+            }
+
+            Request::Shutdown => {
+                self.exit = true;
+                Ok(Value::Null)
+            }
+
+            // Answered in `handle_request`
+            Request::Transfers => Err("Unexpected request.".to_string()),
+        }
+    }
+
+    // --- Helpers ---
+
+    /// Asks the swarm task to pause, resume or cancel the transfer of `filename`.
+    fn control_transfer(&self, action: TransferAction, filename: String, peer: Option<String>) -> Result<Value, String> {
+        let peer_id = peer.map(|peer| self.resolve_peer(&peer)).transpose()?;
+        self.send(Command::ControlTransfer { action, peer_id, filename })?;
+        Ok(Value::Null)
+    }
+
+    /// Finds a peer by its full peer ID, or by nickname if exactly one peer uses it.
+    fn resolve_peer(&self, name: &str) -> Result<PeerId, String> {
+        if let Ok(peer_id) = name.parse::<PeerId>() {
+            return Ok(peer_id);
+        }
+        let matches: Vec<PeerId> = self.peers.iter()
+            .filter(|(_, peer)| peer.nickname.as_ref().is_some_and(|nick| nick.eq_ignore_ascii_case(name)))
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        match matches.as_slice() {
+            [peer_id] => Ok(*peer_id),
+            [] => Err(format!("User '{}' not found.", name)),
+            _ => Err(format!("Several users are called '{}'. Use their peer ID.", name)),
+        }
+    }
+
+    fn nickname_of(&self, peer_id: &PeerId) -> Option<&str> {
+        self.peers.get(peer_id).and_then(|peer| peer.nickname.as_deref())
+    }
+
+    /// Describes a known peer; it is online while its heartbeats keep coming.
+    fn peer_json(&self, peer_id: &PeerId) -> Value {
+        let last_seen = self.peers.get(peer_id).map(|peer| peer.last_seen.elapsed());
+        json!({
+            "peer_id": peer_id,
+            "nickname": self.nickname_of(peer_id),
+            "online": last_seen.is_some_and(|elapsed| elapsed <= self.config.peer_timeout),
+            "last_seen_secs": last_seen.map(|elapsed| elapsed.as_secs()),
+        })
+    }
+
+    /// Removes and returns the pending offer from `peer_id` picked by `selector`: its ID or file name,
+    /// or nothing if only one offer is pending.
+    fn take_pending_offer(&mut self, peer_id: PeerId, selector: &str) -> Result<IncomingOffer, String> {
+        let offers = self.pending_offers.entry(peer_id).or_default();
+        let matching: Vec<usize> = offers.iter()
+            .enumerate()
+            .filter(|(_, offer)| selector.is_empty() || offer.offer_id == selector || offer.filename == selector)
+            .map(|(index, _)| index)
+            .collect();
+        match matching.as_slice() {
+            [index] => Ok(offers.remove(*index)),
+            [] if offers.is_empty() => Err("No offer from this peer is pending.".to_string()),
+            [] => Err(format!("No pending offer matches '{}'.", selector)),
+            _ => Err(format!("{} offers match. Pick one by its ID.", matching.len())),
+        }
+    }
+
+    /// The bandwidth limits in bytes per second: in total, for each peer, and for particular peers.
+    fn limits_json(&self) -> Value {
+        let RateLimits { upload, download, peer_upload, peer_download, peers } = &self.config.rate_limits;
+        let peers: Vec<Value> = peers.iter()
+            .map(|((peer_id, direction), limit)| json!({ "peer_id": peer_id, "direction": direction, "limit": limit }))
+            .collect();
+        json!({
+            "upload": upload,
+            "download": download,
+            "peer_upload": peer_upload,
+            "peer_download": peer_download,
+            "peers": peers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daemon() -> Daemon {
+        let (commands, _) = mpsc::unbounded_channel();
+        Daemon::new(Config::default(), PeerId::random(), commands)
+    }
+
+    fn offer(offer_id: &str, filename: &str) -> IncomingOffer {
+        IncomingOffer { offer_id: offer_id.to_string(), filename: filename.to_string(), size_bytes: 1, file_count: None }
+    }
+
+    fn parse(line: &str) -> Result<Request, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    #[test]
+    fn requests_are_named_by_their_command() {
+        assert!(matches!(parse(r#"{"id": 1, "command": "peers"}"#), Ok(Request::Peers)));
+        assert!(matches!(
+            parse(r#"{"command": "accept", "peer": "bob"}"#),
+            Ok(Request::Accept { peer, offer: None }) if peer == "bob"
+        ));
+        assert!(matches!(parse(r#"{"command": "swap_accept", "peer": "bob", "path": "a.txt"}"#), Ok(Request::SwapAccept { .. })));
+
+        let unknown = parse(r#"{"command": "format_disk"}"#).unwrap_err();
+        assert!(unknown.to_string().contains("unknown variant `format_disk`"), "{}", unknown);
+        let missing = parse(r#"{"command": "offer", "peer": "bob"}"#).unwrap_err();
+        assert!(missing.to_string().contains("missing field `paths`"), "{}", missing);
+        assert!(parse(r#"{"peer": "bob"}"#).is_err());
+    }
+
+    #[test]
+    fn pending_offers_are_picked_by_id_or_name() {
+        let mut daemon = daemon();
+        let peer = PeerId::random();
+        assert!(daemon.take_pending_offer(peer, "").is_err());
+
+        daemon.pending_offers.insert(peer, vec![offer("a1", "report.pdf"), offer("b2", "report.pdf"), offer("c3", "notes.txt")]);
+        // Two offers of the same file name, so only the ID tells them apart
+        let ambiguous = daemon.take_pending_offer(peer, "report.pdf").unwrap_err();
+        assert!(ambiguous.contains("2 offers match"), "{}", ambiguous);
+        assert!(daemon.take_pending_offer(peer, "").is_err());
+        assert!(daemon.take_pending_offer(peer, "missing.txt").is_err());
+
+        assert_eq!(daemon.take_pending_offer(peer, "b2").unwrap().offer_id, "b2");
+        assert_eq!(daemon.take_pending_offer(peer, "notes.txt").unwrap().offer_id, "c3");
+        // With one offer left, no selector is needed
+        assert_eq!(daemon.take_pending_offer(peer, "").unwrap().offer_id, "a1");
+        assert!(daemon.take_pending_offer(peer, "").is_err());
+    }
+
+    #[test]
+    fn shared_nicknames_need_a_peer_id() {
+        let mut daemon = daemon();
+        let [alice, bob, other_bob] = [(); 3].map(|_| PeerId::random());
+        for (peer_id, nickname) in [(alice, "alice"), (bob, "bob"), (other_bob, "Bob")] {
+            daemon.peers.insert(peer_id, KnownPeer { nickname: Some(nickname.to_string()), last_seen: Instant::now() });
+        }
+
+        assert_eq!(daemon.resolve_peer("ALICE").unwrap(), alice);
+        let ambiguous = daemon.resolve_peer("bob").unwrap_err();
+        assert!(ambiguous.contains("Several users"), "{}", ambiguous);
+        assert_eq!(daemon.resolve_peer(&other_bob.to_string()).unwrap(), other_bob);
+        assert!(daemon.resolve_peer("carol").is_err());
+    }
+}
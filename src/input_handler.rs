@@ -10,10 +10,8 @@ use crossterm::event::{self, KeyCode, KeyEvent, KeyEventKind};
 use tokio::sync::mpsc;
 
 // --- Local Module Imports ---
-use crate::{
-    protocol,
-    tui::{self, App, AppEvent, FocusPane, InputMode},
-};
+use crate::tui::{self, App, FocusPane, InputMode};
+use swapbytes_core::Command;
 
 // --- Function Definitions ---
 
@@ -27,7 +25,7 @@ use crate::{
 /// `false` otherwise.
 pub fn handle_key_event(
     app: &mut App,
-    cmd_tx: &mpsc::UnboundedSender<AppEvent>,
+    cmd_tx: &mpsc::UnboundedSender<Command>,
     key: KeyEvent,
 ) -> bool {
     let mut redraw = false;
@@ -43,6 +41,19 @@ pub fn handle_key_event(
         return false; // Signal immediate exit, no redraw needed
     }
 
+    // Ctrl+t: Show or hide the Transfers pane over the chat
+    if key.kind == KeyEventKind::Press
+        && key.code == KeyCode::Char('t')
+        && key.modifiers.contains(event::KeyModifiers::CONTROL)
+    {
+        app.show_transfers = !app.show_transfers;
+        // The chat input is hidden under the pane, so stop typing into it
+        if app.show_transfers && app.input_mode == InputMode::Chat {
+            app.input_mode = InputMode::Normal;
+        }
+        return true;
+    }
+
     // --- Mode-Specific Keybindings ---
     match app.input_mode {
         // --- Normal Mode ---
@@ -100,7 +111,7 @@ pub fn handle_key_event(
                         redraw = true;
                     }
                     // Any Character (Chat Focus): Enter Chat mode to type a message.
-                    KeyCode::Char(c) if app.focused_pane == FocusPane::Chat => {
+                    KeyCode::Char(c) if app.focused_pane == FocusPane::Chat && !app.show_transfers => {
                         app.input_mode = InputMode::Chat;
                         app.chat_input.clear();
                         app.reset_chat_cursor();
@@ -118,18 +129,10 @@ pub fn handle_key_event(
                 match key.code {
                     // Enter: Submit the entered command for processing.
                     KeyCode::Enter => {
-                        // `submit_command` parses the input and returns an `AppEvent` if valid.
-                        if let Some(event) = app.submit_command() {
-                            match event {
-                                // Handle Quit command locally by setting the exit flag.
-                                AppEvent::Quit => {
-                                    app.exit = true;
-                                }
-                                // Send other valid commands (e.g., Dial, Nickname) to the swarm task.
-                                _ => {
-                                    let _ = cmd_tx.send(event); // Ignore potential send error for now
-                                }
-                            }
+                        // `submit_command` runs the command and returns a `Command` if the swarm task has work to do
+                        // (e.g., Dial, SetNickname). `/quit` sets the exit flag instead.
+                        if let Some(command) = app.submit_command() {
+                            let _ = cmd_tx.send(command); // Ignore potential send error for now
                         }
                         // Don't redraw if quitting, otherwise redraw to clear input/show output.
                         if !app.exit {
@@ -205,63 +208,42 @@ pub fn handle_key_event(
                             match app.current_chat_context {
                                 // --- Sending Global Chat Message ---
                                 tui::ChatContext::Global => {
-                                    let nickname = app.nickname.clone();
-                                    // Construct the network message format.
-                                    let message = protocol::Message::GlobalChatMessage {
-                                        content: content.clone(),
+                                    // Ask the swarm task to publish the message via Gossipsub.
+                                    if let Err(e) = cmd_tx.send(Command::SendGlobalMessage(content.clone())) {
+                                        app.push(format!(
+                                            "Error sending global message event: {}",
+                                            e
+                                        ));
+                                    }
+
+                                    // Add the sent message to the local global chat history.
+                                    let local_chat_msg = tui::ChatMessage {
+                                        sender_id: local_peer_id,
+                                        sender_nickname: app.nickname.clone(),
+                                        content, // Use original content
                                         timestamp_ms,
-                                        nickname: nickname.clone(),
                                     };
+                                    app.global_chat_history.push(local_chat_msg);
 
-                                    // Attempt to serialize the message for network transmission.
-                                    match serde_json::to_vec(&message) {
-                                        Ok(data) => {
-                                            // Send an event to the swarm task to publish the message via Gossipsub.
-                                            if let Err(e) = cmd_tx.send(AppEvent::PublishGossipsub(data)) {
-                                                app.push(format!(
-                                                    "Error sending global message event: {}",
-                                                    e
-                                                ));
-                                            }
-
-                                            // Add the sent message to the local global chat history.
-                                            let local_chat_msg = tui::ChatMessage {
-                                                sender_id: local_peer_id,
-                                                sender_nickname: nickname, // Use original nickname
-                                                content, // Use original content
-                                                timestamp_ms,
-                                            };
-                                            app.global_chat_history.push(local_chat_msg);
-
-                                            // Auto-scroll the chat view to the bottom if it was already there.
-                                            let current_max_scroll = app
-                                                .global_chat_history
-                                                .len()
-                                                .saturating_sub(app.chat_viewport_height.max(1)) // Prevent underflow if height=0
-                                                .saturating_sub(1); // Max scroll *before* adding the new message
-                                            if app.chat_scroll >= current_max_scroll {
-                                                let new_max_scroll = app
-                                                    .global_chat_history
-                                                    .len()
-                                                    .saturating_sub(app.chat_viewport_height.max(1));
-                                                app.chat_scroll = new_max_scroll;
-                                            }
-                                            // Otherwise, keep the user's scrolled position.
-                                        }
-                                        Err(e) => {
-                                            // Log serialization errors locally.
-                                            app.push(format!(
-                                                "Error serializing global chat message: {}",
-                                                e
-                                            ));
-                                            // Decide whether to clear input on error (currently does clear below).
-                                        }
+                                    // Auto-scroll the chat view to the bottom if it was already there.
+                                    let current_max_scroll = app
+                                        .global_chat_history
+                                        .len()
+                                        .saturating_sub(app.chat_viewport_height.max(1)) // Prevent underflow if height=0
+                                        .saturating_sub(1); // Max scroll *before* adding the new message
+                                    if app.chat_scroll >= current_max_scroll {
+                                        let new_max_scroll = app
+                                            .global_chat_history
+                                            .len()
+                                            .saturating_sub(app.chat_viewport_height.max(1));
+                                        app.chat_scroll = new_max_scroll;
                                     }
+                                    // Otherwise, keep the user's scrolled position.
                                 }
                                 // --- Sending Private Chat Message ---
                                 tui::ChatContext::Private { target_peer_id, .. } => {
                                     // Send an event to the swarm task to send the message directly to the target peer.
-                                    if let Err(e) = cmd_tx.send(AppEvent::SendPrivateMessage {
+                                    if let Err(e) = cmd_tx.send(Command::SendPrivateMessage {
                                         target_peer: target_peer_id,
                                         message: content.clone(), // Clone message content for event
                                     }) {
@@ -1,18 +1,20 @@
 /*
-Main entry point for the SwapBytes application.
+Main entry point for the SwapBytes terminal client.
+
+The networking, protocol and transfer logic live in the `swapbytes-core` library crate
+(see `swapbytes-core/src/lib.rs`); this binary is the terminal interface on top of it.
 
 Project Structure:
 
  * main.rs - Entry point, initializes the application state and starts the main event loop.
+ * config.rs - Loads settings from the config file and command-line arguments.
+ * headless.rs - Runs without the TUI, driven through a control socket (`--headless`, Unix only).
+ * oneshot.rs - The one-shot `send`, `receive` and `peers` subcommands for scripts.
  * tui.rs - Manages the Terminal User Interface display and layout.
- * swarm_task.rs - Runs the main libp2p swarm event loop in a separate task.
- * behavior.rs - Defines the combined libp2p network behaviors (Gossipsub, etc.).
- * event_handler.rs - Handles events from the swarm, UI, and other sources.
+ * event_handler.rs - Handles the events reported by the swarm task.
  * input_handler.rs - Parses and processes user input from the TUI.
  * commands.rs - Implements logic for user-executable commands.
- * protocol.rs - Defines data structures for network communication or internal state.
- * constants.rs - Contains application-wide constant values.
- * utils.rs - Provides miscellaneous helper functions and utilities.
+ * constants.rs - Contains constant values of the terminal interface.
 
 */
 
@@ -25,7 +27,10 @@ use tokio_util::sync::CancellationToken;
 use tokio::time::interval;
 
 // --- libp2p Imports ---
-use libp2p::{noise, tcp, yamux, identity, PeerId, gossipsub};
+use libp2p::identity::Keypair;
+
+// --- Command-Line Parsing ---
+use clap::Parser;
 
 // --- Terminal UI Imports ---
 use crossterm::event;
@@ -34,18 +39,21 @@ use ratatui::{
     widgets::Block,
 };
 
+// --- SwapBytes Node ---
+use swapbytes_core::{identity, Config};
+
 // --- Local Module Imports ---
-mod swarm_task;
 mod tui;
-mod utils;
 mod commands;
-mod behavior;
-mod protocol;
 mod constants;
+mod config;
 mod input_handler;
 mod event_handler;
-use tui::{App, AppEvent, InputMode, layout_chunks, OnlineStatus};
-use behavior::SwapBytesBehaviour;
+#[cfg(unix)]
+mod headless;
+mod oneshot;
+use tui::{App, InputMode, layout_chunks, OnlineStatus, TransferRowStatus};
+use config::Cli;
 
 
 // --- Application Entry Point ---
@@ -53,75 +61,88 @@ use behavior::SwapBytesBehaviour;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
 
-    // --- Initialize App State ---
-    // Creates the central state structure for the application.
-    let mut app = App::default();
-
-    // --- Terminal UI Setup ---
-    // Initializes the terminal interface using ratatui.
-    let mut terminal = ratatui::init();
+    // --- Load Configuration ---
+    // Merges command-line flags, the config file and the defaults.
+    // This happens before the TUI starts so errors are reported on the plain terminal.
+    let mut cli = Cli::parse();
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    let (headless, socket_path, action) = (cli.headless, cli.socket.clone(), cli.action.take());
+    if headless && action.is_some() {
+        eprintln!("Error: --headless can't be used with a subcommand.");
+        std::process::exit(2);
+    }
+    // The control socket is a Unix domain socket
+    if headless && cfg!(not(unix)) {
+        eprintln!("Error: --headless is only available on Unix-like systems.");
+        std::process::exit(2);
+    }
+    let config = match config::load(cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // --- Load Local Peer Identity ---
+    // Loads the persistent keypair for this node, creating it on first run.
+    let identity_path = config.identity_path.clone();
+    let (local_key, identity_created) = match &identity_path {
+        Some(path) => match identity::load_or_create_keypair(path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => (Keypair::generate_ed25519(), false),
+    };
+
+    // --- One-Shot Actions ---
+    // `send`, `receive` and `peers` run a node of their own, do one thing and exit (see `oneshot.rs`).
+    if let Some(action) = action {
+        std::process::exit(oneshot::run(action, local_key, config).await);
+    }
 
+    // --- Headless Mode ---
+    // Without the TUI, the node is driven through a control socket instead (see `headless.rs`).
+    #[cfg(unix)]
+    if headless {
+        if let Err(e) = headless::run(local_key, config, socket_path, identity_created).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    // --- Generate Local Peer Identity ---
-    // Creates a unique cryptographic keypair for this node.
-    let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
+    // --- Initialize App State ---
+    // Creates the central state structure for the application.
+    let mut app = App::from_config(&config);
 
     // --- Communication Channels ---
-    // Channel for events from background tasks (Swarm, Keyboard) to the UI loop.
-    let (tx, mut rx) = mpsc::unbounded_channel::<AppEvent>();
+    // Channel for key presses from the keyboard task to the UI loop.
+    let (key_tx, mut key_rx) = mpsc::unbounded_channel::<event::KeyEvent>();
     // Token to signal cancellation to background tasks.
     let cancel = CancellationToken::new();
-    // Channel for commands from the UI loop to the Swarm task.
-    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel::<AppEvent>();
-
-
-    // --- libp2p Swarm Setup ---
-    // 1. Create the custom Swarm behaviour using the generated key.
-    let mut behaviour = SwapBytesBehaviour::new(&local_key)?;
-
-    // 2. Define the Gossipsub topic for chat messages and subscribe to it.
-    let topic = gossipsub::IdentTopic::new(constants::SWAPBYTES_TOPIC);
-    behaviour.gossipsub.subscribe(&topic)?;
-
-    // 3. Build the libp2p Swarm, configuring transport (TCP, Noise, Yamux) and behaviour.
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|_| behaviour)?
-        .build();
-
-    // Listen for incoming connections on all network interfaces using a random OS-assigned port.
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
 
 
     // --- Spawn Swarm Task ---
-    // Clone necessary variables for the swarm task.
-    let swarm_tx = tx.clone();
-    let swarm_cancel = cancel.clone();
-    let initial_nickname = app.nickname.clone(); // Pass initial state to the task
-    let initial_visibility = app.is_visible;
-
-    // Spawn a dedicated asynchronous task to manage the libp2p Swarm.
-    // This task handles network events, peer discovery, and message propagation.
-    // See `swarm_task::run_swarm_loop` for implementation details.
-    tokio::spawn(swarm_task::run_swarm_loop(
-        swarm,
-        swarm_tx,
-        cmd_rx,
-        swarm_cancel,
-        initial_nickname,
-        initial_visibility,
-    ));
+    // Builds the libp2p swarm and runs it in a dedicated task (see `swapbytes_core::spawn`).
+    // Commands go to it through `node.commands`; its events arrive on `node.events`.
+    // The node announces the nickname shown in the UI: the configured one, or the random one `App` picked.
+    let node_config = Config { nickname: app.nickname.clone(), ..config.clone() };
+    let mut node = swapbytes_core::spawn(local_key, node_config, cancel.clone())?;
+    let local_peer_id = node.local_peer_id;
+    let cmd_tx = node.commands.clone();
 
 
+    // --- Terminal UI Setup ---
+    // Initializes the terminal interface using ratatui.
+    let mut terminal = ratatui::init();
+
     // --- Spawn Keyboard Input Task ---
     // Clone necessary variables for the keyboard input task.
-    let kb_tx = tx.clone();
+    let kb_tx = key_tx;
     let kb_cancel = cancel.clone();
     // Spawn a dedicated asynchronous task to listen for keyboard events.
     tokio::spawn(async move {
@@ -131,11 +152,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
             // Poll for keyboard events with a short timeout to avoid blocking.
             if event::poll(Duration::from_millis(150)).unwrap() {
                 // If a key event occurs, read it and send it to the main UI loop via the channel.
-                if let event::Event::Key(key) = event::read().unwrap() {
-                    if kb_tx.send(AppEvent::Input(key)).is_err() {
-                        // Stop the task if the channel is closed.
-                        break;
-                    }
+                if let event::Event::Key(key) = event::read().unwrap()
+                    && kb_tx.send(key).is_err()
+                {
+                    // Stop the task if the channel is closed.
+                    break;
                 }
             }
         }
@@ -147,6 +168,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
     app.local_peer_id = Some(local_peer_id);
     // Add initial messages to the console log.
     app.push("Welcome to SwapBytes!".to_string());
+    match &identity_path {
+        Some(path) if identity_created => app.push(format!("Created a new identity at {}", path.display())),
+        Some(_) => {}
+        None => app.push("Warning: No user data directory found. Using a temporary identity for this session.".to_string()),
+    }
+    if let Some(dir) = &app.download_dir {
+        app.push(format!("Download directory: {}", dir.display()));
+    }
     app.push("Run /help to get started.".to_string());
     // Flag to indicate whether the UI needs to be redrawn.
     let mut redraw = true;
@@ -154,6 +183,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // --- Peer Staleness Check Timer ---
     // Set up a timer to periodically check for inactive peers.
     let mut check_peers_interval = interval(Duration::from_secs(5));
+    // --- Transfers Pane Refresh Timer ---
+    // Keeps rates and ETAs in the Transfers pane moving between progress updates.
+    let mut refresh_transfers_interval = interval(constants::TRANSFERS_REFRESH_INTERVAL);
 
     // --- Main Event Loop ---
     loop {
@@ -243,32 +275,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
         // --- Event Handling ---
         // Wait for events from different sources concurrently.
         tokio::select! {
-            // Handle events received from the Swarm or Keyboard tasks.
-            maybe_ev = rx.recv() => {
+            // Handle events reported by the swarm task.
+            maybe_ev = node.events.recv() => {
                 if let Some(ev) = maybe_ev {
-                    // Delegate event processing to the `handle_app_event` function.
-                    let needs_redraw = event_handler::handle_app_event(&mut app, &cmd_tx, ev);
+                    // Delegate event processing to the `handle_node_event` function.
+                    let needs_redraw = event_handler::handle_node_event(&mut app, &cmd_tx, ev);
                     // Mark UI for redraw if the handler indicates changes.
                     redraw = redraw || needs_redraw;
-
-                    // Check if the application should exit (e.g., user pressed Ctrl+Q).
-                    if app.exit {
-                        // Signal background tasks to stop.
-                        cancel.cancel();
-                        // The main loop's exit condition will handle breaking out.
-                    }
                 } else {
-                    // If the channel is closed, it means producers have stopped; exit the application.
+                    // If the channel is closed, the swarm task has stopped; exit the application.
                     app.exit = true;
                 }
             },
 
+            // Handle key presses from the keyboard task.
+            Some(key) = key_rx.recv() => {
+                // Delegate keyboard input handling to the dedicated input handler module.
+                let needs_redraw = input_handler::handle_key_event(&mut app, &cmd_tx, key);
+                redraw = redraw || needs_redraw;
+
+                // Check if the application should exit (e.g., user pressed Ctrl+Q).
+                if app.exit {
+                    // Signal background tasks to stop.
+                    cancel.cancel();
+                    // The main loop's exit condition will handle breaking out.
+                }
+            },
+
             // --- Peer Staleness Check ---
             // Triggered periodically by the `check_peers_interval`.
             _ = check_peers_interval.tick() => {
                 let mut changed = false;
                 let now = Instant::now();
-                let timeout = constants::PEER_TIMEOUT;
+                let timeout = config.peer_timeout;
 
                 // Iterate through known peers and mark those inactive for too long as Offline.
                 for (_peer_id, peer_info) in app.peers.iter_mut() {
@@ -285,6 +324,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     redraw = true;
                 }
             }
+
+            // --- Transfers Pane Refresh ---
+            _ = refresh_transfers_interval.tick() => {
+                if app.show_transfers && app.transfers.iter().any(|row| row.status == TransferRowStatus::Running) {
+                    redraw = true;
+                }
+            }
         }
 
         // Exit the main loop if the exit flag is set.
@@ -0,0 +1,392 @@
+/*
+One-shot actions (`swapbytes send`, `receive` and `peers`) for scripts and CI jobs.
+
+Each starts a node of its own, waits for peers to turn up over mDNS, rendezvous or `--dial`,
+does one thing and exits. Results go to stdout as JSON, one object per line; progress and
+errors go to stderr. The exit status tells how it went: 0 on success, otherwise one of the
+`EXIT_*` constants (clap exits with 2 on bad arguments).
+*/
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use libp2p::identity::Keypair;
+use libp2p::{Multiaddr, PeerId};
+use serde_json::json;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use swapbytes_core::protocol::TransferDirection;
+use swapbytes_core::{utils, Command, Config, Event, Node};
+
+use crate::config::{Action, WaitArgs};
+use crate::constants::{EXIT_DECLINED, EXIT_FAILURE, EXIT_TIMEOUT, SHUTDOWN_GRACE};
+
+/// Why waiting for an event stopped.
+enum WaitError {
+    TimedOut,
+    NodeStopped,
+}
+
+/// An offer a peer sent us, not accepted or declined yet.
+struct IncomingOffer {
+    sender_id: PeerId,
+    offer_id: String,
+    filename: String,
+    size_bytes: u64,
+    file_count: Option<usize>,
+}
+
+/// A running node and what it has told us about peers so far.
+struct Session {
+    node: Node,
+    /// Whether the node's log messages are printed.
+    verbose: bool,
+    /// Peers seen so far, with their nickname once it's known.
+    peers: HashMap<PeerId, Option<String>>,
+    /// When to give up waiting (`None`: never).
+    deadline: Option<Instant>,
+}
+
+/// Runs a one-shot action and returns the exit status.
+pub async fn run(action: Action, local_key: Keypair, mut config: Config) -> i32 {
+    // Announce a nickname, as the TUI does: the configured one or a random one
+    if config.nickname.is_none() {
+        config.nickname = Some(utils::random_nickname());
+    }
+    if let Action::Receive { dir, auto_accept, .. } = &action {
+        match utils::verify_download_directory(&dir.to_string_lossy()) {
+            Ok(verified) => config.download_dir = Some(verified),
+            Err(e) => return fail(EXIT_FAILURE, format!("Invalid download directory: {}", e)),
+        }
+        if !auto_accept && !io::stdin().is_terminal() {
+            return fail(EXIT_FAILURE, "There is no terminal to ask whether to accept an offer. Use --auto-accept.");
+        }
+    }
+
+    let cancel = CancellationToken::new();
+    let node = match swapbytes_core::spawn(local_key, config, cancel.clone()) {
+        Ok(node) => node,
+        Err(e) => return fail(EXIT_FAILURE, format!("Failed to start the node: {}", e)),
+    };
+    let verbose = matches!(&action, Action::Send { wait, .. } | Action::Receive { wait, .. } if wait.verbose);
+    let mut session = Session { node, verbose, peers: HashMap::new(), deadline: None };
+
+    let status = match action {
+        Action::Send { file, to, wait } => send(&mut session, file, &to, &wait).await,
+        Action::Receive { from, auto_accept, wait, .. } => receive(&mut session, from.as_deref(), auto_accept, &wait).await,
+        Action::Peers { dial, wait } => peers(&mut session, dial, wait).await,
+    };
+
+    // Give the swarm task a moment to stop, so peers see us go
+    cancel.cancel();
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, async { while session.node.events.recv().await.is_some() {} }).await;
+    status
+}
+
+// --- Actions ---
+
+/// Offers `file` to the peer `to` once it's found, and waits until it has downloaded it.
+async fn send(session: &mut Session, file: PathBuf, to: &str, wait: &WaitArgs) -> i32 {
+    let (path, size_bytes) = match utils::verify_offer_file(&file.to_string_lossy()) {
+        Ok(verified) => verified,
+        Err(e) => return fail(EXIT_FAILURE, format!("Can't send '{}': {}", file.display(), e)),
+    };
+    let filename = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    session.dial(&wait.dial);
+
+    // --- Find The Peer ---
+    session.set_timeout(wait.timeout);
+    let peer_id = loop {
+        match session.find_peer(to) {
+            Ok(Some(peer_id)) => break peer_id,
+            Ok(None) => {}
+            Err(e) => return fail(EXIT_FAILURE, e),
+        }
+        match session.next_event().await {
+            Ok(_) => {}
+            Err(e) => return session.stopped(e, format!("Peer '{}' not found.", to)),
+        }
+    };
+
+    // --- Offer The File ---
+    let offer_id = utils::new_offer_id();
+    eprintln!("Offering '{}' ({}) to {}...", filename, utils::format_bytes(size_bytes), session.display_name(&peer_id));
+    let _ = session.node.commands.send(Command::SendFileOffer { target_peer: peer_id, offer_id: offer_id.clone(), file_path: path.clone() });
+    session.set_timeout(wait.timeout);
+    let mut sending = false;
+    loop {
+        let event = match session.next_event().await {
+            Ok(event) => event,
+            Err(e) if sending => return session.stopped(e, format!("The transfer stalled: no progress for {} seconds.", wait.timeout)),
+            Err(e) => return session.stopped(e, format!("{} didn't answer the offer.", session.display_name(&peer_id))),
+        };
+        match event {
+            Event::FileOfferAccepted { peer_id: from, offer_id: accepted, .. } if from == peer_id && accepted == offer_id => {
+                eprintln!("Accepted; sending...");
+                let _ = session.node.commands.send(Command::RegisterOutgoingTransfer { peer_id, filename: filename.clone(), path: path.clone() });
+                // However long the transfer takes, it only has to keep moving
+                sending = true;
+                session.set_timeout(wait.timeout);
+            }
+            Event::UploadProgress { peer_id: to, filename: sent, .. }
+            | Event::TransferQueued { peer_id: to, filename: sent, direction: TransferDirection::Upload, .. }
+            | Event::TransferResumed { peer_id: to, filename: sent, direction: TransferDirection::Upload, .. }
+                if sending && to == peer_id && sent == filename => {
+                    session.set_timeout(wait.timeout);
+                }
+            Event::FileOfferDeclined { peer_id: from, offer_id: declined, .. } if from == peer_id && declined == offer_id => {
+                return fail(EXIT_DECLINED, format!("{} declined the offer.", session.display_name(&peer_id)));
+            }
+            Event::FileOfferRejected { peer_id: from, offer_id: rejected, reason, .. } if from == peer_id && rejected == offer_id => {
+                return fail(EXIT_FAILURE, format!("{} refused the offer: {}", session.display_name(&peer_id), reason));
+            }
+            Event::UploadFailed { peer_id: to, filename: failed, error } if to == peer_id && failed == filename => {
+                return fail(EXIT_FAILURE, format!("Sending '{}' failed: {}", filename, error));
+            }
+            Event::TransferCancelled { peer_id: to, filename: cancelled, direction: TransferDirection::Upload, .. } if to == peer_id && cancelled == filename => {
+                return fail(EXIT_FAILURE, format!("{} cancelled the transfer.", session.display_name(&peer_id)));
+            }
+            Event::UploadComplete { peer_id: to, filename: sent } if to == peer_id && sent == filename => {
+                print_result(json!({
+                    "peer_id": peer_id,
+                    "nickname": session.peers.get(&peer_id).cloned().flatten(),
+                    "offer_id": offer_id,
+                    "filename": filename,
+                    "size_bytes": size_bytes,
+                }));
+                return 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Waits for an offer (from `from`, if given), accepts it and waits until it's downloaded.
+async fn receive(session: &mut Session, from: Option<&str>, auto_accept: bool, wait: &WaitArgs) -> i32 {
+    session.dial(&wait.dial);
+
+    // --- Wait For An Offer ---
+    session.set_timeout(wait.timeout);
+    // Offers that arrive before the sender's first heartbeat wait until we know its nickname
+    let mut undecided: Vec<IncomingOffer> = Vec::new();
+    let (peer_id, filename, is_bundle) = 'offers: loop {
+        let event = match session.next_event().await {
+            Ok(event) => event,
+            Err(e) => return session.stopped(e, "No offer arrived.".to_string()),
+        };
+        match event {
+            Event::FileOfferReceived { sender_id, offer_id, filename, size_bytes, file_count, .. } => {
+                undecided.push(IncomingOffer { sender_id, offer_id, filename, size_bytes, file_count });
+            }
+            // The node accepted it by an auto-accept rule from the config file
+            Event::OfferAutoAccepted { sender_id, filename, file_count, rule, .. } if from.is_none_or(|from| session.is_peer(&sender_id, from)) => {
+                eprintln!("Receiving '{}' from {}, accepted by the rule: {}...", filename, session.display_name(&sender_id), rule);
+                break 'offers (sender_id, filename, file_count.is_some());
+            }
+            _ => {}
+        }
+        let (ready, waiting): (Vec<_>, Vec<_>) = undecided.into_iter().partition(|offer| {
+            from.is_none_or(|from| session.is_peer(&offer.sender_id, from) || session.nickname_known(&offer.sender_id))
+        });
+        undecided = waiting;
+
+        for offer in ready {
+            let sender_name = session.display_name(&offer.sender_id);
+            if from.is_some_and(|from| !session.is_peer(&offer.sender_id, from)) {
+                eprintln!("Ignoring the offer of '{}' from {}.", offer.filename, sender_name);
+                continue;
+            }
+            let description = match offer.file_count {
+                Some(count) => format!("'{}' ({} files, {})", offer.filename, count, utils::format_bytes(offer.size_bytes)),
+                None => format!("'{}' ({})", offer.filename, utils::format_bytes(offer.size_bytes)),
+            };
+            if !auto_accept && !ask(&format!("Accept {} from {}?", description, sender_name)).await {
+                eprintln!("Declined {}.", description);
+                let _ = session.node.commands.send(Command::DeclineFileOffer {
+                    target_peer: offer.sender_id,
+                    offer_id: offer.offer_id,
+                    filename: offer.filename,
+                });
+                continue;
+            }
+            eprintln!("Receiving {} from {}...", description, sender_name);
+            let _ = session.node.commands.send(Command::SendAcceptOffer {
+                target_peer: offer.sender_id,
+                offer_id: offer.offer_id,
+                filename: offer.filename.clone(),
+                subdir: None,
+            });
+            break 'offers (offer.sender_id, offer.filename, offer.file_count.is_some());
+        }
+    };
+
+    // --- Wait For The Download ---
+    // However long the download takes, it only has to keep moving
+    session.set_timeout(wait.timeout);
+    loop {
+        let event = match session.next_event().await {
+            Ok(event) => event,
+            Err(e) => return session.stopped(e, format!("The download stalled: no progress for {} seconds.", wait.timeout)),
+        };
+        match event {
+            Event::FileTransferProgress { peer_id: sender, filename: moving, .. }
+            | Event::DownloadResumed { peer_id: sender, filename: moving, .. }
+            | Event::TransferQueued { peer_id: sender, filename: moving, direction: TransferDirection::Download, .. }
+            | Event::TransferResumed { peer_id: sender, filename: moving, direction: TransferDirection::Download, .. }
+                if sender == peer_id && moving == filename => {
+                    session.set_timeout(wait.timeout);
+                }
+            Event::BundleProgress { peer_id: sender, status, .. } if is_bundle && sender == peer_id && status.name == filename => {
+                session.set_timeout(wait.timeout);
+            }
+            Event::FileTransferComplete { peer_id: sender, filename: done, path, total_size, hash } if !is_bundle && sender == peer_id && done == filename => {
+                print_result(json!({
+                    "peer_id": peer_id,
+                    "nickname": session.peers.get(&peer_id).cloned().flatten(),
+                    "filename": filename,
+                    "path": path,
+                    "size_bytes": total_size,
+                    "hash": hash,
+                }));
+                return 0;
+            }
+            Event::BundleComplete { peer_id: sender, status, path } if is_bundle && sender == peer_id && status.name == filename => {
+                print_result(json!({
+                    "peer_id": peer_id,
+                    "nickname": session.peers.get(&peer_id).cloned().flatten(),
+                    "filename": filename,
+                    "path": path,
+                    "size_bytes": status.total,
+                    "file_count": status.file_count,
+                    "files_failed": status.files_failed,
+                }));
+                return if status.files_failed == 0 { 0 } else { fail(EXIT_FAILURE, format!("{} of the files could not be downloaded.", status.files_failed)) };
+            }
+            Event::FileTransferFailed { peer_id: sender, filename: failed, error } if sender == peer_id && failed == filename => {
+                return fail(EXIT_FAILURE, format!("Downloading '{}' failed: {}", filename, error));
+            }
+            Event::TransferCancelled { peer_id: sender, filename: cancelled, direction: TransferDirection::Download, .. } if sender == peer_id && cancelled == filename => {
+                return fail(EXIT_FAILURE, format!("{} cancelled the transfer.", session.display_name(&peer_id)));
+            }
+            Event::DownloadInterrupted { peer_id: sender, filename: interrupted, received, total } if sender == peer_id && interrupted == filename => {
+                eprintln!("Interrupted at {} of {}; waiting for {} to come back...", utils::format_bytes(received), utils::format_bytes(total), session.display_name(&peer_id));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Looks for peers for `wait` seconds and prints each one found.
+async fn peers(session: &mut Session, dial: Vec<Multiaddr>, wait: u64) -> i32 {
+    session.dial(&dial);
+    // Peers announce their nicknames in heartbeats, so give them the whole time to do it
+    session.deadline = Some(Instant::now() + std::time::Duration::from_secs(wait));
+    loop {
+        match session.next_event().await {
+            Ok(_) => {}
+            Err(WaitError::TimedOut) => break,
+            Err(e) => return session.stopped(e, String::new()),
+        }
+    }
+    let mut found: Vec<(&PeerId, &Option<String>)> = session.peers.iter().collect();
+    found.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
+    for (peer_id, nickname) in found {
+        print_result(json!({ "peer_id": peer_id, "nickname": nickname }));
+    }
+    0
+}
+
+// --- Helpers ---
+
+impl Session {
+    /// Connects to each of `addrs`.
+    fn dial(&self, addrs: &[Multiaddr]) {
+        for addr in addrs {
+            let _ = self.node.commands.send(Command::Dial(addr.clone()));
+        }
+    }
+
+    /// Gives up waiting `secs` seconds from now (0: never).
+    fn set_timeout(&mut self, secs: u64) {
+        self.deadline = (secs > 0).then(|| Instant::now() + std::time::Duration::from_secs(secs));
+    }
+
+    /// Waits for the next event, keeping track of the peers it tells us about.
+    async fn next_event(&mut self) -> Result<Event, WaitError> {
+        let event = match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, self.node.events.recv()).await.map_err(|_| WaitError::TimedOut)?,
+            None => self.node.events.recv().await,
+        };
+        let event = event.ok_or(WaitError::NodeStopped)?;
+        match &event {
+            Event::PeerDiscovered(peer_id) | Event::PeerSeen(peer_id) if *peer_id != self.node.local_peer_id => {
+                self.peers.entry(*peer_id).or_default();
+            }
+            Event::NicknameUpdated(peer_id, nickname) if *peer_id != self.node.local_peer_id => {
+                self.peers.insert(*peer_id, Some(nickname.clone()));
+            }
+            Event::LogMessage(message) if self.verbose => eprintln!("{}", message),
+            _ => {}
+        }
+        Ok(event)
+    }
+
+    /// Finds the peer `who` names among those seen so far: by peer ID, or by nickname if only one peer uses it.
+    fn find_peer(&self, who: &str) -> Result<Option<PeerId>, String> {
+        let matches: Vec<PeerId> = self.peers.keys().filter(|peer_id| self.is_peer(peer_id, who)).copied().collect();
+        match matches.as_slice() {
+            [] => Ok(None),
+            [peer_id] => Ok(Some(*peer_id)),
+            _ => Err(format!("Several peers are called '{}'. Use their peer ID.", who)),
+        }
+    }
+
+    /// Whether `who` is this peer's ID or nickname.
+    fn is_peer(&self, peer_id: &PeerId, who: &str) -> bool {
+        peer_id.to_string() == who
+            || self.peers.get(peer_id).and_then(|nickname| nickname.as_deref()).is_some_and(|nickname| nickname.eq_ignore_ascii_case(who))
+    }
+
+    /// Whether the peer has told us its nickname.
+    fn nickname_known(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(|nickname| nickname.is_some())
+    }
+
+    fn display_name(&self, peer_id: &PeerId) -> String {
+        self.peers.get(peer_id).cloned().flatten().unwrap_or_else(|| utils::peer_id_to_short_string(peer_id))
+    }
+
+    /// Reports why waiting stopped and returns the exit status for it.
+    fn stopped(&self, error: WaitError, timed_out: String) -> i32 {
+        match error {
+            WaitError::TimedOut => fail(EXIT_TIMEOUT, timed_out),
+            WaitError::NodeStopped => fail(EXIT_FAILURE, "The node stopped unexpectedly."),
+        }
+    }
+}
+
+/// Prints a result line to stdout.
+fn print_result(result: serde_json::Value) {
+    println!("{}", result);
+}
+
+/// Prints an error to stderr and returns `status`.
+fn fail(status: i32, message: impl std::fmt::Display) -> i32 {
+    eprintln!("Error: {}", message);
+    status
+}
+
+/// Asks a yes/no question on the terminal. Anything but "y" or "yes" is a no.
+async fn ask(question: &str) -> bool {
+    eprint!("{} [y/N] ", question);
+    let _ = io::stderr().flush();
+    tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        let _ = io::stdin().lock().read_line(&mut answer);
+        matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+    })
+    .await
+    .unwrap_or(false)
+}
@@ -9,26 +9,23 @@ use ratatui::{
     style::Stylize,
     symbols::border,
     text::{Line, Text, Span},
-    widgets::{Block, Paragraph, Widget, List, ListItem},
+    widgets::{Block, Clear, Paragraph, Widget, List, ListItem},
     layout::{Constraint, Layout},
     style::{Color, Style},
 };
-// Import terminal event handling from `crossterm`
-use crossterm::event;
-// Standard library imports for file paths, data structures, time, and random numbers
+// Standard library imports for file paths, data structures and time
 use std::path::PathBuf;
 use std::collections::HashMap;
 use std::time::Instant;
-use rand::Rng;
 
 // Import necessary libp2p types for network interaction
-use libp2p::swarm::SwarmEvent;
 use libp2p::{Multiaddr, PeerId};
-// Import our custom network behavior events
-use crate::behavior::SwapBytesBehaviourEvent;
+// Types shared with the SwapBytes node
+use swapbytes_core::event::{BundleStatus, Reachability, SwapDetails};
+use swapbytes_core::protocol::TransferDirection;
+use swapbytes_core::ratelimit::RateLimits;
+use swapbytes_core::{swap, utils, Command, Config};
 use crate::constants::MAX_LOG_LINES;
-// Import tokio filesystem for file handling in DownloadState
-use tokio::fs::File as TokioFile;
 
 /// Holds the details of a single chat message to be displayed.
 #[derive(Debug, Clone)]
@@ -60,6 +57,23 @@ pub enum PrivateChatItem {
     OfferAccepted(PendingOfferDetails),
     /// A file offer sent by the local user that was accepted by the remote peer.
     RemoteOfferAccepted(PendingOfferDetails),
+    /// A file offer from the peer that an auto-accept rule accepted as it arrived.
+    OfferAutoAccepted {
+        offer: PendingOfferDetails,
+        /// The rule that matched, described (see `AutoAcceptRule`'s `Display`).
+        rule: String,
+    },
+    /// An offer we sent that the peer refused as it arrived, e.g. for a file name it considers unsafe.
+    RemoteOfferRejected {
+        filename: String,
+        reason: String,
+    },
+    /// An offer (or swap proposal) from the peer that was refused before it was shown, e.g. for an unsafe file name.
+    OfferRejected {
+        /// The offered name, with hidden characters escaped (see `sanitize::display`).
+        filename: String,
+        reason: String,
+    },
     /// An ongoing file transfer's progress.
     TransferProgress {
         filename: String,
@@ -67,17 +81,66 @@ pub enum PrivateChatItem {
         total: u64,
         // We might add start_time later for speed calculation
     },
-    /// A successfully completed file transfer (download).
+    /// A successfully completed file transfer (download) whose contents matched the offered hash.
     TransferComplete {
         filename: String,
         final_path: PathBuf,
         size: u64,
+        hash: String,
     },
     /// A file transfer that failed.
     TransferFailed {
         filename: String,
         error: String,
     },
+    /// A download that stopped part-way and can be resumed.
+    TransferInterrupted {
+        filename: String,
+        received: u64,
+        total: u64,
+    },
+    /// A transfer that was stopped for good with `/cancel`, by either side.
+    TransferCancelled {
+        filename: String,
+        /// Our side of the transfer.
+        direction: TransferDirection,
+        by_peer: bool,
+    },
+    /// A transfer that was paused with `/pause`, by either side.
+    TransferPaused {
+        filename: String,
+        direction: TransferDirection,
+        by_peer: bool,
+    },
+    /// A paused transfer that was resumed, by either side.
+    TransferResumed {
+        filename: String,
+        direction: TransferDirection,
+        by_peer: bool,
+    },
+    /// An ongoing download of several files, updated in place.
+    BundleProgress(BundleStatus),
+    /// A finished bundle download. Files that failed are listed as `TransferFailed` items.
+    BundleComplete {
+        status: BundleStatus,
+        /// The download directory the files were saved under.
+        path: PathBuf,
+    },
+    /// A swap proposed by the local user.
+    SwapProposed(SwapDetails),
+    /// A swap proposed by the remote peer, waiting for `/swap accept` or `/swap decline`.
+    SwapProposal(SwapDetails),
+    /// Both sides have committed to their file and the transfers are running.
+    SwapAgreed(SwapDetails),
+    /// Our side of the swap arrived and was verified; it is held until the peer has theirs.
+    SwapWaiting(SwapDetails),
+    /// Both sides received their file, so ours was released.
+    SwapCompleted(SwapDetails),
+    /// A swap that was declined or called off.
+    SwapCancelled {
+        details: SwapDetails,
+        reason: String,
+    },
 }
 
 /// Different modes the user can be in when interacting with the input boxes.
@@ -137,24 +200,167 @@ pub struct PeerInfo {
 /// Stores the details of a file offer that is waiting for acceptance/rejection.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PendingOfferDetails {
+    /// Short ID telling this offer apart from others pending with the same peer.
+    pub offer_id: String,
     pub filename: String,
     pub size_bytes: u64,
     pub path: PathBuf,
+    /// BLAKE3 hash announced by the sender (`None` for offers we send; the swarm task hashes those).
+    pub hash: Option<String>,
+    /// Number of files, for offers of a directory or several files (`None` for a single file).
+    /// `filename` is then the bundle's name and `size_bytes` the total size.
+    pub file_count: Option<usize>,
 }
 
-/// Represents the state of an ongoing file download.
-#[derive(Debug)] // Deriving Debug for App's Debug implementation
-pub struct DownloadState {
-    /// The local path where the file is being saved (likely temporary initially).
-    pub local_path: PathBuf,
-    /// The total expected size of the file in bytes.
-    pub total_size: u64,
-    /// How many bytes have been received so far.
-    pub received: u64,
-    /// The index of the next chunk we expect to receive.
-    pub next_chunk: u64,
-    /// The handle to the file being written to disk.
-    pub file: TokioFile, // Using Tokio's async File
+impl PendingOfferDetails {
+    /// Whether a reply from the peer refers to this offer. Older peers don't echo the ID,
+    /// so the filename has to do for them.
+    pub fn matches(&self, offer_id: &str, filename: &str) -> bool {
+        if offer_id.is_empty() {
+            self.filename == filename
+        } else {
+            self.offer_id == offer_id
+        }
+    }
+
+    /// Describes what is on offer: "file" or "12 files".
+    pub fn kind(&self) -> String {
+        match self.file_count {
+            Some(count) => format!("{} files", count),
+            None => "file".to_string(),
+        }
+    }
+}
+
+/// Describes what a peer was doing with a transfer, for messages like "alice paused sending 'x'".
+/// `direction` is our side of the transfer; `by_peer` tells whether the peer or we did it.
+pub fn transfer_activity(direction: TransferDirection, by_peer: bool) -> &'static str {
+    match (direction, by_peer) {
+        (TransferDirection::Download, false) | (TransferDirection::Upload, true) => "downloading",
+        (TransferDirection::Upload, false) | (TransferDirection::Download, true) => "sending",
+    }
+}
+
+/// Where a transfer shown in the Transfers pane stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferRowStatus {
+    Running,
+    /// Paused with `/pause`, by either side.
+    Paused,
+    /// Cut off by a disconnect or restart.
+    Interrupted,
+    /// Waiting in line (ours or the peer's) at this place.
+    Queued(u32),
+    Complete,
+    Failed,
+    Cancelled,
+}
+
+impl TransferRowStatus {
+    /// Whether the transfer is over for good.
+    pub fn is_finished(self) -> bool {
+        matches!(self, TransferRowStatus::Complete | TransferRowStatus::Failed | TransferRowStatus::Cancelled)
+    }
+}
+
+/// A row of the Transfers pane: one upload or download (or bundle download), running or recently finished.
+/// Rates are measured from the progress updates the swarm task sends.
+#[derive(Debug, Clone)]
+pub struct TransferRow {
+    pub peer_id: PeerId,
+    pub filename: String,
+    /// Our side of the transfer.
+    pub direction: TransferDirection,
+    pub status: TransferRowStatus,
+    /// Bytes transferred so far. For uploads, how far into the file the receiver has got.
+    pub done: u64,
+    pub total: u64,
+    /// When the current run started and how many bytes were done then. A resumed transfer
+    /// starts a new run, so time spent paused doesn't drag the average rate down.
+    pub run_started: Instant,
+    pub run_start_bytes: u64,
+    /// When the last progress update arrived.
+    pub last_update: Instant,
+    /// Smoothed rate over the last few updates, in bytes per second.
+    pub current_rate: f64,
+    /// Whether a bandwidth limit was holding it back at the last update.
+    pub throttled: bool,
+    /// When the transfer finished, stopped or was paused.
+    pub stopped_at: Option<Instant>,
+}
+
+impl TransferRow {
+    fn new(peer_id: PeerId, filename: String, direction: TransferDirection, done: u64, total: u64, now: Instant) -> Self {
+        TransferRow {
+            peer_id,
+            filename,
+            direction,
+            status: TransferRowStatus::Running,
+            done,
+            total,
+            run_started: now,
+            run_start_bytes: done,
+            last_update: now,
+            current_rate: 0.0,
+            throttled: false,
+            stopped_at: None,
+        }
+    }
+
+    /// Records a progress update. A transfer that wasn't running starts a new run.
+    fn record_progress(&mut self, done: u64, total: u64, now: Instant) {
+        if self.status != TransferRowStatus::Running {
+            *self = TransferRow::new(self.peer_id, std::mem::take(&mut self.filename), self.direction, done, total, now);
+        } else {
+            let elapsed = now.duration_since(self.last_update).as_secs_f64();
+            if elapsed > 0.0 && done >= self.done {
+                let sample = (done - self.done) as f64 / elapsed;
+                self.current_rate = if self.current_rate == 0.0 {
+                    sample
+                } else {
+                    crate::constants::TRANSFER_RATE_SMOOTHING * self.current_rate + (1.0 - crate::constants::TRANSFER_RATE_SMOOTHING) * sample
+                };
+            }
+            self.done = done;
+            self.total = total;
+            self.last_update = now;
+        }
+        if total > 0 && done >= total {
+            self.stop(TransferRowStatus::Complete, now);
+        }
+    }
+
+    /// Marks the transfer as no longer running.
+    fn stop(&mut self, status: TransferRowStatus, now: Instant) {
+        if self.status == TransferRowStatus::Running {
+            self.stopped_at = Some(now);
+        }
+        self.status = status;
+    }
+
+    /// Average rate of the current run, in bytes per second.
+    pub fn average_rate(&self, now: Instant) -> f64 {
+        let end = self.stopped_at.unwrap_or(now);
+        let elapsed = end.duration_since(self.run_started).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        self.done.saturating_sub(self.run_start_bytes) as f64 / elapsed
+    }
+
+    /// Whether a running transfer has gone quiet for longer than `TRANSFER_STALL_TIMEOUT`.
+    pub fn is_stalled(&self, now: Instant) -> bool {
+        self.status == TransferRowStatus::Running && now.duration_since(self.last_update) > crate::constants::TRANSFER_STALL_TIMEOUT
+    }
+
+    /// Estimated time left at the current rate, if it is running and moving.
+    pub fn eta(&self, now: Instant) -> Option<std::time::Duration> {
+        if self.status != TransferRowStatus::Running || self.is_stalled(now) || self.current_rate <= 0.0 {
+            return None;
+        }
+        let remaining = self.total.saturating_sub(self.done) as f64;
+        Some(std::time::Duration::from_secs_f64(remaining / self.current_rate))
+    }
 }
 
 /// Holds the entire state of the TUI application.
@@ -183,12 +389,20 @@ pub struct App {
     pub console_viewport_height: usize,
     /// The network addresses our application is listening on.
     pub listening_addresses: Vec<Multiaddr>,
+    /// The addresses peers have told us they see us at (our public address, behind a NAT).
+    pub observed_addresses: Vec<Multiaddr>,
+    /// Addresses confirmed reachable from outside; these are registered with Rendezvous points.
+    pub external_addresses: Vec<Multiaddr>,
+    /// Whether peers outside our network can dial us, according to AutoNAT.
+    pub reachability: Reachability,
     /// The directory where downloaded files will be saved (if set).
     pub download_dir: Option<PathBuf>,
     /// The user's chosen nickname for display in chat and user lists.
     pub nickname: Option<String>,
     /// Our own unique `PeerId`.
     pub local_peer_id: Option<PeerId>,
+    /// Where our identity keypair is stored (`None` if running with a temporary identity).
+    pub identity_path: Option<PathBuf>,
     /// A map storing information (`PeerInfo`) about discovered peers, keyed by their `PeerId`.
     pub peers: HashMap<PeerId, PeerInfo>,
     /// `true` if we are currently waiting for a ping response.
@@ -207,22 +421,27 @@ pub struct App {
     pub chat_viewport_height: usize,
     /// Stores the history of private messages, keyed by the `PeerId` of the other participant.
     pub private_chat_histories: HashMap<PeerId, Vec<PrivateChatItem>>,
-    /// Stores details of the latest pending file offer received from each peer.
-    pub pending_offers: HashMap<PeerId, PendingOfferDetails>,
-    /// Stores the state of ongoing downloads, keyed by PeerId and then filename.
-    pub download_states: HashMap<PeerId, HashMap<String, DownloadState>>,
+    /// Stores the pending file offers received from each peer, oldest first.
+    pub pending_offers: HashMap<PeerId, Vec<PendingOfferDetails>>,
+    /// Swap proposals received and not yet accepted or declined, keyed by swap ID.
+    pub pending_swaps: HashMap<String, (PeerId, SwapDetails)>,
     /// Stores the local path for files we are currently sending, keyed by (PeerId, filename).
     pub outgoing_transfers: HashMap<(PeerId, String), PathBuf>,
+    /// Downloads that stopped part-way, keyed by (PeerId, filename). Value: (bytes received, total bytes).
+    pub interrupted_downloads: HashMap<(PeerId, String), (u64, u64)>,
+    /// Rows of the Transfers pane, oldest first: running transfers and the last `MAX_RECENT_TRANSFERS` finished ones.
+    pub transfers: Vec<TransferRow>,
+    /// Whether the Transfers pane is shown over the chat (toggled with Ctrl+T).
+    pub show_transfers: bool,
+    /// Bandwidth limits as set at startup and with `/limit` (the swarm task applies them).
+    pub rate_limits: RateLimits,
 }
 
 // Provides default values for the `App` state when the application starts.
 impl Default for App {
     fn default() -> Self {
         // Generate a username with a random 4-digit number
-        // e.g. user9350
-        let mut rng = rand::rng();
-        let random_number: u16 = rng.random_range(0..10000);
-        let nickname = format!("user{:04}", random_number);
+        let nickname = utils::random_nickname();
 
         App {
             log: Vec::new(), // Start with an empty log
@@ -236,9 +455,13 @@ impl Default for App {
             console_scroll: 0, // Start scrolled to the top
             console_viewport_height: 2, // Small default height
             listening_addresses: Vec::new(), // No known addresses initially
+            observed_addresses: Vec::new(), // Nobody has seen us yet
+            external_addresses: Vec::new(), // Nothing confirmed yet
+            reachability: Reachability::Unknown, // Not probed yet
             download_dir: None, // No download directory set initially
             nickname: Some(nickname), // Use the generated nickname
             local_peer_id: None, // We don't know our PeerId yet
+            identity_path: None, // Set once the identity has been loaded
             peers: HashMap::new(), // No known peers initially
             pinging: false, // Not pinging initially
             ping_start_time: None, // No ping started yet
@@ -249,8 +472,12 @@ impl Default for App {
             chat_viewport_height: 2, // Small default chat height
             private_chat_histories: HashMap::new(), // No private chats yet
             pending_offers: HashMap::new(), // No pending offers initially
-            download_states: HashMap::new(), // No ongoing downloads initially
+            pending_swaps: HashMap::new(), // No pending swap proposals initially
             outgoing_transfers: HashMap::new(), // No outgoing transfers initially
+            interrupted_downloads: HashMap::new(), // Filled in by the swarm task from the download store
+            transfers: Vec::new(), // No transfers yet
+            show_transfers: false, // The chat is shown until Ctrl+T is pressed
+            rate_limits: RateLimits::default(), // Unlimited unless configured
         }
     }
 }
@@ -258,6 +485,20 @@ impl Default for App {
 
 // Methods for updating the `App` state.
 impl App {
+    /// Creates the initial `App` state, applying the user's configuration on top of the defaults.
+    pub fn from_config(config: &Config) -> Self {
+        let mut app = App::default();
+        // Keep the random nickname unless one was configured.
+        if let Some(nickname) = &config.nickname {
+            app.nickname = Some(nickname.clone());
+        }
+        app.download_dir = config.download_dir.clone();
+        app.is_visible = config.visible;
+        app.identity_path = config.identity_path.clone();
+        app.rate_limits = config.rate_limits.clone();
+        app
+    }
+
     /// Adds a regular message to the console log.
     /// Ensures the log doesn't exceed `MAX_LOG_LINES` and automatically scrolls down.
     pub fn push<S: Into<String>>(&mut self, line: S) {
@@ -295,6 +536,75 @@ impl App {
         self.console_scroll = new_scroll_pos;
     }
 
+    // --- Transfers Pane State ---
+    // These methods keep the rows of the Transfers pane up to date.
+
+    /// Records progress of a transfer, adding a row for it if it is new
+    /// (or if the last transfer of this file is already over).
+    /// `throttled` tells whether a bandwidth limit is holding it back.
+    pub fn track_transfer_progress(&mut self, peer_id: PeerId, filename: &str, direction: TransferDirection, done: u64, total: u64, throttled: bool) {
+        let now = Instant::now();
+        match self.transfer_row_mut(peer_id, filename, direction) {
+            Some(row) if !row.status.is_finished() => {
+                row.record_progress(done, total, now);
+                row.throttled = throttled;
+            }
+            _ => {
+                let mut row = TransferRow::new(peer_id, filename.to_string(), direction, done, total, now);
+                // An empty file (or the last update of a tiny one) may be done straight away
+                row.record_progress(done, total, now);
+                row.throttled = throttled;
+                self.transfers.push(row);
+                self.prune_transfers();
+            }
+        }
+    }
+
+    /// Marks a transfer as paused, interrupted or finished. If there is no row for it yet,
+    /// one is added when its progress (done, total) is known, e.g. for a download paused before a restart.
+    pub fn set_transfer_status(&mut self, peer_id: PeerId, filename: &str, direction: TransferDirection, status: TransferRowStatus, progress: Option<(u64, u64)>) {
+        let now = Instant::now();
+        match self.transfer_row_mut(peer_id, filename, direction) {
+            // Already over (e.g. complete once the last byte arrived): nothing left to change
+            Some(row) if row.status.is_finished() && status.is_finished() => {}
+            Some(row) if !row.status.is_finished() => {
+                if let Some((done, total)) = progress {
+                    row.done = done;
+                    row.total = total;
+                }
+                row.stop(status, now);
+            }
+            _ => {
+                let Some((done, total)) = progress else { return };
+                let mut row = TransferRow::new(peer_id, filename.to_string(), direction, done, total, now);
+                row.stop(status, now);
+                self.transfers.push(row);
+                self.prune_transfers();
+            }
+        }
+    }
+
+    /// The latest row for a transfer, if any.
+    fn transfer_row_mut(&mut self, peer_id: PeerId, filename: &str, direction: TransferDirection) -> Option<&mut TransferRow> {
+        self.transfers
+            .iter_mut()
+            .rev()
+            .find(|row| row.peer_id == peer_id && row.direction == direction && row.filename == filename)
+    }
+
+    /// Drops the oldest finished rows beyond `MAX_RECENT_TRANSFERS`.
+    fn prune_transfers(&mut self) {
+        let finished = self.transfers.iter().filter(|row| row.status.is_finished()).count();
+        let mut excess = finished.saturating_sub(crate::constants::MAX_RECENT_TRANSFERS);
+        self.transfers.retain(|row| {
+            if excess > 0 && row.status.is_finished() {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+
     // --- Command Input Handling ---
     // These methods manage the text and cursor in the command input box.
 
@@ -358,8 +668,8 @@ impl App {
 
     /// Processes the command currently in the input box when Enter is pressed.
     /// It logs the command, clears the input, returns to Normal mode,
-    /// and sends an `AppEvent` to the main loop for handling the command logic.
-    pub fn submit_command(&mut self) -> Option<AppEvent> {
+    /// and returns the `Command` (if any) for the swarm task.
+    pub fn submit_command(&mut self) -> Option<Command> {
         // Add the entered command to the console log for history.
         self.push(format!("> {}", self.input));
 
@@ -369,7 +679,7 @@ impl App {
         let command_input = input_copy.strip_prefix('/').unwrap_or(&input_copy);
 
         // Delegate the actual command parsing and execution logic.
-        let event_to_send = crate::commands::process_command(command_input, self);
+        let command_to_send = crate::commands::process_command(command_input, self);
 
         // Clear the input field and reset the cursor.
         self.input.clear();
@@ -377,8 +687,8 @@ impl App {
         // Go back to normal mode, ready for the next command or focus change.
         self.input_mode = InputMode::Normal;
 
-        // Return the event (e.g., Dial, Quit) for the main loop to handle.
-        event_to_send
+        // Return the command (e.g., Dial) for the swarm task.
+        command_to_send
     }
 
     // --- Chat Input Handling ---
@@ -446,10 +756,10 @@ impl App {
 
         // Help text shown at the bottom depends on the current input mode.
         let console_title_bottom = match self.input_mode {
-            InputMode::Normal => " Focus: Tab | Scroll: ↑/↓ | Quit: Ctrl+Q ".bold(),
+            InputMode::Normal => " Focus: Tab | Scroll: ↑/↓ | Transfers: Ctrl+T | Quit: Ctrl+Q ".bold(),
             InputMode::Command => " Submit: Enter | Cancel: Esc ".bold(),
             // While in Chat mode, console hints might still be relevant if user tabs back.
-            InputMode::Chat => " Focus: Tab | Scroll: ↑/↓ | Quit: Ctrl+Q ".bold(),
+            InputMode::Chat => " Focus: Tab | Scroll: ↑/↓ | Transfers: Ctrl+T | Quit: Ctrl+Q ".bold(),
         };
         // Create the main block for the console area.
         let console_block = Block::bordered()
@@ -504,6 +814,92 @@ impl App {
         input_paragraph.render(input_area, buf);
     }
 
+    /// Draws the Transfers pane over the chat area: every running transfer, then the most recent
+    /// finished ones, each with a progress bar, the peer, current and average rate, and ETA.
+    fn render_transfers_pane(&self, area: Rect, buf: &mut Buffer) {
+        const BAR_WIDTH: usize = 24;
+        let now = Instant::now();
+
+        let block = Block::bordered()
+            .title(" Transfers ".bold())
+            .title_bottom(Line::from(" Close: Ctrl+T ".bold()))
+            .border_set(border::THICK)
+            .border_style(Style::default().fg(Color::Yellow));
+        let inner_area = block.inner(area);
+        // Clear whatever the chat drew underneath
+        Clear.render(area, buf);
+        block.render(area, buf);
+
+        // Running (and paused) transfers first, then finished ones, newest first
+        let (finished, running): (Vec<&TransferRow>, Vec<&TransferRow>) = self.transfers.iter().partition(|row| row.status.is_finished());
+        let rows = running.into_iter().chain(finished.into_iter().rev());
+
+        let mut lines: Vec<Line> = Vec::new();
+        for row in rows {
+            let peer_display_name = self.peers.get(&row.peer_id)
+                .and_then(|info| info.nickname.clone())
+                .unwrap_or_else(|| utils::peer_id_to_short_string(&row.peer_id));
+            let (arrow, preposition) = match row.direction {
+                TransferDirection::Download => ("↓ ", "from"),
+                TransferDirection::Upload => ("↑ ", "to"),
+            };
+            let (status_text, color) = match row.status {
+                TransferRowStatus::Running if row.is_stalled(now) => ("stalled".to_string(), Color::Yellow),
+                TransferRowStatus::Running if row.throttled => ("throttled".to_string(), Color::Magenta),
+                TransferRowStatus::Running => (String::new(), Color::Cyan),
+                TransferRowStatus::Paused => ("paused".to_string(), Color::Yellow),
+                TransferRowStatus::Interrupted => ("interrupted".to_string(), Color::Yellow),
+                TransferRowStatus::Queued(place) => (format!("queued, #{} in line", place), Color::Blue),
+                TransferRowStatus::Complete => ("complete".to_string(), Color::Green),
+                TransferRowStatus::Failed => ("failed".to_string(), Color::Red),
+                TransferRowStatus::Cancelled => ("cancelled".to_string(), Color::Red),
+            };
+
+            // --- First line: what, with whom, and how it stands ---
+            let mut header = vec![
+                Span::styled(arrow, Style::default().fg(color)),
+                Span::styled(row.filename.clone(), Style::default().bold()),
+                Span::raw(format!(" {} {}", preposition, peer_display_name)),
+            ];
+            if !status_text.is_empty() {
+                header.push(Span::styled(format!("  [{}]", status_text), Style::default().fg(color)));
+            }
+            lines.push(Line::from(header));
+
+            // --- Second line: progress bar, sizes, rates and ETA ---
+            let fraction = if row.total == 0 { 1.0 } else { (row.done as f64 / row.total as f64).min(1.0) };
+            let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+            let mut details = vec![
+                Span::raw("  "),
+                Span::styled("█".repeat(filled), Style::default().fg(color)),
+                Span::styled("░".repeat(BAR_WIDTH - filled), Style::default().fg(Color::DarkGray)),
+                Span::raw(format!(
+                    " {:>3.0}%  {} / {}",
+                    fraction * 100.0,
+                    utils::format_bytes(row.done),
+                    utils::format_bytes(row.total)
+                )),
+            ];
+            // No rate until a second update has arrived
+            let rate_text = |rate: f64| if rate > 0.0 { utils::format_rate(rate) } else { "--".to_string() };
+            let average = rate_text(row.average_rate(now));
+            if row.status == TransferRowStatus::Running {
+                let current = if row.is_stalled(now) { utils::format_rate(0.0) } else { rate_text(row.current_rate) };
+                let eta = row.eta(now).map_or_else(|| "--".to_string(), utils::format_duration);
+                details.push(Span::raw(format!("  {} now, {} avg, ETA {}", current, average, eta)));
+            } else if let Some(stopped_at) = row.stopped_at.filter(|_| !matches!(row.status, TransferRowStatus::Queued(_))) {
+                let took = utils::format_duration(stopped_at.duration_since(row.run_started));
+                details.push(Span::raw(format!("  {} avg over {}", average, took)));
+            }
+            lines.push(Line::from(details));
+        }
+        if lines.is_empty() {
+            lines.push(Line::from("No transfers yet. Accepted offers show up here.".italic()));
+        }
+
+        Paragraph::new(Text::from(lines)).render(inner_area, buf);
+    }
+
     /// Draws the pane displaying the list of discovered users and their status.
     fn render_users_pane(&self, area: Rect, buf: &mut Buffer) {
         let focused_style = Style::default().fg(Color::Yellow);
@@ -708,17 +1104,46 @@ impl App {
                                     // Line 1: Offer details
                                     all_lines.push(Line::from(vec![
                                         Span::styled(">> ", Style::default().fg(Color::Blue)),
-                                        Span::styled(format!("{}", sender_display), Style::default().bold()),
+                                        Span::styled(sender_display.to_string(), Style::default().bold()),
                                         Span::raw(format!(
-                                            " offered file: '{}' ({}).",
+                                            " offered {} [{}]: '{}' ({}).",
+                                            offer_details.kind(),
+                                            offer_details.offer_id,
                                             offer_details.filename,
-                                            crate::utils::format_bytes(offer_details.size_bytes)
+                                            utils::format_bytes(offer_details.size_bytes)
                                         )),
                                     ]));
                                     // Line 2: Prompt
                                     all_lines.push(Line::from(vec![
                                         Span::raw("   "), // Indentation
-                                        Span::styled("Use /accept or /decline.", Style::default().fg(Color::Yellow).italic()),
+                                        Span::styled(
+                                            format!("Use /accept {0} or /decline {0}.", offer_details.offer_id),
+                                            Style::default().fg(Color::Yellow).italic(),
+                                        ),
+                                    ]));
+                                }
+                                PrivateChatItem::OfferAutoAccepted { offer, rule } => {
+                                    // The offer as it arrived, then which rule accepted it
+                                    let sender_display = self.peers.get(target_peer_id)
+                                        .and_then(|p| p.nickname.clone())
+                                        .unwrap_or_else(|| utils::peer_id_to_short_string(target_peer_id));
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled(">> ", Style::default().fg(Color::Blue)),
+                                        Span::styled(sender_display, Style::default().bold()),
+                                        Span::raw(format!(
+                                            " offered {} [{}]: '{}' ({}).",
+                                            offer.kind(),
+                                            offer.offer_id,
+                                            offer.filename,
+                                            utils::format_bytes(offer.size_bytes)
+                                        )),
+                                    ]));
+                                    all_lines.push(Line::from(vec![
+                                        Span::raw("   "), // Indentation
+                                        Span::styled(
+                                            format!("Accepted automatically by the rule: {}.", rule),
+                                            Style::default().fg(Color::Green).italic(),
+                                        ),
                                     ]));
                                 }
                                 PrivateChatItem::OfferSent(offer_details) => {
@@ -727,9 +1152,11 @@ impl App {
                                         Span::styled(">> ", Style::default().fg(Color::Blue)),
                                         Span::styled("You", Style::default().bold()),
                                         Span::raw(format!(
-                                            " offered file: '{}' ({}).",
+                                            " offered {} [{}]: '{}' ({}).",
+                                            offer_details.kind(),
+                                            offer_details.offer_id,
                                             offer_details.filename,
-                                            crate::utils::format_bytes(offer_details.size_bytes)
+                                            utils::format_bytes(offer_details.size_bytes)
                                         )),
                                     ]));
                                 }
@@ -739,9 +1166,11 @@ impl App {
                                         Span::styled("<< ", Style::default().fg(Color::Red)),
                                         Span::styled("You", Style::default().bold()),
                                         Span::raw(format!(
-                                            " declined file: '{}' ({}).",
+                                            " declined {} [{}]: '{}' ({}).",
+                                            offer_details.kind(),
+                                            offer_details.offer_id,
                                             offer_details.filename,
-                                            crate::utils::format_bytes(offer_details.size_bytes)
+                                            utils::format_bytes(offer_details.size_bytes)
                                         )),
                                     ]));
                                 }
@@ -756,11 +1185,13 @@ impl App {
                                         });
                                     all_lines.push(Line::from(vec![
                                         Span::styled("<< ", Style::default().fg(Color::Red)), // Different indicator
-                                        Span::styled(format!("{}", peer_display_name), Style::default().bold()),
+                                        Span::styled(peer_display_name.to_string(), Style::default().bold()),
                                         Span::raw(format!(
-                                            " declined file: '{}' ({}).",
+                                            " declined {} [{}]: '{}' ({}).",
+                                            offer_details.kind(),
+                                            offer_details.offer_id,
                                             offer_details.filename,
-                                            crate::utils::format_bytes(offer_details.size_bytes)
+                                            utils::format_bytes(offer_details.size_bytes)
                                         )),
                                     ]));
                                 }
@@ -780,11 +1211,13 @@ impl App {
                                     // Format the remotely accepted offer details
                                     all_lines.push(Line::from(vec![
                                         Span::styled(">> ", Style::default().fg(Color::Green)), // Use Green for accepted
-                                        Span::styled(format!("{}", peer_display_name), Style::default().bold()),
+                                        Span::styled(peer_display_name.to_string(), Style::default().bold()),
                                         Span::raw(format!(
-                                            " accepted file: '{}' ({}).",
+                                            " accepted {} [{}]: '{}' ({}).",
+                                            offer_details.kind(),
+                                            offer_details.offer_id,
                                             offer_details.filename,
-                                            crate::utils::format_bytes(offer_details.size_bytes)
+                                            utils::format_bytes(offer_details.size_bytes)
                                         )),
                                     ]));
                                 }
@@ -804,18 +1237,20 @@ impl App {
                                     // Format the remotely accepted offer details
                                     all_lines.push(Line::from(vec![
                                         Span::styled(">> ", Style::default().fg(Color::Green)), // Use Green for accepted
-                                        Span::styled(format!("{}", peer_display_name), Style::default().bold()),
+                                        Span::styled(peer_display_name.to_string(), Style::default().bold()),
                                         Span::raw(format!(
-                                            " accepted file: '{}' ({}).",
+                                            " accepted {} [{}]: '{}' ({}).",
+                                            offer_details.kind(),
+                                            offer_details.offer_id,
                                             offer_details.filename,
-                                            crate::utils::format_bytes(offer_details.size_bytes)
+                                            utils::format_bytes(offer_details.size_bytes)
                                         )),
                                     ]));
                                 }
                                 PrivateChatItem::TransferProgress { filename, received, total } => {
                                     all_lines.push(Line::from(vec![
                                         Span::styled(">> ", Style::default().fg(Color::Blue)),
-                                        Span::styled(format!("{}", filename), Style::default().bold()),
+                                        Span::styled(filename.to_string(), Style::default().bold()),
                                         Span::raw(format!(
                                             " in progress: {} / {} bytes",
                                             received,
@@ -823,27 +1258,209 @@ impl App {
                                         )),
                                     ]));
                                 }
-                                PrivateChatItem::TransferComplete { filename, final_path, size } => {
+                                PrivateChatItem::TransferComplete { filename, final_path, size, hash } => {
                                     all_lines.push(Line::from(vec![
                                         Span::styled(">> ", Style::default().fg(Color::Green)),
-                                        Span::styled(format!("{}", filename), Style::default().bold()),
+                                        Span::styled(filename.to_string(), Style::default().bold()),
                                         Span::raw(format!(
-                                            " completed: {} bytes, saved to {}",
+                                            " completed: {} bytes, saved to {} ",
                                             size,
                                             final_path.display()
                                         )),
+                                        // Files from peers on 1.0.0 come without a hash to check
+                                        if hash.is_empty() {
+                                            Span::styled("[not verified]", Style::default().fg(Color::Yellow))
+                                        } else {
+                                            Span::styled(
+                                                format!("[verified {}]", utils::short_hash(hash)),
+                                                Style::default().fg(Color::Green),
+                                            )
+                                        },
                                     ]));
                                 }
                                 PrivateChatItem::TransferFailed { filename, error } => {
                                     all_lines.push(Line::from(vec![
                                         Span::styled("<< ", Style::default().fg(Color::Red)),
-                                        Span::styled(format!("{}", filename), Style::default().bold()),
+                                        Span::styled(filename.to_string(), Style::default().bold()),
                                         Span::raw(format!(
                                             " failed: {}",
                                             error
                                         )),
                                     ]));
                                 }
+                                PrivateChatItem::TransferInterrupted { filename, received, total } => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<< ", Style::default().fg(Color::Yellow)),
+                                        Span::styled(filename.to_string(), Style::default().bold()),
+                                        Span::raw(format!(
+                                            " interrupted at {} / {}. It resumes when the peer reconnects, or use /resume.",
+                                            utils::format_bytes(*received),
+                                            utils::format_bytes(*total)
+                                        )),
+                                    ]));
+                                }
+                                PrivateChatItem::TransferCancelled { filename, direction, by_peer }
+                                | PrivateChatItem::TransferPaused { filename, direction, by_peer }
+                                | PrivateChatItem::TransferResumed { filename, direction, by_peer } => {
+                                    let who = if *by_peer {
+                                        self.peers.get(target_peer_id)
+                                            .and_then(|p| p.nickname.clone())
+                                            .unwrap_or_else(|| utils::peer_id_to_short_string(target_peer_id))
+                                    } else {
+                                        "You".to_string()
+                                    };
+                                    let (verb, color) = match item {
+                                        PrivateChatItem::TransferCancelled { .. } => ("cancelled", Color::Red),
+                                        PrivateChatItem::TransferPaused { .. } => ("paused", Color::Yellow),
+                                        _ => ("resumed", Color::Blue),
+                                    };
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("-- ", Style::default().fg(color)),
+                                        Span::styled(who, Style::default().bold()),
+                                        Span::raw(format!(" {} {} '{}'.", verb, transfer_activity(*direction, *by_peer), filename)),
+                                    ]));
+                                }
+                                PrivateChatItem::BundleProgress(status) => {
+                                    // Line 1: The whole bundle
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled(">> ", Style::default().fg(Color::Blue)),
+                                        Span::styled(status.name.to_string(), Style::default().bold()),
+                                        Span::raw(format!(
+                                            " in progress: {} / {} files, {} / {}",
+                                            status.files_done,
+                                            status.file_count,
+                                            utils::format_bytes(status.received),
+                                            utils::format_bytes(status.total)
+                                        )),
+                                    ]));
+                                    // One more line per file in flight
+                                    for (path, received, total) in &status.current {
+                                        let percent = if *total == 0 { 100 } else { received * 100 / total };
+                                        all_lines.push(Line::from(vec![
+                                            Span::raw("   "), // Indentation
+                                            Span::styled(
+                                                format!("{}: {}% of {}", path, percent, utils::format_bytes(*total)),
+                                                Style::default().italic(),
+                                            ),
+                                        ]));
+                                    }
+                                }
+                                PrivateChatItem::BundleComplete { status, path } => {
+                                    // Failed files are listed separately, so only point them out here
+                                    let (color, summary) = if status.files_failed == 0 {
+                                        (Color::Green, format!(
+                                            " completed: {} files ({}) saved under {} ",
+                                            status.files_done,
+                                            utils::format_bytes(status.received),
+                                            path.display()
+                                        ))
+                                    } else {
+                                        (Color::Yellow, format!(
+                                            " finished with {} of {} files missing; {} files ({}) saved under {} ",
+                                            status.files_failed,
+                                            status.file_count,
+                                            status.files_done,
+                                            utils::format_bytes(status.received),
+                                            path.display()
+                                        ))
+                                    };
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled(">> ", Style::default().fg(color)),
+                                        Span::styled(status.name.to_string(), Style::default().bold()),
+                                        Span::raw(summary),
+                                        Span::styled("[verified]", Style::default().fg(Color::Green)),
+                                    ]));
+                                }
+                                PrivateChatItem::SwapProposed(details) => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<> ", Style::default().fg(Color::Magenta)),
+                                        Span::styled("You", Style::default().bold()),
+                                        Span::raw(format!(
+                                            " proposed a swap: your '{}' for their '{}' [{}].",
+                                            details.give,
+                                            details.take,
+                                            swap::short_id(&details.swap_id)
+                                        )),
+                                    ]));
+                                }
+                                PrivateChatItem::SwapProposal(details) => {
+                                    let peer_display_name = self.peers.get(target_peer_id)
+                                        .and_then(|p| p.nickname.clone())
+                                        .unwrap_or_else(|| utils::peer_id_to_short_string(target_peer_id));
+                                    // Line 1: Proposal details
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<> ", Style::default().fg(Color::Magenta)),
+                                        Span::styled(peer_display_name, Style::default().bold()),
+                                        Span::raw(format!(
+                                            " proposes a swap: their '{}' ({}) for your '{}' [{}].",
+                                            details.take,
+                                            utils::format_bytes(details.take_size.unwrap_or(0)),
+                                            details.give,
+                                            swap::short_id(&details.swap_id)
+                                        )),
+                                    ]));
+                                    // Line 2: Prompt
+                                    all_lines.push(Line::from(vec![
+                                        Span::raw("   "), // Indentation
+                                        Span::styled(
+                                            format!("Use /swap accept <path to '{}'> or /swap decline.", details.give),
+                                            Style::default().fg(Color::Yellow).italic(),
+                                        ),
+                                    ]));
+                                }
+                                PrivateChatItem::SwapAgreed(details) => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<> ", Style::default().fg(Color::Green)),
+                                        Span::raw(format!(
+                                            "Swap agreed: '{}' for '{}'. Both transfers are running.",
+                                            details.give,
+                                            details.take
+                                        )),
+                                    ]));
+                                }
+                                PrivateChatItem::SwapWaiting(details) => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<> ", Style::default().fg(Color::Yellow)),
+                                        Span::styled(details.take.to_string(), Style::default().bold()),
+                                        Span::raw(format!(
+                                            " received and verified. Held until the peer has received '{}'.",
+                                            details.give
+                                        )),
+                                    ]));
+                                }
+                                PrivateChatItem::SwapCompleted(details) => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<> ", Style::default().fg(Color::Green)),
+                                        Span::raw(format!(
+                                            "Swap complete: gave '{}', got '{}'.",
+                                            details.give,
+                                            details.take
+                                        )),
+                                    ]));
+                                }
+                                PrivateChatItem::OfferRejected { filename, reason } => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("!! ", Style::default().fg(Color::Red)),
+                                        Span::raw(format!("Refused an offer of '{}': {}", filename, reason)),
+                                    ]));
+                                }
+                                PrivateChatItem::RemoteOfferRejected { filename, reason } => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<< ", Style::default().fg(Color::Red)),
+                                        Span::raw(format!("The peer refused your offer of '{}': {}", filename, reason)),
+                                    ]));
+                                }
+                                PrivateChatItem::SwapCancelled { details, reason } => {
+                                    all_lines.push(Line::from(vec![
+                                        Span::styled("<> ", Style::default().fg(Color::Red)),
+                                        Span::raw(format!(
+                                            "Swap of '{}' for '{}' called off: {}",
+                                            details.give,
+                                            details.take,
+                                            reason
+                                        )),
+                                    ]));
+                                }
                             }
                         }
                         messages = all_lines; // Assign the collected lines
@@ -884,6 +1501,9 @@ impl Widget for &App {
 
         // Call the rendering function for each pane.
         self.render_chat_pane(chat_area, buf);
+        if self.show_transfers {
+            self.render_transfers_pane(chat_area, buf);
+        }
         self.render_console_pane(console_area, buf);
         self.render_users_pane(users_area, buf);
 
@@ -893,90 +1513,6 @@ impl Widget for &App {
     }
 }
 
-/// Represents different events that can happen in the application,
-/// either triggered by user input or by network activity.
-/// These events drive the state changes in the main application loop.
-#[derive(Debug)]
-pub enum AppEvent {
-    /// A key was pressed by the user.
-    Input(event::KeyEvent),
-    /// An event occurred in the underlying libp2p network layer.
-    Swarm(SwarmEvent<SwapBytesBehaviourEvent>),
-    /// User wants to connect to a specific peer address (from command input).
-    Dial(Multiaddr),
-    /// A message needs to be displayed in the console log (often from network task).
-    LogMessage(String),
-    /// A new peer was found on the local network via mDNS.
-    PeerDiscovered(PeerId),
-    /// A peer previously found via mDNS hasn't been seen for a while and is considered gone.
-    PeerExpired(PeerId),
-    /// User wants to exit the application (e.g., pressed Ctrl+Q or typed /quit).
-    Quit,
-    /// User pressed Enter in the chat input box, submitting a message.
-    EnterChat(String),
-    /// User changed their nickname (needs to be broadcast to others).
-    NicknameUpdated(PeerId, String),
-    /// User changed their visibility status (online/offline).
-    VisibilityChanged(bool),
-    /// Received a chat message from the global topic.
-    GlobalMessageReceived {
-        sender_id: PeerId,
-        sender_nickname: Option<String>,
-        content: String,
-        timestamp_ms: u64,
-    },
-    /// UI requests the network task to publish a message to the global chat topic.
-    PublishGossipsub(Vec<u8>), // Raw bytes because Gossipsub deals with bytes
-    /// UI requests the network task to send a private message to a specific peer.
-    SendPrivateMessage { target_peer: PeerId, message: String },
-    /// UI requests the network task to send a file offer to a specific peer.
-    SendFileOffer { target_peer: PeerId, file_path: PathBuf }, // Send PathBuf for now
-    /// UI requests the network task to send a decline message for an offer.
-    DeclineFileOffer { target_peer: PeerId, filename: String },
-    /// UI requests the network task to send an accept message for an offer.
-    SendAcceptOffer { target_peer: PeerId, filename: String, size_bytes: u64 },
-    /// Received a private chat message directly from a peer.
-    PrivateMessageReceived { sender_id: PeerId, content: String },
-    /// Received a file offer directly from a peer.
-    FileOfferReceived {
-        sender_id: PeerId,
-        filename: String,
-        size_bytes: u64,
-    },
-    /// Received confirmation that a peer declined a file offer we sent.
-    FileOfferDeclined { peer_id: PeerId, filename: String },
-    /// Received confirmation that a peer accepted a file offer we sent.
-    FileOfferAccepted { peer_id: PeerId, filename: String },
-    /// Reports progress of an ongoing file download.
-    FileTransferProgress {
-        peer_id: PeerId,
-        filename: String,
-        received: u64, // Bytes received so far
-        total: u64,    // Total file size in bytes
-    },
-    /// Indicates a file transfer has completed successfully.
-    FileTransferComplete {
-        peer_id: PeerId,
-        filename: String,
-        path: PathBuf, // Final path where the file was saved
-        total_size: u64, // <<< Add total size
-    },
-    /// Indicates a file transfer has failed.
-    FileTransferFailed {
-        peer_id: PeerId,
-        filename: String,
-        error: String, // Reason for failure
-    },
-    /// UI informs swarm task of the current download directory.
-    DownloadDirChanged(Option<PathBuf>),
-    /// UI informs swarm task to register an outgoing file transfer.
-    RegisterOutgoingTransfer {
-        peer_id: PeerId,
-        filename: String,
-        path: PathBuf
-    },
-}
-
 // Helper function to divide the main terminal area into the three panes:
 // Chat (top-left), Console (bottom-left), Users (right).
 pub fn layout_chunks(area: Rect) -> (Rect, Rect, Rect) {
@@ -0,0 +1,173 @@
+/*
+Auto-accept rules: offers from trusted peers that are accepted as soon as they arrive,
+without waiting for the user.
+
+The swarm task checks every offer against `Config::auto_accept` with `find_rule` as it arrives, and
+again once the sender's nickname is known. An offer that matches is accepted into the rule's
+subdirectory and reported with `Event::OfferAutoAccepted` instead of `Event::FileOfferReceived`.
+*/
+
+use std::fmt;
+
+use libp2p::PeerId;
+
+use crate::{sanitize, utils};
+
+/// A rule incoming offers are matched against. Every condition that is set must hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AutoAcceptRule {
+    /// Only offers from this peer.
+    pub peer_id: Option<PeerId>,
+    /// Only offers from a peer going by this nickname. Anyone can pick any nickname,
+    /// so on its own this is weaker than `peer_id`.
+    pub nickname: Option<String>,
+    /// Only offers whose name matches this pattern (`*` for any run of characters, `?` for one).
+    /// For an offer of several files this is the name of the bundle.
+    pub filename: Option<String>,
+    /// Only offers of at most this many bytes (all files together for a bundle).
+    pub max_size: Option<u64>,
+    /// Subdirectory of the download directory to save accepted offers in, e.g. "builds/nightly".
+    pub subdir: Option<String>,
+}
+
+impl AutoAcceptRule {
+    /// Checks the rule before it is used, and normalises its subdirectory.
+    ///
+    /// A rule must name a peer or a nickname: one that matched every sender would accept files from anyone.
+    /// Anyone can claim a nickname, so a rule that names no peer must also cap the size.
+    pub fn validate(mut self) -> Result<Self, String> {
+        if self.peer_id.is_none() && self.nickname.is_none() {
+            return Err("a rule needs a peer or a nickname".to_string());
+        }
+        if self.peer_id.is_none() && self.max_size.is_none() {
+            return Err("a rule without a peer needs a max_size, since anyone can use a nickname".to_string());
+        }
+        if let Some(nickname) = &self.nickname {
+            self.nickname = Some(utils::verify_nickname(nickname).map_err(|e| format!("invalid nickname: {}", e))?);
+        }
+        if self.filename.as_deref().is_some_and(str::is_empty) {
+            return Err("the filename pattern is empty".to_string());
+        }
+        if let Some(subdir) = &self.subdir {
+            self.subdir = Some(sanitize::relative_path(subdir).map_err(|e| format!("invalid directory '{}': {}", subdir, e))?);
+        }
+        Ok(self)
+    }
+
+    /// Whether an offer of `filename` (`size_bytes` in total) from `sender`, currently going by
+    /// `nickname` (if known), matches the rule.
+    pub fn matches(&self, sender: &PeerId, nickname: Option<&str>, filename: &str, size_bytes: u64) -> bool {
+        self.peer_id.is_none_or(|peer_id| peer_id == *sender)
+            && self.nickname.as_deref().is_none_or(|wanted| nickname == Some(wanted))
+            && self.filename.as_deref().is_none_or(|pattern| glob_matches(pattern, filename))
+            && self.max_size.is_none_or(|max_size| size_bytes <= max_size)
+    }
+}
+
+/// Describes the rule for the chat history, e.g. "from alice, '*.iso', up to 4.00 GB, into isos".
+impl fmt::Display for AutoAcceptRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        match (&self.nickname, &self.peer_id) {
+            (Some(nickname), _) => parts.push(format!("from {}", nickname)),
+            (None, Some(peer_id)) => parts.push(format!("from {}", utils::peer_id_to_short_string(peer_id))),
+            (None, None) => {}
+        }
+        if let Some(pattern) = &self.filename {
+            parts.push(format!("'{}'", pattern));
+        }
+        if let Some(max_size) = self.max_size {
+            parts.push(format!("up to {}", utils::format_bytes(max_size)));
+        }
+        if let Some(subdir) = &self.subdir {
+            parts.push(format!("into {}", subdir));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Returns the first rule (in the order they were configured) that the offer matches.
+pub fn find_rule<'a>(rules: &'a [AutoAcceptRule], sender: &PeerId, nickname: Option<&str>, filename: &str, size_bytes: u64) -> Option<&'a AutoAcceptRule> {
+    rules.iter().find(|rule| rule.matches(sender, nickname, filename, size_bytes))
+}
+
+/// Matches a name against a pattern where `*` stands for any run of characters and `?` for one.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and the part of the name it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` take one more character and try again from there
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_matches("*.iso", "debian.iso"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("build-??.tar.gz", "build-42.tar.gz"));
+        assert!(glob_matches("*report*.pdf", "q3-report-final.pdf"));
+        assert!(!glob_matches("*.iso", "debian.iso.sig"));
+        assert!(!glob_matches("build-??.tar.gz", "build-4.tar.gz"));
+        assert!(!glob_matches("report.pdf", "Report.pdf"));
+    }
+
+    #[test]
+    fn every_condition_must_hold() {
+        let alice = PeerId::random();
+        let rule = AutoAcceptRule {
+            peer_id: Some(alice),
+            filename: Some("*.iso".to_string()),
+            max_size: Some(1000),
+            ..Default::default()
+        };
+        assert!(rule.matches(&alice, None, "debian.iso", 1000));
+        assert!(!rule.matches(&PeerId::random(), None, "debian.iso", 1000));
+        assert!(!rule.matches(&alice, None, "debian.img", 1000));
+        assert!(!rule.matches(&alice, None, "debian.iso", 1001));
+
+        // A nickname rule needs the sender's nickname to be known
+        let rule = AutoAcceptRule { nickname: Some("alice".to_string()), max_size: Some(1000), ..Default::default() };
+        assert!(rule.matches(&alice, Some("alice"), "notes.txt", 5));
+        assert!(!rule.matches(&alice, None, "notes.txt", 5));
+    }
+
+    #[test]
+    fn rules_must_name_a_sender_and_a_safe_directory() {
+        assert!(AutoAcceptRule { filename: Some("*".to_string()), ..Default::default() }.validate().is_err());
+        let rule = AutoAcceptRule { nickname: Some("alice".to_string()), max_size: Some(1000), subdir: Some("../elsewhere".to_string()), ..Default::default() };
+        assert!(rule.validate().is_err());
+        let rule = AutoAcceptRule { nickname: Some("alice".to_string()), max_size: Some(1000), subdir: Some("builds/nightly".to_string()), ..Default::default() };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn nickname_rules_must_cap_the_size() {
+        // Anyone could take the nickname and have any file accepted
+        let rule = AutoAcceptRule { nickname: Some("alice".to_string()), filename: Some("*.iso".to_string()), ..Default::default() };
+        assert!(rule.validate().is_err());
+        // Naming the peer makes the limit optional again
+        let rule = AutoAcceptRule { peer_id: Some(PeerId::random()), nickname: Some("alice".to_string()), ..Default::default() };
+        assert!(rule.validate().is_ok());
+    }
+}
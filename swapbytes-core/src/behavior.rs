@@ -2,11 +2,15 @@
 Sets up all the different protocols and behaviours for our P2P node.
 */
 
-use libp2p::{ping, swarm::NetworkBehaviour, gossipsub, mdns, identity::Keypair, request_response, rendezvous};
-use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}, time::Duration, iter};
+use libp2p::{ping, swarm::NetworkBehaviour, gossipsub, mdns, identify, identity::Keypair, request_response, rendezvous, relay, dcutr, autonat, kad, StreamProtocol};
+use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}, time::Duration};
 use tokio::io; // Needed for mapping errors
+use crate::constants;
 use crate::protocol::{PrivateCodec, PrivateRequest, PrivateResponse, PrivateProtocol};
 
+/// The protocol of the SwapBytes DHT.
+pub const DHT_PROTOCOL: StreamProtocol = StreamProtocol::new(constants::DHT_PROTOCOL_NAME);
+
 // --- Behaviour Struct Definition ---
 
 // `SwapBytesBehaviour` bundles multiple libp2p protocols into a single logical unit.
@@ -25,6 +29,19 @@ pub struct SwapBytesBehaviour {
     pub request_response: request_response::Behaviour<PrivateCodec>,
     /// Handles registration and discovery with a Rendezvous point.
     pub rendezvous: rendezvous::client::Behaviour,
+    /// Finds other nodes through a Kademlia DHT of SwapBytes nodes, with no server in the middle.
+    pub kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    /// Opens and accepts raw substreams, used to carry file chunks (see `transfer.rs`).
+    pub stream: libp2p_stream::Behaviour,
+    /// Exchanges listen addresses and the address each side sees the other at; hole punching needs them.
+    pub identify: identify::Behaviour,
+    /// Asks peers to dial us back on the addresses they see us at, to learn whether we are reachable
+    /// from outside, and answers the same question for them.
+    pub autonat: autonat::Behaviour,
+    /// Reserves a slot on relays (the Rendezvous points) so peers behind NATs can reach us, and dials through them.
+    pub relay_client: relay::client::Behaviour,
+    /// Upgrades relayed connections to direct ones by hole punching (Direct Connection Upgrade through Relay).
+    pub dcutr: dcutr::Behaviour,
 }
 
 // --- Behaviour Event Enum ---
@@ -39,6 +56,13 @@ pub enum SwapBytesBehaviourEvent {
     Ping(ping::Event),
     RequestResponse(request_response::Event<PrivateRequest, PrivateResponse>),
     Rendezvous(rendezvous::client::Event),
+    Kademlia(kad::Event),
+    /// The stream behaviour reports nothing; streams are handled through its `Control`.
+    Stream,
+    Identify(identify::Event),
+    Autonat(autonat::Event),
+    RelayClient(relay::client::Event),
+    Dcutr(dcutr::Event),
 }
 
 // --- Event Conversion Implementations (`From` traits) ---
@@ -77,15 +101,52 @@ impl From<rendezvous::client::Event> for SwapBytesBehaviourEvent {
     }
 }
 
+impl From<kad::Event> for SwapBytesBehaviourEvent {
+    fn from(event: kad::Event) -> Self {
+        SwapBytesBehaviourEvent::Kademlia(event)
+    }
+}
+
+impl From<identify::Event> for SwapBytesBehaviourEvent {
+    fn from(event: identify::Event) -> Self {
+        SwapBytesBehaviourEvent::Identify(event)
+    }
+}
+
+impl From<autonat::Event> for SwapBytesBehaviourEvent {
+    fn from(event: autonat::Event) -> Self {
+        SwapBytesBehaviourEvent::Autonat(event)
+    }
+}
+
+impl From<relay::client::Event> for SwapBytesBehaviourEvent {
+    fn from(event: relay::client::Event) -> Self {
+        SwapBytesBehaviourEvent::RelayClient(event)
+    }
+}
+
+impl From<dcutr::Event> for SwapBytesBehaviourEvent {
+    fn from(event: dcutr::Event) -> Self {
+        SwapBytesBehaviourEvent::Dcutr(event)
+    }
+}
+
+impl From<()> for SwapBytesBehaviourEvent {
+    fn from(_: ()) -> Self {
+        SwapBytesBehaviourEvent::Stream
+    }
+}
+
 // --- Behaviour Implementation ---
 
 impl SwapBytesBehaviour {
     /// Constructs a new `SwapBytesBehaviour` instance.
     /// Initializes and configures all the constituent protocol behaviours.
     ///
-    /// # Argument: `keypair` - The node's identity keypair, used for signing messages and identification.
-    /// 
-    pub fn new(keypair: &Keypair) -> Result<Self, io::Error> {
+    /// # Arguments
+    /// * `keypair` - The node's identity keypair, used for signing messages and identification.
+    /// * `relay_client` - The relay client half the swarm builder created alongside its relayed transport.
+    pub fn new(keypair: &Keypair, relay_client: relay::client::Behaviour) -> Result<Self, io::Error> {
         let local_peer_id = keypair.public().to_peer_id(); // Get PeerId early
 
         // --- Gossipsub Setup ---
@@ -103,14 +164,14 @@ impl SwapBytesBehaviour {
             .validation_mode(gossipsub::ValidationMode::Strict) // Enforce strict validation of incoming messages.
             .message_id_fn(message_id_fn) // Use the custom message ID function defined above.
             .build()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?; // Map the configuration error to `io::Error`.
+            .map_err(io::Error::other)?; // Map the configuration error to `io::Error`.
 
         // Create the Gossipsub behaviour instance.
         let gossipsub = gossipsub::Behaviour::new(
             gossipsub::MessageAuthenticity::Signed(keypair.clone()), // Ensure messages are signed with the node's keypair.
             gossipsub_config,
         )
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?; // Map the creation error to `io::Error`.
+        .map_err(io::Error::other)?; // Map the creation error to `io::Error`.
 
         // --- mDNS Setup ---
         // Create the mDNS behaviour for discovering peers on the local network.
@@ -130,8 +191,11 @@ impl SwapBytesBehaviour {
 
         // --- Request-Response Setup ---
         // Define the protocols supported by the request-response behaviour.
-        // Here, it uses the custom `PrivateProtocol`.
-        let request_response_protocols = iter::once((PrivateProtocol(), request_response::ProtocolSupport::Full));
+        // Here, it uses every version of the custom `PrivateProtocol`, preferred version first,
+        // so multistream negotiation picks the best encoding both peers understand.
+        let request_response_protocols = PrivateProtocol::ALL
+            .into_iter()
+            .map(|protocol| (protocol, request_response::ProtocolSupport::Full));
         // Create the Request-Response behaviour instance.
         let request_response = request_response::Behaviour::new(
             request_response_protocols, // Specify the supported protocols.
@@ -141,6 +205,42 @@ impl SwapBytesBehaviour {
         // --- Rendezvous Client Setup ---
         let rendezvous = rendezvous::client::Behaviour::new(keypair.clone());
 
+        // --- Kademlia Setup ---
+        // Every node announces itself as a provider of `DHT_PROVIDER_KEY` and looks up the others there.
+        // A node only answers DHT queries once it has a confirmed external address (see AutoNAT below);
+        // until then it still asks.
+        let kademlia = kad::Behaviour::with_config(
+            local_peer_id,
+            kad::store::MemoryStore::new(local_peer_id),
+            kad::Config::new(DHT_PROTOCOL),
+        );
+
+        // --- Stream Setup ---
+        // File chunks are sent as raw bytes over their own substreams instead of through the request-response codec.
+        let stream = libp2p_stream::Behaviour::new();
+
+        // --- Identify Setup ---
+        // Tells peers which addresses we listen on and learns the address they see us at,
+        // which becomes a candidate external address for AutoNAT to check and for hole punching.
+        let identify = identify::Behaviour::new(identify::Config::new(
+            constants::IDENTIFY_PROTOCOL_VERSION.to_string(),
+            keypair.public(),
+        ));
+
+        // --- AutoNAT Setup ---
+        // The addresses peers observe us at are only candidates: one becomes a confirmed external address
+        // (the kind Rendezvous registrations publish) once a peer has dialled us back on it.
+        // Probes start a little after startup, when there are peers (or Rendezvous points) to ask.
+        let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config {
+            boot_delay: constants::AUTONAT_BOOT_DELAY,
+            ..autonat::Config::default()
+        });
+
+        // --- Hole Punching Setup ---
+        // Once two peers are connected through a relay, DCUtR has both dial each other at the same time
+        // to open a direct connection through their NATs. If that fails, the relayed connection stays.
+        let dcutr = dcutr::Behaviour::new(local_peer_id);
+
         // --- Combine Behaviours ---
         // Construct the `SwapBytesBehaviour` struct with all initialized behaviours.
         Ok(Self {
@@ -149,6 +249,12 @@ impl SwapBytesBehaviour {
             ping,
             request_response,
             rendezvous,
+            kademlia,
+            stream,
+            identify,
+            autonat,
+            relay_client,
+            dcutr,
         })
     }
 }
@@ -0,0 +1,198 @@
+/*
+Offers of a directory or several files at once ("bundles").
+
+The sender walks the paths it was given and builds a manifest: one entry per file, with its path
+relative to the bundle root, its size and its BLAKE3 hash. Each file is then served under its
+relative path over the usual transfer protocol. The receiver rebuilds the tree under its download
+directory, fetching a few files at a time.
+*/
+
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::constants;
+use crate::protocol::BundleEntry;
+use crate::sanitize;
+
+/// A file we are about to offer as part of a bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleFile {
+    /// Path inside the bundle, with `/` separators (e.g. "project/src/main.rs"), normalised to NFC.
+    pub relative_path: String,
+    /// Where the file is on disk.
+    pub path: PathBuf,
+    pub size_bytes: u64,
+}
+
+/// Collects every regular file under `paths` (files are taken as they are, directories are walked).
+/// Symlinks are skipped so a link can't pull in files from elsewhere or loop forever.
+///
+/// Returns the bundle's display name and its files.
+pub fn collect_files(paths: &[PathBuf]) -> Result<(String, Vec<BundleFile>), String> {
+    let mut files = Vec::new();
+    let mut names = Vec::new();
+    for path in paths {
+        let canonical_path = path
+            .canonicalize()
+            .map_err(|e| format!("Cannot access '{}': {}", path.display(), e))?;
+        let name = canonical_path
+            .file_name()
+            .map(|name| sanitize::normalise(&name.to_string_lossy()))
+            .ok_or_else(|| format!("'{}' has no name to offer it under", canonical_path.display()))?;
+        collect_into(&canonical_path, &name, &mut files)?;
+        names.push(name);
+    }
+
+    if files.is_empty() {
+        return Err("There are no files to offer in the given paths.".to_string());
+    }
+    if files.len() > constants::MAX_BUNDLE_FILES {
+        return Err(format!("Too many files ({}); at most {} can be offered at once.", files.len(), constants::MAX_BUNDLE_FILES));
+    }
+    // Two paths with the same name would land on the same spot on the other side
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = files.iter().find(|file| !seen.insert(file.relative_path.as_str())) {
+        return Err(format!("'{}' is offered twice. Rename one of the paths.", duplicate.relative_path));
+    }
+
+    let name = match names.as_slice() {
+        [single] => single.clone(),
+        [first, rest @ ..] => format!("{} and {} more", first, rest.len()),
+        [] => unreachable!("files is not empty"),
+    };
+    Ok((name, files))
+}
+
+/// Adds `path` to `files` under `relative_path`, walking it if it is a directory.
+fn collect_into(path: &Path, relative_path: &str, files: &mut Vec<BundleFile>) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(path).map_err(|e| format!("Cannot access '{}': {}", path.display(), e))?;
+    if metadata.is_file() {
+        files.push(BundleFile { relative_path: relative_path.to_string(), path: path.to_path_buf(), size_bytes: metadata.len() });
+    } else if metadata.is_dir() {
+        let mut entries = fs::read_dir(path)
+            .map_err(|e| format!("Cannot read directory '{}': {}", path.display(), e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Cannot read directory '{}': {}", path.display(), e))?;
+        // A stable order makes the manifest (and the download order) predictable
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let child_name = sanitize::normalise(&entry.file_name().to_string_lossy());
+            collect_into(&entry.path(), &format!("{}/{}", relative_path, child_name), files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Turns the relative path of a received bundle entry into a path under `download_dir`.
+///
+/// Every part of the path goes through `sanitize::file_name`, so an entry can never point outside
+/// the download directory.
+pub fn entry_destination(download_dir: &Path, relative_path: &str) -> Result<PathBuf, String> {
+    let relative_path = sanitize::relative_path(relative_path)
+        .map_err(|e| format!("unsafe path in bundle '{}': {}", sanitize::display(relative_path), e))?;
+    let mut destination = download_dir.to_path_buf();
+    destination.extend(relative_path.split('/'));
+    Ok(destination)
+}
+
+/// Checks the manifest of a bundle we were offered: a sane number of files, safe and distinct
+/// paths, and a total size that adds up. Returns the total size.
+pub fn validate_manifest(files: &[BundleEntry]) -> Result<u64, String> {
+    if files.is_empty() {
+        return Err("the bundle is empty".to_string());
+    }
+    if files.len() > constants::MAX_BUNDLE_FILES {
+        return Err(format!("too many files ({}, at most {})", files.len(), constants::MAX_BUNDLE_FILES));
+    }
+    let mut seen = HashSet::new();
+    let mut total_size: u64 = 0;
+    for entry in files {
+        // Compare normalised paths: two spellings of the same name would land on the same file
+        let destination = entry_destination(Path::new(""), &entry.path)?;
+        if !seen.insert(destination) {
+            return Err(format!("'{}' is listed twice", sanitize::display(&entry.path)));
+        }
+        total_size = total_size
+            .checked_add(entry.size_bytes)
+            .ok_or_else(|| "the total size is too large".to_string())?;
+    }
+    Ok(total_size)
+}
+
+/// Tells the swarm loop's bundle queue that a bundle download needs another look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleUpdate {
+    /// The offer was just accepted; start the first files.
+    Accepted,
+    /// One of its files made progress.
+    Progress,
+    /// One of its files arrived and passed the integrity check.
+    FileDone { size_bytes: u64 },
+    /// One of its files could not be downloaded.
+    FileFailed,
+}
+
+/// A bundle being downloaded. Its files are fetched a few at a time (see `BUNDLE_PARALLEL_FILES`).
+#[derive(Debug)]
+pub struct BundleDownload {
+    /// Files not started yet.
+    pub pending: VecDeque<BundleEntry>,
+    /// Size in bytes of the chunks the sender serves the files in.
+    pub chunk_size: u64,
+    pub file_count: usize,
+    pub total_size: u64,
+    /// Files downloaded and verified so far, and their combined size.
+    pub files_done: usize,
+    pub bytes_done: u64,
+    /// Files that could not be downloaded.
+    pub files_failed: usize,
+    /// Subdirectory of the download directory the bundle is saved in, if it was accepted into one.
+    pub subdir: Option<String>,
+}
+
+impl BundleDownload {
+    /// Sets up the download of a bundle whose manifest passed `validate_manifest`.
+    pub fn new(files: Vec<BundleEntry>, total_size: u64, chunk_size: u64) -> Self {
+        BundleDownload {
+            file_count: files.len(),
+            total_size,
+            pending: files.into(),
+            chunk_size,
+            files_done: 0,
+            bytes_done: 0,
+            files_failed: 0,
+            subdir: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> BundleEntry {
+        BundleEntry { path: path.to_string(), size_bytes: 1, hash: String::new() }
+    }
+
+    #[test]
+    fn destinations_stay_in_the_download_directory() {
+        let dir = Path::new("/downloads");
+        assert_eq!(entry_destination(dir, "proj/src/main.rs"), Ok(PathBuf::from("/downloads/proj/src/main.rs")));
+        for path in ["../../.bashrc", "proj/../../etc/passwd", "/etc/passwd", "proj\\..\\..\\x", "proj//x", "C:/x"] {
+            assert!(entry_destination(dir, path).is_err(), "{:?} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn manifest_rejects_hostile_entries() {
+        assert_eq!(validate_manifest(&[entry("proj/a"), entry("proj/b")]), Ok(2));
+        assert!(validate_manifest(&[]).is_err());
+        assert!(validate_manifest(&[entry("proj/a"), entry("../b")]).is_err());
+        assert!(validate_manifest(&[entry("proj/a"), entry("proj/a")]).is_err());
+        // Two spellings of "é" are the same file once normalised
+        assert!(validate_manifest(&[entry("proj/caf\u{e9}"), entry("proj/cafe\u{301}")]).is_err());
+        let huge = BundleEntry { path: "proj/b".to_string(), size_bytes: u64::MAX, hash: String::new() };
+        assert!(validate_manifest(&[entry("proj/a"), huge]).is_err());
+    }
+}
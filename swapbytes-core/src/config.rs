@@ -0,0 +1,110 @@
+/*
+Settings of a SwapBytes node, however they were gathered (config file, command line or code).
+*/
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libp2p::{multiaddr::Protocol as MultiaddrProtocol, Multiaddr, PeerId};
+
+use crate::{autoaccept::AutoAcceptRule, constants, identity, ratelimit::RateLimits};
+
+/// A Rendezvous server to register with and discover peers from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RendezvousPoint {
+    pub peer_id: PeerId,
+    /// Address of the server, without the trailing `/p2p/<peer-id>`.
+    pub addr: Multiaddr,
+}
+
+/// A node to join the DHT through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtSeed {
+    pub peer_id: PeerId,
+    /// Address of the node, without the trailing `/p2p/<peer-id>`.
+    pub addr: Multiaddr,
+}
+
+/// The fully resolved node configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Addresses the swarm listens on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Rendezvous servers to register with.
+    pub rendezvous_points: Vec<RendezvousPoint>,
+    /// Nodes to join the DHT through, besides any peer we connect to.
+    pub dht_seeds: Vec<DhtSeed>,
+    /// Nickname to start with (a random one is generated if unset).
+    pub nickname: Option<String>,
+    /// Verified download directory, if one was configured.
+    pub download_dir: Option<PathBuf>,
+    /// Whether to broadcast heartbeats from the start.
+    pub visible: bool,
+    /// Interval between heartbeat broadcasts.
+    pub heartbeat_interval: Duration,
+    /// Time without a heartbeat before a peer is shown as offline.
+    pub peer_timeout: Duration,
+    /// Size of the chunks we serve files in.
+    pub chunk_size: usize,
+    /// Where the identity keypair is stored (`None` if there's no user data directory).
+    pub identity_path: Option<PathBuf>,
+    /// Bandwidth limits to start with (unlimited unless configured).
+    pub rate_limits: RateLimits,
+    /// Most uploads served at the same time.
+    pub max_uploads: usize,
+    /// Most downloads running at the same time.
+    pub max_downloads: usize,
+    /// Offers to accept without asking, checked in order (see `autoaccept.rs`).
+    pub auto_accept: Vec<AutoAcceptRule>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addrs: constants::DEFAULT_LISTEN_ADDRS.iter().map(|addr| addr.parse().expect("Default listen address is valid")).collect(),
+            rendezvous_points: default_rendezvous_points(),
+            dht_seeds: Vec::new(),
+            nickname: None,
+            download_dir: None,
+            visible: true,
+            heartbeat_interval: constants::DEFAULT_HEARTBEAT_INTERVAL,
+            peer_timeout: constants::DEFAULT_PEER_TIMEOUT,
+            chunk_size: constants::DEFAULT_CHUNK_SIZE,
+            identity_path: identity::default_identity_path(),
+            rate_limits: RateLimits::default(),
+            max_uploads: constants::DEFAULT_MAX_UPLOADS,
+            max_downloads: constants::DEFAULT_MAX_DOWNLOADS,
+            auto_accept: Vec::new(),
+        }
+    }
+}
+
+/// Parses a Rendezvous point given as a multiaddr ending in `/p2p/<peer-id>`.
+pub fn parse_rendezvous_point(addr_str: &str) -> Result<RendezvousPoint, String> {
+    let mut addr: Multiaddr = addr_str
+        .parse()
+        .map_err(|e| format!("Invalid rendezvous address '{}': {}", addr_str, e))?;
+    match addr.pop() {
+        Some(MultiaddrProtocol::P2p(peer_id)) => Ok(RendezvousPoint { peer_id, addr }),
+        _ => Err(format!("Rendezvous address '{}' must end with /p2p/<peer-id>.", addr_str)),
+    }
+}
+
+/// Parses a DHT seed given as a multiaddr ending in `/p2p/<peer-id>`.
+pub fn parse_dht_seed(addr_str: &str) -> Result<DhtSeed, String> {
+    let mut addr: Multiaddr = addr_str
+        .parse()
+        .map_err(|e| format!("Invalid DHT seed address '{}': {}", addr_str, e))?;
+    match addr.pop() {
+        Some(MultiaddrProtocol::P2p(peer_id)) => Ok(DhtSeed { peer_id, addr }),
+        _ => Err(format!("DHT seed address '{}' must end with /p2p/<peer-id>.", addr_str)),
+    }
+}
+
+/// The Rendezvous server used when none is configured.
+fn default_rendezvous_points() -> Vec<RendezvousPoint> {
+    match (constants::RENDEZVOUS_PEER_ID.parse(), constants::RENDEZVOUS_ADDR.parse()) {
+        (Ok(peer_id), Ok(addr)) => vec![RendezvousPoint { peer_id, addr }],
+        _ => Vec::new(),
+    }
+}
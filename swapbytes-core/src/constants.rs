@@ -0,0 +1,93 @@
+/*
+Constants shared by the networking, protocol and transfer code.
+*/
+
+use std::time::Duration;
+
+/// Public topic for global chat messages via Gossipsub.
+pub const SWAPBYTES_TOPIC: &str = "swapbytes-global-chat";
+/// Unique identifier for the private messaging and file transfer protocol (JSON encoding).
+pub const PROTOCOL_NAME: &[u8] = b"/swapbytes/private/1.0.0";
+/// Version 2 of the private protocol: the same messages, encoded as CBOR.
+pub const PROTOCOL_NAME_CBOR: &[u8] = b"/swapbytes/private/2.0.0";
+/// Identifier for the streaming protocol that carries raw file chunks.
+pub const TRANSFER_PROTOCOL_NAME: &str = "/swapbytes/transfer/1.0.0";
+/// Default interval for sending heartbeat messages (see `--heartbeat-interval`).
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+/// Default time before a peer is considered offline if no heartbeat is received (see `--peer-timeout`).
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(8);
+/// Default file transfer chunk size in bytes (see `--chunk-size`).
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024; // 64 KiB
+/// Smallest chunk size that can be configured.
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024; // 4 KiB
+/// Largest chunk size that can be configured.
+/// Out-of-order chunks are held in memory, so this also bounds that buffer (`MAX_CHUNK_WINDOW` chunks).
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024; // 256 KiB
+/// How long a single chunk request may take before the download is treated as interrupted.
+pub const CHUNK_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often opening a download's transfer stream is tried before the download is treated as interrupted.
+pub const TRANSFER_STREAM_ATTEMPTS: u32 = 3;
+/// Pause before retrying to open a transfer stream, multiplied by the number of attempts so far.
+pub const TRANSFER_STREAM_RETRY_DELAY: Duration = Duration::from_millis(250);
+/// How long a connection with no open streams is kept before it is closed.
+/// Transfer streams are short-lived, so this keeps the connection up between chunks and while an offer is pending.
+pub const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// Number of chunk requests kept in flight when a download starts.
+pub const INITIAL_CHUNK_WINDOW: usize = 4;
+/// Fewest chunk requests kept in flight, however slow the link looks.
+pub const MIN_CHUNK_WINDOW: usize = 1;
+/// Most chunk requests kept in flight. Also bounds how many out-of-order chunks are held in memory.
+pub const MAX_CHUNK_WINDOW: usize = 32;
+/// Most chunks of one download that may be waiting to be written to disk. No more are requested
+/// until the disk catches up, so a slow disk doesn't pile up chunks in memory.
+pub const MAX_UNWRITTEN_CHUNKS: usize = 64;
+/// Most files a single offer may hold. Keeps the offer's manifest well below the message size limit.
+pub const MAX_BUNDLE_FILES: usize = 4096;
+/// How many files of a bundle are downloaded at the same time.
+pub const BUNDLE_PARALLEL_FILES: usize = 4;
+/// Longest file name (in UTF-8 bytes) accepted from a peer. Leaves room below the usual 255-byte
+/// file system limit for the temporary suffix and the number added when a name is already taken.
+pub const MAX_FILENAME_BYTES: usize = 200;
+/// Most directory levels a path inside a bundle may have.
+pub const MAX_PATH_DEPTH: usize = 32;
+/// Default number of uploads served at the same time; further requests wait in line (see `--max-uploads`).
+pub const DEFAULT_MAX_UPLOADS: usize = 4;
+/// Default number of downloads running at the same time; further ones wait for a free slot (see `--max-downloads`).
+pub const DEFAULT_MAX_DOWNLOADS: usize = 8;
+/// How often a receiver waiting in the upload queue is reminded of its place, so it doesn't give up on a quiet stream.
+/// Must stay well below `CHUNK_REQUEST_TIMEOUT`.
+pub const QUEUE_NOTICE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often a file being served is compared against its offer (size and modification time) while chunks are read.
+pub const SERVED_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Default addresses to listen on: all interfaces, OS-assigned ports, over TCP and QUIC.
+pub const DEFAULT_LISTEN_ADDRS: [&str; 2] = ["/ip4/0.0.0.0/tcp/0", "/ip4/0.0.0.0/udp/0/quic-v1"];
+/// Number of bytes transferred before sending a progress update to the UI.
+pub const PROGRESS_UPDATE_BYTES: u64 = 512 * 1024; // 512 KiB 
+/// How long a transfer is shown as throttled after a bandwidth limit last held it back.
+pub const THROTTLE_INDICATOR_HOLD: Duration = Duration::from_secs(2);
+
+// --- Identify Configuration ---
+/// Protocol version announced to peers through identify.
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "/swapbytes/1.0.0";
+
+// --- AutoNAT Configuration ---
+/// How long after startup the first reachability probe is sent.
+pub const AUTONAT_BOOT_DELAY: Duration = Duration::from_secs(10);
+
+// --- DHT Configuration ---
+/// Protocol of the SwapBytes Kademlia DHT, kept apart from the public IPFS one.
+pub const DHT_PROTOCOL_NAME: &str = "/swapbytes/kad/1.0.0";
+/// Key every node announces itself as a provider of, so nodes find each other by looking it up.
+pub const DHT_PROVIDER_KEY: &str = "/swapbytes/nodes";
+/// Interval between DHT lookups for other nodes.
+pub const DHT_QUERY_INTERVAL: Duration = Duration::from_secs(30);
+
+// --- Rendezvous Configuration ---
+/// Rendezvous namespace we register in.
+pub const RENDEZVOUS_NS: &str = "swapbytes";
+/// Peer ID of the default Rendezvous server (used when none is configured).
+pub const RENDEZVOUS_PEER_ID: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+/// Multiaddress of the default Rendezvous server (used when none is configured).
+pub const RENDEZVOUS_ADDR: &str = "/ip4/192.168.1.216/tcp/62649";
+// LOCAL MACHINE: 127.0.0.1
+// LAPTOP ON HOME NETWORK: 192.168.1.216
@@ -0,0 +1,308 @@
+/*
+The typed API between a SwapBytes node and whatever drives it (the TUI, or your own tool).
+
+Commands go into the swarm task through one channel and events come out of another
+(see `run_swarm_loop`). Neither side shares any state with the other.
+Events can be serialized (e.g. to JSON) for callers that pass them on to other processes.
+*/
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libp2p::{Multiaddr, PeerId};
+use serde::Serialize;
+
+use crate::bundle::BundleFile;
+use crate::protocol::{SwapFile, TransferDirection};
+use crate::ratelimit::LimitScope;
+
+// --- Shared Types ---
+
+/// How far the download of a bundle (an offer of several files) has got.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BundleStatus {
+    pub name: String,
+    /// Bytes received so far, over all files.
+    pub received: u64,
+    pub total: u64,
+    pub file_count: usize,
+    /// Files downloaded and verified so far.
+    pub files_done: usize,
+    /// Files that could not be downloaded.
+    pub files_failed: usize,
+    /// Files being downloaded right now: (path inside the bundle, bytes received, size).
+    pub current: Vec<(String, u64, u64)>,
+}
+
+/// Where a transfer listed by `Command::ListTransfers` stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TransferState {
+    Running,
+    /// Paused with `/pause`.
+    Paused,
+    /// Paused by the peer.
+    PausedByPeer,
+    /// Cut off by a disconnect or restart; it resumes when the peer is back.
+    Interrupted,
+    /// Waiting for one of our upload or download slots, at this place in line.
+    Queued(u32),
+    /// Waiting in the sender's upload queue, at this place in line.
+    QueuedByPeer(u32),
+}
+
+/// A transfer in either direction, as listed by `Command::ListTransfers`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TransferSummary {
+    pub peer_id: PeerId,
+    pub filename: String,
+    pub direction: TransferDirection,
+    pub state: TransferState,
+    /// Bytes received so far. Only the receiving side knows this.
+    pub received: Option<u64>,
+    pub total: u64,
+}
+
+/// What `Command::ControlTransfer` asks the swarm task to do with a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferAction {
+    Cancel,
+    Pause,
+    Resume,
+}
+
+/// Describes a swap from the local user's point of view.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SwapDetails {
+    pub swap_id: String,
+    /// Name of the file we give.
+    pub give: String,
+    /// Name of the file we get.
+    pub take: String,
+    /// Size of the file we get, once the peer has committed to it.
+    pub take_size: Option<u64>,
+}
+
+/// Whether peers outside our network can dial us, as far as AutoNAT probes have found out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reachability {
+    /// Not probed yet, or the probes disagree.
+    #[default]
+    Unknown,
+    /// A peer dialled us back at this address.
+    Public(Multiaddr),
+    /// Peers could not dial us back: we are behind a NAT or firewall and only reachable through relays.
+    Private,
+}
+
+// --- Commands ---
+
+/// Asks the swarm task to do something on the network.
+#[derive(Debug)]
+pub enum Command {
+    /// Connect to a peer at this address.
+    Dial(Multiaddr),
+    /// Use this nickname in heartbeats and global chat messages from now on.
+    SetNickname(String),
+    /// Start (`true`) or stop broadcasting heartbeats.
+    SetVisibility(bool),
+    /// Publish a message to the global chat.
+    SendGlobalMessage(String),
+    /// Send a private chat message to a peer.
+    SendPrivateMessage { target_peer: PeerId, message: String },
+    /// Offer the file at `file_path` to a peer.
+    SendFileOffer { target_peer: PeerId, offer_id: String, file_path: PathBuf },
+    /// Offer several files at once under the name `name`.
+    SendBundleOffer { target_peer: PeerId, offer_id: String, name: String, files: Vec<BundleFile> },
+    /// Decline an offer a peer sent us, known by the ID from `Event::FileOfferReceived`.
+    DeclineFileOffer { target_peer: PeerId, offer_id: String, filename: String },
+    /// Accept an offer a peer sent us, known by the ID from `Event::FileOfferReceived`, and start
+    /// downloading it. Offers the node doesn't know of are refused.
+    /// With `subdir` set (a relative path, e.g. from an auto-accept rule), it is saved in that
+    /// subdirectory of the download directory, which is created if needed.
+    SendAcceptOffer { target_peer: PeerId, offer_id: String, filename: String, subdir: Option<String> },
+    /// Report every active, paused and interrupted transfer (answered with `Event::TransferList`).
+    ListTransfers,
+    /// Cancel, pause or resume the transfer of `filename`,
+    /// preferably with `peer_id` (e.g. the current private chat) if several peers match.
+    ControlTransfer { action: TransferAction, peer_id: Option<PeerId>, filename: String },
+    /// Set (or with `None`, lift) a bandwidth limit in bytes per second.
+    SetRateLimit { scope: LimitScope, direction: TransferDirection, limit: Option<u64> },
+    /// Propose a swap: the file at `file_path` for the peer's `wanted` file.
+    ProposeSwap { target_peer: PeerId, swap_id: String, file_path: PathBuf, wanted: String },
+    /// Accept a swap proposal, giving the file at `file_path`.
+    AcceptSwap { target_peer: PeerId, swap_id: String, file_path: PathBuf },
+    /// Decline a swap proposal.
+    DeclineSwap { target_peer: PeerId, swap_id: String },
+    /// Save downloads in this directory from now on (`None`: don't accept any).
+    SetDownloadDir(Option<PathBuf>),
+    /// Serve the file at `path` to a peer that accepted our offer of it.
+    RegisterOutgoingTransfer { peer_id: PeerId, filename: String, path: PathBuf },
+}
+
+// --- Events ---
+
+/// Something that happened on the network, reported by the swarm task.
+///
+/// Serialized, an event is tagged with its name in snake case and carries its fields in `data`,
+/// e.g. `{"event":"peer_connected","data":"12D3Koo..."}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    /// The node is listening on a new address.
+    ListeningOn(Multiaddr),
+    /// A peer told us (through identify) the address it sees us at. Each address is reported once.
+    ObservedAddress(Multiaddr),
+    /// An address was confirmed reachable from outside, by an AutoNAT probe or a relay reservation.
+    /// These are the addresses registered with Rendezvous points.
+    ExternalAddressConfirmed(Multiaddr),
+    /// A confirmed external address is no longer reachable.
+    ExternalAddressExpired(Multiaddr),
+    /// AutoNAT changed its mind about whether we are reachable.
+    ReachabilityChanged(Reachability),
+    /// A connection to a peer was opened.
+    PeerConnected(PeerId),
+    /// The last connection to a peer was closed.
+    PeerDisconnected(PeerId),
+    /// A peer was found on the local network via mDNS, through a Rendezvous point or in the DHT.
+    PeerDiscovered(PeerId),
+    /// A peer passed on a global message, so it's still around.
+    PeerSeen(PeerId),
+    /// A ping to or from a peer finished: the round-trip time, or why it failed.
+    PingResult { peer_id: PeerId, result: Result<Duration, String> },
+    /// A message worth showing to the user.
+    LogMessage(String),
+    /// A peer announced its nickname.
+    NicknameUpdated(PeerId, String),
+    /// Received a chat message from the global topic.
+    GlobalMessageReceived {
+        sender_id: PeerId,
+        sender_nickname: Option<String>,
+        content: String,
+        timestamp_ms: u64,
+    },
+    /// Received a private chat message directly from a peer.
+    PrivateMessageReceived { sender_id: PeerId, content: String },
+    /// Received a file offer directly from a peer.
+    FileOfferReceived {
+        sender_id: PeerId,
+        offer_id: String,
+        filename: String,
+        size_bytes: u64,
+        hash: String,
+        /// Set for offers of several files; `filename` is then the bundle's name.
+        file_count: Option<usize>,
+    },
+    /// An offer from a peer matched an auto-accept rule and is being accepted without asking. Sent in place
+    /// of `FileOfferReceived`, or after it once the peer's nickname makes a rule match.
+    OfferAutoAccepted {
+        sender_id: PeerId,
+        offer_id: String,
+        filename: String,
+        size_bytes: u64,
+        hash: String,
+        file_count: Option<usize>,
+        /// The rule that matched, described (see `AutoAcceptRule`'s `Display`).
+        rule: String,
+        /// The subdirectory of the download directory it is saved in.
+        subdir: Option<String>,
+    },
+    /// An offer or swap proposal from a peer was refused without asking the user (unsafe name, bad chunk size, ...).
+    OfferRejected { peer_id: PeerId, filename: String, reason: String },
+    /// Received confirmation that a peer declined a file offer we sent.
+    FileOfferDeclined { peer_id: PeerId, offer_id: String, filename: String },
+    /// Received confirmation that a peer accepted a file offer we sent.
+    FileOfferAccepted { peer_id: PeerId, offer_id: String, filename: String },
+    /// A peer refused a file offer we sent as it arrived, without asking its user (unsafe name, bad chunk size, ...).
+    /// Its files are no longer served.
+    FileOfferRejected { peer_id: PeerId, offer_id: String, filename: String, reason: String },
+    /// Reports progress of an ongoing file download.
+    FileTransferProgress {
+        peer_id: PeerId,
+        filename: String,
+        received: u64, // Bytes received so far
+        total: u64,    // Total file size in bytes
+        throttled: bool, // Whether a download limit is holding it back
+    },
+    /// Reports how far the receiver of one of our files has got (see `serve_transfer`).
+    UploadProgress {
+        peer_id: PeerId,
+        filename: String,
+        sent: u64,  // How far into the file the receiver has got
+        total: u64, // Total file size in bytes
+        throttled: bool, // Whether an upload limit is holding it back
+    },
+    /// The receiver of one of our files got all of it and closed the transfer stream.
+    UploadComplete { peer_id: PeerId, filename: String },
+    /// The stream serving one of our files broke before the receiver had all of it.
+    UploadInterrupted { peer_id: PeerId, filename: String },
+    /// One of our files couldn't be served (e.g. it changed since it was offered); the receiver has dropped the download.
+    UploadFailed { peer_id: PeerId, filename: String, error: String },
+    /// Indicates a file transfer has completed successfully and passed the integrity check.
+    FileTransferComplete {
+        peer_id: PeerId,
+        filename: String,
+        path: PathBuf, // Final path where the file was saved
+        total_size: u64,
+        hash: String, // Verified BLAKE3 hash of the file
+    },
+    /// Indicates a file transfer has failed.
+    FileTransferFailed {
+        peer_id: PeerId,
+        filename: String,
+        error: String, // Reason for failure
+    },
+    /// The answer to `Command::ListTransfers`.
+    TransferList(Vec<TransferSummary>),
+    /// A transfer was cancelled by us or the peer. `direction` is our side of it.
+    TransferCancelled { peer_id: PeerId, filename: String, direction: TransferDirection, by_peer: bool },
+    /// A transfer was paused by us or the peer. Downloads carry how far they got: (received, total).
+    TransferPaused { peer_id: PeerId, filename: String, direction: TransferDirection, by_peer: bool, progress: Option<(u64, u64)> },
+    /// A paused transfer was resumed by us or the peer. (Our own downloads report `DownloadResumed` instead.)
+    TransferResumed { peer_id: PeerId, filename: String, direction: TransferDirection, by_peer: bool },
+    /// A transfer is waiting in line, at `place` (1 is next). `by_peer` means the sender is busy with other uploads;
+    /// otherwise it waits for one of our upload or download slots. Carries (done, total) if known.
+    TransferQueued { peer_id: PeerId, filename: String, direction: TransferDirection, place: u32, by_peer: bool, progress: Option<(u64, u64)> },
+    /// Reports progress of a bundle download, for the whole bundle and the files in flight.
+    /// `throttled` tells whether a download limit is holding it back.
+    BundleProgress { peer_id: PeerId, status: BundleStatus, throttled: bool },
+    /// One file of a bundle arrived and passed the integrity check.
+    BundleFileComplete {
+        peer_id: PeerId,
+        name: String,
+        filename: String, // Path inside the bundle
+        path: PathBuf,
+        size: u64,
+    },
+    /// Every file of a bundle has either arrived or failed.
+    BundleComplete { peer_id: PeerId, status: BundleStatus, path: PathBuf },
+    /// A download stopped part-way (disconnect, failed request or restart) and was saved for resuming.
+    DownloadInterrupted {
+        peer_id: PeerId,
+        filename: String,
+        received: u64,
+        total: u64,
+    },
+    /// An interrupted download picked up where it left off.
+    DownloadResumed {
+        peer_id: PeerId,
+        filename: String,
+        received: u64,
+        total: u64,
+    },
+    /// Received a swap proposal from a peer.
+    SwapProposalReceived {
+        sender_id: PeerId,
+        swap_id: String,
+        offered: SwapFile,
+        wanted: String,
+    },
+    /// Both sides have committed to their file and the swap transfers have started.
+    SwapAgreed { peer_id: PeerId, details: SwapDetails },
+    /// Our side of a swap was verified and is held until the peer reports it has theirs.
+    SwapWaiting { peer_id: PeerId, details: SwapDetails },
+    /// Both sides of a swap arrived; ours has been released (see `FileTransferComplete`).
+    SwapCompleted { peer_id: PeerId, details: SwapDetails },
+    /// A swap was declined or called off, by either side.
+    SwapCancelled { peer_id: PeerId, details: SwapDetails, reason: String },
+}
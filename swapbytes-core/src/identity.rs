@@ -0,0 +1,284 @@
+/*
+Loads, saves and rotates the node's persistent identity keypair.
+*/
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use libp2p::{identity::Keypair, PeerId};
+
+/// Name of the data directory SwapBytes keeps its state in.
+const DATA_DIR_NAME: &str = "swapbytes";
+/// Name of the file holding the protobuf-encoded ed25519 keypair.
+const IDENTITY_FILE_NAME: &str = "identity.key";
+/// How many numbered names a backup of the identity file tries before giving up.
+const MAX_BACKUP_ATTEMPTS: u32 = 100;
+
+/// Returns the per-user directory SwapBytes stores its data in
+/// (e.g. `~/.local/share/swapbytes` on Linux).
+///
+/// Returns `None` if the platform has no notion of a user data directory.
+pub fn default_data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join(DATA_DIR_NAME))
+}
+
+/// Returns the default location of the identity key file.
+pub fn default_identity_path() -> Option<PathBuf> {
+    default_data_dir().map(|dir| dir.join(IDENTITY_FILE_NAME))
+}
+
+/// Loads the keypair stored at `path`, or generates and saves a new ed25519
+/// keypair if the file does not exist yet.
+///
+/// Returns the keypair and `true` if it was newly created,
+/// or `Err(String)` with a descriptive error message on failure.
+pub fn load_or_create_keypair(path: &Path) -> Result<(Keypair, bool), String> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let keypair = Keypair::from_protobuf_encoding(&bytes).map_err(|e| {
+                format!("Identity file '{}' is corrupt: {}", path.display(), e)
+            })?;
+            // Re-apply owner-only permissions in case they were loosened.
+            restrict_permissions(path).map_err(|e| {
+                format!("Failed to protect identity file '{}': {}", path.display(), e)
+            })?;
+            Ok((keypair, false))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate_ed25519();
+            write_keypair(&keypair, path, true)?;
+            Ok((keypair, true))
+        }
+        Err(e) => Err(format!("Failed to read identity file '{}': {}", path.display(), e)),
+    }
+}
+
+/// Copies the keypair stored at `identity_path` to `dest`.
+///
+/// The destination must not already exist and is created with owner-only permissions.
+pub fn export_keypair(identity_path: &Path, dest: &Path) -> Result<PathBuf, String> {
+    let bytes = fs::read(identity_path).map_err(|e| {
+        format!("Failed to read identity file '{}': {}", identity_path.display(), e)
+    })?;
+    // Make sure we only ever export a valid key.
+    Keypair::from_protobuf_encoding(&bytes).map_err(|e| {
+        format!("Identity file '{}' is corrupt: {}", identity_path.display(), e)
+    })?;
+    write_protected(dest, &bytes, false).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => format!("File already exists: '{}'", dest.display()),
+        _ => format!("Failed to write '{}': {}", dest.display(), e),
+    })?;
+    Ok(dest.to_path_buf())
+}
+
+/// Replaces the keypair stored at `identity_path` with a freshly generated one.
+///
+/// The old key file is kept next to it with a timestamped `.bak` suffix, numbered if
+/// an earlier backup already has that name (backups are never overwritten).
+/// Returns the new `PeerId` and the backup path on success.
+pub fn rotate_keypair(identity_path: &Path) -> Result<(PeerId, PathBuf), String> {
+    let bytes = fs::read(identity_path).map_err(|e| {
+        format!("Failed to read identity file '{}': {}", identity_path.display(), e)
+    })?;
+    let backup_path = write_backup(identity_path, &bytes)?;
+
+    let keypair = Keypair::generate_ed25519();
+    write_keypair(&keypair, identity_path, true)?;
+    Ok((keypair.public().to_peer_id(), backup_path))
+}
+
+/// Writes `bytes` to a new backup file next to `identity_path`, named after it.
+fn write_backup(identity_path: &Path, bytes: &[u8]) -> Result<PathBuf, String> {
+    let file_name = identity_path
+        .file_name()
+        .map_or_else(|| IDENTITY_FILE_NAME.into(), |name| name.to_string_lossy());
+    let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
+    let mut backup_path = identity_path.to_path_buf();
+    for n in 0..MAX_BACKUP_ATTEMPTS {
+        let suffix = if n == 0 { timestamp.clone() } else { format!("{}_{}", timestamp, n) };
+        backup_path.set_file_name(format!("{}.{}.bak", file_name, suffix));
+        match write_protected(&backup_path, bytes, false) {
+            Ok(()) => return Ok(backup_path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("Failed to back up identity file to '{}': {}", backup_path.display(), e)),
+        }
+    }
+    Err(format!("Failed to back up identity file: '{}' and the numbered names after it are taken", backup_path.display()))
+}
+
+/// Encodes `keypair` and writes it to `path` with owner-only permissions.
+fn write_keypair(keypair: &Keypair, path: &Path, overwrite: bool) -> Result<(), String> {
+    let bytes = keypair
+        .to_protobuf_encoding()
+        .map_err(|e| format!("Failed to encode keypair: {}", e))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| {
+            format!("Failed to create directory '{}': {}", parent.display(), e)
+        })?;
+    }
+    write_protected(path, &bytes, overwrite)
+        .map_err(|e| format!("Failed to write identity file '{}': {}", path.display(), e))
+}
+
+/// Writes `bytes` to `path`, creating the file with mode 0600 on Unix.
+///
+/// When `overwrite` is set the data is written to a temporary file first and
+/// renamed into place, so a crash never leaves a half-written key behind.
+fn write_protected(path: &Path, bytes: &[u8], overwrite: bool) -> io::Result<()> {
+    let target = if overwrite {
+        let mut tmp = path.to_path_buf();
+        tmp.set_extension("tmp");
+        let _ = fs::remove_file(&tmp);
+        tmp
+    } else {
+        path.to_path_buf()
+    };
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&target)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    if overwrite {
+        fs::rename(&target, path)?;
+    }
+    Ok(())
+}
+
+/// Makes sure only the owner can read or write the file at `path`.
+fn restrict_permissions(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut permissions = fs::metadata(path)?.permissions();
+        if permissions.mode() & 0o077 != 0 {
+            permissions.set_mode(0o600);
+            fs::set_permissions(path, permissions)?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty directory of its own for the test called `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("swapbytes-identity-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn created_identity_is_loaded_again() {
+        let dir = test_dir("reload");
+        let path = dir.join("nested").join(IDENTITY_FILE_NAME);
+
+        let (created, was_created) = load_or_create_keypair(&path).unwrap();
+        assert!(was_created);
+        let (loaded, was_created) = load_or_create_keypair(&path).unwrap();
+        assert!(!was_created);
+        assert_eq!(created.public().to_peer_id(), loaded.public().to_peer_id());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_identity_is_an_error_and_left_alone() {
+        let dir = test_dir("corrupt");
+        let path = dir.join(IDENTITY_FILE_NAME);
+        fs::write(&path, b"not a key").unwrap();
+
+        let error = load_or_create_keypair(&path).unwrap_err();
+        assert!(error.contains("corrupt"), "{}", error);
+        assert_eq!(fs::read(&path).unwrap(), b"not a key");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_is_only_readable_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir("mode");
+        let path = dir.join(IDENTITY_FILE_NAME);
+
+        load_or_create_keypair(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Loosened permissions are tightened again on the next load
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        load_or_create_keypair(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_never_overwrites() {
+        let dir = test_dir("export");
+        let path = dir.join(IDENTITY_FILE_NAME);
+        load_or_create_keypair(&path).unwrap();
+
+        let dest = dir.join("exported.key");
+        assert_eq!(export_keypair(&path, &dest).unwrap(), dest);
+        assert_eq!(fs::read(&dest).unwrap(), fs::read(&path).unwrap());
+
+        fs::write(&dest, b"something else").unwrap();
+        let error = export_keypair(&path, &dest).unwrap_err();
+        assert!(error.contains("already exists"), "{}", error);
+        assert_eq!(fs::read(&dest).unwrap(), b"something else");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_keeps_a_backup_of_the_old_key() {
+        let dir = test_dir("rotate");
+        let path = dir.join(IDENTITY_FILE_NAME);
+        let (old, _) = load_or_create_keypair(&path).unwrap();
+        let old_bytes = fs::read(&path).unwrap();
+
+        let (new_peer_id, backup_path) = rotate_keypair(&path).unwrap();
+        assert_ne!(new_peer_id, old.public().to_peer_id());
+        assert_eq!(fs::read(&backup_path).unwrap(), old_bytes);
+        let (loaded, _) = load_or_create_keypair(&path).unwrap();
+        assert_eq!(loaded.public().to_peer_id(), new_peer_id);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backups_are_named_after_the_key_file_and_never_replaced() {
+        let dir = test_dir("rotate-twice");
+        let path = dir.join("work.key");
+        load_or_create_keypair(&path).unwrap();
+
+        // Two rotations within the same second keep both old keys
+        let first_key = fs::read(&path).unwrap();
+        let (_, first_backup) = rotate_keypair(&path).unwrap();
+        let second_key = fs::read(&path).unwrap();
+        let (_, second_backup) = rotate_keypair(&path).unwrap();
+        assert_ne!(first_backup, second_backup);
+        for backup in [&first_backup, &second_backup] {
+            assert!(backup.file_name().unwrap().to_string_lossy().starts_with("work.key."), "{}", backup.display());
+        }
+        assert_eq!(fs::read(&first_backup).unwrap(), first_key);
+        assert_eq!(fs::read(&second_backup).unwrap(), second_key);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
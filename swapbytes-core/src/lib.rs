@@ -0,0 +1,56 @@
+/*
+SwapBytes core: the networking, protocol and transfer logic of SwapBytes,
+without any user interface.
+
+A node is driven through a typed API: `spawn` starts the swarm in a task of its own,
+`Command`s go in and `Event`s come out (see `event.rs`). The `swapbytes` terminal
+client is one consumer of it.
+
+Project Structure:
+
+ * lib.rs - Crate root, re-exports the parts most callers need.
+ * event.rs - The commands a node accepts and the events it reports.
+ * config.rs - Settings of a node: addresses, limits, download directory, ...
+ * swarm_task.rs - Builds the libp2p swarm and runs its event loop in a separate task.
+ * behavior.rs - Defines the combined libp2p network behaviors (Gossipsub, etc.).
+ * protocol.rs - Defines data structures for network communication or internal state.
+ * identity.rs - Loads and stores the node's persistent identity keypair.
+ * resume.rs - Saves unfinished downloads so they can be resumed after a disconnect or restart.
+ * transfer.rs - The chunk transfer stream protocol and download flow control.
+ * transfer_manager.rs - Does the disk work of downloads in a task of its own, off the swarm loop.
+ * swap.rs - State of two-sided swaps, held back until both sides have their file.
+ * bundle.rs - Offers of a directory or several files: manifests and the download queue.
+ * autoaccept.rs - Rules for accepting offers from trusted peers without asking.
+ * sanitize.rs - Checks file names and paths received from peers before they touch the disk.
+ * ratelimit.rs - Bandwidth limits for uploads and downloads (token buckets).
+ * scheduler.rs - Caps on concurrent uploads and downloads, and the lines waiting for a slot.
+ * constants.rs - Contains protocol and transfer constant values.
+ * utils.rs - Provides miscellaneous helper functions and utilities.
+
+*/
+
+// --- Modules ---
+pub mod event;
+pub mod config;
+pub mod behavior;
+pub mod protocol;
+pub mod identity;
+pub mod transfer;
+pub mod swap;
+pub mod bundle;
+pub mod autoaccept;
+pub mod sanitize;
+pub mod ratelimit;
+pub mod constants;
+pub mod utils;
+mod swarm_task;
+mod resume;
+mod transfer_manager;
+mod scheduler;
+
+// --- Re-exports ---
+pub use config::Config;
+pub use event::{Command, Event};
+pub use swarm_task::{build_swarm, run_swarm_loop, spawn, Node};
+// Callers name peers and addresses with the same libp2p types the node uses
+pub use libp2p;
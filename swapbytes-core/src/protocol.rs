@@ -0,0 +1,478 @@
+/*
+Sets up the protocol for private messages.
+*/
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
+use libp2p::core::UpgradeInfo;
+use libp2p::request_response::Codec;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{io, iter};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
+
+// --- Message Enum ---
+/// Represents messages exchanged over the network, primarily for presence and public chat.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum Message {
+    /// Periodic presence and nickname announcement sent over gossipsub.
+    Heartbeat {
+        timestamp_ms: u64,
+        nickname: Option<String>,
+    },
+    /// Public chat message sent to the global gossipsub topic.
+    GlobalChatMessage {
+        content: String,
+        timestamp_ms: u64,
+        nickname: Option<String>,
+    },
+}
+
+// --- Private Protocol Definition ---
+/// Defines the libp2p protocol structure for private, direct peer-to-peer interactions.
+/// Each version carries the same messages in a different encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivateProtocol {
+    /// "/swapbytes/private/1.0.0": JSON, spoken by every SwapBytes peer. A peer that only knows
+    /// 1.0.0 is from before the transfer protocol: files go to and from it in `RequestChunk` and
+    /// `FileChunk` messages, and it is only sent the messages it knows (see `PrivateRequest::known_to_1_0_0`).
+    Json,
+    /// "/swapbytes/private/2.0.0": CBOR, which is smaller and cheaper to parse.
+    Cbor,
+}
+
+impl PrivateProtocol {
+    /// Every supported version, most preferred first.
+    /// Outbound requests propose the versions in this order, so two up-to-date peers settle on CBOR
+    /// while peers that only know 1.0.0 still get JSON.
+    pub const ALL: [PrivateProtocol; 2] = [PrivateProtocol::Cbor, PrivateProtocol::Json];
+
+    /// Returns the protocol name advertised for this version.
+    fn name(&self) -> &'static str {
+        let name = match self {
+            PrivateProtocol::Json => crate::constants::PROTOCOL_NAME,
+            PrivateProtocol::Cbor => crate::constants::PROTOCOL_NAME_CBOR,
+        };
+        // Assumes the constants contain valid UTF-8.
+        std::str::from_utf8(name).unwrap()
+    }
+}
+
+/// Implements `UpgradeInfo` to advertise and negotiate the private protocol.
+/// Peers use this information to agree on a version of the "/swapbytes/private" protocol
+/// when establishing a direct request-response connection.
+impl UpgradeInfo for PrivateProtocol {
+    type Info = &'static str; // The protocol name is a static string.
+    type InfoIter = iter::Once<Self::Info>; // Each version advertises exactly one protocol name.
+
+    /// Returns the canonical name of this protocol version.
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(self.name())
+    }
+}
+
+/// Provides a simple way to get the protocol name as a string slice.
+/// This is needed internally by the `Codec`.
+impl AsRef<str> for PrivateProtocol {
+    fn as_ref(&self) -> &str {
+        self.name()
+    }
+}
+
+// --- Private Request/Response Types ---
+/// Defines the types of requests that can be sent over the `PrivateProtocol`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrivateRequest {
+    /// A simple text chat message sent directly to a peer.
+    ChatMessage(String),
+    /// Initiates a file transfer by offering a file to the peer.
+    Offer {
+        /// Picked by the sender so both sides can tell several pending offers apart.
+        /// Empty when sent by older versions.
+        #[serde(default)]
+        offer_id: String,
+        filename: String,
+        size_bytes: u64,
+        /// BLAKE3 hash of the file contents (hex), checked by the receiver once the download completes.
+        /// Empty when sent by versions from before the transfer protocol, whose files are fetched
+        /// with `RequestChunk` instead (and can't be checked).
+        #[serde(default)]
+        hash: String,
+        /// Size in bytes of the chunks the file will be served in, so the receiver can place
+        /// chunks that arrive out of order. 0 when sent by versions from before the transfer protocol.
+        #[serde(default)]
+        chunk_size: u64,
+    },
+    /// Offers several files at once: a directory, or a list of paths.
+    /// Accepted and declined like a single file, using `name` as the filename. Each file is then
+    /// fetched over the transfer protocol under its relative path.
+    OfferBundle {
+        offer_id: String,
+        /// What the bundle is called (usually the directory name).
+        name: String,
+        /// One entry per file, in the order they will be downloaded.
+        files: Vec<BundleEntry>,
+        /// Size in bytes of the chunks every file will be served in.
+        chunk_size: u64,
+    },
+    /// Informs the offering peer that the file offer is declined.
+    DeclineOffer {
+        filename: String,
+        /// The ID from the `Offer` (empty when sent by older versions).
+        #[serde(default)]
+        offer_id: String,
+    },
+    /// Informs the offering peer that the file offer is accepted.
+    /// The file itself is then fetched over the transfer protocol (see `transfer.rs`).
+    AcceptOffer {
+        filename: String,
+        /// The ID from the `Offer` (empty when sent by older versions).
+        #[serde(default)]
+        offer_id: String,
+    },
+    /// Proposes a swap: our file for one of the peer's, named by `wanted`.
+    /// Our side of the swap is fixed by the hash in `offered`.
+    ProposeSwap {
+        swap_id: String,
+        offered: SwapFile,
+        wanted: String,
+    },
+    /// Accepts a swap proposal, committing to the hash of the file that was asked for.
+    /// The proposer answers with `Ack` once it is ready to serve its own file.
+    AcceptSwap { swap_id: String, offered: SwapFile },
+    /// Declines a swap proposal, or calls off a swap that is already running.
+    CancelSwap { swap_id: String, reason: String },
+    /// Tells the peer that we have received and verified our side of the swap.
+    SwapReady { swap_id: String },
+    /// Stops a transfer of `filename` for good. The receiver stops serving the file, or deletes
+    /// its partial download.
+    CancelTransfer { filename: String, direction: TransferDirection },
+    /// Tells the peer we paused a transfer. A paused download is not picked up again until
+    /// `ResumeTransfer`, and a paused upload turns new transfer streams away.
+    PauseTransfer { filename: String, direction: TransferDirection },
+    /// Tells the peer we resumed a paused transfer.
+    ResumeTransfer { filename: String, direction: TransferDirection },
+    /// Asks for one chunk of a file, in chunks of `LEGACY_CHUNK_SIZE`. Only exchanged with peers on
+    /// 1.0.0, which have no transfer protocol; answered with `FileChunk`.
+    RequestChunk {
+        filename: String,
+        chunk_index: u64,
+    },
+}
+
+impl PrivateRequest {
+    /// Whether a peer that only speaks 1.0.0 understands the request. Anything else is refused
+    /// on 1.0.0 rather than sent to a peer that can't decode it.
+    pub fn known_to_1_0_0(&self) -> bool {
+        matches!(
+            self,
+            PrivateRequest::ChatMessage(_)
+                | PrivateRequest::Offer { .. }
+                | PrivateRequest::DeclineOffer { .. }
+                | PrivateRequest::AcceptOffer { .. }
+                | PrivateRequest::RequestChunk { .. }
+        )
+    }
+}
+
+/// Which way a file travels, as seen by the peer that sends the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TransferDirection {
+    /// The file is sent to the other peer.
+    Upload,
+    /// The file is fetched from the other peer.
+    Download,
+}
+
+impl TransferDirection {
+    /// The same transfer, seen from the other end.
+    pub fn reversed(self) -> Self {
+        match self {
+            TransferDirection::Upload => TransferDirection::Download,
+            TransferDirection::Download => TransferDirection::Upload,
+        }
+    }
+}
+
+/// One side of a swap: the file a peer puts in, pinned down by its hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwapFile {
+    pub filename: String,
+    pub size_bytes: u64,
+    /// BLAKE3 hash of the file contents (hex), checked by the receiver once the download completes.
+    pub hash: String,
+    /// Size in bytes of the chunks the file will be served in.
+    pub chunk_size: u64,
+}
+
+/// One file in an `OfferBundle` manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleEntry {
+    /// Path inside the bundle, with `/` separators (e.g. "photos/2024/beach.jpg").
+    pub path: String,
+    pub size_bytes: u64,
+    /// BLAKE3 hash of the file contents (hex), checked by the receiver once the file arrives.
+    pub hash: String,
+}
+
+/// Defines the types of responses that can be received over the `PrivateProtocol`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrivateResponse {
+    /// A generic acknowledgement that a request was received.
+    Ack,
+    /// Indicates an error occurred during the file transfer process.
+    TransferError {
+        filename: String,
+        /// A description of the error.
+        error: String,
+    },
+    /// Answers `SwapReady` when the responder has received its side of the swap too.
+    SwapReady { swap_id: String },
+    /// Answers `RequestChunk` with the chunk's bytes.
+    FileChunk {
+        filename: String,
+        chunk_index: u64,
+        data: Vec<u8>,
+        /// Whether this is the final chunk of the file.
+        is_last: bool,
+    },
+}
+
+// --- Private Message Codec ---
+/// Handles encoding (`PrivateRequest`/`PrivateResponse` to bytes) and
+/// decoding (bytes to `PrivateRequest`/`PrivateResponse`) for the `PrivateProtocol`.
+/// Uses length-delimited framing, with JSON or CBOR inside depending on the negotiated version.
+#[derive(Debug, Clone)]
+pub struct PrivateCodec {
+    /// The underlying codec that handles message framing.
+    /// It prepends each message with its length, allowing the receiver
+    /// to know how many bytes constitute a single, complete message.
+    inner: LengthDelimitedCodec,
+}
+
+impl Default for PrivateCodec {
+    fn default() -> Self {
+        Self {
+            // Configure the length-delimited codec.
+            // Set a maximum frame length (e.g., 2 MiB); messages are small now that
+            // file data travels over the transfer protocol, so this is a generous cap.
+            inner: LengthDelimitedCodec::builder()
+                .max_frame_length(2 * 1024 * 1024) // 2 MiB
+                .new_codec(),
+        }
+    }
+}
+
+#[async_trait]
+impl Codec for PrivateCodec {
+    type Protocol = PrivateProtocol; // Associates this codec with `PrivateProtocol`.
+    type Request = PrivateRequest; // Specifies the request type it handles.
+    type Response = PrivateResponse; // Specifies the response type it handles.
+
+    /// Reads bytes from the network stream and decodes them into a `PrivateRequest`.
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol, // The negotiated version decides the encoding.
+        io: &mut T,                // The asynchronous network stream.
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        // Adapt the tokio-based `io` stream for use with the futures-based `FramedRead`.
+        // `FramedRead` uses the `LengthDelimitedCodec` to read one complete message frame.
+        let mut framed = FramedRead::new(io.compat(), self.inner.clone());
+        let frame = framed
+            .next()
+            .await
+            // If `None` is returned, the stream closed unexpectedly.
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended"))?
+            // Propagate any I/O errors encountered while reading the frame.
+            ?;
+
+        // Deserialize the received bytes (JSON or CBOR) into a `PrivateRequest`.
+        decode(protocol, &frame)
+    }
+
+    /// Reads bytes from the network stream and decodes them into a `PrivateResponse`.
+    async fn read_response<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        // Logic mirrors `read_request`, but deserializes into `PrivateResponse`.
+        let mut framed = FramedRead::new(io.compat(), self.inner.clone());
+        let frame = framed
+            .next()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended"))??;
+
+        decode(protocol, &frame)
+    }
+
+    /// Encodes a `PrivateRequest` into bytes and writes it to the network stream.
+    async fn write_request<T>(
+        &mut self,
+        protocol: &Self::Protocol, // The negotiated version decides the encoding.
+        io: &mut T,                // The asynchronous network stream.
+        req: Self::Request,        // The request object to encode and send.
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        // A peer that only speaks 1.0.0 would fail to decode what came after it
+        if *protocol == PrivateProtocol::Json && !req.known_to_1_0_0() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, OLD_PEER));
+        }
+        // Serialize the `PrivateRequest` into JSON or CBOR bytes.
+        let bytes = encode(protocol, &req)?;
+        // Adapt the tokio-based `io` stream for use with the futures-based `FramedWrite`.
+        // `FramedWrite` uses the `LengthDelimitedCodec` to write the message frame (length-prefixed).
+        let mut framed = FramedWrite::new(io.compat_write(), self.inner.clone());
+        // Send the serialized bytes. The codec handles adding the length prefix.
+        framed.send(bytes::Bytes::from(bytes)).await
+    }
+
+    /// Encodes a `PrivateResponse` into bytes and writes it to the network stream.
+    async fn write_response<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response, // The response object to encode and send.
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        // Logic mirrors `write_request`, but serializes a `PrivateResponse`.
+        let bytes = encode(protocol, &res)?;
+        let mut framed = FramedWrite::new(io.compat_write(), self.inner.clone());
+        framed.send(bytes::Bytes::from(bytes)).await
+    }
+}
+
+/// Why a request (e.g. an offer of several files, or a swap) is not sent to a peer that only speaks 1.0.0.
+pub const OLD_PEER: &str = "the peer's version of SwapBytes is too old for this";
+/// Size of the chunks files are exchanged in with peers on 1.0.0, which have it built in.
+pub const LEGACY_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Serializes a message in the encoding of the given protocol version.
+/// Serialization errors are mapped to `io::Error` with `InvalidData` kind.
+fn encode<M: Serialize>(protocol: &PrivateProtocol, message: &M) -> io::Result<Vec<u8>> {
+    match protocol {
+        PrivateProtocol::Json => serde_json::to_vec(message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        PrivateProtocol::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(message, &mut bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            Ok(bytes)
+        }
+    }
+}
+
+/// Deserializes a message in the encoding of the given protocol version.
+/// Deserialization errors are mapped to `io::Error` with `InvalidData` kind.
+fn decode<M: DeserializeOwned>(protocol: &PrivateProtocol, bytes: &[u8]) -> io::Result<M> {
+    match protocol {
+        PrivateProtocol::Json => serde_json::from_slice(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        PrivateProtocol::Cbor => ciborium::from_reader(bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<M: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug>(message: M) {
+        for protocol in PrivateProtocol::ALL {
+            let bytes = encode(&protocol, &message).unwrap();
+            assert_eq!(decode::<M>(&protocol, &bytes).unwrap(), message, "{:?}", protocol);
+        }
+    }
+
+    #[test]
+    fn messages_survive_both_encodings() {
+        round_trip(PrivateRequest::ChatMessage("hi \u{1F600}".to_string()));
+        round_trip(PrivateRequest::Offer {
+            offer_id: "id".to_string(),
+            filename: "caf\u{e9}.txt".to_string(),
+            size_bytes: u64::MAX,
+            hash: "ab".repeat(32),
+            chunk_size: 256 * 1024,
+        });
+        round_trip(PrivateRequest::OfferBundle {
+            offer_id: "id".to_string(),
+            name: "photos".to_string(),
+            files: vec![BundleEntry { path: "2024/beach.jpg".to_string(), size_bytes: 0, hash: "cd".repeat(32) }],
+            chunk_size: 64 * 1024,
+        });
+        round_trip(PrivateRequest::PauseTransfer { filename: "a".to_string(), direction: TransferDirection::Download });
+        round_trip(PrivateResponse::Ack);
+        round_trip(PrivateResponse::TransferError { filename: "a".to_string(), error: "gone".to_string() });
+        round_trip(PrivateResponse::SwapReady { swap_id: "s".to_string() });
+    }
+
+    #[test]
+    fn old_json_messages_still_decode() {
+        // An offer as sent by versions from before offer IDs, hashes and chunk sizes
+        let offer: PrivateRequest = decode(&PrivateProtocol::Json, br#"{"Offer":{"filename":"a.txt","size_bytes":3}}"#).unwrap();
+        assert_eq!(offer, PrivateRequest::Offer {
+            offer_id: String::new(),
+            filename: "a.txt".to_string(),
+            size_bytes: 3,
+            hash: String::new(),
+            chunk_size: 0,
+        });
+        let accept: PrivateRequest = decode(&PrivateProtocol::Json, br#"{"AcceptOffer":{"filename":"a.txt"}}"#).unwrap();
+        assert_eq!(accept, PrivateRequest::AcceptOffer { filename: "a.txt".to_string(), offer_id: String::new() });
+    }
+
+    #[tokio::test]
+    async fn peers_on_1_0_0_are_only_sent_what_they_understand() {
+        let bundle = PrivateRequest::OfferBundle {
+            offer_id: "id".to_string(),
+            name: "photos".to_string(),
+            files: vec![BundleEntry { path: "beach.jpg".to_string(), size_bytes: 1, hash: "cd".repeat(32) }],
+            chunk_size: 64 * 1024,
+        };
+        let mut sent = futures::io::Cursor::new(Vec::new());
+        let error = PrivateCodec::default().write_request(&PrivateProtocol::Json, &mut sent, bundle.clone()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert!(sent.get_ref().is_empty());
+        // Single files and chat still reach them, and everything goes to peers on 2.0.0
+        let offer = PrivateRequest::Offer {
+            offer_id: "id".to_string(),
+            filename: "a.txt".to_string(),
+            size_bytes: 3,
+            hash: "ab".repeat(32),
+            chunk_size: 256 * 1024,
+        };
+        PrivateCodec::default().write_request(&PrivateProtocol::Json, &mut sent, offer).await.unwrap();
+        PrivateCodec::default().write_request(&PrivateProtocol::Json, &mut sent, PrivateRequest::ChatMessage("hi".to_string())).await.unwrap();
+        PrivateCodec::default().write_request(&PrivateProtocol::Cbor, &mut sent, bundle).await.unwrap();
+    }
+
+    #[test]
+    fn chunk_messages_keep_their_1_0_0_shape() {
+        let request: PrivateRequest = decode(&PrivateProtocol::Json, br#"{"RequestChunk":{"filename":"a.txt","chunk_index":2}}"#).unwrap();
+        assert_eq!(request, PrivateRequest::RequestChunk { filename: "a.txt".to_string(), chunk_index: 2 });
+        let chunk = PrivateResponse::FileChunk { filename: "a.txt".to_string(), chunk_index: 2, data: vec![1, 2], is_last: true };
+        assert_eq!(
+            encode(&PrivateProtocol::Json, &chunk).unwrap(),
+            br#"{"FileChunk":{"filename":"a.txt","chunk_index":2,"data":[1,2],"is_last":true}}"#
+        );
+    }
+
+    #[test]
+    fn garbage_is_invalid_data() {
+        for protocol in PrivateProtocol::ALL {
+            let error = decode::<PrivateRequest>(&protocol, b"\xff\x00not a message").unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+/*
+Bandwidth limits for chunk serving (uploads) and chunk requesting (downloads).
+
+Each direction has a token bucket shared by all transfers, and one bucket per peer. A chunk is let
+through straight away and its bytes are taken from both buckets; if that leaves a bucket in debt,
+the caller sleeps until the debt is paid off. Buckets hold at most one second's worth of bytes
+(and at least one chunk), so an idle link can't save up for a long burst.
+*/
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+
+use crate::constants::{MAX_CHUNK_SIZE, THROTTLE_INDICATOR_HOLD};
+use crate::protocol::TransferDirection;
+
+/// Which transfers a limit applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitScope {
+    /// All transfers in one direction together.
+    Total,
+    /// Each peer on its own, unless it has a limit of its own.
+    EachPeer,
+    /// One particular peer.
+    Peer(PeerId),
+}
+
+/// Bandwidth limits in bytes per second. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    pub peer_upload: Option<u64>,
+    pub peer_download: Option<u64>,
+    /// Limits for particular peers, in place of `peer_upload` / `peer_download`.
+    pub peers: HashMap<(PeerId, TransferDirection), Option<u64>>,
+}
+
+impl RateLimits {
+    /// The limit for all transfers in `direction` together.
+    pub fn total(&self, direction: TransferDirection) -> Option<u64> {
+        match direction {
+            TransferDirection::Upload => self.upload,
+            TransferDirection::Download => self.download,
+        }
+    }
+
+    /// The limit for transfers with `peer_id` in `direction`.
+    pub fn for_peer(&self, peer_id: PeerId, direction: TransferDirection) -> Option<u64> {
+        match self.peers.get(&(peer_id, direction)) {
+            Some(limit) => *limit,
+            None => match direction {
+                TransferDirection::Upload => self.peer_upload,
+                TransferDirection::Download => self.peer_download,
+            },
+        }
+    }
+
+    /// Sets (or with `None`, lifts) a limit.
+    pub fn set(&mut self, scope: LimitScope, direction: TransferDirection, limit: Option<u64>) {
+        match (scope, direction) {
+            (LimitScope::Total, TransferDirection::Upload) => self.upload = limit,
+            (LimitScope::Total, TransferDirection::Download) => self.download = limit,
+            (LimitScope::EachPeer, TransferDirection::Upload) => self.peer_upload = limit,
+            (LimitScope::EachPeer, TransferDirection::Download) => self.peer_download = limit,
+            (LimitScope::Peer(peer_id), direction) => {
+                self.peers.insert((peer_id, direction), limit);
+            }
+        }
+    }
+}
+
+/// A token bucket. Its rate is looked up on every use, so a new limit applies straight away.
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be sent without waiting. Negative when in debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Bucket { tokens: capacity(rate), last_refill: now }
+    }
+
+    /// Takes `bytes` from the bucket and returns how long to wait to stay within `rate`.
+    fn take(&mut self, rate: u64, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(capacity(rate));
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 || rate == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+}
+
+/// Most bytes a bucket holds: one second's worth, but at least one chunk so a chunk always fits.
+fn capacity(rate: u64) -> f64 {
+    rate.max(MAX_CHUNK_SIZE as u64) as f64
+}
+
+/// Applies `RateLimits` to chunks as they are served or received. Shared by the transfer tasks.
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    limits: RateLimits,
+    totals: HashMap<TransferDirection, Bucket>,
+    peers: HashMap<(PeerId, TransferDirection), Bucket>,
+    /// When transfers with a peer last had to wait.
+    throttled_at: HashMap<(PeerId, TransferDirection), Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            state: Mutex::new(LimiterState {
+                limits,
+                totals: HashMap::new(),
+                peers: HashMap::new(),
+                throttled_at: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Sets (or with `None`, lifts) a limit. Transfers under way pick it up with their next chunk.
+    pub fn set_limit(&self, scope: LimitScope, direction: TransferDirection, limit: Option<u64>) {
+        let mut state = self.state.lock().expect("rate limiter lock poisoned");
+        state.limits.set(scope, direction, limit);
+    }
+
+    /// Accounts for a chunk of `bytes` to or from `peer_id` and waits as long as the limits require.
+    pub async fn throttle(&self, peer_id: PeerId, direction: TransferDirection, bytes: u64) {
+        let wait = self.reserve(peer_id, direction, bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Whether transfers with `peer_id` in `direction` have been held back by a limit lately.
+    pub fn is_throttled(&self, peer_id: PeerId, direction: TransferDirection) -> bool {
+        let state = self.state.lock().expect("rate limiter lock poisoned");
+        state
+            .throttled_at
+            .get(&(peer_id, direction))
+            .is_some_and(|at| at.elapsed() < THROTTLE_INDICATOR_HOLD)
+    }
+
+    /// Takes `bytes` from the buckets that apply and returns how long to wait, for callers
+    /// that have to keep a stream alive meanwhile.
+    pub fn reserve(&self, peer_id: PeerId, direction: TransferDirection, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let mut guard = self.state.lock().expect("rate limiter lock poisoned");
+        let state = &mut *guard;
+        let mut wait = Duration::ZERO;
+        if let Some(rate) = state.limits.total(direction) {
+            let bucket = state.totals.entry(direction).or_insert_with(|| Bucket::new(rate, now));
+            wait = wait.max(bucket.take(rate, bytes, now));
+        }
+        if let Some(rate) = state.limits.for_peer(peer_id, direction) {
+            let bucket = state.peers.entry((peer_id, direction)).or_insert_with(|| Bucket::new(rate, now));
+            wait = wait.max(bucket.take(rate, bytes, now));
+        }
+        if !wait.is_zero() {
+            state.throttled_at.insert((peer_id, direction), now);
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 1024 * 1024;
+
+    #[test]
+    fn a_full_bucket_lets_one_second_through_at_once() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(RATE, start);
+        assert_eq!(bucket.take(RATE, RATE, start), Duration::ZERO);
+        // The next half second's worth has to wait for the bucket to refill
+        assert_eq!(bucket.take(RATE, RATE / 2, start), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(RATE, start);
+        bucket.take(RATE, RATE, start);
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(RATE, RATE / 4, later), Duration::ZERO);
+        assert_eq!(bucket.take(RATE, RATE / 4, later), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn idle_buckets_dont_save_up_a_longer_burst() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(RATE, start);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(RATE, RATE, later), Duration::ZERO);
+        assert_eq!(bucket.take(RATE, RATE, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_chunk_always_fits_in_a_slow_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(1024, start);
+        assert_eq!(bucket.take(1024, MAX_CHUNK_SIZE as u64, start), Duration::ZERO);
+        assert_eq!(bucket.take(1024, 1024, start), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn the_stricter_of_the_total_and_peer_limit_applies() {
+        let peer_id = PeerId::random();
+        let limits = RateLimits { download: Some(4 * RATE), peer_download: Some(RATE), ..Default::default() };
+        let limiter = RateLimiter::new(limits);
+        assert_eq!(limiter.reserve(peer_id, TransferDirection::Download, RATE), Duration::ZERO);
+        assert!(!limiter.is_throttled(peer_id, TransferDirection::Download));
+        let wait = limiter.reserve(peer_id, TransferDirection::Download, RATE);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+        assert!(limiter.is_throttled(peer_id, TransferDirection::Download));
+        // Uploads have no limit at all
+        assert_eq!(limiter.reserve(peer_id, TransferDirection::Upload, 100 * RATE), Duration::ZERO);
+    }
+}
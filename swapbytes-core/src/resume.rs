@@ -0,0 +1,157 @@
+/*
+Saves the state of unfinished downloads so they can be resumed after a disconnect or restart.
+*/
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Name of the file (inside the data directory) that unfinished downloads are saved to.
+const STORE_FILE_NAME: &str = "downloads.json";
+
+/// Everything needed to pick an unfinished download back up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeRecord {
+    /// The peer we were downloading from (base58 `PeerId`).
+    pub peer_id: String,
+    /// Name of the file as offered by the peer.
+    pub filename: String,
+    /// BLAKE3 hash (hex) the peer announced in its offer.
+    pub hash: String,
+    /// Total size of the file in bytes.
+    pub total_size: u64,
+    /// Bytes safely written to the temporary file.
+    pub received: u64,
+    /// Index of the first chunk not received yet.
+    pub next_chunk: u64,
+    /// Size in bytes of the chunks the sender serves the file in.
+    /// Records saved before this was stored load with 0 and fail [`ResumeRecord::check`].
+    #[serde(default)]
+    pub chunk_size: u64,
+    /// Where the partial download is stored.
+    pub temp_path: PathBuf,
+    /// The swap this download is one side of. Such downloads are kept in memory only.
+    #[serde(default)]
+    pub swap_id: Option<String>,
+    /// The bundle this file is part of. After a restart the file still resumes, but on its own:
+    /// the rest of the bundle is not picked up again.
+    #[serde(default)]
+    pub bundle: Option<String>,
+    /// Paused with `/pause`: not resumed on reconnect, only with `/resume`.
+    #[serde(default)]
+    pub paused: bool,
+    /// Paused by the sender: waits until they resume it.
+    #[serde(default)]
+    pub paused_by_peer: bool,
+}
+
+impl ResumeRecord {
+    /// Parses the stored peer ID. Returns `None` if the record is corrupt.
+    pub fn peer(&self) -> Option<PeerId> {
+        self.peer_id.parse().ok()
+    }
+
+    /// Checks that the record can be picked back up.
+    /// A chunk size outside the range any sender uses means the record is corrupt or from an older version.
+    pub fn check(&self) -> Result<(), String> {
+        crate::transfer::check_chunk_size(self.chunk_size)
+    }
+}
+
+/// Returns the default location of the download store.
+pub fn default_store_path() -> Option<PathBuf> {
+    crate::identity::default_data_dir().map(|dir| dir.join(STORE_FILE_NAME))
+}
+
+/// Loads all saved downloads. A missing store simply means there is nothing to resume.
+pub fn load_records(path: &Path) -> Result<Vec<ResumeRecord>, String> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Download store '{}' is corrupt: {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read download store '{}': {}", path.display(), e)),
+    }
+}
+
+/// Replaces the saved downloads with `records`.
+///
+/// Writes to a temporary file and renames it into place so a crash never leaves a truncated store.
+pub fn save_records(path: &Path, records: &[ResumeRecord]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory '{}': {}", parent.display(), e))?;
+    }
+    let bytes = serde_json::to_vec_pretty(records)
+        .map_err(|e| format!("Failed to encode download store: {}", e))?;
+    let mut tmp_path = path.to_path_buf();
+    tmp_path.set_extension("json.tmp");
+    fs::write(&tmp_path, bytes)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Failed to write download store '{}': {}", path.display(), e))
+}
+
+/// Prepares a partial download for resuming.
+///
+/// Cuts the temporary file back to the last saved position (dropping any bytes written after it)
+/// and re-hashes the data that is kept. Returns the file, still open for writing the rest,
+/// and the hasher, ready to continue with the next chunk.
+/// This reads the whole partial file, so it should be run on a blocking thread.
+pub fn prepare_partial_file(record: &ResumeRecord) -> io::Result<(fs::File, blake3::Hasher)> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(&record.temp_path)?;
+    let on_disk = file.metadata()?.len();
+    if on_disk < record.received {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("partial file holds {} bytes but {} were recorded", on_disk, record.received),
+        ));
+    }
+    file.set_len(record.received)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader((&mut file).take(record.received))?;
+    file.sync_all()?;
+    Ok((file, hasher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+    #[test]
+    fn records_with_an_invalid_chunk_size_fail_the_check() {
+        let path = std::env::temp_dir().join(format!("swapbytes-downloads-{}.json", std::process::id()));
+        let mut record = ResumeRecord {
+            peer_id: PeerId::random().to_string(),
+            filename: "notes.txt".to_string(),
+            hash: "hash".to_string(),
+            total_size: 100_000,
+            received: 0,
+            next_chunk: 0,
+            chunk_size: MIN_CHUNK_SIZE as u64,
+            temp_path: PathBuf::from("notes.txt.tmp"),
+            swap_id: None,
+            bundle: None,
+            paused: false,
+            paused_by_peer: false,
+        };
+        assert!(record.check().is_ok());
+        record.chunk_size = MAX_CHUNK_SIZE as u64 + 1;
+        assert!(record.check().is_err());
+
+        // A record saved without a chunk size still loads, but can't be resumed
+        record.chunk_size = 0;
+        let mut stored = serde_json::to_value(vec![&record]).unwrap();
+        stored[0].as_object_mut().unwrap().remove("chunk_size");
+        fs::write(&path, serde_json::to_vec(&stored).unwrap()).unwrap();
+        let loaded = load_records(&path).unwrap();
+        assert_eq!(loaded, vec![record]);
+        assert!(loaded[0].check().is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+/*
+Checks file names and paths received from peers before they are turned into local paths.
+
+A peer picks the names in its offers, so they are treated as hostile: anything that could point
+outside the download directory, hide what the file really is, or trip up the file system is
+rejected rather than repaired, and the reason is shown to the user. Accepted names are normalised
+to Unicode NFC so the same name always maps to the same file.
+*/
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::constants;
+
+/// Characters Windows does not allow in file names (besides separators and control characters).
+const RESERVED_CHARACTERS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+
+/// Invisible characters and text-direction overrides, which can make "exe.txt" look like
+/// "txt.exe" or two different names look the same.
+const DECEPTIVE_CHARACTERS: [char; 13] = [
+    '\u{061C}', // Arabic letter mark
+    '\u{200B}', '\u{200C}', '\u{200D}', '\u{200E}', '\u{200F}', // Zero-width characters and direction marks
+    '\u{202A}', '\u{202B}', '\u{202C}', '\u{202D}', '\u{202E}', // Embeddings and overrides
+    '\u{2066}', // Isolates start here (2066-2069 are checked as a range below)
+    '\u{FEFF}', // Zero-width no-break space / byte order mark
+];
+
+/// Device names Windows reserves, with or without an extension.
+const RESERVED_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+/// Normalises a name to NFC without checking it. Names we offer and serve are kept in this form,
+/// so a peer asking for "cafe\u{301}.txt" gets the file offered as "caf\u{e9}.txt".
+pub fn normalise(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Checks a single file name received from a peer and returns it normalised to NFC.
+///
+/// Rejects:
+/// 1. Empty names, "." and "..".
+/// 2. Path separators (`/` and `\`), so the name can't reach another directory.
+/// 3. Control characters, invisible characters and text-direction overrides.
+/// 4. Characters and device names Windows reserves (e.g. `:` or "CON.txt"), and trailing dots or spaces.
+/// 5. Names longer than `MAX_FILENAME_BYTES` once encoded as UTF-8.
+///
+/// Returns `Err(String)` describing the problem, suitable for showing to the user.
+pub fn file_name(name: &str) -> Result<String, String> {
+    let name = normalise(name);
+
+    if name.is_empty() {
+        return Err("the name is empty".to_string());
+    }
+    if name == "." || name == ".." {
+        return Err("the name refers to a directory".to_string());
+    }
+    if name.contains(['/', '\\']) {
+        return Err("the name contains a path separator".to_string());
+    }
+    if name.chars().any(char::is_control) {
+        return Err("the name contains control characters".to_string());
+    }
+    if name.chars().any(|c| DECEPTIVE_CHARACTERS.contains(&c) || ('\u{2066}'..='\u{2069}').contains(&c)) {
+        return Err("the name contains invisible or text-direction characters".to_string());
+    }
+    if let Some(c) = name.chars().find(|c| RESERVED_CHARACTERS.contains(c)) {
+        return Err(format!("the name contains the reserved character '{}'", c));
+    }
+    if name.ends_with(['.', ' ']) {
+        return Err("the name ends with a dot or a space".to_string());
+    }
+    // "con.txt" is as reserved as "CON"
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if is_reserved_name(stem) {
+        return Err(format!("'{}' is a reserved device name", stem));
+    }
+    if name.len() > constants::MAX_FILENAME_BYTES {
+        return Err(format!("the name is longer than {} bytes", constants::MAX_FILENAME_BYTES));
+    }
+    Ok(name)
+}
+
+/// Checks a relative path received from a peer (a file inside an offered directory, e.g.
+/// "photos/2024/beach.jpg") and returns it normalised to NFC.
+///
+/// The path must use `/` as separator, and each part must pass `file_name`. Absolute paths,
+/// empty parts ("a//b") and paths deeper than `MAX_PATH_DEPTH` are rejected.
+pub fn relative_path(path: &str) -> Result<String, String> {
+    if path.starts_with(['/', '\\']) {
+        return Err("the path is absolute".to_string());
+    }
+    let parts: Vec<&str> = path.split('/').collect();
+    if parts.len() > constants::MAX_PATH_DEPTH {
+        return Err(format!("the path is more than {} levels deep", constants::MAX_PATH_DEPTH));
+    }
+    let mut normalised = Vec::with_capacity(parts.len());
+    for part in parts {
+        let part = file_name(part).map_err(|e| format!("{} ('{}')", e, display(part)))?;
+        normalised.push(part);
+    }
+    Ok(normalised.join("/"))
+}
+
+/// Makes a name received from a peer safe to print: control and invisible characters are escaped
+/// and very long names are shortened.
+pub fn display(name: &str) -> String {
+    const MAX_DISPLAY_CHARS: usize = 80;
+    let escaped: String = name
+        .chars()
+        .flat_map(|c| {
+            let hidden = c.is_control() || DECEPTIVE_CHARACTERS.contains(&c) || ('\u{2066}'..='\u{2069}').contains(&c);
+            // `escape_default` spells out the code point, e.g. "\u{202e}"
+            let escaped: Vec<char> = if hidden { c.escape_default().collect() } else { vec![c] };
+            escaped
+        })
+        .collect();
+    if escaped.chars().count() > MAX_DISPLAY_CHARS {
+        format!("{}…", escaped.chars().take(MAX_DISPLAY_CHARS).collect::<String>())
+    } else {
+        escaped
+    }
+}
+
+/// Whether `stem` (a name without its extensions) is a device name Windows reserves.
+fn is_reserved_name(stem: &str) -> bool {
+    let upper = stem.to_ascii_uppercase();
+    if RESERVED_NAMES.contains(&upper.as_str()) {
+        return true;
+    }
+    // COM0-COM9 and LPT0-LPT9
+    match upper.strip_prefix("COM").or_else(|| upper.strip_prefix("LPT")) {
+        Some(digit) => digit.len() == 1 && digit.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_ordinary_names() {
+        assert_eq!(file_name("report.pdf"), Ok("report.pdf".to_string()));
+        assert_eq!(file_name(".bashrc"), Ok(".bashrc".to_string()));
+        assert_eq!(file_name("holiday photo (1).jpg"), Ok("holiday photo (1).jpg".to_string()));
+        assert_eq!(file_name("数据.csv"), Ok("数据.csv".to_string()));
+        assert_eq!(file_name("console.log"), Ok("console.log".to_string()));
+        assert_eq!(file_name("COM10"), Ok("COM10".to_string()));
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        for name in ["../../.bashrc", "..", ".", "/etc/passwd", "a/b", "..\\..\\evil.dll", "C:\\Windows\\win.ini", "dir\\file"] {
+            assert!(file_name(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn rejects_control_and_invisible_characters() {
+        for name in ["a\0b", "line\nbreak", "\u{1b}[31mred", "tab\there", "del\u{7f}", "evil\u{202E}gpj.exe", "zero\u{200B}width", "iso\u{2068}late", "\u{FEFF}bom"] {
+            assert!(file_name(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_names_and_characters() {
+        for name in ["CON", "con.txt", "Nul", "aux.tar.gz", "COM1", "lpt9.log", "a:b", "stream.txt:hidden", "what?", "star*", "pipe|", "quote\"", "<tag>", "trailing.", "trailing "] {
+            assert!(file_name(name).is_err(), "{:?} was accepted", name);
+        }
+        assert!(file_name("").is_err());
+    }
+
+    #[test]
+    fn caps_length() {
+        let longest = "a".repeat(constants::MAX_FILENAME_BYTES);
+        assert_eq!(file_name(&longest), Ok(longest.clone()));
+        assert!(file_name(&format!("{}a", longest)).is_err());
+        // The cap counts bytes, not characters
+        let wide = "é".repeat(constants::MAX_FILENAME_BYTES / 2 + 1);
+        assert!(file_name(&wide).is_err());
+    }
+
+    #[test]
+    fn normalises_unicode() {
+        // "e" followed by a combining acute accent becomes the single character "é"
+        assert_eq!(file_name("cafe\u{301}.txt"), Ok("caf\u{e9}.txt".to_string()));
+        assert_eq!(file_name("caf\u{e9}.txt"), file_name("cafe\u{301}.txt"));
+        assert_eq!(relative_path("re\u{301}sume\u{301}/cv.pdf"), Ok("r\u{e9}sum\u{e9}/cv.pdf".to_string()));
+    }
+
+    #[test]
+    fn checks_every_part_of_a_relative_path() {
+        assert_eq!(relative_path("photos/2024/beach.jpg"), Ok("photos/2024/beach.jpg".to_string()));
+        for path in ["", "/abs/path", "\\abs", "a/../b", "a/./b", "a//b", "a/", "../a", "a/b/..", "a\\..\\b", "a/CON/b", "a/\u{202E}txt.exe", "a/b\0/c"] {
+            assert!(relative_path(path).is_err(), "{:?} was accepted", path);
+        }
+        let deep = vec!["d"; constants::MAX_PATH_DEPTH + 1].join("/");
+        assert!(relative_path(&deep).is_err());
+        let deepest = vec!["d"; constants::MAX_PATH_DEPTH].join("/");
+        assert!(relative_path(&deepest).is_ok());
+    }
+
+    #[test]
+    fn display_escapes_hidden_characters() {
+        assert_eq!(display("plain.txt"), "plain.txt");
+        assert_eq!(display("a\nb"), "a\\nb");
+        assert_eq!(display("evil\u{202E}gpj.exe"), "evil\\u{202e}gpj.exe");
+        assert!(display(&"x".repeat(500)).chars().count() <= 81);
+    }
+}
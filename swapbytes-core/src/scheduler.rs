@@ -0,0 +1,239 @@
+/*
+Caps on how many transfers run at once.
+
+Uploads: only `max_uploads` transfer streams are served at a time. Further streams wait in line,
+and the line is served round-robin by peer, so one peer asking for many files can't keep everyone
+else waiting. Each waiting stream is told its place through a `watch` channel; the task holding the
+stream passes it on to the receiver (see `transfer::wait_for_turn`).
+
+Downloads: only `max_downloads` downloads hold an open file at a time. Accepted offers, resumes and
+bundles with files left to start wait in a first-come, first-served line until a download ends.
+*/
+
+use std::collections::{HashMap, VecDeque};
+
+use libp2p::PeerId;
+use tokio::sync::{mpsc, watch};
+
+// --- Uploads ---
+
+/// Held by the task serving an upload. Dropping it (when the task ends or is aborted)
+/// hands the upload's slot, or its place in line, back to the scheduler.
+#[derive(Debug)]
+pub struct UploadSlot {
+    id: u64,
+    done_tx: mpsc::UnboundedSender<u64>,
+}
+
+impl UploadSlot {
+    /// Identifies the upload to the scheduler, e.g. to look up its place in line.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        let _ = self.done_tx.send(self.id);
+    }
+}
+
+/// Decides which uploads are served and in which order the rest follow.
+#[derive(Debug)]
+pub struct UploadScheduler {
+    max_active: usize,
+    next_id: u64,
+    /// Uploads being served, and the peer each one is for.
+    active: HashMap<u64, PeerId>,
+    /// Peers with uploads waiting, in the order they get their next turn.
+    turn_order: VecDeque<PeerId>,
+    /// Waiting uploads of each peer, oldest first.
+    waiting: HashMap<PeerId, VecDeque<u64>>,
+    /// Tells each waiting upload its place in line (1 is next); 0 once it may start.
+    places: HashMap<u64, watch::Sender<u32>>,
+    /// Ended uploads report back here (see `UploadSlot`).
+    done_tx: mpsc::UnboundedSender<u64>,
+}
+
+impl UploadScheduler {
+    /// Creates a scheduler serving at most `max_active` uploads at once.
+    /// Uploads that end are sent to `done_tx`; pass them on to `finish`.
+    pub fn new(max_active: usize, done_tx: mpsc::UnboundedSender<u64>) -> Self {
+        UploadScheduler {
+            max_active: max_active.max(1),
+            next_id: 0,
+            active: HashMap::new(),
+            turn_order: VecDeque::new(),
+            waiting: HashMap::new(),
+            places: HashMap::new(),
+            done_tx,
+        }
+    }
+
+    /// Adds an upload for `peer_id`. Returns its slot (to be held until the upload ends) and its place in line,
+    /// which is 0 straight away if there's a free slot.
+    pub fn admit(&mut self, peer_id: PeerId) -> (UploadSlot, watch::Receiver<u32>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (place_tx, place_rx) = watch::channel(0);
+        if self.active.len() < self.max_active && self.turn_order.is_empty() {
+            self.active.insert(id, peer_id);
+        } else {
+            if !self.waiting.contains_key(&peer_id) {
+                self.turn_order.push_back(peer_id);
+            }
+            self.waiting.entry(peer_id).or_default().push_back(id);
+            self.places.insert(id, place_tx);
+            self.update_places();
+        }
+        (UploadSlot { id, done_tx: self.done_tx.clone() }, place_rx)
+    }
+
+    /// Forgets an upload that ended, whether it was served or still waiting, and lets the next one start.
+    pub fn finish(&mut self, id: u64) {
+        if self.active.remove(&id).is_none() {
+            self.places.remove(&id);
+            let Some(peer_id) = self.waiting.iter().find(|(_, ids)| ids.contains(&id)).map(|(peer_id, _)| *peer_id) else { return };
+            let ids = self.waiting.get_mut(&peer_id).expect("peer was just found");
+            ids.retain(|waiting_id| *waiting_id != id);
+            if ids.is_empty() {
+                self.waiting.remove(&peer_id);
+                self.turn_order.retain(|waiting_peer| *waiting_peer != peer_id);
+            }
+        }
+        while self.active.len() < self.max_active {
+            let Some(peer_id) = self.turn_order.pop_front() else { break };
+            let Some(ids) = self.waiting.get_mut(&peer_id) else { continue };
+            let Some(next) = ids.pop_front() else { continue };
+            if ids.is_empty() {
+                self.waiting.remove(&peer_id);
+            } else {
+                // The peer has more waiting: back of the line for its next turn
+                self.turn_order.push_back(peer_id);
+            }
+            if let Some(place_tx) = self.places.remove(&next) {
+                let _ = place_tx.send(0);
+            }
+            self.active.insert(next, peer_id);
+        }
+        self.update_places();
+    }
+
+    /// The place in line of the upload with this slot ID, if it is waiting.
+    pub fn place(&self, id: u64) -> Option<u32> {
+        self.places.get(&id).map(|place_tx| *place_tx.borrow())
+    }
+
+    /// Tells every waiting upload its current place. Turns go round the peers in `turn_order`,
+    /// one upload each, so the n-th round takes the n-th waiting upload of every peer that has one.
+    fn update_places(&mut self) {
+        let mut place = 0u32;
+        for round in 0.. {
+            let mut any = false;
+            for peer_id in &self.turn_order {
+                let Some(&id) = self.waiting.get(peer_id).and_then(|ids| ids.get(round)) else { continue };
+                any = true;
+                place += 1;
+                if let Some(place_tx) = self.places.get(&id) {
+                    place_tx.send_if_modified(|current| std::mem::replace(current, place) != place);
+                }
+            }
+            if !any {
+                break;
+            }
+        }
+    }
+}
+
+// --- Downloads ---
+
+/// A download waiting for one of the `max_downloads` slots.
+#[derive(Debug)]
+pub enum WaitingDownload {
+    /// An accepted offer of a single file. Nothing is opened until it starts.
+    File { peer_id: PeerId, filename: String, size_bytes: u64, chunk_size: u64, hash: String, subdir: Option<String> },
+    /// An interrupted download to resume. It stays with the interrupted downloads (and in the download store) until then.
+    Resume { peer_id: PeerId, filename: String },
+    /// A bundle with files still to start.
+    Bundle { peer_id: PeerId, name: String },
+}
+
+impl WaitingDownload {
+    pub fn peer_id(&self) -> PeerId {
+        match self {
+            WaitingDownload::File { peer_id, .. } | WaitingDownload::Resume { peer_id, .. } | WaitingDownload::Bundle { peer_id, .. } => *peer_id,
+        }
+    }
+
+    /// The file's name, or the bundle's.
+    pub fn name(&self) -> &str {
+        match self {
+            WaitingDownload::File { filename, .. } | WaitingDownload::Resume { filename, .. } => filename,
+            WaitingDownload::Bundle { name, .. } => name,
+        }
+    }
+
+    /// Whether this is the waiting download of `name` from `peer_id`.
+    pub fn is(&self, peer_id: PeerId, name: &str) -> bool {
+        self.peer_id() == peer_id && self.name() == name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(place: &watch::Receiver<u32>) -> bool {
+        *place.borrow() == 0
+    }
+
+    #[test]
+    fn uploads_take_turns_by_peer() {
+        let (done_tx, _done_rx) = mpsc::unbounded_channel();
+        let mut scheduler = UploadScheduler::new(1, done_tx);
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let (a1, a1_place) = scheduler.admit(alice);
+        assert!(started(&a1_place));
+        // Alice asks for two more files before Bob and Carol ask for one each
+        let (a2, a2_place) = scheduler.admit(alice);
+        let (a3, a3_place) = scheduler.admit(alice);
+        let (b1, b1_place) = scheduler.admit(bob);
+        let (c1, c1_place) = scheduler.admit(carol);
+        assert_eq!([a2.id(), b1.id(), c1.id(), a3.id()].map(|id| scheduler.place(id)), [Some(1), Some(2), Some(3), Some(4)]);
+
+        // Each peer gets one turn per round, whatever it asked for
+        scheduler.finish(a1.id());
+        assert!(started(&a2_place));
+        assert_eq!(scheduler.place(b1.id()), Some(1));
+        scheduler.finish(a2.id());
+        assert!(started(&b1_place));
+        scheduler.finish(b1.id());
+        assert!(started(&c1_place));
+        assert!(!started(&a3_place));
+        scheduler.finish(c1.id());
+        assert!(started(&a3_place));
+    }
+
+    #[test]
+    fn peers_whose_uploads_all_ended_lose_their_turn() {
+        let (done_tx, _done_rx) = mpsc::unbounded_channel();
+        let mut scheduler = UploadScheduler::new(1, done_tx);
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let (a1, _) = scheduler.admit(alice);
+        let (b1, _) = scheduler.admit(bob);
+        let (b2, _) = scheduler.admit(bob);
+        let (c1, c1_place) = scheduler.admit(carol);
+        assert_eq!(scheduler.place(c1.id()), Some(2));
+
+        // Bob gives up (e.g. disconnects) while still waiting
+        scheduler.finish(b1.id());
+        scheduler.finish(b2.id());
+        assert_eq!(scheduler.place(c1.id()), Some(1));
+        assert_eq!(scheduler.place(b1.id()), None);
+
+        scheduler.finish(a1.id());
+        assert!(started(&c1_place));
+    }
+}
@@ -0,0 +1,79 @@
+/*
+State of two-sided swaps: our file for one of the peer's, delivered together.
+
+Both files are pinned down by their hashes before any chunk moves: the proposer's hash travels
+with the proposal and the other side's with its acceptance. Each side downloads the other's file
+as usual, checks it against the committed hash, and then keeps it under its temporary name until
+the peer reports that it has verified its half too. Only then are both files released.
+*/
+
+use std::path::PathBuf;
+
+use libp2p::PeerId;
+
+use crate::protocol::SwapFile;
+use crate::transfer::OfferedFile;
+use crate::event::SwapDetails;
+
+/// Creates a new random swap ID. The proposer picks it and both sides use it from then on.
+pub fn new_swap_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// Shortens a swap ID for display.
+pub fn short_id(swap_id: &str) -> &str {
+    swap_id.get(..8).unwrap_or(swap_id)
+}
+
+/// A download that is complete and verified, but held back until the peer has its file too.
+#[derive(Debug)]
+pub struct HeldDownload {
+    /// The file as the transfer manager knows it.
+    pub file_id: u64,
+    /// The temporary file holding the download.
+    pub temp_path: PathBuf,
+    pub total_size: u64,
+    /// The verified BLAKE3 hash (hex).
+    pub hash: String,
+}
+
+/// One swap, as seen by the swarm task.
+#[derive(Debug)]
+pub struct Swap {
+    pub peer_id: PeerId,
+    /// Whether we proposed the swap (rather than were offered it).
+    pub proposed_by_us: bool,
+    /// Name of the file we give.
+    pub our_filename: String,
+    /// Where the file we give is stored. `None` until we've picked it when accepting.
+    pub our_path: Option<PathBuf>,
+    /// The file we give, once it has been hashed and committed to.
+    pub our_file: Option<SwapFile>,
+    /// Where our file is and what it looked like when it was hashed, to serve it from.
+    pub our_offered: Option<OfferedFile>,
+    /// Name of the file we get.
+    pub their_filename: String,
+    /// The file we get, once the peer has committed to it.
+    pub their_file: Option<SwapFile>,
+    /// Our side of the swap, once it has arrived and passed the integrity check.
+    pub held: Option<HeldDownload>,
+    /// Whether the peer has reported that it received and verified its side.
+    pub peer_ready: bool,
+}
+
+impl Swap {
+    /// Whether both sides have their file, so ours can be released.
+    pub fn can_release(&self) -> bool {
+        self.held.is_some() && self.peer_ready
+    }
+
+    /// Describes the swap for the UI.
+    pub fn details(&self, swap_id: &str) -> SwapDetails {
+        SwapDetails {
+            swap_id: swap_id.to_string(),
+            give: self.our_filename.clone(),
+            take: self.their_filename.clone(),
+            take_size: self.their_file.as_ref().map(|file| file.size_bytes),
+        }
+    }
+}
@@ -0,0 +1,3246 @@
+/*
+Builds the libp2p swarm and runs its event loop in a task of its own.
+*/
+
+// --- Imports ---
+use crate::event::{BundleStatus, Command, Event, Reachability, TransferAction, TransferState, TransferSummary};
+use crate::protocol::TransferDirection;
+use crate::ratelimit::RateLimiter;
+use crate::scheduler::{UploadScheduler, WaitingDownload};
+use crate::transfer::{DownloadState, OfferedFile, ReceivedOffer, ServeError};
+use crate::transfer_manager::{self, AfterSave, DiskEvent, TransferManager};
+use crate::{autoaccept::{self, AutoAcceptRule}, behavior::{self, SwapBytesBehaviour, SwapBytesBehaviourEvent}, protocol, constants, config::Config, resume::{self, ResumeRecord}, transfer, swap::{Swap, HeldDownload}, bundle::{self, BundleDownload, BundleUpdate}, sanitize, utils};
+use libp2p::{
+    gossipsub::{self, IdentTopic},
+    identity::Keypair,
+    core::{ConnectedPoint, Endpoint},
+    core::transport::ListenerId,
+    autonat,
+    dcutr,
+    identify,
+    kad,
+    mdns,
+    noise,
+    relay,
+    ping,
+    request_response::{self, Event as RequestResponseEvent, Message as RequestResponseMessage, OutboundRequestId, ResponseChannel},
+    swarm::{dial_opts::{DialOpts, PeerCondition}, ConnectionId, DialError, Stream, Swarm, SwarmEvent},
+    tcp,
+    yamux,
+    Multiaddr,
+    PeerId,
+    multiaddr::Protocol as MultiaddrProtocol,
+    rendezvous,
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio::time::{interval, Duration};
+use futures::prelude::*;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// --- Swarm Setup ---
+
+/// Builds the libp2p swarm (TCP with Noise and Yamux, QUIC, circuit relay, and the `SwapBytesBehaviour`)
+/// and starts listening on the configured addresses.
+pub fn build_swarm(local_key: Keypair, config: &Config) -> Result<Swarm<SwapBytesBehaviour>, Box<dyn Error>> {
+    // 1. Build the libp2p Swarm, configuring transports (TCP with Noise and Yamux, QUIC, and connections
+    //    through relays) and the custom behaviour. QUIC brings its own encryption and stream multiplexing,
+    //    and needs fewer round trips to connect.
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay_client| SwapBytesBehaviour::new(key, relay_client).map_err(Box::from))?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(constants::IDLE_CONNECTION_TIMEOUT))
+        .build();
+
+    // 2. Subscribe to the Gossipsub topic for global chat messages.
+    let topic = IdentTopic::new(constants::SWAPBYTES_TOPIC);
+    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+
+    // 3. Listen on the configured addresses (by default all interfaces, OS-assigned ports, TCP and QUIC).
+    for addr in &config.listen_addrs {
+        swarm.listen_on(addr.clone())?;
+    }
+    Ok(swarm)
+}
+
+/// Dials a discovered peer on all of its addresses at once, direct QUIC ones first and relayed ones last:
+/// whichever connects first is kept, and a relayed connection is upgraded by hole punching if it can be.
+fn dial_peer(swarm: &mut Swarm<SwapBytesBehaviour>, peer_id: PeerId, mut addrs: Vec<Multiaddr>) -> Result<(), DialError> {
+    // The peer ID is given separately; a `/p2p/...` suffix on an address must match it
+    addrs.retain(|addr| match addr.iter().last() {
+        Some(MultiaddrProtocol::P2p(suffix)) => suffix == peer_id,
+        _ => true,
+    });
+    addrs.sort_by_key(|addr| (utils::is_relayed(addr), !utils::is_quic(addr)));
+    let opts = DialOpts::peer_id(peer_id)
+        .addresses(addrs)
+        .condition(PeerCondition::DisconnectedAndNotDialing)
+        .build();
+    swarm.dial(opts)
+}
+
+/// Announces us as a provider of the DHT key (until other nodes store that) and looks up the other nodes there.
+/// Does nothing while the routing table is empty.
+fn query_dht(swarm: &mut Swarm<SwapBytesBehaviour>, key: &kad::RecordKey, providing: bool, provide_query: &mut Option<kad::QueryId>) {
+    let kademlia = &mut swarm.behaviour_mut().kademlia;
+    if kademlia.kbuckets().all(|bucket| bucket.num_entries() == 0) {
+        return;
+    }
+    if !providing && provide_query.is_none() {
+        *provide_query = kademlia.start_providing(key.clone()).ok();
+    }
+    kademlia.get_providers(key.clone());
+}
+
+/// A node started with `spawn`: commands go in through `commands`, events come out of `events`.
+#[derive(Debug)]
+pub struct Node {
+    pub local_peer_id: PeerId,
+    pub commands: mpsc::UnboundedSender<Command>,
+    pub events: mpsc::UnboundedReceiver<Event>,
+}
+
+/// Builds the swarm and runs its loop in a task of its own until `cancel` is triggered.
+/// Must be called from within a Tokio runtime.
+pub fn spawn(local_key: Keypair, config: Config, cancel: CancellationToken) -> Result<Node, Box<dyn Error>> {
+    let local_peer_id = PeerId::from(local_key.public());
+    let swarm = build_swarm(local_key, &config)?;
+    let (events_tx, events) = mpsc::unbounded_channel();
+    let (commands, commands_rx) = mpsc::unbounded_channel();
+    tokio::spawn(run_swarm_loop(swarm, events_tx, commands_rx, cancel, config));
+    Ok(Node { local_peer_id, commands, events })
+}
+
+// --- Main Swarm Task Function ---
+#[allow(clippy::too_many_lines)]
+/// Runs the swarm until `swarm_cancel` is triggered, taking `Command`s from `cmd_rx` and reporting `Event`s on `swarm_tx`.
+pub async fn run_swarm_loop(
+    mut swarm: Swarm<SwapBytesBehaviour>,
+    swarm_tx: mpsc::UnboundedSender<Event>, // Channel to send events back to the UI/main task
+    mut cmd_rx: mpsc::UnboundedReceiver<Command>, // Channel to receive commands from the UI/main task
+    swarm_cancel: CancellationToken, // Used for graceful shutdown
+    config: Config, // Settings of the node
+) {
+    // --- Local State ---
+    // The node gives itself commands too (accepting offers an auto-accept rule matches): they join the UI's
+    let (own_cmd_tx, mut cmd_rx) = {
+        let (cmd_tx, merged_rx) = mpsc::unbounded_channel();
+        let forward_tx = cmd_tx.clone();
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                if forward_tx.send(cmd).is_err() {
+                    break;
+                }
+            }
+        });
+        (cmd_tx, merged_rx)
+    };
+    let mut current_nickname = config.nickname.clone(); // User's current nickname for gossipsub messages
+    let mut is_visible = config.visible; // Whether the user is broadcasting presence
+    let mut download_dir: Option<PathBuf> = config.download_dir.clone(); // Directory for saving incoming files
+    let chunk_size = config.chunk_size; // Size of the chunks we serve files in
+    // Stores the local path of files being offered to peers. Key: (PeerId, filename)
+    let mut outgoing_transfers: HashMap<(PeerId, String), OfferedFile> = HashMap::new();
+    // Offers we sent that the peer hasn't accepted or declined yet. Key: (PeerId, offer ID), value: names its files are served under
+    let mut sent_offers: HashMap<(PeerId, String), Vec<String>> = HashMap::new();
+    // Offer requests the peer hasn't acknowledged yet. Value: (offer ID, offered name)
+    let mut offer_requests: HashMap<OutboundRequestId, (String, String)> = HashMap::new();
+    // Offers we received (of single files and bundles), until they are accepted or declined. Key: (PeerId, offer ID)
+    let mut received_offers: HashMap<(PeerId, String), ReceivedOffer> = HashMap::new();
+    // Offers these rules match are accepted without asking (see `autoaccept.rs`)
+    let auto_accept_rules = config.auto_accept.clone();
+    // The nicknames peers announce in their heartbeats, which auto-accept rules may name
+    let mut peer_nicknames: HashMap<PeerId, String> = HashMap::new();
+    // Stores the state of incoming file transfers. Key: PeerId -> (filename -> DownloadState)
+    let mut incoming_transfers_state: HashMap<PeerId, HashMap<String, DownloadState>> = HashMap::new();
+    let mut heartbeat_timer = interval(config.heartbeat_interval); // Timer for periodic heartbeat broadcasts
+    let topic = IdentTopic::new(constants::SWAPBYTES_TOPIC); // Gossipsub topic for general communication
+    // Offers whose file is still being hashed on a blocking thread report back here: (peer, offer ID, filename, size, path, hash result)
+    let (offer_hashed_tx, mut offer_hashed_rx) = mpsc::unbounded_channel::<(PeerId, String, String, PathBuf, std::io::Result<(String, OfferedFile)>)>();
+
+    // --- Bundle State ---
+    // Accepted bundles that still have files to download. Key: (PeerId, bundle name)
+    let mut bundle_downloads: HashMap<(PeerId, String), BundleDownload> = HashMap::new();
+    // Bundles we offer report back here once every file has been hashed: (peer, offer ID, name, files, hash results)
+    let (bundle_hashed_tx, mut bundle_hashed_rx) = mpsc::unbounded_channel::<(PeerId, String, String, Vec<bundle::BundleFile>, std::io::Result<Vec<(String, OfferedFile)>>)>();
+    // Bundle downloads whose files started, progressed or finished: (peer, bundle name, what happened)
+    let (bundle_queue_tx, mut bundle_queue_rx) = mpsc::unbounded_channel::<(PeerId, String, BundleUpdate)>();
+
+    // --- Transfer Streams ---
+    // File chunks travel on a substream per download (see `transfer.rs`)
+    let mut stream_control = swarm.behaviour().stream.new_control();
+    let mut incoming_transfer_streams = stream_control
+        .accept(transfer::TRANSFER_PROTOCOL)
+        .expect("The transfer protocol is only registered once");
+    // Fetched chunks (and notices that the sender has put us in line) report back here
+    let (fetch_event_tx, mut fetch_event_rx) = mpsc::unbounded_channel::<transfer::FetchEvent>();
+    // Bandwidth limits, shared with the tasks that serve and fetch chunks (changed with /limit)
+    let rate_limiter = Arc::new(RateLimiter::new(config.rate_limits.clone()));
+    // Peers on 1.0.0 of the private protocol have no transfer protocol: their files are fetched chunk by chunk
+    // with `RequestChunk` messages, which are sent from here (see "Chunk Requests" below)
+    let (chunk_request_tx, mut chunk_request_rx) = mpsc::unbounded_channel::<transfer::ChunkRequest>();
+    let mut chunk_fetcher = transfer::ChunkFetcher::new(stream_control, fetch_event_tx, chunk_request_tx, rate_limiter.clone());
+    // `RequestChunk` messages waiting for their `FileChunk`
+    let mut chunk_requests: HashMap<OutboundRequestId, transfer::ChunkRequest> = HashMap::new();
+    // Chunks read for peers on 1.0.0, ready to be sent: (peer, filename, chunk index, where to answer, chunk and whether it is the last)
+    let (chunk_read_tx, mut chunk_read_rx) = mpsc::unbounded_channel::<(PeerId, String, u64, ResponseChannel<protocol::PrivateResponse>, Result<(Vec<u8>, bool), String>)>();
+    // Incoming transfer streams, once the requested filename has been read: (peer, filename, stream)
+    let (transfer_request_tx, mut transfer_request_rx) = mpsc::unbounded_channel::<(PeerId, String, Stream)>();
+    // Uploads being served or paused. Key: (PeerId, filename)
+    let mut uploads: HashMap<(PeerId, String), transfer::Upload> = HashMap::new();
+    // Caps how many uploads are served at once and keeps the rest waiting in line (see `scheduler.rs`).
+    // Upload tasks that end hand back their slot here.
+    let (upload_done_tx, mut upload_done_rx) = mpsc::unbounded_channel::<u64>();
+    let mut upload_scheduler = UploadScheduler::new(config.max_uploads, upload_done_tx);
+    // Uploads the receiver got all of: (peer, filename)
+    let (upload_complete_tx, mut upload_complete_rx) = mpsc::unbounded_channel::<(PeerId, String)>();
+
+    // --- Transfer Manager ---
+    // Creating, writing, closing and renaming download files happens in a task of its own,
+    // so a slow disk never holds up the loop. Its results report back here (see `transfer_manager.rs`).
+    let (disk_event_tx, mut disk_event_rx) = mpsc::unbounded_channel::<DiskEvent>();
+    let mut transfer_manager = TransferManager::new(disk_event_tx, swarm_tx.clone());
+
+    // --- Resume State ---
+    // Where unfinished downloads are saved so they survive disconnects and restarts
+    let download_store_path = resume::default_store_path();
+    // Downloads waiting to be resumed. Key: PeerId -> (filename -> ResumeRecord)
+    let mut interrupted_downloads: HashMap<PeerId, HashMap<String, ResumeRecord>> = HashMap::new();
+
+    // --- Download Slots ---
+    // At most `max_downloads` downloads hold an open file; the rest wait here for a slot, first come first served
+    let max_downloads = config.max_downloads;
+    let mut waiting_downloads: VecDeque<WaitingDownload> = VecDeque::new();
+    // Resumes whose partial file is being re-hashed. They hold a slot already.
+    let mut resumes_preparing: usize = 0;
+
+    // Load downloads left unfinished by a previous session
+    if let Some(store_path) = &download_store_path {
+        match resume::load_records(store_path) {
+            Ok(records) => {
+                for record in records {
+                    let Some(peer_id) = record.peer() else { continue };
+                    // Dropped from the store with its next save; the partial file is left where it is
+                    if let Err(e) = record.check() {
+                        let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Can't resume '{}': {}", record.filename, e)));
+                        continue;
+                    }
+                    let event = if record.paused || record.paused_by_peer {
+                        Event::TransferPaused {
+                            peer_id,
+                            filename: record.filename.clone(),
+                            direction: TransferDirection::Download,
+                            by_peer: !record.paused,
+                            progress: Some((record.received, record.total_size)),
+                        }
+                    } else {
+                        Event::DownloadInterrupted {
+                            peer_id,
+                            filename: record.filename.clone(),
+                            received: record.received,
+                            total: record.total_size,
+                        }
+                    };
+                    let _ = swarm_tx.send(event);
+                    interrupted_downloads.entry(peer_id).or_default().insert(record.filename.clone(), record);
+                }
+            }
+            Err(e) => {
+                let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] {}", e)));
+            }
+        }
+    }
+
+    // --- Swap State ---
+    // Two-sided swaps we proposed or were offered. Key: swap ID
+    let mut swaps: HashMap<String, Swap> = HashMap::new();
+    // Our side of a swap reports back here once it has been hashed: (swap ID, size, hash result)
+    let (swap_hashed_tx, mut swap_hashed_rx) = mpsc::unbounded_channel::<(String, std::io::Result<(String, OfferedFile)>)>();
+    // ProposeSwap and AcceptSwap requests waiting for an answer. Value: swap ID
+    let mut swap_requests: HashMap<OutboundRequestId, String> = HashMap::new();
+    // Swaps to call off: (swap ID, reason, whether the peer still needs to be told)
+    let (swap_cancel_tx, mut swap_cancel_rx) = mpsc::unbounded_channel::<(String, String, bool)>();
+
+    // --- Rendezvous State ---
+    let mut discover_timer = interval(Duration::from_secs(30)); // Timer for periodic discovery
+    let mut rendezvous_cookies: HashMap<PeerId, rendezvous::Cookie> = HashMap::new(); // Cookies for incremental discovery, per Rendezvous point
+    let rendezvous_peer_ids: Vec<PeerId> = config.rendezvous_points.iter().map(|point| point.peer_id).collect();
+    // Rendezvous points we are registered with; from then on the client re-registers by itself when our addresses change
+    let mut rendezvous_registered: HashSet<PeerId> = HashSet::new();
+    // Rendezvous points double as relays: peers reach us through a circuit on them (see "Relay Events")
+    let mut relay_listeners: HashMap<ListenerId, PeerId> = HashMap::new();
+    // Open connections to each peer, and whether each goes through a relay (see `only_relayed`)
+    let mut connections: HashMap<PeerId, HashMap<ConnectionId, bool>> = HashMap::new();
+    // Addresses peers have told us they see us at (reported to the UI once each)
+    let mut observed_addresses: HashSet<Multiaddr> = HashSet::new();
+    // --- DHT State ---
+    let dht_key = kad::RecordKey::new(&constants::DHT_PROVIDER_KEY);
+    let mut dht_timer = interval(constants::DHT_QUERY_INTERVAL); // Timer for periodic lookups of other nodes
+    // Whether other nodes store our provider record yet, and the query asking them to
+    let mut dht_providing = false;
+    let mut dht_provide_query: Option<kad::QueryId> = None;
+    // Nodes found under the provider key
+    let mut dht_found: HashSet<PeerId> = HashSet::new();
+    let rendezvous_namespace = match rendezvous::Namespace::new(constants::RENDEZVOUS_NS.to_string()) {
+        Ok(ns) => Some(ns),
+        Err(_) => {
+            let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Invalid Rendezvous namespace in constants: {}", constants::RENDEZVOUS_NS)));
+            None
+        }
+    };
+
+    // --- Dial Rendezvous Points ---
+    // Attempt to dial each configured Rendezvous point at startup
+    for point in &config.rendezvous_points {
+        let addr = point.addr.clone().with(MultiaddrProtocol::P2p(point.peer_id));
+        if let Err(e) = swarm.dial(addr.clone()) {
+             let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Failed to dial Rendezvous {}: {}", addr, e)));
+        }
+        // They are always around to check whether we are reachable
+        swarm.behaviour_mut().autonat.add_server(point.peer_id, Some(point.addr.clone()));
+    }
+
+    // --- Join the DHT ---
+    // Kademlia bootstraps by itself from the first nodes in its routing table: these seeds,
+    // and any other SwapBytes node we connect to (see "External Addresses")
+    for seed in &config.dht_seeds {
+        swarm.behaviour_mut().kademlia.add_address(&seed.peer_id, seed.addr.clone());
+        if let Err(e) = dial_peer(&mut swarm, seed.peer_id, vec![seed.addr.clone()]) {
+            let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Failed to dial DHT seed {}: {}", seed.addr, e)));
+        }
+    }
+
+    // --- Main Event Loop ---
+    loop {
+        // --- Waiting Downloads ---
+        // Hand the download slots that came free since the last event to the downloads waiting for one
+        if !waiting_downloads.is_empty() {
+            let mut started = false;
+            let mut line_moved = false;
+            // Downloads from peers we only reach through a relay keep their place until there is a direct connection
+            let mut held = VecDeque::new();
+            while active_download_count(&incoming_transfers_state, resumes_preparing) < max_downloads {
+                let Some(waiting) = waiting_downloads.pop_front() else { break };
+                if only_relayed(&connections, &waiting.peer_id()) {
+                    held.push_back(waiting);
+                    continue;
+                }
+                line_moved = true;
+                match waiting {
+                    WaitingDownload::File { peer_id, filename, size_bytes, chunk_size, hash, subdir } => {
+                        match open_download(&mut transfer_manager, download_dir.as_deref(), subdir.as_deref(), &peer_id, &filename, size_bytes, chunk_size, hash) {
+                            Ok(download_state) => {
+                                begin_download(&mut chunk_fetcher, peer_id, filename, download_state, &mut incoming_transfers_state, &mut interrupted_downloads);
+                                started = true;
+                            }
+                            Err(e) => {
+                                let _ = swarm_tx.send(Event::FileTransferFailed { peer_id, filename, error: format!("Could not start download: {}", e) });
+                            }
+                        }
+                    }
+                    WaitingDownload::Resume { peer_id, filename } => {
+                        // If the peer has gone it is picked up again when they're back; paused ones wait for /resume
+                        if !swarm.is_connected(&peer_id) {
+                            continue;
+                        }
+                        let resumable = interrupted_downloads.get(&peer_id)
+                            .and_then(|downloads| downloads.get(&filename))
+                            .is_some_and(|record| !record.paused && !record.paused_by_peer);
+                        if resumable && let Some(record) = interrupted_downloads.get_mut(&peer_id).and_then(|downloads| downloads.remove(&filename)) {
+                            start_resume(record, &mut transfer_manager, &mut resumes_preparing);
+                            started = true;
+                        }
+                    }
+                    WaitingDownload::Bundle { peer_id, name } => {
+                        let Some(bundle_download) = bundle_downloads.get_mut(&(peer_id, name.clone())) else { continue };
+                        // One file per turn, then back in line if it has more to start
+                        let mut free_slots = 1;
+                        let (running, bundle_started) = start_bundle_files(peer_id, &name, bundle_download, &mut free_slots, download_dir.as_deref(), &mut transfer_manager, &mut chunk_fetcher, &mut incoming_transfers_state, &mut interrupted_downloads, &swarm_tx);
+                        started |= bundle_started;
+                        let wants_more = running < constants::BUNDLE_PARALLEL_FILES && !bundle_download.pending.is_empty();
+                        // The bundle's queue reports its progress (or that it is complete)
+                        let _ = bundle_queue_tx.send((peer_id, name.clone(), BundleUpdate::Progress));
+                        if wants_more {
+                            waiting_downloads.push_back(WaitingDownload::Bundle { peer_id, name });
+                        }
+                    }
+                }
+            }
+            held.append(&mut waiting_downloads);
+            waiting_downloads = held;
+            if started {
+                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+            }
+            if line_moved {
+                report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+            }
+        }
+
+        tokio::select! {
+            // --- Graceful Shutdown ---
+            // Listen for cancellation signal
+            _ = swarm_cancel.cancelled() => break,
+
+            // --- Heartbeat Broadcaster ---
+            // Periodically send heartbeat messages if visible
+            _ = heartbeat_timer.tick() => {
+                if is_visible {
+                    // Get current timestamp in milliseconds
+                    let timestamp_ms = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("Time went backwards")
+                        .as_millis() as u64;
+
+                    // Construct the heartbeat message
+                    let heartbeat_msg = protocol::Message::Heartbeat {
+                        timestamp_ms,
+                        nickname: current_nickname.clone(),
+                    };
+
+                    // Serialize and publish the heartbeat message via gossipsub
+                    match serde_json::to_vec(&heartbeat_msg) {
+                        Ok(encoded_msg) => {
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), encoded_msg) {
+                                // Log errors, ignoring "InsufficientPeers" as it's expected sometimes
+                                if e.to_string() != "InsufficientPeers" {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("Failed to publish heartbeat: {e}")));
+                                }
+                            }
+                        }
+                        Err(e) => {
+                             let _ = swarm_tx.send(Event::LogMessage(format!("Failed to serialize heartbeat: {e}")));
+                        }
+                    }
+                }
+            },
+
+            // --- Rendezvous Discovery Timer ---
+            _ = discover_timer.tick(), if !rendezvous_peer_ids.is_empty() && rendezvous_namespace.is_some() => {
+                for rendezvous_peer in &rendezvous_peer_ids {
+                    swarm.behaviour_mut().rendezvous.discover(
+                        rendezvous_namespace.clone(),
+                        rendezvous_cookies.get(rendezvous_peer).cloned(), // Pass the last cookie for incremental updates
+                        None, // No specific peer limit
+                        *rendezvous_peer,
+                    );
+                    // Optional: Log the discovery attempt
+                    // let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Requesting discovery from Rendezvous: {}", rendezvous_peer)));
+                }
+            },
+
+            // --- DHT Discovery Timer ---
+            _ = dht_timer.tick() => {
+                query_dht(&mut swarm, &dht_key, dht_providing, &mut dht_provide_query);
+            },
+
+            // --- Offer Hashing Results ---
+            // Send offers whose file hash has been computed
+            Some((target_peer, offer_id, filename, file_path, hash_result)) = offer_hashed_rx.recv() => {
+                match hash_result {
+                    Ok((hash, offered)) => {
+                        if name_in_use(&outgoing_transfers, target_peer, [filename.as_str()]).is_some() {
+                            let _ = swarm_tx.send(Event::LogMessage(format!("Can't offer '{}': a file of that name is already offered or being sent to this peer", filename)));
+                            continue;
+                        }
+                        // The offer describes the file as it was hashed
+                        let size_bytes = offered.stamp.size;
+                        // Store the file path immediately, so the file can be served as soon as the offer is accepted
+                        outgoing_transfers.insert((target_peer, filename.clone()), offered);
+
+                        // Construct and send the Offer request via request-response
+                        let request = protocol::PrivateRequest::Offer { offer_id: offer_id.clone(), filename: filename.clone(), size_bytes, hash, chunk_size: chunk_size as u64 };
+                        let request_id = swarm.behaviour_mut().request_response.send_request(&target_peer, request);
+                        sent_offers.insert((target_peer, offer_id.clone()), vec![filename.clone()]);
+                        offer_requests.insert(request_id, (offer_id, filename));
+                    }
+                    Err(e) => {
+                        let _ = swarm_tx.send(Event::LogMessage(format!("Error hashing file for offer: {} ({})", file_path.display(), e)));
+                    }
+                }
+            }
+
+            // --- Bundle Hashing Results ---
+            // Send offers of several files once every file has been hashed
+            Some((target_peer, offer_id, name, files, hash_result)) = bundle_hashed_rx.recv() => {
+                match hash_result {
+                    Ok(hashes) => {
+                        if let Some(taken) = name_in_use(&outgoing_transfers, target_peer, files.iter().map(|file| file.relative_path.as_str())) {
+                            let _ = swarm_tx.send(Event::LogMessage(format!("Can't offer '{}': a file named '{}' is already offered or being sent to this peer", name, taken)));
+                            continue;
+                        }
+                        let mut entries = Vec::with_capacity(files.len());
+                        for (file, (hash, offered)) in files.into_iter().zip(hashes) {
+                            entries.push(protocol::BundleEntry { path: file.relative_path.clone(), size_bytes: offered.stamp.size, hash });
+                            // Each file is served under its path inside the bundle
+                            outgoing_transfers.insert((target_peer, file.relative_path), offered);
+                        }
+                        let served = entries.iter().map(|entry| entry.path.clone()).collect();
+                        let request = protocol::PrivateRequest::OfferBundle { offer_id: offer_id.clone(), name: name.clone(), files: entries, chunk_size: chunk_size as u64 };
+                        let request_id = swarm.behaviour_mut().request_response.send_request(&target_peer, request);
+                        sent_offers.insert((target_peer, offer_id.clone()), served);
+                        offer_requests.insert(request_id, (offer_id, name));
+                    }
+                    Err(e) => {
+                        let _ = swarm_tx.send(Event::LogMessage(format!("Error hashing files for offer '{}': {}", name, e)));
+                    }
+                }
+            }
+
+            // --- Bundle Queue ---
+            // Keep a few files of each accepted bundle downloading, and report how far the bundle has got
+            Some((peer, name, update)) = bundle_queue_rx.recv() => {
+                let bundle_key = (peer, name.clone());
+                let Some(bundle_download) = bundle_downloads.get_mut(&bundle_key) else { continue };
+                match update {
+                    BundleUpdate::FileDone { size_bytes } => {
+                        bundle_download.files_done += 1;
+                        bundle_download.bytes_done += size_bytes;
+                    }
+                    BundleUpdate::FileFailed => bundle_download.files_failed += 1,
+                    BundleUpdate::Accepted | BundleUpdate::Progress => {}
+                }
+
+                // Downloads already waiting for a slot go first, and file data waits for a direct connection
+                let mut free_slots = if waiting_downloads.is_empty() && !only_relayed(&connections, &peer) {
+                    max_downloads.saturating_sub(active_download_count(&incoming_transfers_state, resumes_preparing))
+                } else {
+                    0
+                };
+                let (running, started) = start_bundle_files(peer, &name, bundle_download, &mut free_slots, download_dir.as_deref(), &mut transfer_manager, &mut chunk_fetcher, &mut incoming_transfers_state, &mut interrupted_downloads, &swarm_tx);
+                if started {
+                    save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                }
+                // Out of download slots: wait in line for the next one
+                if running < constants::BUNDLE_PARALLEL_FILES
+                    && !bundle_download.pending.is_empty()
+                    && !waiting_downloads.iter().any(|waiting| waiting.is(peer, &name))
+                {
+                    waiting_downloads.push_back(WaitingDownload::Bundle { peer_id: peer, name: name.clone() });
+                    report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                }
+                let Some(bundle_download) = bundle_downloads.get(&bundle_key) else { continue };
+
+                // --- Bundle Progress ---
+                let in_bundle = |bundle: &Option<String>| bundle.as_deref() == Some(name.as_str());
+                let mut current: Vec<(String, u64, u64)> = incoming_transfers_state
+                    .get(&peer)
+                    .map(|downloads| downloads.iter()
+                        .filter(|(_, state)| in_bundle(&state.bundle))
+                        .map(|(filename, state)| (filename.clone(), state.received, state.total_size))
+                        .collect())
+                    .unwrap_or_default();
+                current.sort();
+                let interrupted_received: u64 = interrupted_downloads
+                    .get(&peer)
+                    .map_or(0, |downloads| downloads.values().filter(|record| in_bundle(&record.bundle)).map(|record| record.received).sum());
+                let status = BundleStatus {
+                    name: name.clone(),
+                    received: bundle_download.bytes_done + interrupted_received + current.iter().map(|(_, received, _)| received).sum::<u64>(),
+                    total: bundle_download.total_size,
+                    file_count: bundle_download.file_count,
+                    files_done: bundle_download.files_done,
+                    files_failed: bundle_download.files_failed,
+                    current,
+                };
+                if running == 0 && bundle_download.pending.is_empty() {
+                    // Nothing is pending or running, so every file has arrived or failed
+                    let path = match (&download_dir, &bundle_download.subdir) {
+                        (Some(dir), Some(subdir)) => subdirectory(dir, subdir).unwrap_or_else(|_| dir.clone()),
+                        (dir, _) => dir.clone().unwrap_or_default(),
+                    };
+                    bundle_downloads.remove(&bundle_key);
+                    let _ = swarm_tx.send(Event::BundleComplete { peer_id: peer, status, path });
+                } else {
+                    let throttled = rate_limiter.is_throttled(peer, TransferDirection::Download);
+                    let _ = swarm_tx.send(Event::BundleProgress { peer_id: peer, status, throttled });
+                }
+            }
+
+            // --- Swap Hashing Results ---
+            // Commit to our side of a swap once its hash is known
+            Some((swap_id, hash_result)) = swap_hashed_rx.recv() => {
+                // The swap may have been called off while hashing
+                let Some(swap) = swaps.get_mut(&swap_id) else { continue };
+                let Some(our_path) = swap.our_path.clone() else { continue };
+                let (hash, offered) = match hash_result {
+                    Ok(hashed) => hashed,
+                    Err(e) => {
+                        let _ = swarm_tx.send(Event::LogMessage(format!("Error hashing file for swap: {} ({})", our_path.display(), e)));
+                        // Only the side accepting has told the peer about the swap yet
+                        let notify_peer = !swap.proposed_by_us;
+                        let _ = swap_cancel_tx.send((swap_id, format!("'{}' could not be read", swap.our_filename), notify_peer));
+                        continue;
+                    }
+                };
+                let our_file = protocol::SwapFile {
+                    filename: swap.our_filename.clone(),
+                    size_bytes: offered.stamp.size,
+                    hash,
+                    chunk_size: chunk_size as u64,
+                };
+                swap.our_file = Some(our_file.clone());
+                swap.our_offered = Some(offered.clone());
+                let peer = swap.peer_id;
+                let request = if swap.proposed_by_us {
+                    protocol::PrivateRequest::ProposeSwap { swap_id: swap_id.clone(), offered: our_file, wanted: swap.their_filename.clone() }
+                } else {
+                    // Be ready to serve our file before committing to it; our own download
+                    // starts once the proposer acknowledges (see "Incoming Response" below)
+                    outgoing_transfers.insert((peer, swap.our_filename.clone()), offered);
+                    protocol::PrivateRequest::AcceptSwap { swap_id: swap_id.clone(), offered: our_file }
+                };
+                let request_id = swarm.behaviour_mut().request_response.send_request(&peer, request);
+                swap_requests.insert(request_id, swap_id);
+            }
+
+            // --- Swap Cancellations ---
+            // Call off a swap and throw away both sides of it
+            Some((swap_id, reason, notify_peer)) = swap_cancel_rx.recv() => {
+                let Some(swap) = swaps.remove(&swap_id) else { continue };
+                let peer = swap.peer_id;
+                if notify_peer {
+                    let request = protocol::PrivateRequest::CancelSwap { swap_id: swap_id.clone(), reason: reason.clone() };
+                    swarm.behaviour_mut().request_response.send_request(&peer, request);
+                }
+                // Stop serving our file
+                if swap.our_file.is_some() {
+                    outgoing_transfers.remove(&(peer, swap.our_filename.clone()));
+                    if let Some(task) = uploads.remove(&(peer, swap.our_filename.clone())).and_then(|upload| upload.task) {
+                        task.abort();
+                    }
+                }
+                // Drop our download, whether it is running, interrupted or finished and held
+                if discard_swap_download(&swap_id, &swap, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager) {
+                    chunk_fetcher.close(peer, &swap.their_filename);
+                }
+                // A download held for a slot leaves nothing to resume
+                let queued = waiting_downloads.len();
+                waiting_downloads.retain(|waiting| !waiting.is(peer, &swap.their_filename));
+                if waiting_downloads.len() != queued {
+                    report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                }
+                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                let _ = swarm_tx.send(Event::SwapCancelled { peer_id: peer, details: swap.details(&swap_id), reason });
+            }
+
+            // --- Chunk Results ---
+            // Write chunks fetched over the transfer protocol and keep the window full
+            Some(fetch_event) = fetch_event_rx.recv() => {
+                let chunk_result = match fetch_event {
+                    transfer::FetchEvent::Chunk(chunk_result) => chunk_result,
+                    // --- Queued By The Sender ---
+                    // The sender is busy with other uploads and has put this download in line
+                    transfer::FetchEvent::Queued { peer_id: peer, filename, place } => {
+                        let Some(state) = incoming_transfers_state.get_mut(&peer).and_then(|downloads| downloads.get_mut(&filename)) else { continue };
+                        // Time spent in line says nothing about the link, so the window shouldn't see it as round-trip time
+                        let now = Instant::now();
+                        for (_, requested_at) in state.in_flight.values_mut() {
+                            *requested_at = now;
+                        }
+                        // Files of a bundle are shown as the bundle, which keeps going with its other files
+                        if state.queued_place != Some(place) && state.bundle.is_none() {
+                            let _ = swarm_tx.send(Event::TransferQueued {
+                                peer_id: peer,
+                                filename,
+                                direction: TransferDirection::Download,
+                                place,
+                                by_peer: true,
+                                progress: Some((state.received, state.total_size)),
+                            });
+                        }
+                        state.queued_place = Some(place);
+                        continue;
+                    }
+                };
+                let transfer::ChunkResult { peer_id: peer, filename, chunk_index, request_id, outcome } = chunk_result;
+                // Find the download state for this peer and filename.
+                // Requests from before the download was interrupted, failed or restarted are stale and ignored.
+                let Some(state) = incoming_transfers_state.get_mut(&peer).and_then(|downloads| downloads.get_mut(&filename)) else { continue };
+                let Some(&(in_flight_id, requested_at)) = state.in_flight.get(&chunk_index) else { continue };
+                if in_flight_id != request_id { continue; }
+                state.in_flight.remove(&chunk_index);
+
+                let data = match outcome {
+                    transfer::ChunkOutcome::Data(data) => data,
+                    transfer::ChunkOutcome::Refused(error) => {
+                        // --- Download Failed (Sender Error) ---
+                        // The remote peer reported an error, so the transfer can't continue
+                        let _ = swarm_tx.send(Event::LogMessage(format!(
+                            "[Swarm Task] {} refused chunk {} of '{}': {}",
+                            peer, chunk_index, filename, error
+                        )));
+                        let fail_event = Event::FileTransferFailed {
+                            peer_id: peer,
+                            filename: filename.clone(),
+                            error: format!("Transfer failed on sender side: {}", error),
+                        };
+                        let _ = swarm_tx.send(fail_event);
+                        chunk_fetcher.close(peer, &filename);
+                        if let Some(state_owned) = incoming_transfers_state.get_mut(&peer).and_then(|downloads| downloads.remove(&filename)) {
+                            // Attempt cleanup of the partial temp file
+                            transfer_manager.remove(Some(state_owned.file_id), state_owned.local_path);
+                            // A swap can't complete without this side
+                            if let Some(swap_id) = state_owned.swap_id {
+                                let _ = swap_cancel_tx.send((swap_id, format!("'{}' could not be downloaded", filename), true));
+                            }
+                            // The rest of its bundle carries on without it
+                            if let Some(name) = state_owned.bundle {
+                                let _ = bundle_queue_tx.send((peer, name, BundleUpdate::FileFailed));
+                            }
+                        }
+                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                        continue;
+                    }
+                    transfer::ChunkOutcome::NotOffered => {
+                        // The sender has forgotten the file (e.g. it restarted), which is not a cancel:
+                        // keep the partial file so the download can be resumed once they offer it again
+                        let _ = swarm_tx.send(Event::LogMessage(format!(
+                            "[Swarm Task] {} no longer offers '{}'. The partial file is kept; /resume it once they offer it again.",
+                            peer, filename
+                        )));
+                        chunk_fetcher.close(peer, &filename);
+                        interrupt_download(peer, &filename, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager, &swarm_tx);
+                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                        continue;
+                    }
+                    transfer::ChunkOutcome::Failed(e) => {
+                        // The stream broke or timed out; keep the download so it can be resumed later
+                        let _ = swarm_tx.send(Event::LogMessage(format!(
+                            "[Swarm Task] Chunk {} of '{}' from {} failed: {}",
+                            chunk_index, filename, peer, e
+                        )));
+                        chunk_fetcher.close(peer, &filename);
+                        interrupt_download(peer, &filename, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager, &swarm_tx);
+                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                        continue;
+                    }
+                };
+                // Let the window adapt to how long the chunk took
+                state.window.on_rtt_sample(requested_at.elapsed());
+                // Chunks are coming, so we're out of the sender's queue
+                state.queued_place = None;
+
+                // Every chunk but the last one must be full-sized
+                let offset = chunk_index * state.chunk_size;
+                let expected_len = state.chunk_size.min(state.total_size.saturating_sub(offset));
+                if data.len() as u64 != expected_len {
+                    // --- Download Failed (Malformed Chunk) ---
+                    let error = format!("chunk {} has {} bytes, expected {}", chunk_index, data.len(), expected_len);
+                    let _ = swarm_tx.send(Event::LogMessage(format!(
+                        "[Swarm Task] Error writing chunk {} for file '{}' to '{}': {}. Download failed.",
+                        chunk_index, filename, state.local_path.display(), error
+                    )));
+                    fail_download(peer, &filename, format!("Failed to write to file: {}", error), &mut chunk_fetcher, &mut incoming_transfers_state, &transfer_manager, &swap_cancel_tx, &bundle_queue_tx, &swarm_tx);
+                    save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                    continue;
+                }
+                // Chunks can arrive in any order, so the transfer manager writes each one at its own offset.
+                // It shares the chunk's buffer with the hash below rather than getting a copy.
+                transfer_manager.write(state.file_id, offset, data.clone());
+                state.unwritten += 1;
+
+                // --- Hash In Order ---
+                // Feed the running hash with every chunk that now follows on without a gap
+                let previous_progress_marker = state.received / crate::constants::PROGRESS_UPDATE_BYTES;
+                state.hash_in_order(chunk_index, data);
+                let current_progress_marker = state.received / crate::constants::PROGRESS_UPDATE_BYTES;
+                let is_complete = state.next_chunk >= state.total_chunks;
+
+                // Files of a bundle are reported for the bundle as a whole (see "Bundle Queue" above)
+                if let Some(name) = &state.bundle {
+                    if current_progress_marker > previous_progress_marker && !is_complete {
+                        let _ = bundle_queue_tx.send((peer, name.clone(), BundleUpdate::Progress));
+                    }
+                }
+                // Send progress update to UI if a threshold is crossed or if the download is complete
+                else if (current_progress_marker > previous_progress_marker || is_complete)
+                    && let Err(e) = swarm_tx.send(Event::FileTransferProgress {
+                        peer_id: peer,
+                        filename: filename.clone(),
+                        received: state.received,
+                        total: state.total_size,
+                        throttled: rate_limiter.is_throttled(peer, TransferDirection::Download),
+                    })
+                {
+                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending progress update to UI: {}", e)));
+                }
+
+                if is_complete {
+                    // Remove the download state and finish it off
+                    chunk_fetcher.close(peer, &filename);
+                    let Some(state_owned) = incoming_transfers_state.get_mut(&peer).and_then(|downloads| downloads.remove(&filename)) else { continue };
+                    // Finished or failed, it no longer needs a resume point
+                    save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                    let swap_id = state_owned.swap_id.clone();
+                    // Files of a bundle report back to its queue, which starts the next ones.
+                    // After a restart the bundle is gone, and the file finishes on its own.
+                    let bundle = state_owned.bundle.clone().filter(|name| bundle_downloads.contains_key(&(peer, name.clone())));
+                    let Some(held) = verify_download(peer, &filename, state_owned, &transfer_manager, &swarm_tx) else {
+                        if let Some(swap_id) = swap_id {
+                            let _ = swap_cancel_tx.send((swap_id, format!("'{}' did not match the hash they committed to", filename), true));
+                        } else if let Some(name) = bundle {
+                            let _ = bundle_queue_tx.send((peer, name, BundleUpdate::FileFailed));
+                        }
+                        continue;
+                    };
+                    let Some(swap_id) = swap_id else {
+                        // The transfer manager moves it to its final name; it is reported from there (see "Disk Events" below)
+                        let then = match bundle {
+                            Some(name) => AfterSave::BundleFile { name, size: held.total_size },
+                            None => AfterSave::File { total_size: held.total_size, hash: held.hash },
+                        };
+                        transfer_manager.save(held.file_id, peer, filename, held.temp_path, then);
+                        continue;
+                    };
+
+                    // --- Swap Download Finished ---
+                    // It checked out, but is held back until the peer has its side too
+                    let Some(swap) = swaps.get_mut(&swap_id) else {
+                        // The swap was called off while the last chunks were arriving
+                        transfer_manager.remove(Some(held.file_id), held.temp_path);
+                        continue;
+                    };
+                    swap.held = Some(held);
+                    let _ = swarm_tx.send(Event::SwapWaiting { peer_id: peer, details: swap.details(&swap_id) });
+                    swarm.behaviour_mut().request_response.send_request(&peer, protocol::PrivateRequest::SwapReady { swap_id: swap_id.clone() });
+                    if swap.can_release()
+                        && let Some(swap) = swaps.remove(&swap_id)
+                    {
+                        release_swap(&swap_id, swap, &mut outgoing_transfers, &transfer_manager);
+                    }
+                } else {
+                    // Save a resume point whenever a threshold is crossed. It is saved once the transfer manager
+                    // reports the data up to it on disk, so the saved position never runs ahead of the file.
+                    if current_progress_marker > previous_progress_marker {
+                        transfer_manager.checkpoint(state.file_id, state.received, state.next_chunk);
+                    }
+                    // --- Request More Chunks ---
+                    // Top the window back up
+                    request_chunks(&mut chunk_fetcher, peer, &filename, state);
+                }
+            }
+
+            // --- Disk Events ---
+            // Results of the disk work handed to the transfer manager
+            Some(disk_event) = disk_event_rx.recv() => {
+                match disk_event {
+                    DiskEvent::Written { file_id } => {
+                        // A download held back by the disk can have more chunks now
+                        let Some((peer, filename, state)) = download_by_file(&mut incoming_transfers_state, file_id) else { continue };
+                        state.unwritten = state.unwritten.saturating_sub(1);
+                        request_chunks(&mut chunk_fetcher, peer, &filename, state);
+                    }
+                    DiskEvent::Checkpoint { file_id, received, next_chunk } => {
+                        let Some((_, _, state)) = download_by_file(&mut incoming_transfers_state, file_id) else { continue };
+                        state.checkpoint = (received, next_chunk);
+                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                    }
+                    DiskEvent::Renamed { file_id, from, to } => {
+                        // The download's temporary file was created under another name; a download
+                        // held back right away is only known by its resume record
+                        if let Some((_, _, state)) = download_by_file(&mut incoming_transfers_state, file_id) {
+                            state.local_path = to;
+                        } else if let Some(record) = interrupted_downloads.values_mut().flat_map(|downloads| downloads.values_mut()).find(|record| record.temp_path == from) {
+                            record.temp_path = to;
+                        }
+                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                    }
+                    DiskEvent::Failed { file_id, error } => {
+                        // --- Download Failed (Write Error) ---
+                        let Some((peer, filename, state)) = download_by_file(&mut incoming_transfers_state, file_id) else { continue };
+                        let _ = swarm_tx.send(Event::LogMessage(format!(
+                            "[Swarm Task] Error saving '{}' to '{}': {}. Download failed.",
+                            filename, state.local_path.display(), error
+                        )));
+                        fail_download(peer, &filename, error, &mut chunk_fetcher, &mut incoming_transfers_state, &transfer_manager, &swap_cancel_tx, &bundle_queue_tx, &swarm_tx);
+                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                    }
+                    DiskEvent::Saved { peer_id, filename, then, result } => {
+                        let final_path = match result {
+                            Ok(final_path) => final_path,
+                            Err(error) => {
+                                let _ = swarm_tx.send(Event::FileTransferFailed { peer_id, filename, error });
+                                if let AfterSave::BundleFile { name, .. } = then {
+                                    let _ = bundle_queue_tx.send((peer_id, name, BundleUpdate::FileFailed));
+                                }
+                                continue;
+                            }
+                        };
+                        // --- Download Successful ---
+                        match then {
+                            AfterSave::File { total_size, hash } => {
+                                let _ = swarm_tx.send(Event::FileTransferComplete { peer_id, filename, path: final_path, total_size, hash });
+                            }
+                            AfterSave::BundleFile { name, size } => {
+                                let _ = swarm_tx.send(Event::BundleFileComplete { peer_id, name: name.clone(), filename, path: final_path, size });
+                                let _ = bundle_queue_tx.send((peer_id, name, BundleUpdate::FileDone { size_bytes: size }));
+                            }
+                            AfterSave::Swap { details, total_size, hash } => {
+                                let _ = swarm_tx.send(Event::FileTransferComplete { peer_id, filename, path: final_path, total_size, hash });
+                                let _ = swarm_tx.send(Event::SwapCompleted { peer_id, details });
+                            }
+                        }
+                    }
+                    DiskEvent::Resumed { file_id, record, result } => {
+                        // --- Resume Preparation Results ---
+                        // Continue downloads whose partial file has been checked and re-hashed.
+                        // From here on the download holds its slot as a running (or failed) download.
+                        resumes_preparing = resumes_preparing.saturating_sub(1);
+                        let Some(peer_id) = record.peer() else { continue };
+                        let prepared = result
+                            .map_err(|e| e.to_string())
+                            .and_then(|hasher| Ok((DownloadState::new(record.temp_path.clone(), file_id, record.total_size, record.chunk_size, record.hash.clone())?, hasher)));
+                        match prepared {
+                            Ok((mut download_state, hasher)) => {
+                                // Pick up right after the last chunk we kept, with a fresh window
+                                download_state.received = record.received;
+                                download_state.next_chunk = record.next_chunk;
+                                download_state.next_request = record.next_chunk;
+                                download_state.checkpoint = (record.received, record.next_chunk);
+                                download_state.hasher = *hasher;
+                                download_state.swap_id = record.swap_id.clone();
+                                download_state.bundle = record.bundle.clone();
+
+                                let _ = swarm_tx.send(Event::DownloadResumed {
+                                    peer_id,
+                                    filename: record.filename.clone(),
+                                    received: record.received,
+                                    total: record.total_size,
+                                });
+                                request_chunks(&mut chunk_fetcher, peer_id, &record.filename, &mut download_state);
+                                incoming_transfers_state.entry(peer_id).or_default().insert(record.filename, download_state);
+                                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                            }
+                            Err(e) => {
+                                let _ = swarm_tx.send(Event::FileTransferFailed {
+                                    peer_id,
+                                    filename: record.filename.clone(),
+                                    error: format!("Could not resume download: {}", e),
+                                });
+                                transfer_manager.remove(Some(file_id), record.temp_path);
+                                if let Some(swap_id) = record.swap_id {
+                                    let _ = swap_cancel_tx.send((swap_id, format!("'{}' could not be resumed", record.filename), true));
+                                }
+                                if let Some(name) = record.bundle {
+                                    let _ = bundle_queue_tx.send((peer_id, name, BundleUpdate::FileFailed));
+                                }
+                                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // --- Incoming Transfer Streams ---
+            // Read which file each new transfer stream is for without holding up the loop
+            Some((peer, mut stream)) = incoming_transfer_streams.next() => {
+                let transfer_request_tx = transfer_request_tx.clone();
+                tokio::spawn(async move {
+                    if let Ok(Ok(filename)) = tokio::time::timeout(constants::CHUNK_REQUEST_TIMEOUT, transfer::read_transfer_request(&mut stream)).await {
+                        let _ = transfer_request_tx.send((peer, filename, stream));
+                    }
+                });
+            }
+
+            // --- Transfer Requests ---
+            // Serve the chunks of files we offered
+            Some((peer, filename, stream)) = transfer_request_rx.recv() => {
+                // Files are served under their NFC names, whichever form the receiver asks in
+                let upload_key = (peer, sanitize::normalise(&filename));
+                // A paused upload turns the stream away; the receiver keeps its partial file and can try again later
+                if uploads.get(&upload_key).is_some_and(|upload| upload.paused) {
+                    drop(stream);
+                    continue;
+                }
+                // Only files with a registered outgoing transfer are served
+                let offered = outgoing_transfers.get(&upload_key).cloned();
+                let is_offered = offered.is_some();
+                // Offered files wait for one of the upload slots; refusals go out straight away
+                let (slot, place) = if is_offered {
+                    let (slot, place) = upload_scheduler.admit(peer);
+                    (Some(slot), Some(place))
+                } else {
+                    (None, None)
+                };
+                let slot_id = slot.as_ref().map(|slot| slot.id());
+                let task_swarm_tx = swarm_tx.clone();
+                let task_filename = upload_key.1.clone();
+                let limiter = rate_limiter.clone();
+                let upload_complete_tx = upload_complete_tx.clone();
+                let task = tokio::spawn(async move {
+                    // Held until the upload ends, however it ends; the next one in line gets the slot then
+                    let _slot = slot;
+                    let mut stream = stream;
+                    if let (Some(place), Some(offered)) = (place, offered.as_ref()) {
+                        let total = offered.stamp.size;
+                        let on_queued = |place| {
+                            let _ = task_swarm_tx.send(Event::TransferQueued {
+                                peer_id: peer,
+                                filename: task_filename.clone(),
+                                direction: TransferDirection::Upload,
+                                place,
+                                by_peer: false,
+                                progress: Some((0, total)),
+                            });
+                        };
+                        if let Err(e) = transfer::wait_for_turn(&mut stream, place, on_queued).await {
+                            let _ = task_swarm_tx.send(Event::LogMessage(format!("[Swarm Task] {} stopped waiting for '{}': {}", peer, task_filename, e)));
+                            let _ = task_swarm_tx.send(Event::UploadInterrupted { peer_id: peer, filename: task_filename });
+                            return;
+                        }
+                    }
+                    let on_progress = |sent, total| {
+                        let throttled = limiter.is_throttled(peer, TransferDirection::Upload);
+                        let _ = task_swarm_tx.send(Event::UploadProgress { peer_id: peer, filename: task_filename.clone(), sent, total, throttled });
+                    };
+                    match transfer::serve_transfer(stream, peer, offered.as_ref(), chunk_size, &limiter, on_progress).await {
+                        Ok(true) => {
+                            let _ = upload_complete_tx.send((peer, task_filename.clone()));
+                            let _ = task_swarm_tx.send(Event::UploadComplete { peer_id: peer, filename: task_filename });
+                        }
+                        // Paused by the receiver; it comes back with a new stream
+                        Ok(false) => {}
+                        // The receiver resumes with a new stream if it can
+                        Err(ServeError::Interrupted(e)) => {
+                            let _ = task_swarm_tx.send(Event::LogMessage(format!("[Swarm Task] Error serving file to {}: {}", peer, e)));
+                            let _ = task_swarm_tx.send(Event::UploadInterrupted { peer_id: peer, filename: task_filename });
+                        }
+                        // The receiver was told why and drops the download
+                        Err(ServeError::Refused(e)) => {
+                            let _ = task_swarm_tx.send(Event::LogMessage(format!("[Swarm Task] Error serving file to {}: {}", peer, e)));
+                            if is_offered {
+                                let _ = task_swarm_tx.send(Event::UploadFailed { peer_id: peer, filename: task_filename, error: e });
+                            }
+                        }
+                    }
+                });
+                if is_offered {
+                    // A new stream means the receiver is (back) on it
+                    let upload = uploads.entry(upload_key).or_default();
+                    upload.task = Some(task.abort_handle());
+                    upload.slot = slot_id;
+                    upload.paused_by_peer = false;
+                }
+            }
+
+            // --- Chunk Requests ---
+            // Chunks of downloads from peers on 1.0.0 are asked for one message each
+            Some(chunk_request) = chunk_request_rx.recv() => {
+                let (peer, filename, chunk_index, _) = &chunk_request;
+                let request = protocol::PrivateRequest::RequestChunk { filename: filename.clone(), chunk_index: *chunk_index };
+                let request_id = swarm.behaviour_mut().request_response.send_request(peer, request);
+                chunk_requests.insert(request_id, chunk_request);
+            }
+
+            // --- Chunks Read For Chunk Requests ---
+            // Answer a peer on 1.0.0 with the chunk it asked for
+            Some((peer, filename, chunk_index, channel, read)) = chunk_read_rx.recv() => {
+                let response = match read {
+                    Ok((data, is_last)) => {
+                        let total = outgoing_transfers.get(&(peer, filename.clone())).map_or(0, |offered| offered.stamp.size);
+                        let sent = (chunk_index * protocol::LEGACY_CHUNK_SIZE + data.len() as u64).min(total);
+                        let throttled = rate_limiter.is_throttled(peer, TransferDirection::Upload);
+                        let _ = swarm_tx.send(Event::UploadProgress { peer_id: peer, filename: filename.clone(), sent, total, throttled });
+                        if is_last {
+                            let _ = upload_complete_tx.send((peer, filename.clone()));
+                            let _ = swarm_tx.send(Event::UploadComplete { peer_id: peer, filename: filename.clone() });
+                        }
+                        protocol::PrivateResponse::FileChunk { filename, chunk_index, data, is_last }
+                    }
+                    Err(error) => {
+                        let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm Task] Error serving file to {}: {}", peer, error)));
+                        let _ = swarm_tx.send(Event::UploadFailed { peer_id: peer, filename: filename.clone(), error: error.clone() });
+                        protocol::PrivateResponse::TransferError { filename, error }
+                    }
+                };
+                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, response) {
+                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending chunk response to {}: {:?}", peer, e)));
+                }
+            }
+
+            // --- Upload Slots ---
+            // An upload ended (or left the queue): let the next one in line start
+            Some(slot_id) = upload_done_rx.recv() => {
+                upload_scheduler.finish(slot_id);
+            }
+
+            // --- Finished Uploads ---
+            // The receiver has all of the file: stop serving it, which frees its name for another offer.
+            // Our side of a swap is served until the swap is through (see `release_swap`).
+            Some(upload_key) = upload_complete_rx.recv() => {
+                if !swaps.values().any(|swap| swap.peer_id == upload_key.0 && swap.our_filename == upload_key.1) {
+                    uploads.remove(&upload_key);
+                    outgoing_transfers.remove(&upload_key);
+                }
+            }
+
+            // --- Command Handling ---
+            // Process commands received from the UI task via the command channel
+            Some(cmd) = cmd_rx.recv() => {
+                match cmd {
+                    // --- Dial Command ---
+                    Command::Dial(addr) => {
+                        let log_msg = match swarm.dial(addr.clone()) {
+                            Ok(()) => format!("Dialing {addr}"),
+                            Err(e) => format!("Dial error: {e}"),
+                        };
+                        let _ = swarm_tx.send(Event::LogMessage(log_msg));
+                    }
+                    // --- Nickname Update Command ---
+                    Command::SetNickname(nickname) => {
+                        // Update the local nickname state
+                        current_nickname = Some(nickname);
+                    }
+                    // --- Visibility Change Command ---
+                    Command::SetVisibility(new_visibility) => {
+                        is_visible = new_visibility;
+                    }
+                    // --- Global Chat Command ---
+                    Command::SendGlobalMessage(content) => {
+                        let timestamp_ms = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .expect("Time went backwards")
+                            .as_millis() as u64;
+                        let message = protocol::Message::GlobalChatMessage {
+                            content,
+                            timestamp_ms,
+                            nickname: current_nickname.clone(),
+                        };
+                        match serde_json::to_vec(&message) {
+                            Ok(data) => {
+                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data)
+                                    && e.to_string() != "InsufficientPeers"
+                                {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("Failed to publish chat message: {e}")));
+                                }
+                            }
+                            Err(e) => {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("Failed to serialize chat message: {e}")));
+                            }
+                        }
+                    }
+                    // --- Send Private Message Command ---
+                    Command::SendPrivateMessage { target_peer, message } => {
+                        let request = protocol::PrivateRequest::ChatMessage(message);
+                        // Use the request-response protocol to send a private message
+                        swarm.behaviour_mut().request_response.send_request(&target_peer, request);
+                    }
+                    // --- Send File Offer Command ---
+                    Command::SendFileOffer { target_peer, offer_id, file_path } => {
+                        match std::fs::metadata(&file_path) {
+                            Ok(metadata) => {
+                                if metadata.is_file() {
+                                    // Offered (and served) under its NFC name, which is what the receiver will ask for
+                                    let filename = file_path.file_name().map_or_else(
+                                        || "unknown_file".to_string(), // Fallback filename
+                                        |os_name| sanitize::normalise(&os_name.to_string_lossy())
+                                    );
+
+                                    // Hash the file on a blocking thread so large files don't stall the event loop.
+                                    // The offer is sent once the hash is ready (see "Offer Hashing Results" below).
+                                    let hashed_tx = offer_hashed_tx.clone();
+                                    tokio::task::spawn_blocking(move || {
+                                        let hash_result = OfferedFile::hash(file_path.clone());
+                                        let _ = hashed_tx.send((target_peer, offer_id, filename, file_path, hash_result));
+                                    });
+                                } else {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("Error: Offer path is not a file: {}", file_path.display())));
+                                }
+                            }
+                            Err(e) => {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("Error getting file metadata for offer: {} ({})", file_path.display(), e)));
+                            }
+                        }
+                    }
+                    // --- Send Bundle Offer Command ---
+                    Command::SendBundleOffer { target_peer, offer_id, name, files } => {
+                        // Hash every file on a blocking thread; the offer is sent once all hashes are ready
+                        // (see "Bundle Hashing Results" above)
+                        let hashed_tx = bundle_hashed_tx.clone();
+                        tokio::task::spawn_blocking(move || {
+                            let hash_result = files
+                                .iter()
+                                .map(|file| OfferedFile::hash(file.path.clone())
+                                    .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", file.path.display(), e))))
+                                .collect();
+                            let _ = hashed_tx.send((target_peer, offer_id, name, files, hash_result));
+                        });
+                    }
+                    // --- Decline File Offer Command ---
+                    Command::DeclineFileOffer { target_peer, offer_id, filename } => {
+                        // Remove the pending offer from local state
+                        let Some(offer) = received_offers.remove(&(target_peer, offer_id.clone())) else {
+                            let _ = swarm_tx.send(Event::LogMessage(format!("Error: No pending offer {} ('{}') from {}", offer_id, filename, target_peer)));
+                            continue;
+                        };
+                        // Construct and send the DeclineOffer request via request-response
+                        let request = protocol::PrivateRequest::DeclineOffer { filename: offer.filename, offer_id };
+                        swarm.behaviour_mut().request_response.send_request(&target_peer, request);
+                    }
+                    // --- Accept File Offer Command ---
+                    Command::SendAcceptOffer { target_peer, offer_id, filename, subdir } => {
+                        // Only an offer the peer actually sent can be accepted, as it was sent
+                        let Some(offer) = received_offers.remove(&(target_peer, offer_id.clone())) else {
+                            let _ = swarm_tx.send(Event::LogMessage(format!("Error: No pending offer {} ('{}') from {}", offer_id, filename, target_peer)));
+                            continue;
+                        };
+                        let ReceivedOffer { filename, size_bytes, hash, chunk_size: offered_chunk_size, bundle } = offer;
+                        // With every download slot taken, nothing is opened until one comes free.
+                        // The same goes for a peer we only reach through a relay, until there is a direct connection.
+                        let relayed = only_relayed(&connections, &target_peer);
+                        let starts_now = bundle.is_none() && !relayed && download_slot_free(max_downloads, &waiting_downloads, &incoming_transfers_state, resumes_preparing);
+                        // Make sure the download has somewhere to go before telling the sender: if not, the offer is declined
+                        let opened = if starts_now {
+                            open_download(&mut transfer_manager, download_dir.as_deref(), subdir.as_deref(), &target_peer, &filename, size_bytes, offered_chunk_size, hash.clone()).map(Some)
+                        } else {
+                            download_location(download_dir.as_deref(), subdir.as_deref()).map(|_| None)
+                        };
+                        let opened = match opened {
+                            Ok(opened) => opened,
+                            Err(e) => {
+                                let request = protocol::PrivateRequest::DeclineOffer { filename: filename.clone(), offer_id };
+                                swarm.behaviour_mut().request_response.send_request(&target_peer, request);
+                                let _ = swarm_tx.send(Event::FileTransferFailed { peer_id: target_peer, filename, error: format!("Cannot accept the offer: {}", e) });
+                                continue;
+                            }
+                        };
+                        // Notify the sender before the first chunk is asked for
+                        let accept_request = protocol::PrivateRequest::AcceptOffer { filename: filename.clone(), offer_id };
+                        swarm.behaviour_mut().request_response.send_request(&target_peer, accept_request);
+
+                        // An offer of several files is downloaded a few files at a time (see "Bundle Queue" above)
+                        if let Some(mut bundle_download) = bundle {
+                            bundle_download.subdir = subdir;
+                            bundle_downloads.insert((target_peer, filename.clone()), bundle_download);
+                            let _ = bundle_queue_tx.send((target_peer, filename, BundleUpdate::Accepted));
+                            continue;
+                        }
+
+                        // Begin the local download process
+                        if let Some(download_state) = opened {
+                            begin_download(&mut chunk_fetcher, target_peer, filename, download_state, &mut incoming_transfers_state, &mut interrupted_downloads);
+                            save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                            continue;
+                        }
+                        if relayed {
+                            let _ = swarm_tx.send(Event::LogMessage(format!("'{}' starts once there is a direct connection to the peer: relays don't carry file data.", filename)));
+                        }
+                        waiting_downloads.push_back(WaitingDownload::File { peer_id: target_peer, filename, size_bytes, chunk_size: offered_chunk_size, hash, subdir });
+                        report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                    }
+                    // --- Propose Swap Command ---
+                    Command::ProposeSwap { target_peer, swap_id, file_path, wanted } => {
+                        match std::fs::metadata(&file_path) {
+                            Ok(metadata) if metadata.is_file() => {
+                                let our_filename = file_path.file_name().map_or_else(
+                                    || "unknown_file".to_string(),
+                                    |os_name| sanitize::normalise(&os_name.to_string_lossy())
+                                );
+                                swaps.insert(swap_id.clone(), Swap {
+                                    peer_id: target_peer,
+                                    proposed_by_us: true,
+                                    our_filename,
+                                    our_path: Some(file_path.clone()),
+                                    our_file: None,
+                                    our_offered: None,
+                                    their_filename: sanitize::normalise(&wanted),
+                                    their_file: None,
+                                    held: None,
+                                    peer_ready: false,
+                                });
+                                // The proposal goes out once the hash is ready (see "Swap Hashing Results" below)
+                                spawn_swap_hash(swap_id, file_path, &swap_hashed_tx);
+                            }
+                            Ok(_) => {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("Error: Swap path is not a file: {}", file_path.display())));
+                            }
+                            Err(e) => {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("Error getting file metadata for swap: {} ({})", file_path.display(), e)));
+                            }
+                        }
+                    }
+                    // --- Accept Swap Command ---
+                    Command::AcceptSwap { target_peer, swap_id, file_path } => {
+                        let Some(swap) = swaps.get_mut(&swap_id).filter(|swap| swap.peer_id == target_peer && swap.our_path.is_none()) else {
+                            let _ = swarm_tx.send(Event::LogMessage("That swap proposal is no longer open.".to_string()));
+                            continue;
+                        };
+                        match std::fs::metadata(&file_path) {
+                            Ok(metadata) if metadata.is_file() => {
+                                swap.our_path = Some(file_path.clone());
+                                // We commit to the file once it has been hashed
+                                spawn_swap_hash(swap_id, file_path, &swap_hashed_tx);
+                            }
+                            _ => {
+                                let _ = swap_cancel_tx.send((swap_id, format!("'{}' could not be read", swap.our_filename), true));
+                            }
+                        }
+                    }
+                    // --- Decline Swap Command ---
+                    Command::DeclineSwap { target_peer, swap_id } if swaps.get(&swap_id).is_some_and(|swap| swap.peer_id == target_peer) => {
+                        let _ = swap_cancel_tx.send((swap_id, "declined".to_string(), true));
+                    }
+                    // --- Bandwidth Limit Command ---
+                    Command::SetRateLimit { scope, direction, limit } => {
+                        rate_limiter.set_limit(scope, direction, limit);
+                    }
+                    // --- List Transfers Command ---
+                    Command::ListTransfers => {
+                        uploads.retain(|_, upload| upload.is_live());
+                        let mut transfers = Vec::new();
+                        for (peer_id, downloads) in &incoming_transfers_state {
+                            for (filename, state) in downloads {
+                                transfers.push(TransferSummary {
+                                    peer_id: *peer_id,
+                                    filename: filename.clone(),
+                                    direction: TransferDirection::Download,
+                                    state: state.queued_place.map_or(TransferState::Running, TransferState::QueuedByPeer),
+                                    received: Some(state.received),
+                                    total: state.total_size,
+                                });
+                            }
+                        }
+                        // Where each download waiting for a slot stands in line
+                        let waiting_place = |peer_id: &PeerId, name: &str| waiting_downloads
+                            .iter()
+                            .position(|waiting| waiting.is(*peer_id, name))
+                            .map(|index| TransferState::Queued(index as u32 + 1));
+                        for waiting in &waiting_downloads {
+                            if let WaitingDownload::File { peer_id, filename, size_bytes, .. } = waiting {
+                                transfers.push(TransferSummary {
+                                    peer_id: *peer_id,
+                                    filename: filename.clone(),
+                                    direction: TransferDirection::Download,
+                                    state: waiting_place(peer_id, filename).unwrap_or(TransferState::Running),
+                                    received: Some(0),
+                                    total: *size_bytes,
+                                });
+                            }
+                        }
+                        for (peer_id, downloads) in &interrupted_downloads {
+                            for (filename, record) in downloads {
+                                let state = if let Some(place) = waiting_place(peer_id, filename) {
+                                    place
+                                } else if record.paused {
+                                    TransferState::Paused
+                                } else if record.paused_by_peer {
+                                    TransferState::PausedByPeer
+                                } else {
+                                    TransferState::Interrupted
+                                };
+                                transfers.push(TransferSummary {
+                                    peer_id: *peer_id,
+                                    filename: filename.clone(),
+                                    direction: TransferDirection::Download,
+                                    state,
+                                    received: Some(record.received),
+                                    total: record.total_size,
+                                });
+                            }
+                        }
+                        for ((peer_id, filename), upload) in &uploads {
+                            let place = upload.slot.and_then(|slot| upload_scheduler.place(slot));
+                            let state = if upload.paused {
+                                TransferState::Paused
+                            } else if upload.paused_by_peer {
+                                TransferState::PausedByPeer
+                            } else if let Some(place) = place {
+                                TransferState::Queued(place)
+                            } else {
+                                TransferState::Running
+                            };
+                            let total = outgoing_transfers
+                                .get(&(*peer_id, filename.clone()))
+                                .map_or(0, |offered| offered.stamp.size);
+                            transfers.push(TransferSummary {
+                                peer_id: *peer_id,
+                                filename: filename.clone(),
+                                direction: TransferDirection::Upload,
+                                state,
+                                received: None,
+                                total,
+                            });
+                        }
+                        let _ = swarm_tx.send(Event::TransferList(transfers));
+                    }
+                    // --- Cancel, Pause and Resume Commands ---
+                    Command::ControlTransfer { action, peer_id: preferred_peer, filename } => {
+                        uploads.retain(|_, upload| upload.is_live());
+                        // Every transfer with this name: downloads (running or interrupted), bundles and uploads
+                        let running = incoming_transfers_state.iter().filter(|(_, downloads)| downloads.contains_key(&filename)).map(|(peer_id, _)| *peer_id);
+                        let interrupted = interrupted_downloads.iter().filter(|(_, downloads)| downloads.contains_key(&filename)).map(|(peer_id, _)| *peer_id);
+                        let bundles = bundle_downloads.keys().filter(|(_, name)| *name == filename).map(|(peer_id, _)| *peer_id);
+                        let waiting = waiting_downloads.iter().filter(|waiting| waiting.name() == filename).map(|waiting| waiting.peer_id());
+                        let mut matches: Vec<(PeerId, TransferDirection)> = running.chain(interrupted).chain(bundles).chain(waiting)
+                            .map(|peer_id| (peer_id, TransferDirection::Download))
+                            .chain(uploads.keys().filter(|(_, name)| *name == filename).map(|(peer_id, _)| (*peer_id, TransferDirection::Upload)))
+                            .collect();
+                        matches.sort();
+                        matches.dedup();
+                        // Prefer the peer of the current private chat
+                        if let Some(preferred_peer) = preferred_peer
+                            && matches.iter().any(|(peer_id, _)| *peer_id == preferred_peer)
+                        {
+                            matches.retain(|(peer_id, _)| *peer_id == preferred_peer);
+                        }
+                        let (peer, direction) = match matches.as_slice() {
+                            [single] => *single,
+                            [] => {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("No transfer named '{}'. Use /transfers to list them.", filename)));
+                                continue;
+                            }
+                            _ => {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("Several transfers are named '{}'. Open a private chat with the peer and try again.", filename)));
+                                continue;
+                            }
+                        };
+
+                        // A download waiting for a slot can only be dropped until it starts
+                        let waiting_file = waiting_downloads.iter().any(|waiting| matches!(waiting, WaitingDownload::File { .. }) && waiting.is(peer, &filename));
+                        if waiting_file && action != TransferAction::Cancel {
+                            let _ = swarm_tx.send(Event::LogMessage(format!("'{}' is waiting for a free download slot. Use /cancel to drop it.", filename)));
+                            continue;
+                        }
+
+                        match (action, direction) {
+                            // --- Cancel A Download ---
+                            (TransferAction::Cancel, TransferDirection::Download) => {
+                                // Out of the line for a download slot, if it was waiting for one
+                                waiting_downloads.retain(|waiting| !waiting.is(peer, &filename));
+                                if waiting_file {
+                                    let request = protocol::PrivateRequest::CancelTransfer { filename: filename.clone(), direction: TransferDirection::Download };
+                                    swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                }
+                                // A bundle is cancelled as a whole: its files under way and the ones still to come
+                                let files: Vec<String> = if bundle_downloads.remove(&(peer, filename.clone())).is_some() {
+                                    let in_bundle = |bundle: &Option<String>| bundle.as_deref() == Some(filename.as_str());
+                                    incoming_transfers_state.get(&peer).into_iter()
+                                        .flat_map(|downloads| downloads.iter().filter(|(_, state)| in_bundle(&state.bundle)).map(|(name, _)| name.clone()))
+                                        .chain(interrupted_downloads.get(&peer).into_iter()
+                                            .flat_map(|downloads| downloads.iter().filter(|(_, record)| in_bundle(&record.bundle)).map(|(name, _)| name.clone())))
+                                        .collect()
+                                } else {
+                                    vec![filename.clone()]
+                                };
+                                let mut cancelled = waiting_file;
+                                for file in files {
+                                    chunk_fetcher.close(peer, &file);
+                                    let Some((swap_id, bundle)) = drop_download(peer, &file, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager) else { continue };
+                                    // A swap can't go ahead with one side missing
+                                    if let Some(swap_id) = swap_id {
+                                        let _ = swap_cancel_tx.send((swap_id, format!("'{}' was cancelled", file), true));
+                                        continue;
+                                    }
+                                    if let Some(name) = bundle {
+                                        let _ = bundle_queue_tx.send((peer, name, BundleUpdate::FileFailed));
+                                    }
+                                    let request = protocol::PrivateRequest::CancelTransfer { filename: file, direction: TransferDirection::Download };
+                                    swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                    cancelled = true;
+                                }
+                                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                if cancelled {
+                                    let _ = swarm_tx.send(Event::TransferCancelled { peer_id: peer, filename, direction, by_peer: false });
+                                }
+                            }
+                            // --- Cancel An Upload ---
+                            (TransferAction::Cancel, TransferDirection::Upload) => {
+                                let upload_key = (peer, filename.clone());
+                                let swap_id = swaps.iter()
+                                    .find(|(_, swap)| swap.peer_id == peer && swap.our_filename == filename && swap.our_file.is_some())
+                                    .map(|(swap_id, _)| swap_id.clone());
+                                if let Some(swap_id) = swap_id {
+                                    let _ = swap_cancel_tx.send((swap_id, format!("'{}' was cancelled", filename), true));
+                                    continue;
+                                }
+                                if let Some(task) = uploads.remove(&upload_key).and_then(|upload| upload.task) {
+                                    task.abort();
+                                }
+                                outgoing_transfers.remove(&upload_key);
+                                // An answer to the offer, if one still comes, must not touch a later offer under the same name
+                                sent_offers.retain(|(offer_peer, _), served| *offer_peer != peer || !served.contains(&filename));
+                                let request = protocol::PrivateRequest::CancelTransfer { filename: filename.clone(), direction: TransferDirection::Upload };
+                                swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                let _ = swarm_tx.send(Event::TransferCancelled { peer_id: peer, filename, direction, by_peer: false });
+                            }
+                            // --- Pause A Download ---
+                            (TransferAction::Pause, TransferDirection::Download) => {
+                                if interrupted_downloads.get(&peer).and_then(|downloads| downloads.get(&filename)).is_some_and(|record| record.paused) {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("'{}' is already paused.", filename)));
+                                    continue;
+                                }
+                                chunk_fetcher.close(peer, &filename);
+                                let Some(progress) = pause_download(peer, &filename, false, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager) else {
+                                    // Only a bundle's name matches without a download of its own
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("'{}' holds several files; pause them one at a time.", filename)));
+                                    continue;
+                                };
+                                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                let request = protocol::PrivateRequest::PauseTransfer { filename: filename.clone(), direction: TransferDirection::Download };
+                                swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                let _ = swarm_tx.send(Event::TransferPaused { peer_id: peer, filename, direction, by_peer: false, progress: Some(progress) });
+                            }
+                            // --- Pause An Upload ---
+                            (TransferAction::Pause, TransferDirection::Upload) => {
+                                let Some(upload) = uploads.get_mut(&(peer, filename.clone())) else { continue };
+                                if upload.paused {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("'{}' is already paused.", filename)));
+                                    continue;
+                                }
+                                upload.paused = true;
+                                // Tell the receiver first, so it keeps its partial file when the stream goes
+                                let request = protocol::PrivateRequest::PauseTransfer { filename: filename.clone(), direction: TransferDirection::Upload };
+                                swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                if let Some(task) = upload.task.take() {
+                                    task.abort();
+                                }
+                                let _ = swarm_tx.send(Event::TransferPaused { peer_id: peer, filename, direction, by_peer: false, progress: None });
+                            }
+                            // --- Resume A Download ---
+                            (TransferAction::Resume, TransferDirection::Download) => {
+                                let Some(record) = interrupted_downloads.get_mut(&peer).and_then(|downloads| downloads.get_mut(&filename)) else {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("'{}' is already running.", filename)));
+                                    continue;
+                                };
+                                if record.paused_by_peer {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("The sender paused '{}'. It continues when they resume it.", filename)));
+                                    continue;
+                                }
+                                let was_paused = record.paused;
+                                record.paused = false;
+                                if was_paused {
+                                    let request = protocol::PrivateRequest::ResumeTransfer { filename: filename.clone(), direction: TransferDirection::Download };
+                                    swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                }
+                                if swarm.is_connected(&peer) {
+                                    if download_slot_free(max_downloads, &waiting_downloads, &incoming_transfers_state, resumes_preparing) && !only_relayed(&connections, &peer) {
+                                        if let Some(record) = interrupted_downloads.get_mut(&peer).and_then(|downloads| downloads.remove(&filename)) {
+                                            start_resume(record, &mut transfer_manager, &mut resumes_preparing);
+                                        }
+                                    } else if !waiting_downloads.iter().any(|waiting| waiting.is(peer, &filename)) {
+                                        waiting_downloads.push_back(WaitingDownload::Resume { peer_id: peer, filename: filename.clone() });
+                                        report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                                    }
+                                } else {
+                                    // The download resumes automatically once the connection is back
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("Peer is not connected. '{}' will resume when they reconnect.", filename)));
+                                    let _ = swarm.dial(peer);
+                                }
+                                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                            }
+                            // --- Resume An Upload ---
+                            (TransferAction::Resume, TransferDirection::Upload) => {
+                                let Some(upload) = uploads.get_mut(&(peer, filename.clone())).filter(|upload| upload.paused) else {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("'{}' isn't paused.", filename)));
+                                    continue;
+                                };
+                                upload.paused = false;
+                                let request = protocol::PrivateRequest::ResumeTransfer { filename: filename.clone(), direction: TransferDirection::Upload };
+                                swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                let _ = swarm_tx.send(Event::TransferResumed { peer_id: peer, filename, direction, by_peer: false });
+                            }
+                        }
+                    }
+                    // --- Download Directory Change Command ---
+                    Command::SetDownloadDir(new_dir) => {
+                        // Update the local download directory path
+                        download_dir = new_dir;
+                    }
+                    // --- Register Outgoing Transfer Command ---
+                    // This is typically used when an offer is accepted by a remote peer
+                    Command::RegisterOutgoingTransfer { peer_id, filename, path } => {
+                        // Store the mapping for an active outgoing transfer.
+                        // A file registered when it was offered keeps the stamp it was hashed with.
+                        let key = (peer_id, sanitize::normalise(&filename));
+                        if outgoing_transfers.get(&key).is_some_and(|offered| offered.path == path) {
+                            continue;
+                        }
+                        match OfferedFile::stamp(path) {
+                            Ok(offered) => {
+                                outgoing_transfers.insert(key, offered);
+                            }
+                            Err(e) => {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm Task] Error: Cannot serve '{}': {}", key.1, e)));
+                            }
+                        }
+                    }
+                    // Declining a swap we don't have with that peer does nothing
+                    Command::DeclineSwap { .. } => {}
+                }
+            }
+
+            // --- Swarm Event Handling ---
+            // Process events generated by the libp2p Swarm
+            ev = swarm.next() => {
+                if let Some(event) = ev {
+                    match event {
+                        // --- Connection Established ---
+                        SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                            // Streams go over any of a peer's connections, so once there is a direct one (e.g. after
+                            // hole punching) the circuits are closed: transfer streams must not end up on a relay.
+                            // Of a new direct connection, the side that answered it closes them: it is the last to
+                            // see the connection, so the other side never finds itself without one in between.
+                            let peer_connections = connections.entry(peer_id).or_default();
+                            peer_connections.insert(connection_id, endpoint.is_relayed());
+                            let answered = matches!(endpoint, ConnectedPoint::Listener { .. } | ConnectedPoint::Dialer { role_override: Endpoint::Listener, .. });
+                            let direct = peer_connections.values().any(|relayed| !relayed);
+                            if direct && (endpoint.is_relayed() || answered) {
+                                let circuits: Vec<ConnectionId> = peer_connections.iter().filter(|(_, relayed)| **relayed).map(|(id, _)| *id).collect();
+                                for circuit in circuits {
+                                    swarm.close_connection(circuit);
+                                }
+                            }
+                            // Pick up any downloads from this peer that were cut off (paused ones wait for /resume),
+                            // as far as there are download slots for them; the rest wait in line.
+                            // A relay only passes on a little data per circuit, so file data waits for a direct connection.
+                            let resumable: Vec<String> = interrupted_downloads
+                                .get(&peer_id)
+                                .filter(|_| !endpoint.is_relayed())
+                                .map(|downloads| downloads.iter().filter(|(_, record)| !record.paused && !record.paused_by_peer).map(|(filename, _)| filename.clone()).collect())
+                                .unwrap_or_default();
+                            let mut queued = false;
+                            for filename in resumable {
+                                if download_slot_free(max_downloads, &waiting_downloads, &incoming_transfers_state, resumes_preparing) {
+                                    if let Some(record) = interrupted_downloads.get_mut(&peer_id).and_then(|downloads| downloads.remove(&filename)) {
+                                        start_resume(record, &mut transfer_manager, &mut resumes_preparing);
+                                    }
+                                } else if !waiting_downloads.iter().any(|waiting| waiting.is(peer_id, &filename)) {
+                                    waiting_downloads.push_back(WaitingDownload::Resume { peer_id, filename });
+                                    queued = true;
+                                }
+                            }
+                            if queued {
+                                report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                            }
+                            // Repeat any SwapReady that may have been lost with the old connection
+                            for (swap_id, swap) in &swaps {
+                                if swap.peer_id == peer_id && swap.held.is_some() && !swap.peer_ready {
+                                    swarm.behaviour_mut().request_response.send_request(&peer_id, protocol::PrivateRequest::SwapReady { swap_id: swap_id.clone() });
+                                }
+                            }
+                            // let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Connection established with: {} ({:?})", peer_id, endpoint.get_remote_address())));
+                            // NEW: If we connected to the Rendezvous point, register ourselves
+                            // (once we have a confirmed external address; until then see "External Addresses")
+                            if rendezvous_peer_ids.contains(&peer_id)
+                                && let Some(ns) = rendezvous_namespace.clone()
+                                && swarm.external_addresses().next().is_some()
+                            {
+                                if let Err(e) = swarm.behaviour_mut().rendezvous.register(ns, peer_id, None) { // Use default TTL (usually 2 hours)
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Failed to send register request to {}: {}", peer_id, e)));
+                                } else {
+                                     let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Sent register request to Rendezvous point: {}", peer_id)));
+                                }
+                            }
+                            // Ask the Rendezvous point for a relay slot, so peers that can't dial us directly can still reach us
+                            if let Some(point) = config.rendezvous_points.iter().find(|point| point.peer_id == peer_id)
+                                && !endpoint.is_relayed()
+                                && !relay_listeners.values().any(|relay| *relay == peer_id)
+                            {
+                                let circuit_addr = point.addr.clone()
+                                    .with(MultiaddrProtocol::P2p(peer_id))
+                                    .with(MultiaddrProtocol::P2pCircuit);
+                                match swarm.listen_on(circuit_addr) {
+                                    Ok(listener_id) => { relay_listeners.insert(listener_id, peer_id); }
+                                    Err(e) => { let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Failed to listen through relay {}: {}", peer_id, e))); }
+                                }
+                            }
+                            // Let the UI mark the peer as online
+                            let _ = swarm_tx.send(Event::PeerConnected(peer_id));
+                        },
+                        // --- Connection Closed ---
+                        SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, .. } => {
+                            if let Some(peer_connections) = connections.get_mut(&peer_id) {
+                                peer_connections.remove(&connection_id);
+                                if peer_connections.is_empty() {
+                                    connections.remove(&peer_id);
+                                }
+                            }
+                            if num_established > 0 {
+                                // Down to a circuit: running downloads stop and wait in line for a direct connection
+                                if only_relayed(&connections, &peer_id) {
+                                    let filenames: Vec<String> = incoming_transfers_state
+                                        .get(&peer_id)
+                                        .map(|downloads| downloads.keys().cloned().collect())
+                                        .unwrap_or_default();
+                                    let held = !filenames.is_empty();
+                                    for filename in filenames {
+                                        chunk_fetcher.close(peer_id, &filename);
+                                        interrupt_download(peer_id, &filename, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager, &swarm_tx);
+                                        waiting_downloads.push_back(WaitingDownload::Resume { peer_id, filename });
+                                    }
+                                    if held {
+                                        report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                    }
+                                }
+                                continue;
+                            }
+                            // A Rendezvous point we lost is registered with again once we reconnect
+                            rendezvous_registered.remove(&peer_id);
+                            // The last connection to this peer is gone, so its downloads can't continue
+                            let filenames: Vec<String> = incoming_transfers_state
+                                .get(&peer_id)
+                                .map(|downloads| downloads.keys().cloned().collect())
+                                .unwrap_or_default();
+                            for filename in filenames {
+                                chunk_fetcher.close(peer_id, &filename);
+                                interrupt_download(peer_id, &filename, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager, &swarm_tx);
+                            }
+                            if !interrupted_downloads.is_empty() {
+                                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                            }
+                            let _ = swarm_tx.send(Event::PeerDisconnected(peer_id));
+                        }
+                        // --- mDNS Events ---
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                            // A peer is announced once per address it listens on (TCP and QUIC)
+                            let mut discovered: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+                            for (peer_id, multiaddr) in list {
+                                discovered.entry(peer_id).or_default().push(multiaddr);
+                            }
+                            for (peer_id, addrs) in discovered {
+                                // Add newly discovered peers to Gossipsub for routing
+                                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                                // Connect over any of its addresses, QUIC first
+                                if !swarm.is_connected(&peer_id) {
+                                    let _ = dial_peer(&mut swarm, peer_id, addrs);
+                                }
+                                // Notify the UI about the discovered peer
+                                let _ = swarm_tx.send(Event::PeerDiscovered(peer_id));
+                                // let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Discovered peer via mDNS: {}", peer_id)));
+                            }
+                        }
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                            for (peer_id, _multiaddr) in list {
+                                // Remove expired peers from Gossipsub
+                                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                            }
+                        }
+                        // --- Gossipsub Message Events ---
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                            propagation_source: peer_id, // The peer who forwarded the message
+                            message_id: _id,
+                            message,
+                        })) => {
+                            // Attempt to deserialize the incoming gossipsub message data
+                            match serde_json::from_slice::<protocol::Message>(&message.data) {
+                                Ok(deserialized_msg) => {
+                                    match deserialized_msg {
+                                        // Handle Heartbeat messages
+                                        protocol::Message::Heartbeat { timestamp_ms: _, nickname } => {
+                                            // Update nickname if provided
+                                            if let Some(nick) = nickname {
+                                                // Use the message source if available (requires signing), else use the forwarder
+                                                let source_peer_id = message.source.unwrap_or(peer_id);
+                                                let _ = swarm_tx.send(Event::NicknameUpdated(source_peer_id, nick.clone()));
+                                                // Offers that arrived before the nickname did may match an auto-accept rule now
+                                                if peer_nicknames.get(&source_peer_id) != Some(&nick) {
+                                                    for ((_, offer_id), offer) in received_offers.iter().filter(|((sender, _), _)| *sender == source_peer_id) {
+                                                        auto_accept(&auto_accept_rules, Some(&nick), download_dir.as_deref(), source_peer_id, offer_id, offer, &own_cmd_tx, &swarm_tx);
+                                                    }
+                                                    peer_nicknames.insert(source_peer_id, nick);
+                                                }
+                                            }
+                                            // The forwarder is still around
+                                            let _ = swarm_tx.send(Event::PeerSeen(peer_id));
+                                        }
+                                        // Handle Global Chat messages
+                                        protocol::Message::GlobalChatMessage { content, timestamp_ms, nickname } => {
+                                            // Send a specific event to the UI for global chat messages
+                                            let source_peer_id = message.source.unwrap_or(peer_id);
+                                            let _ = swarm_tx.send(Event::GlobalMessageReceived {
+                                                sender_id: source_peer_id,
+                                                sender_nickname: nickname,
+                                                content,
+                                                timestamp_ms,
+                                            });
+                                            // The forwarder is still around
+                                            let _ = swarm_tx.send(Event::PeerSeen(peer_id));
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    // Log deserialization error, but the forwarder is still around
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Failed to deserialize gossipsub msg from {}: {}", peer_id, e)));
+                                    let _ = swarm_tx.send(Event::PeerSeen(peer_id));
+                                }
+                            }
+                        }
+                        // --- Request/Response Events ---
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::RequestResponse(event)) => {
+                            match event {
+                                // --- Incoming Request ---
+                                RequestResponseEvent::Message { peer, message, .. } => match message {
+                                    RequestResponseMessage::Request { request, channel, .. } => {
+                                        match request {
+                                            // --- Handle Incoming Private Chat Message ---
+                                            protocol::PrivateRequest::ChatMessage(text) => {
+                                                // Notify UI of the received private message
+                                                if let Err(e) = swarm_tx.send(Event::PrivateMessageReceived {
+                                                    sender_id: peer,
+                                                    content: text,
+                                                }) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending PrivateMessageReceived to UI: {}", e)));
+                                                }
+                                                // Send an acknowledgement response
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                            }
+                                            // --- Handle Incoming File Offer ---
+                                            protocol::PrivateRequest::Offer { offer_id, filename, size_bytes, hash, chunk_size: offered_chunk_size } => {
+                                                // Peers on 1.0.0 offer no hash or ID, and serve their files in chunks of a fixed size.
+                                                // Their offers are given an ID here, to be accepted or declined by.
+                                                let from_old_peer = hash.is_empty() && offered_chunk_size == 0;
+                                                let offer_id = if offer_id.is_empty() { new_received_offer_id(&received_offers, peer) } else { offer_id };
+                                                let offered_chunk_size = if from_old_peer { protocol::LEGACY_CHUNK_SIZE } else { offered_chunk_size };
+                                                // Refuse chunk sizes and file names we couldn't download safely, and names already in use
+                                                let checked = if from_old_peer {
+                                                    sanitize::file_name(&filename).map_err(|e| format!("Unsafe file name: {}", e))
+                                                } else {
+                                                    check_offer(&filename, &hash, offered_chunk_size)
+                                                };
+                                                let checked = checked.and_then(|name| {
+                                                    check_offer_name(peer, Some(&offer_id), &name, &[], &received_offers, &bundle_downloads, &incoming_transfers_state, &waiting_downloads, &interrupted_downloads)
+                                                        .map(|()| name)
+                                                });
+                                                let filename = match checked {
+                                                    Ok(name) => name,
+                                                    Err(error) => {
+                                                        let _ = swarm_tx.send(Event::OfferRejected { peer_id: peer, filename: sanitize::display(&filename), reason: error.clone() });
+                                                        let error_response = protocol::PrivateResponse::TransferError { filename, error };
+                                                        if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, error_response) {
+                                                            let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending TransferError response to {}: {:?}", peer, e)));
+                                                        }
+                                                        continue;
+                                                    }
+                                                };
+                                                // From here on the offer is known by its NFC name
+                                                let offer = ReceivedOffer { filename: filename.clone(), size_bytes, hash: hash.clone(), chunk_size: offered_chunk_size, bundle: None };
+                                                // Notify UI of the received file offer, unless a rule accepts it straight away
+                                                let nickname = peer_nicknames.get(&peer).map(String::as_str);
+                                                if !auto_accept(&auto_accept_rules, nickname, download_dir.as_deref(), peer, &offer_id, &offer, &own_cmd_tx, &swarm_tx)
+                                                    && let Err(e) = swarm_tx.send(Event::FileOfferReceived {
+                                                        sender_id: peer,
+                                                        offer_id: offer_id.clone(),
+                                                        filename: filename.clone(),
+                                                        size_bytes,
+                                                        hash,
+                                                        file_count: None,
+                                                    })
+                                                {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending FileOfferReceived to UI: {}", e)));
+                                                }
+                                                received_offers.insert((peer, offer_id), offer);
+                                                // Send an acknowledgement response
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                            }
+                                            // --- Handle Incoming Bundle Offer ---
+                                            protocol::PrivateRequest::OfferBundle { offer_id, name, files, chunk_size: offered_chunk_size } => {
+                                                // Refuse chunk sizes and manifests we couldn't download safely
+                                                let checked = if let Err(e) = transfer::check_chunk_size(offered_chunk_size) {
+                                                    Err(e)
+                                                } else if let Err(e) = sanitize::file_name(&name) {
+                                                    Err(format!("Unsafe name: {}", e))
+                                                } else {
+                                                    bundle::validate_manifest(&files).map_err(|e| format!("Unusable offer: {}", e))
+                                                };
+                                                let checked = checked.and_then(|total_size| {
+                                                    let paths: Vec<&str> = files.iter().map(|entry| entry.path.as_str()).collect();
+                                                    check_offer_name(peer, Some(&offer_id), &name, &paths, &received_offers, &bundle_downloads, &incoming_transfers_state, &waiting_downloads, &interrupted_downloads)
+                                                        .map(|()| total_size)
+                                                });
+                                                let total_size = match checked {
+                                                    Ok(total_size) => total_size,
+                                                    Err(error) => {
+                                                        let _ = swarm_tx.send(Event::OfferRejected { peer_id: peer, filename: sanitize::display(&name), reason: error.clone() });
+                                                        let error_response = protocol::PrivateResponse::TransferError { filename: name, error };
+                                                        if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, error_response) {
+                                                            let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending TransferError response to {}: {:?}", peer, e)));
+                                                        }
+                                                        continue;
+                                                    }
+                                                };
+                                                let file_count = files.len();
+                                                let bundle = BundleDownload::new(files, total_size, offered_chunk_size);
+                                                let offer = ReceivedOffer { filename: name.clone(), size_bytes: total_size, hash: String::new(), chunk_size: offered_chunk_size, bundle: Some(bundle) };
+                                                // Shown like a single file offer, under the bundle's name, unless a rule accepts it straight away
+                                                let nickname = peer_nicknames.get(&peer).map(String::as_str);
+                                                if !auto_accept(&auto_accept_rules, nickname, download_dir.as_deref(), peer, &offer_id, &offer, &own_cmd_tx, &swarm_tx) {
+                                                    let _ = swarm_tx.send(Event::FileOfferReceived {
+                                                        sender_id: peer,
+                                                        offer_id: offer_id.clone(),
+                                                        filename: name,
+                                                        size_bytes: total_size,
+                                                        hash: String::new(), // Each file carries its own hash
+                                                        file_count: Some(file_count),
+                                                    });
+                                                }
+                                                received_offers.insert((peer, offer_id), offer);
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                            }
+                                            // --- Handle Incoming Decline Offer Message ---
+                                            protocol::PrivateRequest::DeclineOffer { filename, offer_id } => {
+                                                let offer_id = sent_offer_id(&sent_offers, peer, offer_id, &filename);
+                                                // Nobody will ask for its files now
+                                                if let Some(served) = sent_offers.remove(&(peer, offer_id.clone())) {
+                                                    for served_name in served {
+                                                        outgoing_transfers.remove(&(peer, served_name));
+                                                    }
+                                                }
+                                                // Notify UI that the remote peer declined an offer we sent
+                                                if let Err(e) = swarm_tx.send(Event::FileOfferDeclined { peer_id: peer, offer_id, filename }) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending FileOfferDeclined to UI: {}", e)));
+                                                }
+                                                // Send an acknowledgement response
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                            }
+                                            // --- Handle Incoming Accept Offer Message ---
+                                            protocol::PrivateRequest::AcceptOffer { filename, offer_id } => {
+                                                let offer_id = sent_offer_id(&sent_offers, peer, offer_id, &filename);
+                                                // Its files stay registered until they have been sent
+                                                sent_offers.remove(&(peer, offer_id.clone()));
+                                                // Notify UI that the remote peer accepted an offer we sent
+                                                // This typically triggers the `RegisterOutgoingTransfer` command handler
+                                                if let Err(e) = swarm_tx.send(Event::FileOfferAccepted { peer_id: peer, offer_id, filename: filename.clone() }) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending FileOfferAccepted to UI: {}", e)));
+                                                }
+                                                // Send an acknowledgement response
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                            }
+                                            // --- Handle Incoming Swap Proposal ---
+                                            protocol::PrivateRequest::ProposeSwap { swap_id, mut offered, wanted } => {
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                                // Turn down proposals we couldn't go through with
+                                                let checked = if swaps.contains_key(&swap_id) {
+                                                    Err("swap ID already in use".to_string())
+                                                } else {
+                                                    check_offer(&offered.filename, &offered.hash, offered.chunk_size)
+                                                };
+                                                // Their file is downloaded under its name like any other, so it must not be taken
+                                                let checked = checked.and_then(|name| {
+                                                    check_offer_name(peer, None, &name, &[], &received_offers, &bundle_downloads, &incoming_transfers_state, &waiting_downloads, &interrupted_downloads)
+                                                        .map(|()| name)
+                                                });
+                                                match checked {
+                                                    Ok(name) => offered.filename = name,
+                                                    Err(reason) => {
+                                                        let _ = swarm_tx.send(Event::OfferRejected { peer_id: peer, filename: sanitize::display(&offered.filename), reason: reason.clone() });
+                                                        let request = protocol::PrivateRequest::CancelSwap { swap_id, reason };
+                                                        swarm.behaviour_mut().request_response.send_request(&peer, request);
+                                                        continue;
+                                                    }
+                                                }
+                                                // The file they want is looked up among ours by its NFC name
+                                                let wanted = sanitize::normalise(&wanted);
+                                                swaps.insert(swap_id.clone(), Swap {
+                                                    peer_id: peer,
+                                                    proposed_by_us: false,
+                                                    our_filename: wanted.clone(),
+                                                    our_path: None,
+                                                    our_file: None,
+                                                    our_offered: None,
+                                                    their_filename: offered.filename.clone(),
+                                                    their_file: Some(offered.clone()),
+                                                    held: None,
+                                                    peer_ready: false,
+                                                });
+                                                let _ = swarm_tx.send(Event::SwapProposalReceived { sender_id: peer, swap_id, offered, wanted });
+                                            }
+                                            // --- Handle Incoming Swap Acceptance ---
+                                            protocol::PrivateRequest::AcceptSwap { swap_id, mut offered } => {
+                                                let Some(swap) = swaps.get_mut(&swap_id).filter(|swap| swap.peer_id == peer && swap.proposed_by_us && swap.their_file.is_none()) else {
+                                                    let _ = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack);
+                                                    continue;
+                                                };
+                                                // They must commit to the file we asked for, however they spell it
+                                                let checked = if sanitize::normalise(&offered.filename) != swap.their_filename {
+                                                    Err(format!("they offered '{}' instead of '{}'", sanitize::display(&offered.filename), swap.their_filename))
+                                                } else {
+                                                    check_offer(&offered.filename, &offered.hash, offered.chunk_size)
+                                                };
+                                                let checked = checked.and_then(|name| {
+                                                    check_offer_name(peer, None, &name, &[], &received_offers, &bundle_downloads, &incoming_transfers_state, &waiting_downloads, &interrupted_downloads)
+                                                        .map(|()| name)
+                                                });
+                                                match checked {
+                                                    Ok(name) => offered.filename = name,
+                                                    Err(reason) => {
+                                                        let _ = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack);
+                                                        let _ = swap_cancel_tx.send((swap_id, reason, true));
+                                                        continue;
+                                                    }
+                                                }
+                                                let download = open_download(&mut transfer_manager, download_dir.as_deref(), None, &peer, &offered.filename, offered.size_bytes, offered.chunk_size, offered.hash.clone());
+                                                let mut download_state = match download {
+                                                    Ok(download_state) => download_state,
+                                                    Err(e) => {
+                                                        let _ = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack);
+                                                        let _ = swap_cancel_tx.send((swap_id, e, true));
+                                                        continue;
+                                                    }
+                                                };
+                                                swap.their_file = Some(offered);
+                                                // Serve our file before acknowledging, since the peer starts downloading as soon as it sees the Ack
+                                                if let Some(offered) = swap.our_offered.clone() {
+                                                    outgoing_transfers.insert((peer, swap.our_filename.clone()), offered);
+                                                }
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                                let _ = swarm_tx.send(Event::SwapAgreed { peer_id: peer, details: swap.details(&swap_id) });
+                                                download_state.swap_id = Some(swap_id);
+                                                // Like an accepted offer, it waits for a download slot (or a direct connection)
+                                                if only_relayed(&connections, &peer) || !download_slot_free(max_downloads, &waiting_downloads, &incoming_transfers_state, resumes_preparing) {
+                                                    hold_download(peer, swap.their_filename.clone(), download_state, &mut interrupted_downloads, &mut waiting_downloads, &transfer_manager);
+                                                    report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                                                } else {
+                                                    begin_download(&mut chunk_fetcher, peer, swap.their_filename.clone(), download_state, &mut incoming_transfers_state, &mut interrupted_downloads);
+                                                }
+                                                save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                            }
+                                            // --- Handle Incoming Swap Cancellation ---
+                                            protocol::PrivateRequest::CancelSwap { swap_id, reason } => {
+                                                if swaps.get(&swap_id).is_some_and(|swap| swap.peer_id == peer) {
+                                                    let _ = swap_cancel_tx.send((swap_id, reason, false));
+                                                }
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending Ack response to {}: {:?}", peer, e)));
+                                                }
+                                            }
+                                            // --- Handle Incoming Swap Ready ---
+                                            protocol::PrivateRequest::SwapReady { swap_id } => {
+                                                let Some(swap) = swaps.get_mut(&swap_id).filter(|swap| swap.peer_id == peer) else {
+                                                    let _ = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack);
+                                                    continue;
+                                                };
+                                                swap.peer_ready = true;
+                                                // Tell them if we have our side too, in case our own SwapReady went missing
+                                                let response = if swap.held.is_some() {
+                                                    protocol::PrivateResponse::SwapReady { swap_id: swap_id.clone() }
+                                                } else {
+                                                    protocol::PrivateResponse::Ack
+                                                };
+                                                if let Err(e) = swarm.behaviour_mut().request_response.send_response(channel, response) {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Error sending SwapReady response to {}: {:?}", peer, e)));
+                                                }
+                                                if swap.can_release()
+                                                    && let Some(swap) = swaps.remove(&swap_id)
+                                                {
+                                                    release_swap(&swap_id, swap, &mut outgoing_transfers, &transfer_manager);
+                                                }
+                                            }
+                                            // --- Handle Incoming Transfer Cancellation ---
+                                            protocol::PrivateRequest::CancelTransfer { filename, direction } => {
+                                                let _ = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack);
+                                                let direction = direction.reversed(); // Our side of it
+                                                let found = match direction {
+                                                    // They stopped sending: throw away what we have
+                                                    TransferDirection::Download => {
+                                                        chunk_fetcher.close(peer, &filename);
+                                                        let waiting_count = waiting_downloads.len();
+                                                        waiting_downloads.retain(|waiting| !waiting.is(peer, &filename));
+                                                        let was_waiting = waiting_downloads.len() < waiting_count;
+                                                        let dropped = drop_download(peer, &filename, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager);
+                                                        if let Some((swap_id, bundle)) = &dropped {
+                                                            if let Some(swap_id) = swap_id {
+                                                                let _ = swap_cancel_tx.send((swap_id.clone(), format!("they cancelled '{}'", filename), true));
+                                                            }
+                                                            if let Some(name) = bundle {
+                                                                let _ = bundle_queue_tx.send((peer, name.clone(), BundleUpdate::FileFailed));
+                                                            }
+                                                            save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                                        }
+                                                        dropped.is_some() || was_waiting
+                                                    }
+                                                    // They stopped downloading: stop serving the file
+                                                    TransferDirection::Upload => {
+                                                        let upload_key = (peer, filename.clone());
+                                                        if let Some(task) = uploads.remove(&upload_key).and_then(|upload| upload.task) {
+                                                            task.abort();
+                                                        }
+                                                        sent_offers.retain(|(offer_peer, _), served| *offer_peer != peer || !served.contains(&filename));
+                                                        outgoing_transfers.remove(&upload_key).is_some()
+                                                    }
+                                                };
+                                                if found {
+                                                    let _ = swarm_tx.send(Event::TransferCancelled { peer_id: peer, filename, direction, by_peer: true });
+                                                }
+                                            }
+                                            // --- Handle Incoming Transfer Pause ---
+                                            protocol::PrivateRequest::PauseTransfer { filename, direction } => {
+                                                let _ = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack);
+                                                let direction = direction.reversed();
+                                                match direction {
+                                                    // They stopped serving: keep the partial file until they resume
+                                                    TransferDirection::Download => {
+                                                        chunk_fetcher.close(peer, &filename);
+                                                        if let Some(progress) = pause_download(peer, &filename, true, &mut incoming_transfers_state, &mut interrupted_downloads, &transfer_manager) {
+                                                            save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                                            let _ = swarm_tx.send(Event::TransferPaused { peer_id: peer, filename, direction, by_peer: true, progress: Some(progress) });
+                                                        }
+                                                    }
+                                                    // They stopped downloading; their stream closes on its own
+                                                    TransferDirection::Upload => {
+                                                        let upload_key = (peer, filename.clone());
+                                                        if outgoing_transfers.contains_key(&upload_key) {
+                                                            uploads.entry(upload_key).or_default().paused_by_peer = true;
+                                                            let _ = swarm_tx.send(Event::TransferPaused { peer_id: peer, filename, direction, by_peer: true, progress: None });
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                            // --- Handle Incoming Transfer Resume ---
+                                            protocol::PrivateRequest::ResumeTransfer { filename, direction } => {
+                                                let _ = swarm.behaviour_mut().request_response.send_response(channel, protocol::PrivateResponse::Ack);
+                                                let direction = direction.reversed();
+                                                match direction {
+                                                    // They serve the file again: carry on unless we paused it ourselves
+                                                    TransferDirection::Download => {
+                                                        let Some(record) = interrupted_downloads.get_mut(&peer).and_then(|downloads| downloads.get_mut(&filename)).filter(|record| record.paused_by_peer) else { continue };
+                                                        record.paused_by_peer = false;
+                                                        if !record.paused {
+                                                            if download_slot_free(max_downloads, &waiting_downloads, &incoming_transfers_state, resumes_preparing) && !only_relayed(&connections, &peer) {
+                                                                if let Some(record) = interrupted_downloads.get_mut(&peer).and_then(|downloads| downloads.remove(&filename)) {
+                                                                    start_resume(record, &mut transfer_manager, &mut resumes_preparing);
+                                                                }
+                                                            } else if !waiting_downloads.iter().any(|waiting| waiting.is(peer, &filename)) {
+                                                                waiting_downloads.push_back(WaitingDownload::Resume { peer_id: peer, filename: filename.clone() });
+                                                                report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                                                            }
+                                                        }
+                                                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                                        let _ = swarm_tx.send(Event::TransferResumed { peer_id: peer, filename, direction, by_peer: true });
+                                                    }
+                                                    // They are about to open a new stream
+                                                    TransferDirection::Upload => {
+                                                        let Some(upload) = uploads.get_mut(&(peer, filename.clone())) else { continue };
+                                                        upload.paused_by_peer = false;
+                                                        let _ = swarm_tx.send(Event::TransferResumed { peer_id: peer, filename, direction, by_peer: true });
+                                                    }
+                                                }
+                                            }
+                                            // --- Handle Incoming Chunk Request ---
+                                            // Peers on 1.0.0 ask for every chunk of a file they accepted on its own
+                                            protocol::PrivateRequest::RequestChunk { filename, chunk_index } => {
+                                                let Some(offered) = outgoing_transfers.get(&(peer, filename.clone())).cloned() else {
+                                                    let error_response = protocol::PrivateResponse::TransferError { filename, error: transfer::NOT_OFFERED.to_string() };
+                                                    let _ = swarm.behaviour_mut().request_response.send_response(channel, error_response);
+                                                    continue;
+                                                };
+                                                // Read (and held back to the upload limits) off the swarm loop
+                                                let chunk_read_tx = chunk_read_tx.clone();
+                                                let limiter = rate_limiter.clone();
+                                                tokio::spawn(async move {
+                                                    let read = transfer::read_requested_chunk(&offered, chunk_index).await;
+                                                    if let Ok((data, _)) = &read {
+                                                        limiter.throttle(peer, TransferDirection::Upload, data.len() as u64).await;
+                                                    }
+                                                    let _ = chunk_read_tx.send((peer, filename, chunk_index, channel, read));
+                                                });
+                                            }
+                                        }
+                                    }
+                                    // --- Incoming Response ---
+                                    RequestResponseMessage::Response { request_id, response } => {
+                                        match response {
+                                            // --- Handle Acknowledgement Response ---
+                                            protocol::PrivateResponse::Ack => {
+                                                // Acknowledge responses are typically for confirming receipt of messages like Offer, Decline, Accept, ChatMessage.
+                                                // An acknowledged offer has been shown to the peer's user, who accepts or declines it later.
+                                                if offer_requests.remove(&request_id).is_some() {
+                                                    continue;
+                                                }
+                                                // The proposer acknowledging our AcceptSwap means it serves its file now, so our download can start.
+                                                let Some(swap_id) = swap_requests.remove(&request_id) else { continue };
+                                                let Some(swap) = swaps.get(&swap_id).filter(|swap| !swap.proposed_by_us) else { continue };
+                                                let Some(their_file) = swap.their_file.clone() else { continue };
+                                                // The name may have been taken since the proposal arrived
+                                                if let Err(reason) = check_offer_name(peer, None, &their_file.filename, &[], &received_offers, &bundle_downloads, &incoming_transfers_state, &waiting_downloads, &interrupted_downloads) {
+                                                    let _ = swap_cancel_tx.send((swap_id, reason, true));
+                                                    continue;
+                                                }
+                                                match open_download(&mut transfer_manager, download_dir.as_deref(), None, &peer, &their_file.filename, their_file.size_bytes, their_file.chunk_size, their_file.hash) {
+                                                    Ok(mut download_state) => {
+                                                        let _ = swarm_tx.send(Event::SwapAgreed { peer_id: peer, details: swap.details(&swap_id) });
+                                                        download_state.swap_id = Some(swap_id);
+                                                        // Like an accepted offer, it waits for a download slot (or a direct connection)
+                                                        if only_relayed(&connections, &peer) || !download_slot_free(max_downloads, &waiting_downloads, &incoming_transfers_state, resumes_preparing) {
+                                                            hold_download(peer, their_file.filename, download_state, &mut interrupted_downloads, &mut waiting_downloads, &transfer_manager);
+                                                            report_waiting_downloads(&waiting_downloads, &incoming_transfers_state, &interrupted_downloads, &bundle_downloads, &swarm_tx);
+                                                        } else {
+                                                            begin_download(&mut chunk_fetcher, peer, their_file.filename, download_state, &mut incoming_transfers_state, &mut interrupted_downloads);
+                                                        }
+                                                        save_download_store(download_store_path.as_deref(), &incoming_transfers_state, &interrupted_downloads, &transfer_manager);
+                                                    }
+                                                    Err(e) => {
+                                                        let _ = swap_cancel_tx.send((swap_id, e, true));
+                                                    }
+                                                }
+                                            }
+                                            // --- Handle Swap Ready Response ---
+                                            protocol::PrivateResponse::SwapReady { swap_id } => {
+                                                // The peer has its side of the swap as well
+                                                let Some(swap) = swaps.get_mut(&swap_id).filter(|swap| swap.peer_id == peer) else { continue };
+                                                swap.peer_ready = true;
+                                                if swap.can_release()
+                                                    && let Some(swap) = swaps.remove(&swap_id)
+                                                {
+                                                    release_swap(&swap_id, swap, &mut outgoing_transfers, &transfer_manager);
+                                                }
+                                            }
+                                            // --- Handle File Chunk Response ---
+                                            // A chunk from a peer on 1.0.0, handled like one fetched over the transfer protocol
+                                            protocol::PrivateResponse::FileChunk { data, .. } => {
+                                                if let Some(chunk_request) = chunk_requests.remove(&request_id) {
+                                                    chunk_fetcher.answer_chunk_request(chunk_request, Ok(data.into()));
+                                                }
+                                            }
+                                            // --- Handle Transfer Error Response ---
+                                            protocol::PrivateResponse::TransferError { filename, error } => {
+                                                // A peer on 1.0.0 couldn't serve a chunk we asked for
+                                                if let Some(chunk_request) = chunk_requests.remove(&request_id) {
+                                                    chunk_fetcher.answer_chunk_request(chunk_request, Err(Some(error)));
+                                                    continue;
+                                                }
+                                                // The peer refused one of our offers as it arrived (unsafe name, bad chunk size, ...)
+                                                let Some((offer_id, name)) = offer_requests.remove(&request_id) else {
+                                                    let _ = swarm_tx.send(Event::LogMessage(format!(
+                                                        "[Swarm Task] Received TransferError from {} for file '{}': {}",
+                                                        peer, sanitize::display(&filename), error
+                                                    )));
+                                                    continue;
+                                                };
+                                                // Nobody will ask for its files now
+                                                for served_name in sent_offers.remove(&(peer, offer_id.clone())).unwrap_or_default() {
+                                                    outgoing_transfers.remove(&(peer, served_name));
+                                                }
+                                                let _ = swarm_tx.send(Event::FileOfferRejected { peer_id: peer, offer_id, filename: name, reason: error });
+                                            }
+                                        }
+                                    }
+                                }
+                                // --- Outbound Request Failure ---
+                                RequestResponseEvent::OutboundFailure { peer, request_id, error, .. } => {
+                                    // Log failures when sending requests (e.g., network issues, peer disconnected)
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm Task] Outbound RequestResponse Failure to {}: ReqID {:?}, Error: {}", peer, request_id, error)));
+                                    if let Some(chunk_request) = chunk_requests.remove(&request_id) {
+                                        chunk_fetcher.answer_chunk_request(chunk_request, Err(None));
+                                    }
+                                    // Offers of several files and swaps are never sent to peers that only speak 1.0.0 (see `PrivateCodec::write_request`)
+                                    let old_peer = matches!(&error, request_response::OutboundFailure::Io(e) if e.kind() == std::io::ErrorKind::Unsupported);
+                                    if let Some((offer_id, name)) = offer_requests.remove(&request_id)
+                                        && old_peer
+                                    {
+                                        // The peer never saw the offer, so nobody will ask for its files
+                                        for served_name in sent_offers.remove(&(peer, offer_id.clone())).unwrap_or_default() {
+                                            outgoing_transfers.remove(&(peer, served_name));
+                                        }
+                                        let _ = swarm_tx.send(Event::FileOfferRejected { peer_id: peer, offer_id, filename: name, reason: protocol::OLD_PEER.to_string() });
+                                    }
+                                    // A swap that never reached the peer can't go ahead
+                                    if let Some(swap_id) = swap_requests.remove(&request_id) {
+                                        let reason = if old_peer { protocol::OLD_PEER.to_string() } else { format!("the peer could not be reached ({})", error) };
+                                        let _ = swap_cancel_tx.send((swap_id, reason, false));
+                                    }
+                                }
+                                // --- Inbound Request Failure ---
+                                RequestResponseEvent::InboundFailure { peer, request_id, error, .. } => {
+                                    // Log failures processing incoming requests (e.g., deserialization error on our side)
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Inbound RequestResponse failure from {}: Request {:?}, Error: {}", peer, request_id, error)));
+                                }
+                                // --- Response Sent Confirmation ---
+                                RequestResponseEvent::ResponseSent { peer, request_id, .. } => {
+                                    // Optional: Log confirmation that a response was successfully sent
+                                    // Usually not needed unless debugging specific request/response flows
+                                    let _ = peer;
+                                    let _ = request_id;
+                                }
+                            }
+                        },
+                        // --- Rendezvous Client Events ---
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(rendezvous::client::Event::Registered { rendezvous_node, namespace, ttl })) => {
+                            if rendezvous_peer_ids.contains(&rendezvous_node) {
+                                rendezvous_registered.insert(rendezvous_node);
+                                let _ = swarm_tx.send(Event::LogMessage(
+                                    format!("[Swarm] Successfully registered with Rendezvous {} in '{}' (TTL: {}s)", rendezvous_node, namespace, ttl)
+                                ));
+                            } else {
+                                // Log if registered with an unexpected peer
+                                let _ = swarm_tx.send(Event::LogMessage(
+                                    format!("[Swarm] WARN: Registered with unexpected peer {} in '{}'", rendezvous_node, namespace)
+                                ));
+                            }
+                        },
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(rendezvous::client::Event::RegisterFailed { rendezvous_node, namespace, error })) => {
+                            // Namespace is not optional here, use it directly.
+                            // let ns_str = namespace.to_string();
+                            // let _ = swarm_tx.send(Event::LogMessage(
+                            //     format!("[Swarm] Failed to register with Rendezvous {} in '{}': {:?}", rendezvous_node, ns_str, error)
+                            // ));
+                            let _ = rendezvous_node;
+                            let _ = namespace;
+                            let _ = error;
+                        },
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(rendezvous::client::Event::Discovered { registrations, cookie: new_cookie, rendezvous_node })) => {
+                            // Update the cookie for the next incremental discovery request
+                            rendezvous_cookies.insert(rendezvous_node, new_cookie);
+                            let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Received {} registrations from Rendezvous", registrations.len())));
+
+                            for registration in registrations {
+                                let peer = registration.record.peer_id();
+                                // Don't try to dial ourselves or peers we are already connected to
+                                if peer == *swarm.local_peer_id() || swarm.is_connected(&peer) { continue; }
+
+                                // Dial all addresses associated with the discovered peer at once, QUIC first
+                                let addrs = registration.record.addresses().to_vec();
+                                let described = addrs.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+                                if let Err(e) = dial_peer(&mut swarm, peer, addrs) {
+                                    // Avoid logging dial errors for addresses we might already be trying
+                                    let _ = e;
+                                } else {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Dialing discovered peer {} at {}", peer, described)));
+                                    // Add to gossipsub immediately to potentially receive messages sooner
+                                    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                                }
+                                // Send a PeerDiscovered event to the UI so it appears in the list
+                                let _ = swarm_tx.send(Event::PeerDiscovered(peer));
+                            }
+                        },
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(rendezvous::client::Event::DiscoverFailed { rendezvous_node, namespace, error })) => {
+                            // let _ = swarm_tx.send(Event::LogMessage(
+                            //     format!("[Swarm] Failed to discover from Rendezvous {} in {:?}: {:?}", rendezvous_node, namespace, error)
+                            // ));
+                            // Clear the cookie if discovery fails, to retry full discovery next time
+                            rendezvous_cookies.remove(&rendezvous_node);
+                            let _ = namespace;
+                            let _ = error;
+                        },
+                        // --- DHT Events ---
+                        // The first nodes to ask: announce ourselves and look for others without waiting for the timer
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::RoutingUpdated { is_new_peer: true, .. }))
+                            if !dht_providing && dht_provide_query.is_none() => {
+                                query_dht(&mut swarm, &dht_key, dht_providing, &mut dht_provide_query);
+                            }
+                        // Kademlia republishes the record from now on; a failed attempt is retried on the next tick
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, result: kad::QueryResult::StartProviding(result), .. }))
+                            if dht_provide_query == Some(id) => {
+                                dht_provide_query = None;
+                                if result.is_ok() && !dht_providing {
+                                    let _ = swarm_tx.send(Event::LogMessage("[Swarm] Announced ourselves in the DHT".to_string()));
+                                }
+                                dht_providing = result.is_ok();
+                            }
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result: kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })), .. })) => {
+                            for peer in providers {
+                                if peer == *swarm.local_peer_id() { continue; }
+                                // Lookups may already have connected us (Kademlia dials the nodes it asks).
+                                // Otherwise dial; Kademlia knows the addresses the lookup turned up
+                                if !swarm.is_connected(&peer) {
+                                    let _ = dial_peer(&mut swarm, peer, Vec::new());
+                                }
+                                // Records outlive the nodes that left, so each node is reported to the UI once;
+                                // from then on heartbeats tell whether it is still around
+                                if dht_found.insert(peer) {
+                                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Found peer {} in the DHT", peer)));
+                                    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                                    let _ = swarm_tx.send(Event::PeerDiscovered(peer));
+                                }
+                            }
+                        }
+                        // --- Ping Events ---
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Ping(ping::Event { peer, result, .. })) => {
+                            let _ = swarm_tx.send(Event::PingResult { peer_id: peer, result: result.map_err(|e| e.to_string()) });
+                        }
+                        // --- Listen Addresses ---
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            let _ = swarm_tx.send(Event::ListeningOn(address));
+                        }
+                        // --- Relay Events ---
+                        SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                            // A relay that refused us (or went away) is asked again the next time we connect to it
+                            if let Some(relay) = relay_listeners.remove(&listener_id)
+                                && let Err(e) = reason
+                            {
+                                let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Stopped listening through relay {}: {}", relay, e)));
+                            }
+                        }
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal: false, .. })) => {
+                            // The relayed address itself arrives as a confirmed external address
+                            let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Reachable through relay {}", relay_peer_id)));
+                        }
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                            // Either way the peer stays connected; without a direct connection, chat and offers go through the relay
+                            let message = match result {
+                                Ok(_) => format!("[Swarm] Hole punching to {} succeeded: connected directly, waiting downloads can start", remote_peer_id),
+                                Err(e) => format!("[Swarm] Hole punching to {} failed ({}); chat and offers go through the relay, file transfers wait for a direct connection", remote_peer_id, e),
+                            };
+                            let _ = swarm_tx.send(Event::LogMessage(message));
+                        }
+                        // --- External Addresses ---
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                            // Only a candidate so far; AutoNAT checks it by having peers dial us back.
+                            // Over a relayed connection the peer sees the relay, not us.
+                            if !utils::is_relayed(&info.observed_addr) && observed_addresses.insert(info.observed_addr.clone()) {
+                                let _ = swarm_tx.send(Event::ObservedAddress(info.observed_addr));
+                            }
+                            // Any node answering DHT queries goes into the routing table
+                            if info.protocols.contains(&behavior::DHT_PROTOCOL) {
+                                for addr in info.listen_addrs {
+                                    swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                                }
+                            }
+                        }
+                        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. })) => {
+                            let (reachability, message) = match new {
+                                autonat::NatStatus::Public(address) => {
+                                    let message = format!("[Swarm] Reachable from outside at {}", address);
+                                    (Reachability::Public(address), message)
+                                }
+                                autonat::NatStatus::Private => (Reachability::Private, "[Swarm] Not reachable from outside (behind a NAT or firewall); peers reach us through relays".to_string()),
+                                autonat::NatStatus::Unknown => (Reachability::Unknown, "[Swarm] Reachability from outside is unknown again".to_string()),
+                            };
+                            let _ = swarm_tx.send(Event::LogMessage(message));
+                            let _ = swarm_tx.send(Event::ReachabilityChanged(reachability));
+                        }
+                        SwarmEvent::ExternalAddrConfirmed { address } => {
+                            let _ = swarm_tx.send(Event::ExternalAddressConfirmed(address));
+                            // Only confirmed addresses are published, so this is when registering becomes possible
+                            if let Some(ns) = &rendezvous_namespace {
+                                for rendezvous_peer in &rendezvous_peer_ids {
+                                    if swarm.is_connected(rendezvous_peer) && !rendezvous_registered.contains(rendezvous_peer)
+                                        && let Err(e) = swarm.behaviour_mut().rendezvous.register(ns.clone(), *rendezvous_peer, None)
+                                    {
+                                        let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] Failed to send register request to {}: {}", rendezvous_peer, e)));
+                                    }
+                                }
+                            }
+                        }
+                        SwarmEvent::ExternalAddrExpired { address } => {
+                            let _ = swarm_tx.send(Event::ExternalAddressExpired(address));
+                        }
+                        // Nothing else needs handling (or reporting)
+                        _ => {}
+                    }
+                } else {
+                    // The swarm event stream has ended, break the loop
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// --- Download Helpers ---
+
+/// Closes a download whose chunks have all been handed to the transfer manager and checks its hash.
+/// Returns the verified file, still under its temporary name. A mismatch is reported to the UI
+/// and the file is discarded.
+fn verify_download(peer: PeerId, filename: &str, state: DownloadState, transfer_manager: &TransferManager, swarm_tx: &mpsc::UnboundedSender<Event>) -> Option<HeldDownload> {
+    // Sync and close the file once the last chunks are written, before it is renamed or removed
+    let DownloadState { local_path, total_size, expected_hash, hasher, file_id, .. } = state;
+    transfer_manager.close(file_id, true);
+
+    // --- Verify Integrity ---
+    // The file must hash to exactly what the sender offered. Peers on 1.0.0 offer no hash to check against.
+    let actual_hash = hasher.finalize().to_hex().to_string();
+    if !expected_hash.is_empty() && actual_hash != expected_hash {
+        let _ = swarm_tx.send(Event::FileTransferFailed {
+            peer_id: peer,
+            filename: filename.to_string(),
+            error: format!(
+                "Integrity check failed: expected hash {}, got {}. File discarded.",
+                crate::utils::short_hash(&expected_hash),
+                crate::utils::short_hash(&actual_hash)
+            ),
+        });
+        transfer_manager.remove(Some(file_id), local_path);
+        return None;
+    }
+    Some(HeldDownload { file_id, temp_path: local_path, total_size, hash: actual_hash })
+}
+
+/// Checks the hash, chunk size and file name of a single-file offer (or one side of a swap) from a peer.
+/// Returns the name normalised to NFC, which the offer is stored, downloaded and saved under.
+fn check_offer(filename: &str, hash: &str, chunk_size: u64) -> Result<String, String> {
+    // Only sent by versions from before the transfer protocol, which can't serve the file to us
+    if hash.is_empty() {
+        return Err("The sender's version of SwapBytes is too old to send files to this one".to_string());
+    }
+    transfer::check_chunk_size(chunk_size)?;
+    sanitize::file_name(filename).map_err(|e| format!("Unsafe file name: {}", e))
+}
+
+/// Accepts an offer from `peer` without asking if an auto-accept rule matches it and the download directory is
+/// usable. The UI is sent `Event::OfferAutoAccepted`, and the accept goes through `commands` like one of its own.
+/// Returns whether the offer was accepted.
+#[allow(clippy::too_many_arguments)]
+fn auto_accept(
+    rules: &[AutoAcceptRule],
+    nickname: Option<&str>,
+    download_dir: Option<&Path>,
+    peer: PeerId,
+    offer_id: &str,
+    offer: &ReceivedOffer,
+    commands: &mpsc::UnboundedSender<Command>,
+    swarm_tx: &mpsc::UnboundedSender<Event>,
+) -> bool {
+    let Some(rule) = autoaccept::find_rule(rules, &peer, nickname, &offer.filename, offer.size_bytes) else {
+        return false;
+    };
+    let dir_check = match download_dir {
+        None => Err("the download directory is not set".to_string()),
+        Some(dir) => utils::verify_download_directory(&dir.to_string_lossy()).map(|_| ()),
+    };
+    if let Err(e) = dir_check {
+        let _ = swarm_tx.send(Event::LogMessage(format!("Warning: An auto-accept rule matches '{}' from {}, but {}. It waits to be accepted.", offer.filename, peer, e)));
+        return false;
+    }
+    let _ = swarm_tx.send(Event::OfferAutoAccepted {
+        sender_id: peer,
+        offer_id: offer_id.to_string(),
+        filename: offer.filename.clone(),
+        size_bytes: offer.size_bytes,
+        hash: offer.hash.clone(),
+        file_count: offer.bundle.as_ref().map(|bundle| bundle.file_count),
+        rule: rule.to_string(),
+        subdir: rule.subdir.clone(),
+    });
+    let _ = commands.send(Command::SendAcceptOffer { target_peer: peer, offer_id: offer_id.to_string(), filename: offer.filename.clone(), subdir: rule.subdir.clone() });
+    true
+}
+
+/// An ID for an offer from `peer` on 1.0.0, which sends none, unlike those of its other pending offers.
+fn new_received_offer_id(received_offers: &HashMap<(PeerId, String), ReceivedOffer>, peer: PeerId) -> String {
+    loop {
+        let offer_id = utils::new_offer_id();
+        if !received_offers.contains_key(&(peer, offer_id.clone())) {
+            return offer_id;
+        }
+    }
+}
+
+/// The ID of the offer an `AcceptOffer` or `DeclineOffer` from `peer` answers. Peers on 1.0.0 leave it
+/// empty, so it is found from the one file they were offered under `filename`.
+fn sent_offer_id(sent_offers: &HashMap<(PeerId, String), Vec<String>>, peer: PeerId, offer_id: String, filename: &str) -> String {
+    if !offer_id.is_empty() {
+        return offer_id;
+    }
+    sent_offers
+        .iter()
+        .find(|((offered_to, _), served)| *offered_to == peer && served.len() == 1 && served[0] == filename)
+        .map_or(offer_id, |((_, id), _)| id.clone())
+}
+
+/// The first of `names` that a file offered to (or being sent to) `peer` is already served under, if any.
+/// The peer asks for files by name, so a second file under one of them would be mixed up with the first.
+fn name_in_use<'a>(outgoing_transfers: &HashMap<(PeerId, String), OfferedFile>, peer: PeerId, names: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    names.into_iter().find(|name| outgoing_transfers.contains_key(&(peer, name.to_string())))
+}
+
+/// Refuses an offer `offer_id` (or a swap, which has none) from `peer` under `name` (of files downloaded under
+/// `paths`, for a bundle) while its ID or one of those names belongs to another offer from that peer or to a
+/// download from it. Downloads are known by name, so the second would take the first one's place.
+#[allow(clippy::too_many_arguments)]
+fn check_offer_name(
+    peer: PeerId,
+    offer_id: Option<&str>,
+    name: &str,
+    paths: &[&str],
+    received_offers: &HashMap<(PeerId, String), ReceivedOffer>,
+    bundle_downloads: &HashMap<(PeerId, String), BundleDownload>,
+    active: &HashMap<PeerId, HashMap<String, DownloadState>>,
+    waiting: &VecDeque<WaitingDownload>,
+    interrupted: &HashMap<PeerId, HashMap<String, ResumeRecord>>,
+) -> Result<(), String> {
+    if let Some(offer_id) = offer_id
+        && received_offers.contains_key(&(peer, offer_id.to_string()))
+    {
+        return Err(format!("An offer with the ID {} from you is still open", offer_id));
+    }
+    let offered = received_offers.iter().any(|((sender, _), offer)| *sender == peer && offer.filename == name);
+    if offered || bundle_downloads.contains_key(&(peer, name.to_string())) {
+        return Err(format!("An offer of '{}' from you is still open", name));
+    }
+    let downloading = |file: &str| {
+        active.get(&peer).is_some_and(|downloads| downloads.contains_key(file))
+            || interrupted.get(&peer).is_some_and(|downloads| downloads.contains_key(file))
+            || waiting.iter().any(|download| download.is(peer, file))
+    };
+    match std::iter::once(name).chain(paths.iter().copied()).find(|file| downloading(file)) {
+        Some(file) => Err(format!("'{}' is still being downloaded from you", file)),
+        None => Ok(()),
+    }
+}
+
+/// Works out where the temporary file of a new download from `peer_id` goes, in the download directory
+/// (or `subdir` of it), and has the transfer manager create it. Returns the download's initial state.
+#[allow(clippy::too_many_arguments)]
+fn open_download(transfer_manager: &mut TransferManager, download_dir: Option<&Path>, subdir: Option<&str>, peer_id: &PeerId, filename: &str, total_size: u64, chunk_size: u64, hash: String) -> Result<DownloadState, String> {
+    let Some(dir) = download_dir else {
+        return Err("download directory is not set".to_string());
+    };
+    // The name comes from the peer: never let it leave the download directory
+    let local_name = sanitize::file_name(filename)
+        .map_err(|e| format!("unsafe file name '{}': {}", sanitize::display(filename), e))?;
+    // A subdirectory is created on the way
+    let (temp_dir, within) = match subdir {
+        Some(subdir) => (subdirectory(dir, subdir)?, Some(dir.to_path_buf())),
+        None => (dir.to_path_buf(), None),
+    };
+    let temp_path = transfer_manager::temp_path(&temp_dir.join(local_name), peer_id);
+    let file_id = transfer_manager.create(temp_path.clone(), within);
+    DownloadState::new(temp_path.clone(), file_id, total_size, chunk_size, hash)
+        .inspect_err(|_| transfer_manager.remove(Some(file_id), temp_path))
+}
+
+/// Like `open_download`, for one file of a bundle: its directories are rebuilt under the download directory.
+fn open_bundle_download(transfer_manager: &mut TransferManager, download_dir: Option<&Path>, subdir: Option<&str>, peer_id: &PeerId, entry: &protocol::BundleEntry, chunk_size: u64) -> Result<DownloadState, String> {
+    let dir = download_location(download_dir, subdir)?;
+    let dir = dir.as_path();
+    let destination = bundle::entry_destination(dir, &entry.path)?;
+    let temp_path = transfer_manager::temp_path(&destination, peer_id);
+    let file_id = transfer_manager.create(temp_path.clone(), Some(dir.to_path_buf()));
+    DownloadState::new(temp_path.clone(), file_id, entry.size_bytes, chunk_size, entry.hash.clone())
+        .inspect_err(|_| transfer_manager.remove(Some(file_id), temp_path))
+}
+
+/// The directory a download accepted into `subdir` (if any) is saved in, if there is one.
+fn download_location(download_dir: Option<&Path>, subdir: Option<&str>) -> Result<PathBuf, String> {
+    let Some(dir) = download_dir else {
+        return Err("download directory is not set".to_string());
+    };
+    match subdir {
+        Some(subdir) => subdirectory(dir, subdir),
+        None => Ok(dir.to_path_buf()),
+    }
+}
+
+/// The subdirectory of the download directory a download was accepted into (see `Command::SendAcceptOffer`).
+fn subdirectory(download_dir: &Path, subdir: &str) -> Result<PathBuf, String> {
+    let subdir = sanitize::relative_path(subdir)
+        .map_err(|e| format!("unsafe directory '{}': {}", sanitize::display(subdir), e))?;
+    let mut path = download_dir.to_path_buf();
+    path.extend(subdir.split('/'));
+    Ok(path)
+}
+
+/// Fails a download whose file couldn't be created or written: the UI is told, the partial file
+/// is removed, and its swap (if any) is called off and its bundle carries on without it.
+#[allow(clippy::too_many_arguments)]
+fn fail_download(
+    peer: PeerId,
+    filename: &str,
+    error: String,
+    chunk_fetcher: &mut transfer::ChunkFetcher,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    transfer_manager: &TransferManager,
+    swap_cancel_tx: &mpsc::UnboundedSender<(String, String, bool)>,
+    bundle_queue_tx: &mpsc::UnboundedSender<(PeerId, String, BundleUpdate)>,
+    swarm_tx: &mpsc::UnboundedSender<Event>,
+) {
+    let _ = swarm_tx.send(Event::FileTransferFailed { peer_id: peer, filename: filename.to_string(), error });
+    chunk_fetcher.close(peer, filename);
+    let Some(state) = active.get_mut(&peer).and_then(|downloads| downloads.remove(filename)) else { return };
+    // Attempt cleanup of temp file
+    transfer_manager.remove(Some(state.file_id), state.local_path);
+    if let Some(swap_id) = state.swap_id {
+        let _ = swap_cancel_tx.send((swap_id, format!("'{}' could not be saved", filename), true));
+    }
+    if let Some(name) = state.bundle {
+        let _ = bundle_queue_tx.send((peer, name, BundleUpdate::FileFailed));
+    }
+}
+
+/// Starts a new download: fills the window with the first chunk requests and stores its state,
+/// replacing any interrupted download of the same file.
+fn begin_download(
+    chunk_fetcher: &mut transfer::ChunkFetcher,
+    peer_id: PeerId,
+    filename: String,
+    mut state: DownloadState,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &mut HashMap<PeerId, HashMap<String, ResumeRecord>>,
+) {
+    if let Some(downloads) = interrupted.get_mut(&peer_id) {
+        downloads.remove(&filename);
+    }
+    request_chunks(chunk_fetcher, peer_id, &filename, &mut state);
+    active.entry(peer_id).or_default().insert(filename, state);
+}
+
+/// Puts a new download that can't start yet in line as an interrupted one: it starts from the waiting
+/// line like a resume (see `only_relayed`).
+fn hold_download(
+    peer_id: PeerId,
+    filename: String,
+    state: DownloadState,
+    interrupted: &mut HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    waiting: &mut VecDeque<WaitingDownload>,
+    transfer_manager: &TransferManager,
+) {
+    transfer_manager.close(state.file_id, false);
+    interrupted.entry(peer_id).or_default().insert(filename.clone(), resume_record(peer_id, &filename, &state));
+    waiting.push_back(WaitingDownload::Resume { peer_id, filename });
+}
+
+/// Starts files of a bundle while fewer than `BUNDLE_PARALLEL_FILES` of them are under way, taking one of
+/// `free_slots` for each. Returns how many of its files are under way now and whether any started.
+/// Interrupted files count as under way: they pick up again when the peer is back.
+#[allow(clippy::too_many_arguments)]
+fn start_bundle_files(
+    peer_id: PeerId,
+    name: &str,
+    bundle_download: &mut BundleDownload,
+    free_slots: &mut usize,
+    download_dir: Option<&Path>,
+    transfer_manager: &mut TransferManager,
+    chunk_fetcher: &mut transfer::ChunkFetcher,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &mut HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    swarm_tx: &mpsc::UnboundedSender<Event>,
+) -> (usize, bool) {
+    let in_bundle = |bundle: &Option<String>| bundle.as_deref() == Some(name);
+    let mut running = active.get(&peer_id).map_or(0, |downloads| downloads.values().filter(|state| in_bundle(&state.bundle)).count())
+        + interrupted.get(&peer_id).map_or(0, |downloads| downloads.values().filter(|record| in_bundle(&record.bundle)).count());
+    let mut started = false;
+    while running < constants::BUNDLE_PARALLEL_FILES && *free_slots > 0 {
+        let Some(entry) = bundle_download.pending.pop_front() else { break };
+        match open_bundle_download(transfer_manager, download_dir, bundle_download.subdir.as_deref(), &peer_id, &entry, bundle_download.chunk_size) {
+            Ok(mut download_state) => {
+                download_state.bundle = Some(name.to_string());
+                begin_download(chunk_fetcher, peer_id, entry.path, download_state, active, interrupted);
+                running += 1;
+                *free_slots -= 1;
+                started = true;
+            }
+            Err(e) => {
+                let _ = swarm_tx.send(Event::FileTransferFailed {
+                    peer_id,
+                    filename: entry.path,
+                    error: format!("Could not start download: {}", e),
+                });
+                bundle_download.files_failed += 1;
+            }
+        }
+    }
+    (running, started)
+}
+
+/// Sends chunk requests until the download's window is full or every chunk has been requested.
+/// Nothing more is requested while too many chunks are still waiting to be written to disk.
+fn request_chunks(chunk_fetcher: &mut transfer::ChunkFetcher, peer_id: PeerId, filename: &str, state: &mut DownloadState) {
+    while state.in_flight.len() < state.window.size()
+        && state.unwritten < constants::MAX_UNWRITTEN_CHUNKS
+        && state.next_request < state.total_chunks
+    {
+        let request_id = if state.uses_chunk_requests() {
+            chunk_fetcher.request_chunk(peer_id, filename, state.next_request)
+        } else {
+            chunk_fetcher.fetch(peer_id, filename, state.next_request)
+        };
+        state.in_flight.insert(state.next_request, (request_id, Instant::now()));
+        state.next_request += 1;
+    }
+}
+
+// --- Download Slot Helpers ---
+
+/// Whether we reach `peer_id` only through relays. A relay passes on just a little data per circuit,
+/// so downloads from such a peer wait in line until there is a direct connection (e.g. by hole punching).
+fn only_relayed(connections: &HashMap<PeerId, HashMap<ConnectionId, bool>>, peer_id: &PeerId) -> bool {
+    connections.get(peer_id).is_some_and(|peer_connections| peer_connections.values().all(|relayed| *relayed))
+}
+
+/// Number of downloads holding a slot: the running ones and the resumes being prepared.
+fn active_download_count(active: &HashMap<PeerId, HashMap<String, DownloadState>>, resumes_preparing: usize) -> usize {
+    active.values().map(HashMap::len).sum::<usize>() + resumes_preparing
+}
+
+/// Whether a download may start now: a slot is free and no other download is waiting for one.
+fn download_slot_free(
+    max_downloads: usize,
+    waiting: &VecDeque<WaitingDownload>,
+    active: &HashMap<PeerId, HashMap<String, DownloadState>>,
+    resumes_preparing: usize,
+) -> bool {
+    waiting.is_empty() && active_download_count(active, resumes_preparing) < max_downloads
+}
+
+/// Tells the UI where each download waiting for a slot stands in line.
+/// Bundles that have files under way already are left showing their progress.
+fn report_waiting_downloads(
+    waiting: &VecDeque<WaitingDownload>,
+    active: &HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    bundle_downloads: &HashMap<(PeerId, String), BundleDownload>,
+    swarm_tx: &mpsc::UnboundedSender<Event>,
+) {
+    for (index, waiting) in waiting.iter().enumerate() {
+        let progress = match waiting {
+            WaitingDownload::File { size_bytes, .. } => Some((0, *size_bytes)),
+            WaitingDownload::Resume { peer_id, filename } => interrupted
+                .get(peer_id)
+                .and_then(|downloads| downloads.get(filename))
+                .map(|record| (record.received, record.total_size)),
+            WaitingDownload::Bundle { peer_id, name } => {
+                let under_way = active.get(peer_id).is_some_and(|downloads| downloads.values().any(|state| state.bundle.as_deref() == Some(name.as_str())));
+                if under_way {
+                    continue;
+                }
+                bundle_downloads.get(&(*peer_id, name.clone())).map(|bundle_download| (bundle_download.bytes_done, bundle_download.total_size))
+            }
+        };
+        let _ = swarm_tx.send(Event::TransferQueued {
+            peer_id: waiting.peer_id(),
+            filename: waiting.name().to_string(),
+            direction: TransferDirection::Download,
+            place: index as u32 + 1,
+            by_peer: false,
+            progress,
+        });
+    }
+}
+
+// --- Swap Helpers ---
+
+/// Hashes our side of a swap on a blocking thread. The result is picked up by the swarm loop,
+/// which then sends the proposal or acceptance committing to it.
+fn spawn_swap_hash(swap_id: String, file_path: PathBuf, swap_hashed_tx: &mpsc::UnboundedSender<(String, std::io::Result<(String, OfferedFile)>)>) {
+    let swap_hashed_tx = swap_hashed_tx.clone();
+    tokio::task::spawn_blocking(move || {
+        let hash_result = OfferedFile::hash(file_path);
+        let _ = swap_hashed_tx.send((swap_id, hash_result));
+    });
+}
+
+/// Finishes a swap once both sides have their file: releases our held download and stops serving ours.
+/// The swap is reported complete once the download has its final name (see "Disk Events").
+fn release_swap(swap_id: &str, swap: Swap, outgoing_transfers: &mut HashMap<(PeerId, String), OfferedFile>, transfer_manager: &TransferManager) {
+    // The peer has verified our file, so it no longer needs serving
+    outgoing_transfers.remove(&(swap.peer_id, swap.our_filename.clone()));
+    let details = swap.details(swap_id);
+    let Some(held) = swap.held else { return };
+    let then = AfterSave::Swap { details, total_size: held.total_size, hash: held.hash };
+    transfer_manager.save(held.file_id, swap.peer_id, swap.their_filename, held.temp_path, then);
+}
+
+/// Drops the download of a cancelled swap, whether it is running, interrupted or finished and held, and
+/// deletes its file. Returns whether it was running, so its chunk requests can be closed.
+fn discard_swap_download(
+    swap_id: &str,
+    swap: &Swap,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &mut HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    transfer_manager: &TransferManager,
+) -> bool {
+    let peer = swap.peer_id;
+    let is_this_swap = |id: &Option<String>| id.as_deref() == Some(swap_id);
+    let mut running = false;
+    if active.get(&peer).and_then(|downloads| downloads.get(&swap.their_filename)).is_some_and(|state| is_this_swap(&state.swap_id))
+        && let Some(state) = active.get_mut(&peer).and_then(|downloads| downloads.remove(&swap.their_filename))
+    {
+        transfer_manager.remove(Some(state.file_id), state.local_path);
+        running = true;
+    }
+    if interrupted.get(&peer).and_then(|downloads| downloads.get(&swap.their_filename)).is_some_and(|record| is_this_swap(&record.swap_id))
+        && let Some(record) = interrupted.get_mut(&peer).and_then(|downloads| downloads.remove(&swap.their_filename))
+    {
+        transfer_manager.remove(None, record.temp_path);
+    }
+    if let Some(held) = &swap.held {
+        transfer_manager.remove(Some(held.file_id), held.temp_path.clone());
+    }
+    running
+}
+
+// --- Resume Helpers ---
+
+/// Captures the current position of a download so it can be resumed later.
+fn resume_record(peer_id: PeerId, filename: &str, state: &DownloadState) -> ResumeRecord {
+    ResumeRecord {
+        peer_id: peer_id.to_base58(),
+        filename: filename.to_string(),
+        hash: state.expected_hash.clone(),
+        total_size: state.total_size,
+        received: state.received,
+        next_chunk: state.next_chunk,
+        chunk_size: state.chunk_size,
+        temp_path: state.local_path.clone(),
+        swap_id: state.swap_id.clone(),
+        bundle: state.bundle.clone(),
+        paused: false,
+        paused_by_peer: false,
+    }
+}
+
+/// Has the transfer manager rewrite the download store with every active and interrupted download.
+fn save_download_store(
+    store_path: Option<&Path>,
+    active: &HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    transfer_manager: &TransferManager,
+) {
+    let Some(store_path) = store_path else { return };
+    let mut records: Vec<ResumeRecord> = active
+        .iter()
+        .flat_map(|(peer_id, downloads)| downloads.iter().map(move |(filename, state)| {
+            // Running downloads are saved as of their last checkpoint, which is on disk for sure
+            let mut record = resume_record(*peer_id, filename, state);
+            (record.received, record.next_chunk) = state.checkpoint;
+            record
+        }))
+        .collect();
+    records.extend(interrupted.values().flat_map(|downloads| downloads.values().cloned()));
+    // Swaps only live in memory, so their downloads can't be picked up after a restart
+    records.retain(|record| record.swap_id.is_none());
+    transfer_manager.save_store(store_path.to_path_buf(), records);
+}
+
+/// Stops an active download and keeps its partial file so it can be resumed.
+fn interrupt_download(
+    peer_id: PeerId,
+    filename: &str,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &mut HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    transfer_manager: &TransferManager,
+    swarm_tx: &mpsc::UnboundedSender<Event>,
+) {
+    let Some(record) = stop_download(peer_id, filename, active, transfer_manager) else { return };
+    let _ = swarm_tx.send(Event::DownloadInterrupted {
+        peer_id,
+        filename: filename.to_string(),
+        received: record.received,
+        total: record.total_size,
+    });
+    interrupted.entry(peer_id).or_default().insert(filename.to_string(), record);
+}
+
+/// Pauses a download, running or interrupted, so it is only picked up again by `/resume`
+/// (or, if the sender paused it, once they resume it). Returns how far it got: (received, total).
+fn pause_download(
+    peer_id: PeerId,
+    filename: &str,
+    by_peer: bool,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &mut HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    transfer_manager: &TransferManager,
+) -> Option<(u64, u64)> {
+    let mut record = match stop_download(peer_id, filename, active, transfer_manager) {
+        Some(record) => record,
+        None => interrupted.get_mut(&peer_id).and_then(|downloads| downloads.remove(filename))?,
+    };
+    if by_peer {
+        record.paused_by_peer = true;
+    } else {
+        record.paused = true;
+    }
+    let progress = (record.received, record.total_size);
+    interrupted.entry(peer_id).or_default().insert(filename.to_string(), record);
+    Some(progress)
+}
+
+/// Removes an active download from `active` and captures where it stopped.
+/// The caller closes its stream.
+fn stop_download(
+    peer_id: PeerId,
+    filename: &str,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    transfer_manager: &TransferManager,
+) -> Option<ResumeRecord> {
+    let state = active.get_mut(&peer_id).and_then(|downloads| downloads.remove(filename))?;
+    // The file is closed after the chunks already handed over, so everything counted in `received` ends up on disk.
+    // A resume goes through the transfer manager too, so it can't start before then.
+    transfer_manager.close(state.file_id, false);
+    Some(resume_record(peer_id, filename, &state))
+}
+
+/// Throws away a download, running or interrupted, and its partial file. The caller closes its stream.
+/// Returns the swap and bundle it was part of, or `None` if there was no such download.
+fn drop_download(
+    peer_id: PeerId,
+    filename: &str,
+    active: &mut HashMap<PeerId, HashMap<String, DownloadState>>,
+    interrupted: &mut HashMap<PeerId, HashMap<String, ResumeRecord>>,
+    transfer_manager: &TransferManager,
+) -> Option<(Option<String>, Option<String>)> {
+    if let Some(state) = active.get_mut(&peer_id).and_then(|downloads| downloads.remove(filename)) {
+        let DownloadState { local_path, file_id, swap_id, bundle, .. } = state;
+        transfer_manager.remove(Some(file_id), local_path);
+        return Some((swap_id, bundle));
+    }
+    let record = interrupted.get_mut(&peer_id).and_then(|downloads| downloads.remove(filename))?;
+    transfer_manager.remove(None, record.temp_path);
+    Some((record.swap_id, record.bundle))
+}
+
+/// Has the transfer manager check, re-hash and reopen the partial file of an interrupted download.
+/// The result is picked up by the swarm loop, which then requests the remaining chunks.
+/// The download holds a slot from now on, counted in `resumes_preparing` until the result is in.
+fn start_resume(record: ResumeRecord, transfer_manager: &mut TransferManager, resumes_preparing: &mut usize) {
+    *resumes_preparing += 1;
+    transfer_manager.resume(record);
+}
+
+/// Finds the active download writing to the transfer manager's file `file_id`.
+fn download_by_file(active: &mut HashMap<PeerId, HashMap<String, DownloadState>>, file_id: u64) -> Option<(PeerId, String, &mut DownloadState)> {
+    active.iter_mut().find_map(|(peer_id, downloads)| {
+        downloads.iter_mut().find(|(_, state)| state.file_id == file_id).map(|(filename, state)| (*peer_id, filename.clone(), state))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn offers_are_stored_and_saved_under_their_nfc_name() {
+        // "e" followed by a combining acute accent, as macOS spells it
+        let offered = "cafe\u{301}.txt";
+        let name = check_offer(offered, "hash", constants::MIN_CHUNK_SIZE as u64).unwrap();
+        assert_eq!(name, "caf\u{e9}.txt");
+
+        let (events_tx, _events_rx) = mpsc::unbounded_channel();
+        let (swarm_tx, _swarm_rx) = mpsc::unbounded_channel();
+        let mut transfer_manager = TransferManager::new(events_tx, swarm_tx);
+        // A directory that doesn't exist, so nothing is written
+        let dir = Path::new("/nonexistent/downloads");
+        let peer_id = PeerId::random();
+        let download = open_download(&mut transfer_manager, Some(dir), None, &peer_id, offered, 1, constants::MIN_CHUNK_SIZE as u64, String::new()).unwrap();
+        assert_eq!(download.local_path, transfer_manager::temp_path(&dir.join("caf\u{e9}.txt"), &peer_id));
+
+        // The same name offered by another peer is downloaded to a temporary file of its own
+        let other = open_download(&mut transfer_manager, Some(dir), None, &PeerId::random(), offered, 1, constants::MIN_CHUNK_SIZE as u64, String::new()).unwrap();
+        assert_ne!(other.local_path, download.local_path);
+    }
+
+    #[test]
+    fn offers_with_unsafe_names_or_chunk_sizes_are_refused() {
+        assert!(check_offer("../evil", "hash", constants::MIN_CHUNK_SIZE as u64).is_err());
+        assert!(check_offer("fine.txt", "hash", constants::MAX_CHUNK_SIZE as u64 + 1).is_err());
+        // An offer from a version that predates file hashes
+        assert!(check_offer("fine.txt", "", 0).is_err());
+    }
+
+    #[test]
+    fn offers_are_only_accepted_with_somewhere_to_save_them() {
+        let dir = Path::new("/downloads");
+        assert!(download_location(None, None).is_err());
+        assert!(download_location(Some(dir), Some("../elsewhere")).is_err());
+        assert_eq!(download_location(Some(dir), None).unwrap(), dir);
+        assert_eq!(download_location(Some(dir), Some("photos/2024")).unwrap(), dir.join("photos").join("2024"));
+    }
+
+    #[test]
+    fn offers_a_rule_matches_are_accepted_by_the_node() {
+        let dir = std::env::temp_dir().join(format!("swapbytes-autoaccept-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let peer = PeerId::random();
+        let rules = vec![AutoAcceptRule { nickname: Some("alice".to_string()), max_size: Some(100), subdir: Some("from-alice".to_string()), ..Default::default() }];
+        let offer = ReceivedOffer { filename: "notes.txt".to_string(), size_bytes: 10, hash: "hash".to_string(), chunk_size: constants::MIN_CHUNK_SIZE as u64, bundle: None };
+        let (commands_tx, mut commands_rx) = mpsc::unbounded_channel();
+        let (swarm_tx, mut swarm_rx) = mpsc::unbounded_channel();
+
+        // The rule names a nickname, so nothing matches until the peer has announced one
+        assert!(!auto_accept(&rules, None, Some(&dir), peer, "a1", &offer, &commands_tx, &swarm_tx));
+        assert!(swarm_rx.try_recv().is_err());
+        // Without a download directory the offer waits to be accepted
+        assert!(!auto_accept(&rules, Some("alice"), None, peer, "a1", &offer, &commands_tx, &swarm_tx));
+        assert!(matches!(swarm_rx.try_recv(), Ok(Event::LogMessage(_))));
+        assert!(commands_rx.try_recv().is_err());
+
+        assert!(auto_accept(&rules, Some("alice"), Some(&dir), peer, "a1", &offer, &commands_tx, &swarm_tx));
+        assert!(matches!(
+            swarm_rx.try_recv(),
+            Ok(Event::OfferAutoAccepted { sender_id, offer_id, subdir, .. }) if sender_id == peer && offer_id == "a1" && subdir.as_deref() == Some("from-alice")
+        ));
+        assert!(matches!(
+            commands_rx.try_recv(),
+            Ok(Command::SendAcceptOffer { target_peer, offer_id, subdir, .. }) if target_peer == peer && offer_id == "a1" && subdir.as_deref() == Some("from-alice")
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn answers_from_peers_on_1_0_0_are_matched_to_the_offer_by_name() {
+        let peer = PeerId::random();
+        let mut sent_offers = HashMap::new();
+        sent_offers.insert((peer, "offer-1".to_string()), vec!["report.pdf".to_string()]);
+        sent_offers.insert((peer, "offer-2".to_string()), vec!["photos/a.jpg".to_string(), "photos/b.jpg".to_string()]);
+
+        assert_eq!(sent_offer_id(&sent_offers, peer, "offer-9".to_string(), "report.pdf"), "offer-9");
+        assert_eq!(sent_offer_id(&sent_offers, peer, String::new(), "report.pdf"), "offer-1");
+        // Only single files are offered to them, and only this peer's offers count
+        assert_eq!(sent_offer_id(&sent_offers, peer, String::new(), "photos/a.jpg"), "");
+        assert_eq!(sent_offer_id(&sent_offers, PeerId::random(), String::new(), "report.pdf"), "");
+    }
+
+    #[tokio::test]
+    async fn downloads_are_hashed_in_order_and_checked_against_the_offer() {
+        let chunk_size = constants::MIN_CHUNK_SIZE as u64;
+        let contents: Vec<u8> = (0..chunk_size * 4 + 100).map(|i| (i % 251) as u8).collect();
+        let offered_hash = blake3::hash(&contents).to_hex().to_string();
+        let (events_tx, _events_rx) = mpsc::unbounded_channel();
+        let (manager_tx, _manager_rx) = mpsc::unbounded_channel();
+        let transfer_manager = TransferManager::new(events_tx, manager_tx);
+        let peer = PeerId::random();
+
+        // The chunks arrive in a different order than they were requested in
+        let download = |expected_hash: &str| {
+            let mut state = DownloadState::new(PathBuf::from("/nonexistent/file.bin.tmp"), 0, contents.len() as u64, chunk_size, expected_hash.to_string()).unwrap();
+            // Chunks after a gap are held back until it is filled: (chunk, first chunk not hashed yet)
+            for (chunk_index, next_chunk) in [(3, 0), (0, 1), (4, 1), (2, 1), (1, 5)] {
+                let start = (chunk_index * chunk_size) as usize;
+                let end = (start + chunk_size as usize).min(contents.len());
+                state.hash_in_order(chunk_index, Bytes::copy_from_slice(&contents[start..end]));
+                assert_eq!(state.next_chunk, next_chunk);
+            }
+            assert_eq!((state.next_chunk, state.received), (state.total_chunks, contents.len() as u64));
+            assert!(state.out_of_order.is_empty());
+            state
+        };
+
+        let (swarm_tx, mut swarm_rx) = mpsc::unbounded_channel();
+        let held = verify_download(peer, "file.bin", download(&offered_hash), &transfer_manager, &swarm_tx).unwrap();
+        assert_eq!(held.hash, offered_hash);
+        assert!(swarm_rx.try_recv().is_err());
+
+        // Bytes that don't hash to what was offered are discarded and reported
+        let other_hash = blake3::hash(b"something else").to_hex().to_string();
+        assert!(verify_download(peer, "file.bin", download(&other_hash), &transfer_manager, &swarm_tx).is_none());
+        match swarm_rx.try_recv() {
+            Ok(Event::FileTransferFailed { peer_id, filename, error }) => {
+                assert_eq!((peer_id, filename.as_str()), (peer, "file.bin"));
+                assert!(error.contains("Integrity check failed"), "{}", error);
+            }
+            other => panic!("expected FileTransferFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_second_offer_under_a_pending_name_is_refused() {
+        let peer = PeerId::random();
+        let other_peer = PeerId::random();
+        let offered = OfferedFile { path: PathBuf::from("/files/one/report.pdf"), stamp: transfer::FileStamp { size: 1, modified: None } };
+
+        // Sending: the first offer of 'report.pdf' is served under that name until it is answered or sent
+        let mut outgoing_transfers = HashMap::new();
+        assert_eq!(name_in_use(&outgoing_transfers, peer, ["report.pdf"]), None);
+        outgoing_transfers.insert((peer, "report.pdf".to_string()), offered);
+        assert_eq!(name_in_use(&outgoing_transfers, peer, ["report.pdf"]), Some("report.pdf"));
+        assert_eq!(name_in_use(&outgoing_transfers, peer, ["notes.txt", "report.pdf"]), Some("report.pdf"));
+        assert_eq!(name_in_use(&outgoing_transfers, other_peer, ["report.pdf"]), None);
+
+        // Receiving: two offers of 'report.pdf' from one peer would share one download
+        let mut received_offers = HashMap::new();
+        let bundle_downloads = HashMap::new();
+        let mut active = HashMap::new();
+        let waiting = VecDeque::new();
+        let interrupted = HashMap::new();
+        let check = |peer, offer_id, name, paths: &[&str], received_offers: &HashMap<(PeerId, String), ReceivedOffer>, active: &HashMap<PeerId, HashMap<String, DownloadState>>| {
+            check_offer_name(peer, Some(offer_id), name, paths, received_offers, &bundle_downloads, active, &waiting, &interrupted)
+        };
+        assert!(check(peer, "a1", "report.pdf", &[], &received_offers, &active).is_ok());
+        let offer = ReceivedOffer { filename: "report.pdf".to_string(), size_bytes: 1, hash: "hash".to_string(), chunk_size: constants::MIN_CHUNK_SIZE as u64, bundle: None };
+        received_offers.insert((peer, "a1".to_string()), offer);
+        assert!(check(peer, "b2", "report.pdf", &[], &received_offers, &active).is_err());
+        assert!(check(other_peer, "b2", "report.pdf", &[], &received_offers, &active).is_ok());
+        // Nor may a second offer take the first one's ID
+        assert!(check(peer, "a1", "notes.txt", &[], &received_offers, &active).is_err());
+        assert!(check(other_peer, "a1", "notes.txt", &[], &received_offers, &active).is_ok());
+
+        // Once accepted, it is a download of that name until it finishes
+        received_offers.clear();
+        let download = DownloadState::new(PathBuf::from("/downloads/report.pdf.part"), 0, 1, constants::MIN_CHUNK_SIZE as u64, String::new()).unwrap();
+        active.entry(peer).or_default().insert("report.pdf".to_string(), download);
+        assert!(check(peer, "b2", "report.pdf", &[], &received_offers, &active).is_err());
+        // Also as one of the files of a bundle
+        assert!(check(peer, "b2", "reports", &["reports/report.pdf", "report.pdf"], &received_offers, &active).is_err());
+        assert!(check(peer, "b2", "reports", &["reports/summary.pdf"], &received_offers, &active).is_ok());
+        // A swap has no offer ID, but its file is downloaded under its name all the same
+        assert!(check_offer_name(peer, None, "report.pdf", &[], &received_offers, &bundle_downloads, &active, &waiting, &interrupted).is_err());
+        assert!(check_offer_name(peer, None, "summary.pdf", &[], &received_offers, &bundle_downloads, &active, &waiting, &interrupted).is_ok());
+        received_offers.insert((peer, "c3".to_string()), ReceivedOffer { filename: "summary.pdf".to_string(), size_bytes: 1, hash: "hash".to_string(), chunk_size: constants::MIN_CHUNK_SIZE as u64, bundle: None });
+        assert!(check_offer_name(peer, None, "summary.pdf", &[], &received_offers, &bundle_downloads, &active, &waiting, &interrupted).is_err());
+    }
+
+    #[tokio::test]
+    async fn held_swap_downloads_are_saved_on_release_and_deleted_on_cancel() {
+        let dir = std::env::temp_dir().join(format!("swapbytes-swap-release-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let (swarm_tx, _swarm_rx) = mpsc::unbounded_channel();
+        let transfer_manager = TransferManager::new(events_tx, swarm_tx);
+        let peer = PeerId::random();
+        let held_swap = |file_id, their_filename: &str| {
+            let temp_path = transfer_manager::temp_path(&dir.join(their_filename), &peer);
+            fs::write(&temp_path, b"theirs").unwrap();
+            Swap {
+                peer_id: peer,
+                proposed_by_us: true,
+                our_filename: "ours.txt".to_string(),
+                our_path: None,
+                our_file: None,
+                our_offered: None,
+                their_filename: their_filename.to_string(),
+                their_file: None,
+                held: Some(HeldDownload { file_id, temp_path, total_size: 6, hash: "hash".to_string() }),
+                peer_ready: false,
+            }
+        };
+
+        // Cancelled while held: nothing is saved and the temporary file goes
+        let cancelled = held_swap(0, "cancelled.txt");
+        let cancelled_temp = cancelled.held.as_ref().unwrap().temp_path.clone();
+        let mut active = HashMap::new();
+        let mut interrupted = HashMap::new();
+        assert!(!discard_swap_download("s1", &cancelled, &mut active, &mut interrupted, &transfer_manager));
+
+        // Held until the peer has verified its side, then saved under its name
+        let mut released = held_swap(1, "theirs.txt");
+        assert!(!released.can_release());
+        released.peer_ready = true;
+        assert!(released.can_release());
+        let offered = OfferedFile { path: dir.join("ours.txt"), stamp: transfer::FileStamp { size: 1, modified: None } };
+        let mut outgoing_transfers = HashMap::from([((peer, "ours.txt".to_string()), offered)]);
+        release_swap("s2", released, &mut outgoing_transfers, &transfer_manager);
+        assert!(outgoing_transfers.is_empty());
+        match events_rx.recv().await {
+            Some(DiskEvent::Saved { filename, then: AfterSave::Swap { details, .. }, result: Ok(path), .. }) => {
+                assert_eq!(filename, "theirs.txt");
+                assert_eq!(details.swap_id, "s2");
+                assert_eq!(path, dir.join("theirs.txt"));
+                assert_eq!(fs::read(&path).unwrap(), b"theirs");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        // The disk task works in order, so the cancelled file is gone by now
+        assert!(!cancelled_temp.exists());
+        assert!(!dir.join("cancelled.txt").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1069 @@
+/*
+Moves file data between peers: the `/swapbytes/transfer/1.0.0` chunk protocol
+and the flow control that decides how many chunk requests are kept in flight.
+
+The receiver opens one substream per download and names the file once.
+It then writes chunk requests as its window allows, without waiting for
+earlier answers, and the sender answers them in the same order:
+
+    open:     [filename length: u16][filename: UTF-8]
+    request:  [chunk index: u64]
+    response: [status: u8 = 0][data length: u32][data]
+           or [status: u8 = 1][message length: u16][message: UTF-8]
+    notice:   [status: u8 = 2][place in line: u32]
+
+A sender that is already serving as many uploads as it allows keeps the stream
+waiting and sends notices instead of answers: the receiver's place in its upload
+queue, whenever it changes and every `QUEUE_NOTICE_INTERVAL` so the stream doesn't
+look dead. The answers follow once it is the receiver's turn.
+
+A bandwidth limit can hold either side back for longer than the other waits. Until
+the limit lets the next chunk through, the held-back side keeps the stream alive
+every `QUEUE_NOTICE_INTERVAL`: the sender with a notice of place 0, the receiver
+with a request for chunk 2^64 - 1, which is not answered.
+
+The sender closes the stream after an error response. The receiver closes it
+once the download is complete. All integers are big-endian.
+*/
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use libp2p::{swarm::Stream, PeerId, StreamProtocol};
+use libp2p_stream::{Control, OpenStreamError};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::protocol::TransferDirection;
+use crate::ratelimit::RateLimiter;
+use crate::constants::{
+    CHUNK_REQUEST_TIMEOUT, INITIAL_CHUNK_WINDOW, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, MAX_CHUNK_WINDOW, MIN_CHUNK_WINDOW,
+    PROGRESS_UPDATE_BYTES, QUEUE_NOTICE_INTERVAL, SERVED_FILE_CHECK_INTERVAL, TRANSFER_PROTOCOL_NAME, TRANSFER_STREAM_ATTEMPTS,
+    TRANSFER_STREAM_RETRY_DELAY,
+};
+
+/// The chunk transfer protocol.
+pub const TRANSFER_PROTOCOL: StreamProtocol = StreamProtocol::new(TRANSFER_PROTOCOL_NAME);
+
+/// Response status: the chunk data follows.
+const STATUS_OK: u8 = 0;
+/// Response status: an error message follows.
+const STATUS_ERROR: u8 = 1;
+/// Notice status: the receiver's place in the upload queue follows.
+const STATUS_QUEUED: u8 = 2;
+/// The chunk index of a receiver's keepalive, which the sender doesn't answer. No file has that many chunks.
+const KEEPALIVE_CHUNK: u64 = u64::MAX;
+/// The error a sender answers with when it doesn't (or no longer) offer the file, e.g. after a restart.
+/// The receiver keeps its partial file, since the sender may offer the file again.
+pub const NOT_OFFERED: &str = "No active transfer found for this file";
+/// How much is read from a transfer stream at once.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Sliding window of outstanding chunk requests, sized from measured round-trip times.
+///
+/// Works like TCP Vegas: while the smoothed RTT stays close to the lowest RTT seen,
+/// the link isn't queueing and the window grows. Once requests start waiting in a queue
+/// (the smoothed RTT climbs well above the minimum) the window shrinks again.
+#[derive(Debug, Clone)]
+pub struct ChunkWindow {
+    /// Number of requests allowed in flight right now.
+    size: usize,
+    /// Exponentially weighted moving average of the RTT.
+    srtt: Option<Duration>,
+    /// Lowest RTT seen, taken as the RTT of an idle link.
+    min_rtt: Option<Duration>,
+}
+
+impl Default for ChunkWindow {
+    fn default() -> Self {
+        ChunkWindow {
+            size: INITIAL_CHUNK_WINDOW,
+            srtt: None,
+            min_rtt: None,
+        }
+    }
+}
+
+impl ChunkWindow {
+    /// Number of chunk requests that may be in flight.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Records the time between sending a chunk request and receiving its chunk,
+    /// and resizes the window accordingly.
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        // Same smoothing factor (1/8) as TCP.
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+        let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+        self.srtt = Some(srtt);
+        self.min_rtt = Some(min_rtt);
+
+        if srtt < min_rtt * 3 / 2 {
+            // Little queueing: there is room for more requests.
+            self.size = (self.size + 1).min(MAX_CHUNK_WINDOW);
+        } else if srtt > min_rtt * 2 {
+            // Requests are piling up behind each other: back off.
+            self.size = self.size.saturating_sub(1).max(MIN_CHUNK_WINDOW);
+        }
+    }
+}
+
+// --- Receiving Side ---
+
+/// Represents the state of an ongoing file download.
+#[derive(Debug)]
+pub struct DownloadState {
+    /// The local path where the file is being saved (likely temporary initially).
+    pub local_path: PathBuf,
+    /// The total expected size of the file in bytes.
+    pub total_size: u64,
+    /// How many bytes have been received without gaps from the start of the file.
+    pub received: u64,
+    /// The index of the first chunk we haven't received yet.
+    pub next_chunk: u64,
+    /// The file being written to disk, as the transfer manager knows it (see `transfer_manager`).
+    pub file_id: u64,
+    /// Chunks handed to the transfer manager that aren't on disk yet.
+    pub unwritten: usize,
+    /// The last position known to be on disk: (received, next chunk). Resume points are saved from here.
+    pub checkpoint: (u64, u64),
+    /// The BLAKE3 hash (hex) the sender announced in its offer.
+    pub expected_hash: String,
+    /// Running hash of the bytes received so far.
+    pub hasher: blake3::Hasher,
+    /// Size in bytes of the chunks the sender serves this file in.
+    pub chunk_size: u64,
+    /// Number of chunks making up the file.
+    pub total_chunks: u64,
+    /// The index of the next chunk to request.
+    pub next_request: u64,
+    /// Chunks requested but not received yet. Value: (request ID, time the request was sent).
+    pub in_flight: HashMap<u64, (u64, std::time::Instant)>,
+    /// Chunks that arrived ahead of `next_chunk`. They are already on disk,
+    /// but are kept until the gap before them is filled so the hash can be computed in order.
+    pub out_of_order: BTreeMap<u64, Bytes>,
+    /// Decides how many chunk requests may be in flight.
+    pub window: ChunkWindow,
+    /// The swap this download is one side of, if any. Swap downloads are held back once verified.
+    pub swap_id: Option<String>,
+    /// The bundle this file is part of, if any.
+    pub bundle: Option<String>,
+    /// Our place in the sender's upload queue, while it hasn't started serving us.
+    pub queued_place: Option<u32>,
+}
+
+impl DownloadState {
+    /// Creates the state for a download that starts at the beginning of the file.
+    ///
+    /// The chunk size comes from the sender's offer or the download store, so it is checked here too.
+    pub fn new(local_path: PathBuf, file_id: u64, total_size: u64, chunk_size: u64, expected_hash: String) -> Result<Self, String> {
+        check_chunk_size(chunk_size)?;
+        Ok(DownloadState {
+            local_path,
+            total_size,
+            received: 0,
+            next_chunk: 0,
+            file_id,
+            unwritten: 0,
+            checkpoint: (0, 0),
+            expected_hash,
+            hasher: blake3::Hasher::new(),
+            chunk_size,
+            // An empty file still takes one (empty) chunk
+            total_chunks: total_size.div_ceil(chunk_size).max(1),
+            next_request: 0,
+            in_flight: HashMap::new(),
+            out_of_order: BTreeMap::new(),
+            window: ChunkWindow::default(),
+            swap_id: None,
+            bundle: None,
+            queued_place: None,
+        })
+    }
+
+    /// Whether the file is fetched with `RequestChunk` messages: a download from a peer on 1.0.0 of the
+    /// private protocol, which has no transfer protocol and sends no hash with its offers.
+    pub fn uses_chunk_requests(&self) -> bool {
+        self.expected_hash.is_empty()
+    }
+
+    /// Feeds the running hash with chunk `chunk_index` and every chunk held back that now follows on
+    /// without a gap. A chunk that arrives ahead of a gap waits in `out_of_order` until it is filled.
+    pub fn hash_in_order(&mut self, chunk_index: u64, data: Bytes) {
+        self.out_of_order.insert(chunk_index, data);
+        while let Some(chunk) = self.out_of_order.remove(&self.next_chunk) {
+            self.hasher.update(&chunk);
+            self.received += chunk.len() as u64;
+            self.next_chunk += 1;
+        }
+    }
+}
+
+/// Checks that `chunk_size` is one a sender may serve a file in (see `MIN_CHUNK_SIZE` and `MAX_CHUNK_SIZE`).
+pub fn check_chunk_size(chunk_size: u64) -> Result<(), String> {
+    if !(MIN_CHUNK_SIZE as u64..=MAX_CHUNK_SIZE as u64).contains(&chunk_size) {
+        return Err(format!("Unsupported chunk size: {} bytes", chunk_size));
+    }
+    Ok(())
+}
+
+/// How a chunk request ended.
+#[derive(Debug)]
+pub enum ChunkOutcome {
+    /// The chunk's bytes.
+    Data(Bytes),
+    /// The sender couldn't serve the chunk (e.g. the file can't be read or has changed).
+    Refused(String),
+    /// The sender doesn't offer the file (any more), e.g. because it restarted. The partial file is kept.
+    NotOffered,
+    /// The stream broke or timed out. The download can be resumed later.
+    Failed(io::Error),
+}
+
+/// The result of a chunk request, reported back to the swarm loop.
+#[derive(Debug)]
+pub struct ChunkResult {
+    pub peer_id: PeerId,
+    pub filename: String,
+    pub chunk_index: u64,
+    /// Identifies the request, so results of requests from before an interruption can be told apart.
+    pub request_id: u64,
+    pub outcome: ChunkOutcome,
+}
+
+/// What a download's stream reports back to the swarm loop.
+#[derive(Debug)]
+pub enum FetchEvent {
+    /// A chunk request was answered, or failed.
+    Chunk(ChunkResult),
+    /// The sender is busy with other uploads; ours is `place` in its line (1 is next).
+    Queued { peer_id: PeerId, filename: String, place: u32 },
+}
+
+/// A chunk request waiting to be written to a download's stream: `(chunk index, request ID)`.
+type QueuedRequest = (u64, u64);
+/// A chunk request for the swarm loop to send as a `RequestChunk` message: `(peer, filename, chunk index, request ID)`.
+pub type ChunkRequest = (PeerId, String, u64, u64);
+/// A parsed response: the chunk's bytes, or the sender's error message.
+type ChunkResponse = Result<Bytes, String>;
+
+/// Something read from a transfer stream: an answer, or a notice of our place in the sender's queue.
+enum Response {
+    Chunk(ChunkResponse),
+    Queued(u32),
+}
+
+/// Fetches chunks in background tasks and reports each one as a `ChunkResult` (see `FetchEvent`).
+///
+/// Each download gets its own stream, which is opened on the first request
+/// and kept until the download is closed.
+pub struct ChunkFetcher {
+    control: Control,
+    results_tx: mpsc::UnboundedSender<FetchEvent>,
+    /// Holds back answers (and with them, new requests) to stay within the download limits.
+    limiter: Arc<RateLimiter>,
+    next_request_id: u64,
+    /// Request queues of the download streams, by peer and filename.
+    sessions: HashMap<(PeerId, String), mpsc::UnboundedSender<QueuedRequest>>,
+    /// Chunk requests of downloads from peers on 1.0.0, which the swarm loop sends as `RequestChunk`.
+    chunk_requests_tx: mpsc::UnboundedSender<ChunkRequest>,
+}
+
+impl ChunkFetcher {
+    pub fn new(
+        control: Control,
+        results_tx: mpsc::UnboundedSender<FetchEvent>,
+        chunk_requests_tx: mpsc::UnboundedSender<ChunkRequest>,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        ChunkFetcher { control, results_tx, limiter, next_request_id: 0, sessions: HashMap::new(), chunk_requests_tx }
+    }
+
+    /// Requests a chunk from a peer on 1.0.0, through the swarm loop, and returns the request's ID.
+    /// The answer is passed to `answer_chunk_request`.
+    pub fn request_chunk(&mut self, peer_id: PeerId, filename: &str, chunk_index: u64) -> u64 {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let _ = self.chunk_requests_tx.send((peer_id, filename.to_string(), chunk_index, request_id));
+        request_id
+    }
+
+    /// Reports the answer to a `request_chunk` request like any other chunk, once the download
+    /// limits allow. `Err` is the sender's error message; a `None` chunk means the request failed.
+    pub fn answer_chunk_request(&self, request: ChunkRequest, answer: Result<Bytes, Option<String>>) {
+        let (peer_id, filename, chunk_index, request_id) = request;
+        let results_tx = self.results_tx.clone();
+        let limiter = self.limiter.clone();
+        tokio::spawn(async move {
+            let outcome = match answer {
+                Ok(data) => {
+                    limiter.throttle(peer_id, TransferDirection::Download, data.len() as u64).await;
+                    ChunkOutcome::Data(data)
+                }
+                Err(Some(message)) if message == NOT_OFFERED => ChunkOutcome::NotOffered,
+                Err(Some(message)) => ChunkOutcome::Refused(message),
+                Err(None) => ChunkOutcome::Failed(io::Error::new(io::ErrorKind::ConnectionAborted, "the chunk request failed")),
+            };
+            let _ = results_tx.send(FetchEvent::Chunk(ChunkResult { peer_id, filename, chunk_index, request_id, outcome }));
+        });
+    }
+
+    /// Requests a chunk and returns the request's ID.
+    pub fn fetch(&mut self, peer_id: PeerId, filename: &str, chunk_index: u64) -> u64 {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        let key = (peer_id, filename.to_string());
+        // A stream that broke has reported its failure already, so start over on a new one
+        let request = match self.sessions.get(&key) {
+            Some(requests_tx) => requests_tx.send((chunk_index, request_id)).err().map(|e| e.0),
+            None => Some((chunk_index, request_id)),
+        };
+        if let Some(request) = request {
+            let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+            let _ = requests_tx.send(request);
+            tokio::spawn(run_session(self.control.clone(), peer_id, filename.to_string(), requests_rx, self.results_tx.clone(), self.limiter.clone()));
+            self.sessions.insert(key, requests_tx);
+        }
+        request_id
+    }
+
+    /// Closes the stream of a download that finished or stopped.
+    /// Requests still in flight are dropped without a result.
+    pub fn close(&mut self, peer_id: PeerId, filename: &str) {
+        self.sessions.remove(&(peer_id, filename.to_string()));
+    }
+}
+
+/// Runs one download's stream until it is closed or breaks.
+/// If it breaks, every request that hasn't been answered is reported as failed.
+async fn run_session(
+    mut control: Control,
+    peer_id: PeerId,
+    filename: String,
+    mut requests: mpsc::UnboundedReceiver<QueuedRequest>,
+    results_tx: mpsc::UnboundedSender<FetchEvent>,
+    limiter: Arc<RateLimiter>,
+) {
+    let mut pending = VecDeque::new();
+    let mut answered = false;
+    let mut attempt = 1;
+    let error = loop {
+        match drive_session(&mut control, peer_id, &filename, &mut requests, &mut pending, &mut answered, &results_tx, &limiter).await {
+            Ok(()) => return,
+            // A busy sender drops new streams it can't take yet, so give a fresh stream a few tries
+            Err(_) if !answered && attempt < TRANSFER_STREAM_ATTEMPTS => {
+                tokio::time::sleep(TRANSFER_STREAM_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(e) => break e,
+        }
+    };
+
+    requests.close();
+    while let Ok(request) = requests.try_recv() {
+        pending.push_back(request);
+    }
+    for (chunk_index, request_id) in pending {
+        let outcome = ChunkOutcome::Failed(io::Error::new(error.kind(), error.to_string()));
+        let _ = results_tx.send(FetchEvent::Chunk(ChunkResult { peer_id, filename: filename.clone(), chunk_index, request_id, outcome }));
+    }
+}
+
+/// Opens a stream for `filename`, re-sends any `pending` requests and then pipelines new ones,
+/// reporting each answer as it arrives. Returns `Ok(())` once the download is closed.
+///
+/// `pending` holds the requests written but not answered yet, oldest first. Answers are passed on
+/// no faster than the download limits allow; the swarm loop only sends new requests as answers
+/// come in, so this paces the requests too.
+#[allow(clippy::too_many_arguments)]
+async fn drive_session(
+    control: &mut Control,
+    peer_id: PeerId,
+    filename: &str,
+    requests: &mut mpsc::UnboundedReceiver<QueuedRequest>,
+    pending: &mut VecDeque<QueuedRequest>,
+    answered: &mut bool,
+    results_tx: &mpsc::UnboundedSender<FetchEvent>,
+    limiter: &RateLimiter,
+) -> io::Result<()> {
+    let mut stream = control.open_stream(peer_id, TRANSFER_PROTOCOL).await.map_err(|e| match e {
+        OpenStreamError::Io(e) => e,
+        other => io::Error::other(other.to_string()),
+    })?;
+
+    // --- Open ---
+    let name = filename.as_bytes();
+    let name_len = u16::try_from(name.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "filename too long"))?;
+    let mut header = Vec::with_capacity(2 + name.len() + 8 * pending.len());
+    header.extend_from_slice(&name_len.to_be_bytes());
+    header.extend_from_slice(name);
+    for (chunk_index, _) in pending.iter() {
+        header.extend_from_slice(&chunk_index.to_be_bytes());
+    }
+    stream.write_all(&header).await?;
+    stream.flush().await?;
+
+    let mut received = Vec::new();
+    let mut read_buf = vec![0u8; READ_BUFFER_SIZE];
+    let mut deadline = Instant::now() + CHUNK_REQUEST_TIMEOUT;
+    loop {
+        tokio::select! {
+            // --- Requests ---
+            request = requests.recv() => {
+                let Some((chunk_index, request_id)) = request else {
+                    // The download is closed; nobody is waiting for the remaining answers
+                    let _ = stream.close().await;
+                    return Ok(());
+                };
+                if pending.is_empty() {
+                    deadline = Instant::now() + CHUNK_REQUEST_TIMEOUT;
+                }
+                stream.write_all(&chunk_index.to_be_bytes()).await?;
+                stream.flush().await?;
+                pending.push_back((chunk_index, request_id));
+            }
+
+            // --- Responses ---
+            read = stream.read(&mut read_buf), if !pending.is_empty() => {
+                let n = read?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the sender closed the stream"));
+                }
+                received.extend_from_slice(&read_buf[..n]);
+                // Answers arrive in the order the requests were written
+                while let Some((response, len)) = parse_response(&received)? {
+                    received.drain(..len);
+                    let response = match response {
+                        Response::Chunk(response) => response,
+                        Response::Queued(place) => {
+                            // Not an answer: the requests are served once it is our turn,
+                            // or (place 0) once the sender's upload limit lets the next chunk through
+                            deadline = Instant::now() + CHUNK_REQUEST_TIMEOUT;
+                            if place > 0 {
+                                let _ = results_tx.send(FetchEvent::Queued { peer_id, filename: filename.to_string(), place });
+                            }
+                            continue;
+                        }
+                    };
+                    let Some((chunk_index, request_id)) = pending.pop_front() else {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "answer to a chunk that wasn't requested"));
+                    };
+                    *answered = true;
+                    if let Ok(data) = &response {
+                        let wait = limiter.reserve(peer_id, TransferDirection::Download, data.len() as u64);
+                        wait_throttled(&mut stream, wait, &KEEPALIVE_CHUNK.to_be_bytes()).await?;
+                    }
+                    deadline = Instant::now() + CHUNK_REQUEST_TIMEOUT;
+                    let refused = response.is_err();
+                    let outcome = match response {
+                        Ok(data) => ChunkOutcome::Data(data),
+                        Err(message) if message == NOT_OFFERED => ChunkOutcome::NotOffered,
+                        Err(message) => ChunkOutcome::Refused(message),
+                    };
+                    let _ = results_tx.send(FetchEvent::Chunk(ChunkResult { peer_id, filename: filename.to_string(), chunk_index, request_id, outcome }));
+                    if refused {
+                        // The sender closes the stream after an error
+                        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the sender stopped the transfer"));
+                    }
+                }
+            }
+
+            // --- Timeout ---
+            _ = tokio::time::sleep_until(deadline), if !pending.is_empty() => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "chunk request timed out"));
+            }
+        }
+    }
+}
+
+/// Parses the response at the start of `buf`, if it has arrived completely.
+/// Returns the chunk data (or the sender's error message, or our place in its queue) and the number of bytes it took up.
+fn parse_response(buf: &[u8]) -> io::Result<Option<(Response, usize)>> {
+    let Some(&status) = buf.first() else { return Ok(None) };
+    match status {
+        STATUS_OK => {
+            let Some(len) = buf.get(1..5) else { return Ok(None) };
+            let len = u32::from_be_bytes(len.try_into().expect("slice has 4 bytes")) as usize;
+            if len > MAX_CHUNK_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("chunk of {} bytes exceeds the limit", len)));
+            }
+            Ok(buf.get(5..5 + len).map(|data| (Response::Chunk(Ok(Bytes::copy_from_slice(data))), 5 + len)))
+        }
+        STATUS_ERROR => {
+            let Some(len) = buf.get(1..3) else { return Ok(None) };
+            let len = u16::from_be_bytes(len.try_into().expect("slice has 2 bytes")) as usize;
+            Ok(buf.get(3..3 + len).map(|message| (Response::Chunk(Err(String::from_utf8_lossy(message).into_owned())), 3 + len)))
+        }
+        STATUS_QUEUED => {
+            let Some(place) = buf.get(1..5) else { return Ok(None) };
+            let place = u32::from_be_bytes(place.try_into().expect("slice has 4 bytes"));
+            Ok(Some((Response::Queued(place), 5)))
+        }
+        other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown response status {}", other))),
+    }
+}
+
+// --- Sending Side ---
+
+/// An upload the swarm loop is serving, or has paused.
+#[derive(Debug, Default)]
+pub struct Upload {
+    /// The task answering the receiver's transfer stream, while one is open.
+    pub task: Option<tokio::task::AbortHandle>,
+    /// The task's slot ID with the upload scheduler, to look up its place in line while it waits.
+    pub slot: Option<u64>,
+    /// We paused it: new transfer streams for the file are turned away until it is resumed.
+    pub paused: bool,
+    /// The receiver paused it, so no stream is expected until they resume.
+    pub paused_by_peer: bool,
+}
+
+impl Upload {
+    /// Whether the upload is still going (or paused). Uploads whose stream has closed otherwise
+    /// are over: the receiver either has the file or will open a new stream to resume.
+    pub fn is_live(&self) -> bool {
+        self.paused || self.paused_by_peer || self.task.as_ref().is_some_and(|task| !task.is_finished())
+    }
+}
+
+/// Reads the name of the requested file at the start of an incoming transfer stream.
+pub async fn read_transfer_request(stream: &mut Stream) -> io::Result<String> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut name = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut name).await?;
+    String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Keeps the receiver posted on its place in the upload queue until `place` drops to 0
+/// and it is this upload's turn. `on_queued` is called whenever the place changes.
+///
+/// Fails if the stream breaks or the upload leaves the queue without getting a turn.
+pub async fn wait_for_turn(stream: &mut Stream, mut place: watch::Receiver<u32>, mut on_queued: impl FnMut(u32)) -> io::Result<()> {
+    let mut told = 0;
+    loop {
+        let current = *place.borrow_and_update();
+        if current == 0 {
+            return Ok(());
+        }
+        if current != told {
+            on_queued(current);
+            told = current;
+        }
+        stream.write_all(&queued_frame(current)).await?;
+        stream.flush().await?;
+        // Repeat the notice now and then even if nothing moved, so the receiver doesn't time out
+        tokio::select! {
+            changed = place.changed() => changed.map_err(|_| io::Error::other("the upload left the queue"))?,
+            _ = tokio::time::sleep(QUEUE_NOTICE_INTERVAL) => {}
+        }
+    }
+}
+
+/// Why serving a transfer stream ended early.
+#[derive(Debug)]
+pub enum ServeError {
+    /// The stream broke or went idle. The receiver can resume with a new stream.
+    Interrupted(String),
+    /// The file can't be served: it isn't offered, can't be read or has changed since it was offered.
+    /// The receiver has been sent the reason; it keeps its partial file only if the file isn't offered.
+    Refused(String),
+}
+
+impl std::fmt::Display for ServeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServeError::Interrupted(message) | ServeError::Refused(message) => f.write_str(message),
+        }
+    }
+}
+
+/// Answers the chunk requests on a transfer stream with chunks read from `offered`
+/// until the receiver closes the stream. If the file isn't offered (`None`), can't be read
+/// or no longer matches its offer, the receiver is sent an error and the stream is closed.
+///
+/// `on_progress` is called with (how far into the file the receiver has got, file size) after the
+/// first chunk and then every `PROGRESS_UPDATE_BYTES`, like the receiver's own progress updates.
+/// Chunks are sent no faster than the upload limits for `peer_id` allow.
+///
+/// Returns whether the receiver had got to the end of the file when it closed the stream
+/// (a receiver that pauses closes it part-way).
+pub async fn serve_transfer(
+    mut stream: Stream,
+    peer_id: PeerId,
+    offered: Option<&OfferedFile>,
+    chunk_size: usize,
+    limiter: &RateLimiter,
+    mut on_progress: impl FnMut(u64, u64),
+) -> Result<bool, ServeError> {
+    let Some(offered) = offered else {
+        let message = NOT_OFFERED.to_string();
+        let _ = send_error(&mut stream, &message).await;
+        return Err(ServeError::Refused(message));
+    };
+    let path = offered.path.as_path();
+    // Opened once for the whole stream and closed when this returns (or the task is aborted)
+    let mut file = match ServedFile::open(offered).await {
+        Ok(file) => file,
+        Err(e) => {
+            let message = e.describe(path, "Failed to open");
+            let _ = send_error(&mut stream, &message).await;
+            return Err(ServeError::Refused(message));
+        }
+    };
+    let file_size = offered.stamp.size;
+    // The furthest byte sent so far. A resumed download starts part-way, so this is not the bytes sent on this stream.
+    let mut position: Option<u64> = None;
+
+    loop {
+        // A receiver that stops asking without closing the stream is given up on
+        let mut chunk_index = [0u8; 8];
+        match tokio::time::timeout(CHUNK_REQUEST_TIMEOUT, stream.read_exact(&mut chunk_index)).await {
+            Ok(Ok(())) => {}
+            // The receiver closed the stream: the download is done
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Ok(Err(e)) => return Err(ServeError::Interrupted(format!("Transfer stream of '{}' broke: {}", path.display(), e))),
+            Err(_) => return Err(ServeError::Interrupted(format!("Transfer stream of '{}' went idle", path.display()))),
+        }
+        let chunk_index = u64::from_be_bytes(chunk_index);
+        if chunk_index == KEEPALIVE_CHUNK {
+            // The receiver's download limit is holding it back
+            continue;
+        }
+
+        let data = match file.read_chunk(chunk_index, chunk_size).await {
+            Ok(data) => data,
+            Err(e) => {
+                let message = e.describe(path, &format!("Failed to read chunk {} of", chunk_index));
+                let _ = send_error(&mut stream, &message).await;
+                return Err(ServeError::Refused(message));
+            }
+        };
+        let send_failed = |e: io::Error| ServeError::Interrupted(format!("Failed to send chunk {}: {}", chunk_index, e));
+        let wait = limiter.reserve(peer_id, TransferDirection::Upload, data.len() as u64);
+        wait_throttled(&mut stream, wait, &queued_frame(0)).await.map_err(send_failed)?;
+        let response = chunk_frame(&data);
+        stream.write_all(&response).await.map_err(send_failed)?;
+        stream.flush().await.map_err(send_failed)?;
+
+        let chunk_end = chunk_index * chunk_size as u64 + data.len() as u64;
+        let previous = position;
+        let current = previous.unwrap_or(0).max(chunk_end);
+        position = Some(current);
+        let crossed_marker = previous.is_none_or(|previous| previous / PROGRESS_UPDATE_BYTES < current / PROGRESS_UPDATE_BYTES);
+        if crossed_marker || (current == file_size && previous != Some(current)) {
+            on_progress(current, file_size);
+        }
+    }
+
+    let _ = stream.close().await;
+    Ok(position.unwrap_or(0) >= file_size)
+}
+
+/// Waits out `wait`, for which a bandwidth limit holds the next chunk back, writing `keepalive`
+/// every `QUEUE_NOTICE_INTERVAL` so the other side doesn't give up on the stream meanwhile.
+async fn wait_throttled(stream: &mut (impl AsyncWriteExt + Unpin), wait: Duration, keepalive: &[u8]) -> io::Result<()> {
+    let until = Instant::now() + wait;
+    loop {
+        let next = Instant::now() + QUEUE_NOTICE_INTERVAL;
+        if next >= until {
+            tokio::time::sleep_until(until).await;
+            return Ok(());
+        }
+        tokio::time::sleep_until(next).await;
+        stream.write_all(keepalive).await?;
+        stream.flush().await?;
+    }
+}
+
+/// Sends an error response and closes the stream.
+async fn send_error(stream: &mut Stream, message: &str) -> io::Result<()> {
+    stream.write_all(&error_frame(message)).await?;
+    stream.close().await
+}
+
+/// A response carrying a chunk's bytes.
+fn chunk_frame(data: &[u8]) -> Vec<u8> {
+    let mut response = Vec::with_capacity(5 + data.len());
+    response.push(STATUS_OK);
+    response.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    response.extend_from_slice(data);
+    response
+}
+
+/// A response carrying an error message, cut off at the longest length the frame can state.
+fn error_frame(message: &str) -> Vec<u8> {
+    let bytes = &message.as_bytes()[..message.len().min(u16::MAX as usize)];
+    let mut response = Vec::with_capacity(3 + bytes.len());
+    response.push(STATUS_ERROR);
+    response.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    response.extend_from_slice(bytes);
+    response
+}
+
+/// A notice of the receiver's place in the upload queue.
+fn queued_frame(place: u32) -> [u8; 5] {
+    let mut notice = [0u8; 5];
+    notice[0] = STATUS_QUEUED;
+    notice[1..].copy_from_slice(&place.to_be_bytes());
+    notice
+}
+
+// --- Served Files ---
+
+/// Size and modification time of a file when it was offered. The offer's hash only describes
+/// the file as long as neither changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    /// `None` on platforms that don't record it.
+    pub modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        FileStamp { size: metadata.len(), modified: metadata.modified().ok() }
+    }
+}
+
+/// A file we offered to a peer, as it was when it was offered.
+#[derive(Debug, Clone)]
+pub struct OfferedFile {
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+}
+
+impl OfferedFile {
+    /// Stamps `path` as it is now.
+    pub fn stamp(path: PathBuf) -> io::Result<Self> {
+        let stamp = FileStamp::of(&std::fs::metadata(&path)?);
+        Ok(OfferedFile { path, stamp })
+    }
+
+    /// Hashes `path` for an offer and stamps it. Fails if the file changes while it is being hashed,
+    /// since the hash could then match neither version. Blocks, so call it on a blocking thread.
+    pub fn hash(path: PathBuf) -> io::Result<(String, Self)> {
+        let mut file = std::fs::File::open(&path)?;
+        let stamp = FileStamp::of(&file.metadata()?);
+        let hash = crate::utils::hash_reader(&mut file)?;
+        if FileStamp::of(&file.metadata()?) != stamp {
+            return Err(io::Error::other("the file changed while it was being hashed"));
+        }
+        Ok((hash, OfferedFile { path, stamp }))
+    }
+}
+
+/// An offer a peer sent us, kept by its ID until it is accepted or declined.
+#[derive(Debug)]
+pub struct ReceivedOffer {
+    /// The name it was offered (and is downloaded) under.
+    pub filename: String,
+    pub size_bytes: u64,
+    /// BLAKE3 hash of the file. Empty from a peer on 1.0.0, and for a bundle, whose files carry their own.
+    pub hash: String,
+    /// Size in bytes of the chunks the file is served in.
+    pub chunk_size: u64,
+    /// The files of an offer of several, downloaded under `filename`.
+    pub bundle: Option<crate::bundle::BundleDownload>,
+}
+
+/// Why a chunk of a served file couldn't be read.
+#[derive(Debug)]
+enum ServedFileError {
+    /// The file's size or modification time no longer match its offer.
+    Changed,
+    Io(io::Error),
+}
+
+impl ServedFileError {
+    /// The message sent to the receiver, e.g. "Failed to open '/files/a.txt': ...".
+    fn describe(&self, path: &Path, action: &str) -> String {
+        match self {
+            ServedFileError::Changed => format!("'{}' has changed since it was offered", path.display()),
+            ServedFileError::Io(e) => format!("{} '{}': {}", action, path.display(), e),
+        }
+    }
+}
+
+/// An offered file held open while a transfer stream is served. Chunks are read at their
+/// offset, so nothing is reopened or seeked per chunk. Closed when dropped.
+struct ServedFile {
+    file: Arc<std::fs::File>,
+    stamp: FileStamp,
+    /// When the file was last compared against its offer.
+    checked_at: Instant,
+}
+
+impl ServedFile {
+    /// Opens the file, refusing it if it no longer matches its offer.
+    async fn open(offered: &OfferedFile) -> Result<Self, ServedFileError> {
+        let path = offered.path.clone();
+        let (file, stamp) = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(path)?;
+            let stamp = FileStamp::of(&file.metadata()?);
+            Ok((file, stamp))
+        })
+        .await
+        .map_err(|e| ServedFileError::Io(io::Error::other(e)))?
+        .map_err(ServedFileError::Io)?;
+        if stamp != offered.stamp {
+            return Err(ServedFileError::Changed);
+        }
+        Ok(ServedFile { file: Arc::new(file), stamp, checked_at: Instant::now() })
+    }
+
+    /// Reads chunk `chunk_index`. The file is compared against its offer again every
+    /// `SERVED_FILE_CHECK_INTERVAL` and before the last chunk, and whenever it turns out shorter than offered.
+    async fn read_chunk(&mut self, chunk_index: u64, chunk_size: usize) -> Result<Vec<u8>, ServedFileError> {
+        let size = self.stamp.size;
+        let out_of_bounds = || ServedFileError::Io(io::Error::new(io::ErrorKind::InvalidInput, "Requested chunk index out of bounds"));
+        let offset = chunk_index.checked_mul(chunk_size as u64).ok_or_else(out_of_bounds)?;
+        // Chunk 0 of an empty file is the one valid empty chunk
+        if offset >= size && chunk_index > 0 {
+            return Err(out_of_bounds());
+        }
+        let len = (size - offset).min(chunk_size as u64) as usize;
+        let check = offset + len as u64 == size || self.checked_at.elapsed() >= SERVED_FILE_CHECK_INTERVAL;
+        if check {
+            self.checked_at = Instant::now();
+        }
+
+        let file = self.file.clone();
+        let stamp = self.stamp;
+        tokio::task::spawn_blocking(move || {
+            if check && FileStamp::of(&file.metadata().map_err(ServedFileError::Io)?) != stamp {
+                return Err(ServedFileError::Changed);
+            }
+            let mut data = vec![0u8; len];
+            let mut filled = 0;
+            while filled < len {
+                match read_at(&file, &mut data[filled..], offset + filled as u64) {
+                    Ok(0) => return Err(ServedFileError::Changed),
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(ServedFileError::Io(e)),
+                }
+            }
+            Ok(data)
+        })
+        .await
+        .map_err(|e| ServedFileError::Io(io::Error::other(e)))?
+    }
+}
+
+/// Reads chunk `chunk_index` of an offered file for a peer on 1.0.0, which asks for every chunk with a
+/// `RequestChunk` message (in chunks of `LEGACY_CHUNK_SIZE`). Returns the chunk and whether it is the last one.
+pub async fn read_requested_chunk(offered: &OfferedFile, chunk_index: u64) -> Result<(Vec<u8>, bool), String> {
+    let chunk_size = crate::protocol::LEGACY_CHUNK_SIZE;
+    let path = offered.path.as_path();
+    // Opened for every chunk, as there is no stream to keep it open for
+    let mut file = ServedFile::open(offered).await.map_err(|e| e.describe(path, "Failed to open"))?;
+    let data = file.read_chunk(chunk_index, chunk_size as usize).await.map_err(|e| e.describe(path, "Failed to read"))?;
+    let is_last = chunk_index * chunk_size + data.len() as u64 >= offered.stamp.size;
+    Ok((data, is_last))
+}
+
+/// Reads from `offset` without using (or, on Unix, moving) the file's cursor.
+#[cfg(unix)]
+fn read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Reads from `offset`. Every read names its offset, so the moved cursor doesn't matter.
+#[cfg(windows)]
+fn read_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `samples` RTTs of `millis` each to the window.
+    fn feed(window: &mut ChunkWindow, millis: u64, samples: usize) {
+        for _ in 0..samples {
+            window.on_rtt_sample(Duration::from_millis(millis));
+        }
+    }
+
+    #[test]
+    fn window_grows_while_the_rtt_stays_flat() {
+        let mut window = ChunkWindow::default();
+        assert_eq!(window.size(), INITIAL_CHUNK_WINDOW);
+        feed(&mut window, 50, 1);
+        assert_eq!(window.size(), INITIAL_CHUNK_WINDOW + 1);
+        feed(&mut window, 50, 2 * MAX_CHUNK_WINDOW);
+        assert_eq!(window.size(), MAX_CHUNK_WINDOW);
+    }
+
+    #[test]
+    fn window_shrinks_once_requests_queue_up() {
+        let mut window = ChunkWindow::default();
+        feed(&mut window, 50, 2 * MAX_CHUNK_WINDOW);
+        // The RTT climbs to four times the idle RTT and stays there
+        let mut previous = window.size();
+        for _ in 0..20 * MAX_CHUNK_WINDOW {
+            feed(&mut window, 200, 1);
+            assert!(window.size() <= previous);
+            previous = window.size();
+        }
+        assert_eq!(window.size(), MIN_CHUNK_WINDOW);
+    }
+
+    #[test]
+    fn window_backs_off_after_a_loss_and_recovers() {
+        let mut window = ChunkWindow::default();
+        feed(&mut window, 50, 2 * MAX_CHUNK_WINDOW);
+        // A lost segment is resent by the stream, so its chunk shows up as one very late answer
+        feed(&mut window, 2000, 1);
+        assert_eq!(window.size(), MAX_CHUNK_WINDOW - 1);
+        // Every chunk queued behind it is late too, until the backlog has cleared
+        feed(&mut window, 1000, 4);
+        assert!(window.size() < MAX_CHUNK_WINDOW - 1);
+        feed(&mut window, 50, 20 * MAX_CHUNK_WINDOW);
+        assert_eq!(window.size(), MAX_CHUNK_WINDOW);
+    }
+
+    #[test]
+    fn downloads_refuse_chunk_sizes_out_of_range() {
+        let new_state = |chunk_size| DownloadState::new(PathBuf::from("notes.txt.tmp"), 0, 100_000, chunk_size, String::new());
+        assert!(new_state(0).is_err());
+        assert!(new_state(MIN_CHUNK_SIZE as u64 - 1).is_err());
+        assert!(new_state(MAX_CHUNK_SIZE as u64 + 1).is_err());
+        assert_eq!(new_state(MIN_CHUNK_SIZE as u64).unwrap().total_chunks, 100_000u64.div_ceil(MIN_CHUNK_SIZE as u64));
+        // An empty file still takes one chunk
+        let empty = DownloadState::new(PathBuf::from("empty.tmp"), 0, 0, MAX_CHUNK_SIZE as u64, String::new()).unwrap();
+        assert_eq!(empty.total_chunks, 1);
+    }
+
+    #[test]
+    fn frames_parse_back_to_what_was_sent() {
+        let mut stream = chunk_frame(b"chunk data");
+        stream.extend_from_slice(&queued_frame(3));
+        stream.extend_from_slice(&chunk_frame(b""));
+        stream.extend_from_slice(&error_frame(NOT_OFFERED));
+
+        let mut rest = stream.as_slice();
+        let mut responses = Vec::new();
+        while let Some((response, len)) = parse_response(rest).unwrap() {
+            responses.push(response);
+            rest = &rest[len..];
+        }
+        assert!(rest.is_empty());
+        assert!(matches!(&responses[..], [
+            Response::Chunk(Ok(data)),
+            Response::Queued(3),
+            Response::Chunk(Ok(empty)),
+            Response::Chunk(Err(message)),
+        ] if data.as_ref() == b"chunk data" && empty.is_empty() && message == NOT_OFFERED));
+    }
+
+    #[test]
+    fn overlong_error_messages_are_cut_to_fit() {
+        let message = "x".repeat(u16::MAX as usize + 10);
+        let frame = error_frame(&message);
+        let Some((Response::Chunk(Err(parsed)), len)) = parse_response(&frame).unwrap() else { panic!("not an error response") };
+        assert_eq!(len, frame.len());
+        assert_eq!(parsed.len(), u16::MAX as usize);
+    }
+
+    #[test]
+    fn truncated_frames_wait_for_more_bytes() {
+        for frame in [chunk_frame(b"chunk data"), error_frame("gone"), queued_frame(7).to_vec()] {
+            for len in 0..frame.len() {
+                assert!(parse_response(&frame[..len]).unwrap().is_none(), "{} of {} bytes", len, frame.len());
+            }
+            assert!(parse_response(&frame).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_invalid_data() {
+        // Unknown status
+        let error = parse_response(&[9, 0, 0, 0, 0]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // A chunk longer than any sender may serve is refused before its bytes arrive
+        let mut frame = vec![STATUS_OK];
+        frame.extend_from_slice(&(MAX_CHUNK_SIZE as u32 + 1).to_be_bytes());
+        let error = parse_response(&frame).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn served_files_that_change_after_their_offer_are_refused() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("swapbytes-served-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("served.txt");
+        let offer = || {
+            std::fs::write(&path, b"hello world!").unwrap();
+            OfferedFile::hash(path.clone()).unwrap().1
+        };
+
+        // Unchanged, every chunk reads back
+        let offered = offer();
+        let mut file = ServedFile::open(&offered).await.unwrap();
+        assert_eq!(file.read_chunk(0, 4).await.unwrap(), b"hell");
+        assert_eq!(file.read_chunk(2, 4).await.unwrap(), b"rld!");
+
+        // Grown: refused on opening, and when the last chunk is checked
+        let mut file = ServedFile::open(&offered).await.unwrap();
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b" more").unwrap();
+        assert!(matches!(ServedFile::open(&offered).await, Err(ServedFileError::Changed)));
+        assert!(matches!(file.read_chunk(2, 4).await, Err(ServedFileError::Changed)));
+
+        // Cut short: a chunk between checks comes up empty
+        let offered = offer();
+        let mut file = ServedFile::open(&offered).await.unwrap();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(2).unwrap();
+        assert!(matches!(ServedFile::open(&offered).await, Err(ServedFileError::Changed)));
+        assert!(matches!(file.read_chunk(1, 4).await, Err(ServedFileError::Changed)));
+
+        // Rewritten in place: same size, new modification time
+        let offered = offer();
+        let mut file = ServedFile::open(&offered).await.unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        assert!(matches!(ServedFile::open(&offered).await, Err(ServedFileError::Changed)));
+        assert!(matches!(file.read_chunk(2, 4).await, Err(ServedFileError::Changed)));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chunks_held_back_by_a_slow_limit_keep_the_stream_alive() {
+        // At 1 KiB/s, a second chunk has to wait far longer than the other side waits for it
+        let peer_id = PeerId::random();
+        let limiter = RateLimiter::new(crate::ratelimit::RateLimits { upload: Some(1024), ..Default::default() });
+        limiter.reserve(peer_id, TransferDirection::Upload, MAX_CHUNK_SIZE as u64);
+        let wait = limiter.reserve(peer_id, TransferDirection::Upload, MAX_CHUNK_SIZE as u64);
+        assert!(wait > CHUNK_REQUEST_TIMEOUT * 2, "{:?}", wait);
+
+        // The stream hears from us every QUEUE_NOTICE_INTERVAL until the chunk may go
+        let mut stream = futures::io::Cursor::new(Vec::new());
+        let start = Instant::now();
+        wait_throttled(&mut stream, wait, &queued_frame(0)).await.unwrap();
+        assert!(start.elapsed() >= wait);
+        let sent = stream.into_inner();
+        let notices = (wait.as_secs_f64() / QUEUE_NOTICE_INTERVAL.as_secs_f64()).ceil() as usize - 1;
+        assert_eq!(sent, queued_frame(0).repeat(notices));
+        // ...which the receiver takes as a sign of life, not a place in the queue
+        assert!(matches!(parse_response(&sent).unwrap(), Some((Response::Queued(0), 5))));
+
+        // A short wait needs none
+        let mut stream = futures::io::Cursor::new(Vec::new());
+        wait_throttled(&mut stream, QUEUE_NOTICE_INTERVAL / 2, &KEEPALIVE_CHUNK.to_be_bytes()).await.unwrap();
+        assert!(stream.into_inner().is_empty());
+    }
+}
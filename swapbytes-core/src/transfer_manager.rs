@@ -0,0 +1,491 @@
+/*
+The transfer manager: a task of its own that does the disk work of downloads, so a slow disk never
+holds up the swarm loop (and with it heartbeats, chat and every other peer's traffic).
+
+The swarm loop hands it commands through a `TransferManager` and carries on. The task works through
+them one at a time, in the order they were sent, so a chunk written before a download is closed,
+saved or removed is always on disk first. Whatever the loop has to act on comes back as a `DiskEvent`.
+
+The task owns the open file of every download. Downloads refer to their file by the ID it was given
+when it was created (or reopened to resume), so results for a file that has since been closed and
+replaced by another download of the same name can't be mixed up with the new one.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use libp2p::PeerId;
+use tokio::sync::mpsc;
+
+use crate::resume::{self, ResumeRecord};
+use crate::event::{Event, SwapDetails};
+
+/// What to report once a verified download has been moved to its final name.
+#[derive(Debug)]
+pub enum AfterSave {
+    /// A single file that was offered on its own.
+    File { total_size: u64, hash: String },
+    /// One file of the bundle `name`.
+    BundleFile { name: String, size: u64 },
+    /// Our side of a swap, which completes the swap.
+    Swap { details: SwapDetails, total_size: u64, hash: String },
+}
+
+/// Results the swarm loop has to act on.
+#[derive(Debug)]
+pub enum DiskEvent {
+    /// A chunk handed to `write` is on disk.
+    Written { file_id: u64 },
+    /// Every chunk written before `checkpoint` was called is on disk, so the download
+    /// can safely be resumed from (`received`, `next_chunk`).
+    Checkpoint { file_id: u64, received: u64, next_chunk: u64 },
+    /// The temporary file's name was taken, so it was created as `to` instead of `from`.
+    Renamed { file_id: u64, from: PathBuf, to: PathBuf },
+    /// The file couldn't be created or written. Nothing more is written to it.
+    Failed { file_id: u64, error: String },
+    /// A download has been moved to its final name (or couldn't be; the temporary file is gone either way).
+    Saved { peer_id: PeerId, filename: String, then: AfterSave, result: Result<PathBuf, String> },
+    /// The partial file of an interrupted download has been checked, re-hashed and reopened as `file_id`.
+    /// The hasher is boxed to keep the other events small.
+    Resumed { file_id: u64, record: ResumeRecord, result: io::Result<Box<blake3::Hasher>> },
+}
+
+enum DiskCommand {
+    Create { file_id: u64, path: PathBuf, within: Option<PathBuf> },
+    Write { file_id: u64, offset: u64, data: Bytes },
+    Checkpoint { file_id: u64, received: u64, next_chunk: u64 },
+    Close { file_id: u64, sync: bool },
+    Remove { file_id: Option<u64>, path: PathBuf },
+    Save { file_id: u64, peer_id: PeerId, filename: String, temp_path: PathBuf, then: AfterSave },
+    Resume { file_id: u64, record: ResumeRecord },
+    SaveStore { path: PathBuf, records: Vec<ResumeRecord> },
+}
+
+/// Hands disk work to the transfer manager's task. Dropping it ends the task once the
+/// commands sent so far are done.
+pub struct TransferManager {
+    commands_tx: mpsc::UnboundedSender<DiskCommand>,
+    next_file_id: u64,
+}
+
+impl TransferManager {
+    /// Starts the task. Results are sent to `events_tx`; errors nobody has to act on are logged through `swarm_tx`.
+    pub fn new(events_tx: mpsc::UnboundedSender<DiskEvent>, swarm_tx: mpsc::UnboundedSender<Event>) -> Self {
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || run(commands_rx, events_tx, swarm_tx));
+        TransferManager { commands_tx, next_file_id: 0 }
+    }
+
+    /// Creates the temporary file of a new download at `path` and returns its file ID. An existing
+    /// file is never overwritten: if `path` is taken, a number is added to it (reported as `DiskEvent::Renamed`).
+    /// With `within` set, missing directories on the way are created, and the file must end up inside `within`.
+    /// Failure is reported as `DiskEvent::Failed`.
+    pub fn create(&mut self, path: PathBuf, within: Option<PathBuf>) -> u64 {
+        let file_id = self.new_file_id();
+        self.send(DiskCommand::Create { file_id, path, within });
+        file_id
+    }
+
+    /// Writes a chunk at `offset`. Reported as `DiskEvent::Written`, or `DiskEvent::Failed`.
+    pub fn write(&self, file_id: u64, offset: u64, data: Bytes) {
+        self.send(DiskCommand::Write { file_id, offset, data });
+    }
+
+    /// Asks for a `DiskEvent::Checkpoint` once everything written so far is on disk.
+    pub fn checkpoint(&self, file_id: u64, received: u64, next_chunk: u64) {
+        self.send(DiskCommand::Checkpoint { file_id, received, next_chunk });
+    }
+
+    /// Closes a download's file once everything written so far is on disk, syncing it first if `sync` is set.
+    pub fn close(&self, file_id: u64, sync: bool) {
+        self.send(DiskCommand::Close { file_id, sync });
+    }
+
+    /// Closes the file (if it is still open) and deletes it.
+    pub fn remove(&self, file_id: Option<u64>, path: PathBuf) {
+        self.send(DiskCommand::Remove { file_id, path });
+    }
+
+    /// Moves a closed, verified download to its final name (the destination its temporary name was
+    /// made from, see `temp_path`, with a number added if that is taken). Reported as `DiskEvent::Saved`.
+    pub fn save(&self, file_id: u64, peer_id: PeerId, filename: String, temp_path: PathBuf, then: AfterSave) {
+        self.send(DiskCommand::Save { file_id, peer_id, filename, temp_path, then });
+    }
+
+    /// Prepares the partial file of an interrupted download (see `resume::prepare_partial_file`)
+    /// and reopens it under a new file ID. Reported as `DiskEvent::Resumed`.
+    pub fn resume(&mut self, record: ResumeRecord) {
+        let file_id = self.new_file_id();
+        self.send(DiskCommand::Resume { file_id, record });
+    }
+
+    /// Rewrites the download store at `path`.
+    pub fn save_store(&self, path: PathBuf, records: Vec<ResumeRecord>) {
+        self.send(DiskCommand::SaveStore { path, records });
+    }
+
+    fn new_file_id(&mut self) -> u64 {
+        let file_id = self.next_file_id;
+        self.next_file_id += 1;
+        file_id
+    }
+
+    fn send(&self, command: DiskCommand) {
+        // The task only ends once this handle is dropped
+        let _ = self.commands_tx.send(command);
+    }
+}
+
+/// The temporary file a download from `peer_id` is written to until it is saved as `destination`.
+/// The peer is part of the name, so downloads of the same name from different peers don't share a file.
+pub fn temp_path(destination: &Path, peer_id: &PeerId) -> PathBuf {
+    let mut name = destination.as_os_str().to_os_string();
+    name.push(format!(".{}.tmp", peer_tag(peer_id)));
+    PathBuf::from(name)
+}
+
+/// The destination `temp_path` made the temporary file of a download from `peer_id` from, with or
+/// without the number `create_file` adds. Downloads recorded before the peer was part of the name only end in ".tmp".
+fn destination(temp_path: &Path, peer_id: &PeerId) -> PathBuf {
+    let name = temp_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+    let tag = peer_tag(peer_id);
+    let name = match name.rsplit_once('.') {
+        Some((destination, suffix))
+            if suffix == tag
+                || suffix.strip_prefix(tag.as_str()).and_then(|n| n.strip_prefix('-')).is_some_and(|n| n.parse::<u32>().is_ok()) =>
+        {
+            destination
+        }
+        _ => name,
+    };
+    temp_path.with_file_name(name)
+}
+
+/// `path` with `-<n>` added before its ".tmp", for when `path` is taken.
+fn numbered_temp_path(path: &Path, n: u32) -> PathBuf {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+    path.with_file_name(format!("{}-{}.tmp", name, n))
+}
+
+/// The end of the peer's ID, enough to tell apart the peers offering files of the same name.
+fn peer_tag(peer_id: &PeerId) -> String {
+    let id = peer_id.to_base58();
+    id[id.len().saturating_sub(8)..].to_string()
+}
+
+/// How many numbered names are tried when a download's temporary or final name is taken.
+const MAX_NAME_ATTEMPTS: u32 = 1000;
+
+// --- Task ---
+
+/// Works through the commands until the `TransferManager` is dropped.
+fn run(mut commands_rx: mpsc::UnboundedReceiver<DiskCommand>, events_tx: mpsc::UnboundedSender<DiskEvent>, swarm_tx: mpsc::UnboundedSender<Event>) {
+    let mut files: HashMap<u64, fs::File> = HashMap::new();
+    // Files whose writes failed. Their downloads are dropped, and must never be saved.
+    let mut failed: HashSet<u64> = HashSet::new();
+    // Files created under another name than the one asked for, until they are saved or removed
+    let mut renamed: HashMap<u64, PathBuf> = HashMap::new();
+
+    while let Some(command) = commands_rx.blocking_recv() {
+        match command {
+            DiskCommand::Create { file_id, path, within } => match create_file(&path, within.as_deref()) {
+                Ok((file, created)) => {
+                    files.insert(file_id, file);
+                    if created != path {
+                        renamed.insert(file_id, created.clone());
+                        let _ = events_tx.send(DiskEvent::Renamed { file_id, from: path, to: created });
+                    }
+                }
+                Err(error) => {
+                    failed.insert(file_id);
+                    let _ = events_tx.send(DiskEvent::Failed { file_id, error });
+                }
+            },
+            DiskCommand::Write { file_id, offset, data } => {
+                // Writes to a file that failed or was closed are dropped
+                let Some(file) = files.get(&file_id) else { continue };
+                match write_all_at(file, &data, offset) {
+                    Ok(()) => {
+                        let _ = events_tx.send(DiskEvent::Written { file_id });
+                    }
+                    Err(e) => {
+                        files.remove(&file_id);
+                        failed.insert(file_id);
+                        let _ = events_tx.send(DiskEvent::Failed { file_id, error: format!("Failed to write to file: {}", e) });
+                    }
+                }
+            }
+            DiskCommand::Checkpoint { file_id, received, next_chunk } => {
+                // Writes go straight to the file, so everything before this command is there already
+                if files.contains_key(&file_id) {
+                    let _ = events_tx.send(DiskEvent::Checkpoint { file_id, received, next_chunk });
+                }
+            }
+            DiskCommand::Close { file_id, sync } => {
+                if let Some(file) = files.remove(&file_id)
+                    && sync
+                    && let Err(e) = file.sync_all()
+                {
+                    let _ = swarm_tx.send(Event::LogMessage(format!("[Transfer Manager] Error syncing file {}: {}", file_id, e)));
+                }
+            }
+            DiskCommand::Remove { file_id, path } => {
+                // Close the file before removing it
+                let path = match file_id {
+                    Some(file_id) => {
+                        files.remove(&file_id);
+                        failed.remove(&file_id);
+                        renamed.remove(&file_id).unwrap_or(path)
+                    }
+                    None => path,
+                };
+                let _ = fs::remove_file(&path);
+            }
+            DiskCommand::Save { file_id, peer_id, filename, temp_path, then } => {
+                files.remove(&file_id);
+                // The swarm loop may not have seen `DiskEvent::Renamed` yet
+                let temp_path = renamed.remove(&file_id).unwrap_or(temp_path);
+                let result = if failed.remove(&file_id) {
+                    let _ = fs::remove_file(&temp_path);
+                    Err("Part of the file could not be written to disk".to_string())
+                } else {
+                    save_file(&temp_path, &peer_id, &swarm_tx)
+                };
+                let _ = events_tx.send(DiskEvent::Saved { peer_id, filename, then, result });
+            }
+            DiskCommand::Resume { file_id, record } => {
+                let result = resume::prepare_partial_file(&record).map(|(file, hasher)| {
+                    files.insert(file_id, file);
+                    Box::new(hasher)
+                });
+                let _ = events_tx.send(DiskEvent::Resumed { file_id, record, result });
+            }
+            DiskCommand::SaveStore { path, records } => {
+                if let Err(e) = resume::save_records(&path, &records) {
+                    let _ = swarm_tx.send(Event::LogMessage(format!("[Swarm] {}", e)));
+                }
+            }
+        }
+    }
+}
+
+/// Creates a download's temporary file at `path`, or if that is taken (say, by the partial file of an
+/// interrupted download) at `path` with a number added. Returns the file and where it was created.
+/// With `within` set, rebuilds the directories leading up to it, which must stay inside `within`.
+fn create_file(path: &Path, within: Option<&Path>) -> Result<(fs::File, PathBuf), String> {
+    if let (Some(dir), Some(parent)) = (within, path.parent()) {
+        create_dirs_within(dir, parent)?;
+    }
+    let mut candidate = path.to_path_buf();
+    for n in 1..=MAX_NAME_ATTEMPTS {
+        match fs::OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(file) => return Ok((file, candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => candidate = numbered_temp_path(path, n),
+            Err(e) => return Err(format!("could not create temp file '{}': {}", candidate.display(), e)),
+        }
+    }
+    Err(format!("could not find a free name for temp file '{}'", path.display()))
+}
+
+/// Creates the directories from `dir` down to `parent`, one level at a time. Each level must be a real
+/// directory: one that already exists as a symlink (or anything else) could lead outside `dir`, so
+/// nothing is created below it.
+fn create_dirs_within(dir: &Path, parent: &Path) -> Result<(), String> {
+    let outside = || format!("'{}' is outside the download directory", parent.display());
+    let relative = parent.strip_prefix(dir).map_err(|_| outside())?;
+    // The download directory itself is ours to create
+    fs::create_dir_all(dir).map_err(|e| format!("could not create directory '{}': {}", dir.display(), e))?;
+    let mut level = dir.to_path_buf();
+    for component in relative.components() {
+        let std::path::Component::Normal(name) = component else {
+            return Err(outside());
+        };
+        level.push(name);
+        match fs::create_dir(&level) {
+            Ok(()) => continue,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(format!("could not create directory '{}': {}", level.display(), e)),
+        }
+        // Already there: only go on into it if it is a directory itself, not a link to one
+        let is_dir = fs::symlink_metadata(&level).is_ok_and(|metadata| metadata.file_type().is_dir());
+        if !is_dir {
+            return Err(format!("'{}' is not a directory inside the download directory", level.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Moves a download from its temporary name to its final one and returns that.
+/// The temporary file is removed if it can't be moved.
+fn save_file(local_path: &Path, peer_id: &PeerId, swarm_tx: &mpsc::UnboundedSender<Event>) -> Result<PathBuf, String> {
+    // --- Rename Temporary File ---
+    // Construct final path and handle potential collisions
+    let original_final_path = destination(local_path, peer_id);
+    let mut final_path = original_final_path.clone();
+
+    // Add a number if filename collision occurs. The move itself finds out whether a name is taken,
+    // so a file that turns up under it in the meantime is never replaced.
+    for counter in 1..=MAX_NAME_ATTEMPTS {
+        match move_no_replace(local_path, &final_path) {
+            Ok(()) => return Ok(final_path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let new_stem = format!(
+                    "{}_({})",
+                    original_final_path.file_stem().unwrap_or_default().to_string_lossy(),
+                    counter
+                );
+                final_path.set_file_name(new_stem);
+                if let Some(ext) = original_final_path.extension() {
+                    final_path.set_extension(ext);
+                }
+            }
+            Err(e) => {
+                let _ = swarm_tx.send(Event::LogMessage(format!(
+                    "[Transfer Manager] Error renaming temp file '{}' to '{}': {}. Download failed.",
+                    local_path.display(), final_path.display(), e
+                )));
+                // Attempt cleanup of temp file
+                let _ = fs::remove_file(local_path);
+                return Err(format!("Failed to rename temp file: {}", e));
+            }
+        }
+    }
+    let _ = swarm_tx.send(Event::LogMessage(format!("[Transfer Manager] Error: Could not find unique filename for '{}' after {} attempts. Aborting rename.", original_final_path.display(), MAX_NAME_ATTEMPTS)));
+    // Attempt cleanup of temp file
+    let _ = fs::remove_file(local_path);
+    Err("Failed to create unique final filename".to_string())
+}
+
+/// Moves `from` to `to`, failing with `AlreadyExists` instead of replacing a file that is already there.
+fn move_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Ok(()) => {
+            // The file is saved either way; at worst the temporary name is left behind
+            let _ = fs::remove_file(from);
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        // Filesystems without hard links (FAT, some network shares): claim the name, then move onto the claim
+        Err(_) => {
+            fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+            fs::rename(from, to).inspect_err(|_| {
+                let _ = fs::remove_file(to);
+            })
+        }
+    }
+}
+
+/// Writes all of `data` at `offset`. Chunks arrive in any order, so each one goes to its own offset.
+#[cfg(unix)]
+fn write_all_at(file: &fs::File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+/// Writes all of `data` at `offset`. Every write names its offset, so the moved cursor doesn't matter.
+#[cfg(windows)]
+fn write_all_at(file: &fs::File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    while !data.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, data, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole chunk")),
+            Ok(n) => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temporary_names_lead_back_to_their_destination() {
+        let peer_id = PeerId::random();
+        let destination_path = Path::new("/downloads/report.tar.gz");
+        let temp = temp_path(destination_path, &peer_id);
+        assert!(temp.to_string_lossy().ends_with(".tmp"));
+        assert_ne!(temp, temp_path(destination_path, &PeerId::random()));
+        assert_eq!(destination(&temp, &peer_id), destination_path);
+
+        // A temporary file created with a number added because its name was taken
+        assert_eq!(destination(&numbered_temp_path(&temp, 2), &peer_id), destination_path);
+
+        // A download recorded before the peer was part of the temporary name
+        assert_eq!(destination(Path::new("/downloads/report.tar.gz.tmp"), &peer_id), destination_path);
+    }
+
+    #[test]
+    fn taken_names_are_never_overwritten() {
+        let dir = std::env::temp_dir().join(format!("swapbytes-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let peer_id = PeerId::random();
+        let destination_path = dir.join("notes.txt");
+        let temp = temp_path(&destination_path, &peer_id);
+
+        // The partial file of an interrupted download is in the way
+        fs::write(&temp, b"partial").unwrap();
+        let (_, created) = create_file(&temp, None).unwrap();
+        assert_ne!(created, temp);
+        assert_eq!(fs::read(&temp).unwrap(), b"partial");
+
+        // Saving twice under the same name counts up instead of replacing the first file
+        let (swarm_tx, _swarm_rx) = mpsc::unbounded_channel();
+        assert_eq!(save_file(&temp, &peer_id, &swarm_tx).unwrap(), destination_path);
+        assert_eq!(save_file(&created, &peer_id, &swarm_tx).unwrap(), dir.join("notes_(1).txt"));
+        assert_eq!(fs::read(&destination_path).unwrap(), b"partial");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saving_never_replaces_a_file_that_appeared_meanwhile() {
+        let dir = std::env::temp_dir().join(format!("swapbytes-save-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let temp = dir.join("download.tmp");
+        let taken = dir.join("taken.txt");
+        fs::write(&temp, b"new").unwrap();
+        fs::write(&taken, b"theirs").unwrap();
+
+        assert_eq!(move_no_replace(&temp, &taken).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&taken).unwrap(), b"theirs");
+        assert_eq!(fs::read(&temp).unwrap(), b"new");
+
+        move_no_replace(&temp, &dir.join("free.txt")).unwrap();
+        assert!(!temp.exists());
+        assert_eq!(fs::read(dir.join("free.txt")).unwrap(), b"new");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_are_never_created_through_a_symlink() {
+        let base = std::env::temp_dir().join(format!("swapbytes-dirs-test-{}", std::process::id()));
+        let downloads = base.join("downloads");
+        let elsewhere = base.join("elsewhere");
+        fs::create_dir_all(&downloads).unwrap();
+        fs::create_dir_all(&elsewhere).unwrap();
+        std::os::unix::fs::symlink(&elsewhere, downloads.join("photos")).unwrap();
+
+        // A bundle path through the symlinked directory creates nothing on the other side
+        let error = create_file(&downloads.join("photos/2024/beach.jpg.tmp"), Some(&downloads)).unwrap_err();
+        assert!(error.contains("not a directory"), "{}", error);
+        assert!(!elsewhere.join("2024").exists());
+
+        // Real directories are created level by level
+        let (_, created) = create_file(&downloads.join("music/2024/song.mp3.tmp"), Some(&downloads)).unwrap();
+        assert_eq!(created, downloads.join("music/2024/song.mp3.tmp"));
+        assert!(create_file(&base.join("outside.tmp"), Some(&downloads)).is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -5,9 +5,10 @@ Functions for utility operations.
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 // --- libp2p Imports ---
-use libp2p::PeerId;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
 
 /// Verifies if a given path is suitable as a download directory.
 ///
@@ -144,6 +145,34 @@ pub fn verify_offer_file(path_str: &str) -> Result<(PathBuf, u64), String> {
     }
 }
 
+/// Computes the BLAKE3 hash of everything `reader` yields (e.g. an open file), returned as a lowercase hex string.
+///
+/// Reads the whole file, so it should be run on a blocking thread (e.g. via `spawn_blocking`).
+pub fn hash_reader(reader: impl io::Read) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(reader)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Shortens a hex hash for display (e.g. "3f2a9c01b7d4…").
+pub fn short_hash(hash: &str) -> String {
+    if hash.len() <= 12 {
+        hash.to_string()
+    } else {
+        format!("{}…", &hash[..12])
+    }
+}
+
+/// Picks the nickname announced until the user sets one, e.g. "user9350".
+pub fn random_nickname() -> String {
+    format!("user{:04}", rand::random_range(0..10000u16))
+}
+
+/// Creates a short random ID for a file offer, easy to type in `/accept <id>`.
+pub fn new_offer_id() -> String {
+    format!("{:06x}", rand::random::<u32>() & 0xff_ffff)
+}
+
 /// Formats a byte count into a human-readable string with units (Bytes, KB, MB, GB).
 ///
 /// Examples:
@@ -177,6 +206,64 @@ pub fn format_bytes(bytes: u64) -> String {
     }
 }
 
+/// Formats a transfer rate in bytes per second, e.g. "1.25 MB/s".
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec.max(0.0) as u64))
+}
+
+/// Parses a transfer rate like "500K", "1.5M", "2MB/s" or "80000" (bytes per second).
+/// Units are powers of 1024, as in `format_bytes`. "off" means no limit and returns `None`.
+pub fn parse_rate(text: &str) -> Result<Option<u64>, String> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("off") || text.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    // Accept "1M", "1MB", "1MB/s" and "1 MB/s" alike
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_uppercase();
+    let compact = compact.strip_suffix("/S").unwrap_or(&compact);
+    let bytes = parse_bytes(compact).ok_or_else(|| format!("'{}' is not a rate. Use e.g. 500K, 2M or off.", text))?;
+    if bytes < 1.0 {
+        return Err(format!("'{}' is too low. Use off to remove a limit.", text));
+    }
+    Ok(Some(bytes as u64))
+}
+
+/// Parses a size like "700M", "4.5G", "10 KB" or "2048" (bytes). Units are powers of 1024, as in `format_bytes`.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_uppercase();
+    let bytes = parse_bytes(&compact).ok_or_else(|| format!("'{}' is not a size. Use e.g. 700M or 4G.", text))?;
+    Ok(bytes as u64)
+}
+
+/// Reads a number of bytes with an optional K, M or G unit (and B), without spaces, in upper case.
+fn parse_bytes(compact: &str) -> Option<f64> {
+    let compact = compact.strip_suffix('B').unwrap_or(compact);
+    let (number, multiplier) = match compact.chars().last() {
+        Some('K') => (&compact[..compact.len() - 1], 1024.0),
+        Some('M') => (&compact[..compact.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&compact[..compact.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (compact, 1.0),
+    };
+    let value: f64 = number.parse().ok()?;
+    let bytes = (value * multiplier).round();
+    (bytes.is_finite() && bytes >= 0.0).then_some(bytes)
+}
+
+/// Formats a duration as minutes and seconds, or hours once it is that long.
+///
+/// Examples:
+/// - `format_duration(Duration::from_secs(75))` -> "1:15"
+/// - `format_duration(Duration::from_secs(3725))` -> "1:02:05"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
 // --- PeerId Utilities ---
 
 /// Converts a PeerId into a short, readable string (e.g., "user(...abcdef)").
@@ -189,3 +276,63 @@ pub fn peer_id_to_short_string(peer_id: &PeerId) -> String {
         format!("user(...{})", &id_str[len - 6..])
     }
 }
+
+// --- Multiaddr Utilities ---
+
+/// Whether an address is reached over QUIC (e.g. "/ip4/192.168.1.5/udp/4001/quic-v1").
+pub fn is_quic(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| matches!(protocol, Protocol::QuicV1 | Protocol::Quic))
+}
+
+/// Whether an address goes through a relay (e.g. "/ip4/203.0.113.7/tcp/62649/p2p/12D3Koo…/p2p-circuit").
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| matches!(protocol, Protocol::P2pCircuit))
+}
+
+/// Names the transport of an address for display: "relayed", "QUIC", "TCP" or "other".
+pub fn transport_name(addr: &Multiaddr) -> &'static str {
+    if is_relayed(addr) {
+        "relayed"
+    } else if is_quic(addr) {
+        "QUIC"
+    } else if addr.iter().any(|protocol| matches!(protocol, Protocol::Tcp(_))) {
+        "TCP"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_take_units_and_spellings_alike() {
+        assert_eq!(parse_rate("512K"), Ok(Some(512 * 1024)));
+        assert_eq!(parse_rate("2M"), Ok(Some(2 * 1024 * 1024)));
+        assert_eq!(parse_rate("2 mb/s"), Ok(Some(2 * 1024 * 1024)));
+        assert_eq!(parse_rate("1.5K"), Ok(Some(1536)));
+        assert_eq!(parse_rate("100"), Ok(Some(100)));
+        assert_eq!(parse_rate("off"), Ok(None));
+        assert_eq!(parse_rate("None"), Ok(None));
+    }
+
+    #[test]
+    fn bad_or_zero_rates_are_refused() {
+        for text in ["", "fast", "2X", "M", "-1M", "1M/min", "inf"] {
+            assert!(parse_rate(text).is_err(), "{:?}", text);
+        }
+        // A limit of nothing would stall every transfer; "off" is how a limit is lifted
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("0K").is_err());
+    }
+
+    #[test]
+    fn sizes_share_the_rate_units() {
+        assert_eq!(parse_size("700M"), Ok(700 * 1024 * 1024));
+        assert_eq!(parse_size("4.5G"), Ok(4608 * 1024 * 1024));
+        assert_eq!(parse_size("10 KB"), Ok(10 * 1024));
+        assert_eq!(parse_size("0"), Ok(0));
+        assert!(parse_size("big").is_err());
+    }
+}